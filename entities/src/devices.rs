@@ -9,12 +9,30 @@ pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub id: Uuid,
     pub owner_id: Uuid,
+    pub sync_scan_offset: Option<i64>,
+    pub proxy_store: Option<bool>,
+    pub last_sync_token: Option<String>,
+    pub last_sync_token_at: Option<DateTimeUtc>,
+    pub refresh_token: Option<String>,
+    pub sync_tag: Option<String>,
+    pub last_analytics: Option<String>,
+    pub last_analytics_at: Option<DateTimeUtc>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(has_many = "super::book_sync::Entity")]
     BookSync,
+    #[sea_orm(has_one = "super::device_sync_state::Entity")]
+    DeviceSyncState,
+    #[sea_orm(has_many = "super::device_users::Entity")]
+    DeviceUsers,
+    #[sea_orm(has_many = "super::reading_state::Entity")]
+    ReadingState,
+    #[sea_orm(has_many = "super::sync_error::Entity")]
+    SyncError,
+    #[sea_orm(has_one = "super::sync_state::Entity")]
+    SyncState,
     #[sea_orm(
         belongs_to = "super::user::Entity",
         from = "Column::OwnerId",
@@ -31,6 +49,36 @@ impl Related<super::book_sync::Entity> for Entity {
     }
 }
 
+impl Related<super::device_sync_state::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeviceSyncState.def()
+    }
+}
+
+impl Related<super::device_users::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeviceUsers.def()
+    }
+}
+
+impl Related<super::reading_state::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::ReadingState.def()
+    }
+}
+
+impl Related<super::sync_error::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::SyncError.def()
+    }
+}
+
+impl Related<super::sync_state::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::SyncState.def()
+    }
+}
+
 impl Related<super::user::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::User.def()
@@ -3,5 +3,10 @@
 pub mod prelude;
 
 pub mod book_sync;
+pub mod device_sync_state;
+pub mod device_users;
 pub mod devices;
+pub mod reading_state;
+pub mod sync_error;
+pub mod sync_state;
 pub mod user;
@@ -1,5 +1,10 @@
 //! `SeaORM` Entity, @generated by sea-orm-codegen 1.1.14
 
 pub use super::book_sync::Entity as BookSync;
+pub use super::device_sync_state::Entity as DeviceSyncState;
+pub use super::device_users::Entity as DeviceUsers;
 pub use super::devices::Entity as Devices;
+pub use super::reading_state::Entity as ReadingState;
+pub use super::sync_error::Entity as SyncError;
+pub use super::sync_state::Entity as SyncState;
 pub use super::user::Entity as User;
@@ -13,10 +13,18 @@ pub struct Model {
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
+    #[sea_orm(has_many = "super::device_users::Entity")]
+    DeviceUsers,
     #[sea_orm(has_many = "super::devices::Entity")]
     Devices,
 }
 
+impl Related<super::device_users::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::DeviceUsers.def()
+    }
+}
+
 impl Related<super::devices::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Devices.def()
@@ -3,6 +3,19 @@ pub use sea_orm_migration::prelude::*;
 mod m20250819_215543_create_user_table;
 mod m20250820_115221_create_devices_table;
 mod m20250820_115913_create_book_sync_table;
+mod m20261016_090000_create_sync_error_table;
+mod m20261016_100000_create_reading_state_table;
+mod m20261016_110000_add_sync_scan_offset_to_devices;
+mod m20261016_120000_add_proxy_store_to_devices;
+mod m20261016_130000_create_device_sync_state_table;
+mod m20261016_140000_add_last_sync_token_to_devices;
+mod m20261016_150000_add_failure_count_to_sync_error;
+mod m20261016_160000_add_auth_tokens_to_devices;
+mod m20261016_170000_add_sync_tag_to_devices;
+mod m20261016_180000_add_last_analytics_to_devices;
+mod m20261016_190000_create_device_users_table;
+mod m20261016_200000_create_sync_state_table;
+mod m20261016_210000_book_sync_timestamp_with_time_zone;
 
 pub struct Migrator;
 
@@ -13,6 +26,19 @@ impl MigratorTrait for Migrator {
             Box::new(m20250819_215543_create_user_table::Migration),
             Box::new(m20250820_115221_create_devices_table::Migration),
             Box::new(m20250820_115913_create_book_sync_table::Migration),
+            Box::new(m20261016_090000_create_sync_error_table::Migration),
+            Box::new(m20261016_100000_create_reading_state_table::Migration),
+            Box::new(m20261016_110000_add_sync_scan_offset_to_devices::Migration),
+            Box::new(m20261016_120000_add_proxy_store_to_devices::Migration),
+            Box::new(m20261016_130000_create_device_sync_state_table::Migration),
+            Box::new(m20261016_140000_add_last_sync_token_to_devices::Migration),
+            Box::new(m20261016_150000_add_failure_count_to_sync_error::Migration),
+            Box::new(m20261016_160000_add_auth_tokens_to_devices::Migration),
+            Box::new(m20261016_170000_add_sync_tag_to_devices::Migration),
+            Box::new(m20261016_180000_add_last_analytics_to_devices::Migration),
+            Box::new(m20261016_190000_create_device_users_table::Migration),
+            Box::new(m20261016_200000_create_sync_state_table::Migration),
+            Box::new(m20261016_210000_book_sync_timestamp_with_time_zone::Migration),
         ]
     }
 }
@@ -42,4 +42,12 @@ pub enum Devices {
     Table,
     Id,
     OwnerId,
+    SyncScanOffset,
+    ProxyStore,
+    LastSyncToken,
+    LastSyncTokenAt,
+    RefreshToken,
+    SyncTag,
+    LastAnalytics,
+    LastAnalyticsAt,
 }
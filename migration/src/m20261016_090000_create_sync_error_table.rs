@@ -0,0 +1,52 @@
+use crate::m20250820_115221_create_devices_table::Devices;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(SyncError::Table)
+                    .if_not_exists()
+                    .col(uuid(SyncError::Id).primary_key())
+                    .col(uuid(SyncError::DeviceId))
+                    .col(string(SyncError::AbsItemId))
+                    .col(string(SyncError::Error))
+                    .col(timestamp_with_time_zone(SyncError::Timestamp))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_sync_error_device_id")
+                            .from(SyncError::Table, SyncError::DeviceId)
+                            .to(Devices::Table, Devices::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(SyncError::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum SyncError {
+    Table,
+    Id,
+    DeviceId,
+    AbsItemId,
+    Error,
+    Timestamp,
+    FailureCount,
+    ItemUpdatedAt,
+}
@@ -0,0 +1,50 @@
+use crate::m20250820_115221_create_devices_table::Devices;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ReadingState::Table)
+                    .if_not_exists()
+                    .col(uuid(ReadingState::Id).primary_key())
+                    .col(uuid(ReadingState::DeviceId))
+                    .col(string(ReadingState::AbsItemId))
+                    .col(json(ReadingState::State))
+                    .col(timestamp_with_time_zone(ReadingState::LastModified))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_reading_state_device_id")
+                            .from(ReadingState::Table, ReadingState::DeviceId)
+                            .to(Devices::Table, Devices::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ReadingState::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum ReadingState {
+    Table,
+    Id,
+    DeviceId,
+    AbsItemId,
+    State,
+    LastModified,
+}
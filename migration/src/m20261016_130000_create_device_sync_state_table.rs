@@ -0,0 +1,48 @@
+use crate::m20250820_115221_create_devices_table::Devices;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DeviceSyncState::Table)
+                    .if_not_exists()
+                    .col(uuid(DeviceSyncState::DeviceId).primary_key())
+                    .col(big_integer(DeviceSyncState::CursorUpdatedAt))
+                    .col(string(DeviceSyncState::CursorItemId))
+                    .col(timestamp_with_time_zone(DeviceSyncState::LastModified))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_device_sync_state_device_id")
+                            .from(DeviceSyncState::Table, DeviceSyncState::DeviceId)
+                            .to(Devices::Table, Devices::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DeviceSyncState::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum DeviceSyncState {
+    Table,
+    DeviceId,
+    CursorUpdatedAt,
+    CursorItemId,
+    LastModified,
+}
@@ -0,0 +1,51 @@
+use crate::m20261016_090000_create_sync_error_table::SyncError;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite only supports one column per ALTER TABLE
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SyncError::Table)
+                    .add_column(integer(SyncError::FailureCount).default(1))
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SyncError::Table)
+                    .add_column(big_integer_null(SyncError::ItemUpdatedAt))
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SyncError::Table)
+                    .drop_column(SyncError::ItemUpdatedAt)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(SyncError::Table)
+                    .drop_column(SyncError::FailureCount)
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use crate::{
+    m20250819_215543_create_user_table::User, m20250820_115221_create_devices_table::Devices,
+};
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(DeviceUsers::Table)
+                    .if_not_exists()
+                    .col(uuid(DeviceUsers::Id).primary_key())
+                    .col(uuid(DeviceUsers::DeviceId))
+                    .col(uuid(DeviceUsers::UserId))
+                    .col(string(DeviceUsers::UserKey))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_device_users_device_id")
+                            .from(DeviceUsers::Table, DeviceUsers::DeviceId)
+                            .to(Devices::Table, Devices::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_device_users_user_id")
+                            .from(DeviceUsers::Table, DeviceUsers::UserId)
+                            .to(User::Table, User::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        // A Kobo user key selects at most one user per device
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_device_users_device_id_user_key")
+                    .table(DeviceUsers::Table)
+                    .col(DeviceUsers::DeviceId)
+                    .col(DeviceUsers::UserKey)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(DeviceUsers::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum DeviceUsers {
+    Table,
+    Id,
+    DeviceId,
+    UserId,
+    UserKey,
+}
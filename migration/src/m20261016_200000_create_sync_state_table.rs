@@ -0,0 +1,52 @@
+use crate::m20250820_115221_create_devices_table::Devices;
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(SyncState::Table)
+                    .if_not_exists()
+                    .col(uuid(SyncState::DeviceId).primary_key())
+                    .col(timestamp_with_time_zone_null(SyncState::BooksLastModified))
+                    .col(timestamp_with_time_zone_null(SyncState::BooksLastCreated))
+                    .col(timestamp_with_time_zone_null(
+                        SyncState::ReadingStateLastModified,
+                    ))
+                    .col(timestamp_with_time_zone(SyncState::UpdatedAt))
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk_sync_state_device_id")
+                            .from(SyncState::Table, SyncState::DeviceId)
+                            .to(Devices::Table, Devices::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(SyncState::Table).to_owned())
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+pub enum SyncState {
+    Table,
+    DeviceId,
+    BooksLastModified,
+    BooksLastCreated,
+    ReadingStateLastModified,
+    UpdatedAt,
+}
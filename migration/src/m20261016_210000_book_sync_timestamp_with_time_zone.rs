@@ -0,0 +1,46 @@
+use sea_orm_migration::{prelude::*, schema::*};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // SQLite stores both column types the same way and can't alter a column's type
+        if manager.get_database_backend() == sea_orm::DatabaseBackend::Sqlite {
+            return Ok(());
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BookSync::Table)
+                    .modify_column(timestamp_with_time_zone(BookSync::Timestamp))
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        if manager.get_database_backend() == sea_orm::DatabaseBackend::Sqlite {
+            return Ok(());
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(BookSync::Table)
+                    .modify_column(timestamp(BookSync::Timestamp))
+                    .to_owned(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(DeriveIden)]
+enum BookSync {
+    Table,
+    Timestamp,
+}
@@ -1,13 +1,41 @@
 // empty
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub struct AbsClient {
     base_url: String,
     client: reqwest::Client,
+    /// Authors rarely change, so lookups are cached for the lifetime of the client
+    author_cache: Arc<Mutex<HashMap<String, Author>>>,
+    /// ABS version from `/status`, detected once per client
+    server_version: Arc<Mutex<Option<AbsVersion>>>,
+    /// Item listings keyed by library, user and query, see [`Self::get_library_items_if_changed`]
+    library_items_cache: Arc<Mutex<HashMap<String, CachedItems>>>,
+}
+
+/// How long a cached item listing may be reused. ABS bumps a library's `lastUpdate` when items
+/// are added or removed but not when one is edited, so edits show up after this at the latest.
+const LIBRARY_ITEMS_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+/// Item listings kept at most, the oldest is evicted to make room
+const LIBRARY_ITEMS_CACHE_ENTRIES: usize = 16;
+
+#[derive(Debug)]
+struct CachedItems {
+    /// Library `lastUpdate` the items were fetched at
+    last_update: i64,
+    fetched_at: Instant,
+    items: LibraryItemsResponse,
 }
 
 impl AbsClient {
@@ -19,6 +47,9 @@ impl AbsClient {
         Ok(AbsClient {
             base_url: base_url_str.trim_end_matches('/').to_string(),
             client,
+            author_cache: Default::default(),
+            server_version: Default::default(),
+            library_items_cache: Default::default(),
         })
     }
 
@@ -47,6 +78,55 @@ impl AbsClient {
         Ok(parsed)
     }
 
+    /// ABS server version, fetched from `/status` on first use and cached. `None` if ABS could
+    /// not be asked or reported no parseable version.
+    pub async fn server_version(&self) -> Option<AbsVersion> {
+        if let Some(version) = *self.server_version.lock().unwrap() {
+            return Some(version);
+        }
+        let version = match self.get_status().await {
+            Ok(status) => status.server_version.as_deref().and_then(AbsVersion::parse),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to detect ABS version");
+                None
+            }
+        };
+        if let Some(version) = version {
+            *self.server_version.lock().unwrap() = Some(version);
+        }
+        version
+    }
+
+    /// Resolve DNS and open a pooled connection to ABS ahead of the first device request with a
+    /// single `/status` call, detecting the server version on the way. Failures are only logged.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn warmup(&self) {
+        let started = std::time::Instant::now();
+        match self.get_status().await {
+            Ok(status) => {
+                if let Some(version) = status.server_version.as_deref().and_then(AbsVersion::parse)
+                {
+                    *self.server_version.lock().unwrap() = Some(version);
+                }
+                tracing::info!(
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    "warmed up ABS connection"
+                );
+            }
+            Err(e) => tracing::warn!(error = %e, "ABS connection warmup failed"),
+        }
+    }
+
+    /// Download URL of an item's ebook for the given ABS version (see [`ebook_download_path`])
+    pub fn ebook_download_url(
+        &self,
+        version: Option<AbsVersion>,
+        item_id: &Uuid,
+        ebook_ino: Option<&str>,
+    ) -> String {
+        self.url(&ebook_download_path(version, item_id, ebook_ino))
+    }
+
     /// GET /api/items/:id
     #[tracing::instrument(level = "debug", skip(self))]
     pub async fn get_item(
@@ -56,6 +136,66 @@ impl AbsClient {
         include: Option<&str>,
         api_key: &String,
     ) -> anyhow::Result<ItemResponse> {
+        let url = self.item_url(&item_id, expanded, include);
+        tracing::debug!(%url, expanded, include = include.unwrap_or(""), "GET item");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        // Expanded items can be large; parse straight from the bytes without a String copy
+        let body = status.bytes().await?;
+        let parsed: ItemResponse = serde_json::from_slice(&body)?;
+        Ok(parsed)
+    }
+
+    /// GET /api/items/:id?expanded=1, deserialized into the same [`LibraryItem`] as listings
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_library_item(
+        &self,
+        item_id: Uuid,
+        include: Option<&str>,
+        api_key: &String,
+    ) -> anyhow::Result<LibraryItem> {
+        let url = self.item_url(&item_id, true, include);
+        tracing::debug!(%url, include = include.unwrap_or(""), "GET library item");
+        let (k, v) = Self::auth_header(api_key);
+        let resp = self.client.get(&url).header(&k, &v).send().await?;
+        let body = resp.error_for_status()?.bytes().await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// POST /api/items/batch/get
+    /// Expanded items for several ids in one request; ids ABS doesn't know are left out
+    #[tracing::instrument(level = "debug", skip(self, item_ids, api_key), fields(items = item_ids.len()))]
+    pub async fn get_library_items_batch(
+        &self,
+        item_ids: &[Uuid],
+        api_key: &String,
+    ) -> anyhow::Result<Vec<LibraryItem>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct BatchResponse {
+            library_items: Vec<LibraryItem>,
+        }
+
+        let url = self.url("/api/items/batch/get");
+        tracing::debug!(%url, "POST library items batch");
+        let (k, v) = Self::auth_header(api_key);
+        let resp = self
+            .client
+            .post(&url)
+            .header(&k, &v)
+            .json(&serde_json::json!({ "libraryItemIds": item_ids }))
+            .send()
+            .await?;
+        let body = resp.error_for_status()?.bytes().await?;
+        Ok(serde_json::from_slice::<BatchResponse>(&body)?.library_items)
+    }
+
+    /// Build the URL for fetching a single item with optional `expanded` and `include` params.
+    pub fn item_url(&self, item_id: &Uuid, expanded: bool, include: Option<&str>) -> String {
         let mut path = format!("/api/items/{}", item_id);
         let mut q = vec![];
         if expanded {
@@ -72,9 +212,18 @@ impl AbsClient {
                 .join("&");
             path = format!("{}?{}", path, qs);
         }
+        self.url(&path)
+    }
+
+    /// GET /api/authors/:id (cached)
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_author(&self, author_id: &str, api_key: &String) -> anyhow::Result<Author> {
+        if let Some(author) = self.author_cache.lock().unwrap().get(author_id) {
+            return Ok(author.clone());
+        }
 
-        let url = self.url(&path);
-        tracing::debug!(%url, expanded, include = include.unwrap_or(""), "GET item");
+        let url = self.url(&format!("/api/authors/{}", author_id));
+        tracing::debug!(%url, "GET author");
         let mut req = self.client.get(&url);
         let (k, v) = Self::auth_header(api_key);
         req = req.header(&k, &v);
@@ -82,7 +231,11 @@ impl AbsClient {
         let resp = req.send().await?;
         let status = resp.error_for_status()?;
         let body = status.text().await?;
-        let parsed: ItemResponse = serde_json::from_str(&body)?;
+        let parsed: Author = serde_json::from_str(&body)?;
+        self.author_cache
+            .lock()
+            .unwrap()
+            .insert(author_id.to_string(), parsed.clone());
         Ok(parsed)
     }
 
@@ -113,6 +266,154 @@ impl AbsClient {
         self.url(&path)
     }
 
+    /// GET /api/me/progress/:id, `None` when the user has no progress on the item
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_media_progress(
+        &self,
+        item_id: &Uuid,
+        api_key: &String,
+    ) -> anyhow::Result<Option<MediaProgress>> {
+        let url = self.url(&format!("/api/me/progress/{}", item_id));
+        tracing::debug!(%url, "GET media progress");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let status = resp.error_for_status()?;
+        let body = status.text().await?;
+        Ok(Some(serde_json::from_str(&body)?))
+    }
+
+    /// GET /api/me, the user's progress on all items at once
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_my_progress(&self, api_key: &String) -> anyhow::Result<Vec<MediaProgress>> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct MeResponse {
+            #[serde(default)]
+            media_progress: Vec<MediaProgress>,
+        }
+
+        let url = self.url("/api/me");
+        tracing::debug!(%url, "GET me");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        let body = status.bytes().await?;
+        Ok(serde_json::from_slice::<MeResponse>(&body)?.media_progress)
+    }
+
+    /// POST /login, returning the user's API token (`user.token`, which doesn't expire)
+    #[tracing::instrument(level = "debug", skip(self, password))]
+    pub async fn login(&self, username: &str, password: &str) -> anyhow::Result<String> {
+        #[derive(Deserialize)]
+        struct LoginUser {
+            token: String,
+        }
+        #[derive(Deserialize)]
+        struct LoginResponse {
+            user: LoginUser,
+        }
+
+        let url = self.url("/login");
+        tracing::debug!(%url, "POST login");
+        let resp = self
+            .client
+            .post(&url)
+            .json(&serde_json::json!({ "username": username, "password": password }))
+            .send()
+            .await?;
+        let status = resp.error_for_status()?;
+        let body = status.bytes().await?;
+        Ok(serde_json::from_slice::<LoginResponse>(&body)?.user.token)
+    }
+
+    /// PATCH /api/me/progress/:id
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn update_media_progress(
+        &self,
+        item_id: &Uuid,
+        update: &MediaProgressUpdate,
+        api_key: &String,
+    ) -> anyhow::Result<()> {
+        let url = self.url(&format!("/api/me/progress/{}", item_id));
+        tracing::debug!(%url, "PATCH media progress");
+        let mut req = self.client.patch(&url).json(update);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        resp.error_for_status()?;
+        Ok(())
+    }
+
+    /// POST /api/session/local: record a reading session, which is what the ABS listening stats
+    /// are built from
+    #[tracing::instrument(level = "debug", skip(self, session, api_key))]
+    pub async fn sync_local_session(
+        &self,
+        session: &LocalSession,
+        api_key: &String,
+    ) -> anyhow::Result<()> {
+        let url = self.url("/api/session/local");
+        tracing::debug!(%url, item_id = %session.library_item_id, "POST local session");
+        let mut req = self.client.post(&url).json(session);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        resp.error_for_status()?;
+        Ok(())
+    }
+
+    /// GET /api/items/:id/cover, streamed. `raw` requests the original unscaled image.
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_cover(
+        &self,
+        item_id: &Uuid,
+        size: Option<(u32, u32)>,
+        raw: bool,
+        api_key: &String,
+    ) -> anyhow::Result<AbsStream> {
+        let url = self.cover_url(item_id, size, None, raw);
+        tracing::debug!(%url, raw, "GET cover");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        Ok(AbsStream::from_response(status))
+    }
+
+    /// An item's ebook file, streamed, from the route the connected ABS version supports (see
+    /// [`ebook_download_path`])
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_ebook(
+        &self,
+        item_id: &Uuid,
+        ebook_ino: Option<&str>,
+        api_key: &String,
+    ) -> anyhow::Result<AbsStream> {
+        let version = self.server_version().await;
+        let url = self.ebook_download_url(version, item_id, ebook_ino);
+        tracing::debug!(%url, "GET ebook");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        Ok(AbsStream::from_response(status))
+    }
+
     /// GET /api/libraries
     #[tracing::instrument(level = "debug", skip(self, api_key))]
     pub async fn get_libraries(&self, api_key: &String) -> anyhow::Result<LibrariesResponse> {
@@ -129,6 +430,96 @@ impl AbsClient {
         Ok(parsed)
     }
 
+    /// GET /api/libraries/{lib_id}
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_library(&self, lib_id: &Uuid, api_key: &String) -> anyhow::Result<Library> {
+        let url = self.url(&format!("/api/libraries/{}", lib_id));
+        tracing::debug!(%url, "GET library");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        let body = status.text().await?;
+        let parsed: Library = serde_json::from_str(&body)?;
+        Ok(parsed)
+    }
+
+    /// [`Self::get_library_items`], but reuses the previous result for the same query and user
+    /// while the library's `lastUpdate` is unchanged, for up to [`LIBRARY_ITEMS_MAX_AGE`].
+    /// Libraries without a `lastUpdate` (or that can't be looked up) are always fetched. A `limit`
+    /// of 0 fetches every item with [`Self::get_all_library_items`].
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_library_items_if_changed(
+        &self,
+        lib_id: &Uuid,
+        limit: i64,
+        page: Option<i64>,
+        include: Option<&str>,
+        filter: Option<&str>,
+        sort: Option<&LibraryItemSort>,
+        api_key: &String,
+    ) -> anyhow::Result<LibraryItemsResponse> {
+        let last_update = match self.get_library(lib_id, api_key).await {
+            Ok(library) => library.last_update,
+            Err(e) => {
+                tracing::warn!(error = %e, %lib_id, "Failed to fetch library, fetching items");
+                None
+            }
+        };
+        // The user is told apart by a hash of their key, so the key itself isn't kept around
+        let key = format!(
+            "{}|{}|{}|{}|{}|{}|{:?}",
+            lib_id,
+            Uuid::new_v3(&Uuid::NAMESPACE_OID, api_key.as_bytes()),
+            limit,
+            page.unwrap_or(0),
+            include.unwrap_or(""),
+            filter.unwrap_or(""),
+            sort
+        );
+        if let Some(last_update) = last_update
+            && let Some(cached) = self.library_items_cache.lock().unwrap().get(&key)
+            && cached.last_update == last_update
+            && cached.fetched_at.elapsed() < LIBRARY_ITEMS_MAX_AGE
+        {
+            tracing::debug!(%lib_id, last_update, "library unchanged, reusing items");
+            return Ok(cached.items.clone());
+        }
+
+        let items = if limit > 0 {
+            self.get_library_items(lib_id, limit, page, include, filter, sort, api_key)
+                .await?
+        } else {
+            self.get_all_library_items(lib_id, include, filter, sort, api_key)
+                .await?
+        };
+        if let Some(last_update) = last_update {
+            let mut cache = self.library_items_cache.lock().unwrap();
+            cache.retain(|_, cached| cached.fetched_at.elapsed() < LIBRARY_ITEMS_MAX_AGE);
+            if !cache.contains_key(&key)
+                && cache.len() >= LIBRARY_ITEMS_CACHE_ENTRIES
+                && let Some(oldest) = cache
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.fetched_at)
+                    .map(|(key, _)| key.clone())
+            {
+                cache.remove(&oldest);
+            }
+            cache.insert(
+                key,
+                CachedItems {
+                    last_update,
+                    fetched_at: Instant::now(),
+                    items: items.clone(),
+                },
+            );
+        }
+        Ok(items)
+    }
+
     /// GET /api/libraries/{lib_id}/series
     #[tracing::instrument(level = "debug", skip(self))]
     pub async fn get_library_series(
@@ -159,8 +550,85 @@ impl AbsClient {
         Ok(parsed)
     }
 
+    /// GET /api/libraries/{lib_id}/filterdata
+    /// Values present in the library for each filter group, as used by the ABS filter menus
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_library_filter_data(
+        &self,
+        lib_id: &Uuid,
+        api_key: &String,
+    ) -> anyhow::Result<LibraryFilterData> {
+        let url = self.url(&format!("/api/libraries/{}/filterdata", lib_id));
+        tracing::debug!(%url, "GET library filter data");
+        let mut req = self.client.get(&url);
+        let (k, v) = Self::auth_header(api_key);
+        req = req.header(&k, &v);
+
+        let resp = req.send().await?;
+        let status = resp.error_for_status()?;
+        let body = status.text().await?;
+        let parsed: LibraryFilterData = serde_json::from_str(&body)?;
+        Ok(parsed)
+    }
+
+    /// Every item of a library, fetched [`ALL_ITEMS_PAGE_SIZE`] at a time until `total` is reached.
+    /// Prefer this over `limit=0`, which makes ABS build the whole listing in one response.
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn get_all_library_items(
+        &self,
+        lib_id: &Uuid,
+        include: Option<&str>,
+        filter: Option<&str>,
+        sort: Option<&LibraryItemSort>,
+        api_key: &String,
+    ) -> anyhow::Result<LibraryItemsResponse> {
+        self.get_library_items_in_pages(lib_id, ALL_ITEMS_PAGE_SIZE, include, filter, sort, api_key)
+            .await
+    }
+
+    async fn get_library_items_in_pages(
+        &self,
+        lib_id: &Uuid,
+        page_size: i64,
+        include: Option<&str>,
+        filter: Option<&str>,
+        sort: Option<&LibraryItemSort>,
+        api_key: &String,
+    ) -> anyhow::Result<LibraryItemsResponse> {
+        let mut all = self
+            .get_library_items(lib_id, page_size, Some(0), include, filter, sort, api_key)
+            .await?;
+        let mut page = 0;
+        // Stop on a short or empty page too, in case items were removed while paging
+        while (all.results.len() as i64) < all.total
+            && all.results.len() as i64 == (page + 1) * page_size
+        {
+            page += 1;
+            let next = self
+                .get_library_items(
+                    lib_id,
+                    page_size,
+                    Some(page),
+                    include,
+                    filter,
+                    sort,
+                    api_key,
+                )
+                .await?;
+            all.total = next.total;
+            all.results.extend(next.results);
+        }
+        tracing::debug!(%lib_id, pages = page + 1, items = all.results.len(), total = all.total, "fetched all library items");
+        // The combined response covers the whole library, like a `limit=0` one
+        all.limit = 0;
+        all.page = 0;
+        all.offset = None;
+        Ok(all)
+    }
+
     /// GET /api/libraries/{lib_id}/items
-    /// Common useful params: limit, page, include (e.g. "media,media.metadata"), filter
+    /// Common useful params: limit, page, include (e.g. "media,media.metadata"), filter, sort
+    #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(level = "debug", skip(self))]
     pub async fn get_library_items(
         &self,
@@ -169,6 +637,7 @@ impl AbsClient {
         page: Option<i64>,
         include: Option<&str>,
         filter: Option<&str>,
+        sort: Option<&LibraryItemSort>,
         api_key: &String,
     ) -> anyhow::Result<LibraryItemsResponse> {
         let url = self.url(&format!("/api/libraries/{}/items", lib_id));
@@ -189,6 +658,10 @@ impl AbsClient {
         if let Some(f) = filter {
             q.push(("filter".into(), f.to_string()));
         }
+        if let Some(sort) = sort {
+            q.push(("sort".into(), sort.key().to_string()));
+            q.push(("desc".into(), if sort.desc() { "1" } else { "0" }.into()));
+        }
         let req = req.query(&q);
 
         let resp = req.send().await?;
@@ -206,6 +679,272 @@ impl AbsClient {
     }
 }
 
+/// Items requested per page by [`AbsClient::get_all_library_items`]
+pub const ALL_ITEMS_PAGE_SIZE: i64 = 500;
+
+/// Sort keys ABS accepts for library items
+pub const LIBRARY_ITEM_SORT_KEYS: &[&str] = &[
+    "addedAt",
+    "updatedAt",
+    "birthtimeMs",
+    "mtimeMs",
+    "size",
+    "media.duration",
+    "media.metadata.title",
+    "media.metadata.authorName",
+    "media.metadata.authorNameLF",
+    "media.metadata.publishedYear",
+    "random",
+];
+
+/// Validated library item sort, e.g. `addedAt desc`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryItemSort {
+    key: String,
+    desc: bool,
+}
+
+impl LibraryItemSort {
+    pub fn new(key: &str, desc: bool) -> Result<Self, String> {
+        if !LIBRARY_ITEM_SORT_KEYS.contains(&key) {
+            return Err(format!(
+                "Unsupported sort key '{}', expected one of: {}",
+                key,
+                LIBRARY_ITEM_SORT_KEYS.join(", ")
+            ));
+        }
+        Ok(Self {
+            key: key.to_string(),
+            desc,
+        })
+    }
+
+    /// Parse `<key>` or `<key> asc|desc`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.split_whitespace();
+        let key = parts.next().unwrap_or_default();
+        let desc = match parts.next().map(|d| d.to_ascii_lowercase()).as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(other) => return Err(format!("Unsupported sort direction '{}'", other)),
+        };
+        Self::new(key, desc)
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn desc(&self) -> bool {
+        self.desc
+    }
+}
+
+/// Filter groups ABS accepts for library items
+pub const LIBRARY_ITEM_FILTER_GROUPS: &[&str] = &[
+    "genres",
+    "tags",
+    "series",
+    "authors",
+    "narrators",
+    "publishers",
+    "languages",
+    "progress",
+    "missing",
+    "ebooks",
+];
+
+/// Library item filter, sent to ABS as `<group>.<base64 value>` (e.g. `genres.RmljdGlvbg==`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbsFilter {
+    group: String,
+    value: String,
+}
+
+impl AbsFilter {
+    /// `group` may be singular (`genre`) or the ABS group name (`genres`)
+    pub fn new(group: &str, value: &str) -> Result<Self, String> {
+        let group = group.trim().to_ascii_lowercase();
+        let group = LIBRARY_ITEM_FILTER_GROUPS
+            .iter()
+            .find(|g| **g == group || g.strip_suffix('s') == Some(group.as_str()))
+            .ok_or_else(|| {
+                format!(
+                    "Unsupported filter group '{}', expected one of: {}",
+                    group,
+                    LIBRARY_ITEM_FILTER_GROUPS.join(", ")
+                )
+            })?;
+        Ok(Self {
+            group: group.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    /// Parse a human-readable `<group>:<value>`, e.g. `genre:Fiction`
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (group, value) = value
+            .split_once(':')
+            .ok_or_else(|| format!("Expected '<group>:<value>', got '{}'", value))?;
+        Self::new(group, value.trim())
+    }
+
+    /// Value of the ABS `filter` query parameter
+    pub fn encode(&self) -> String {
+        format!("{}.{}", self.group, BASE64_STANDARD.encode(&self.value))
+    }
+}
+
+impl std::fmt::Display for AbsFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.group, self.value)
+    }
+}
+
+/// Binary ABS response (covers, files) passed through without buffering
+pub struct AbsStream {
+    pub content_type: Option<String>,
+    pub content_length: Option<u64>,
+    pub body: poem::Body,
+}
+
+impl AbsStream {
+    fn from_response(resp: reqwest::Response) -> Self {
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let content_length = resp.content_length();
+        let body =
+            poem::Body::from_bytes_stream(resp.bytes_stream().map_err(std::io::Error::other));
+        Self {
+            content_type,
+            content_length,
+            body,
+        }
+    }
+}
+
+/// ABS server version, e.g. `2.26.0`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AbsVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl AbsVersion {
+    /// First version serving ebooks at `/api/items/{id}/ebook`
+    pub const EBOOK_ROUTE: AbsVersion = AbsVersion {
+        major: 2,
+        minor: 5,
+        patch: 0,
+    };
+
+    /// Parse `major.minor.patch`, tolerating a leading `v` and pre-release/build suffixes
+    pub fn parse(value: &str) -> Option<Self> {
+        let core = value
+            .trim()
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()?;
+        let mut parts = core.split('.').map(|p| p.parse::<u32>().ok());
+        Some(Self {
+            major: parts.next()??,
+            minor: parts.next().flatten().unwrap_or(0),
+            patch: parts.next().flatten().unwrap_or(0),
+        })
+    }
+}
+
+impl std::fmt::Display for AbsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Ebook download path for an ABS version. Servers since [`AbsVersion::EBOOK_ROUTE`] (and
+/// unknown versions) use `/api/items/{id}/ebook`; older ones only serve library files by inode.
+pub fn ebook_download_path(
+    version: Option<AbsVersion>,
+    item_id: &Uuid,
+    ebook_ino: Option<&str>,
+) -> String {
+    match (version, ebook_ino) {
+        (Some(version), Some(ino)) if version < AbsVersion::EBOOK_ROUTE => {
+            format!("/api/items/{}/file/{}/download", item_id, ino)
+        }
+        _ => format!("/api/items/{}/ebook", item_id),
+    }
+}
+
+/// A user's progress on a library item
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaProgress {
+    pub library_item_id: Option<Uuid>,
+    /// Overall progress, 0.0 to 1.0
+    #[serde(default)]
+    pub progress: f64,
+    /// Reader position, an EPUB CFI for epubs read in the ABS web reader
+    pub ebook_location: Option<String>,
+    pub ebook_progress: Option<f64>,
+    #[serde(default)]
+    pub is_finished: bool,
+    /// Milliseconds since the epoch
+    pub last_update: i64,
+    pub started_at: Option<i64>,
+}
+
+impl MediaProgress {
+    pub fn last_updated(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.last_update).unwrap_or(DateTime::UNIX_EPOCH)
+    }
+}
+
+/// Body of a media progress update. Unset fields are left unchanged by ABS.
+#[derive(Debug, Serialize, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaProgressUpdate {
+    /// Overall progress, 0.0 to 1.0
+    pub progress: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_finished: Option<bool>,
+    /// Reader position, an EPUB CFI for epubs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ebook_location: Option<String>,
+    /// Ebook progress, 0.0 to 1.0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ebook_progress: Option<f64>,
+}
+
+/// Playback session reported by a client that tracked it locally. ABS also updates media progress
+/// from a session newer than the stored progress, so `updated_at` must not be newer than the
+/// position the session ended at.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LocalSession {
+    pub id: String,
+    pub library_item_id: Uuid,
+    pub media_type: String,
+    /// Seconds spent in the session
+    pub time_listening: f64,
+    /// Unix millis
+    pub started_at: i64,
+    /// Unix millis
+    pub updated_at: i64,
+    /// Day the session counts towards in the stats, `YYYY-MM-DD`
+    pub date: String,
+    pub day_of_week: String,
+}
+
+/// HTTP status of a failed ABS request, if the failure came from an ABS response
+pub fn upstream_status(err: &anyhow::Error) -> Option<reqwest::StatusCode> {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct StatusResponse {
     pub app: Option<String>,
@@ -219,11 +958,29 @@ pub struct StatusResponse {
 pub struct ItemResponse {
     pub id: String,
     pub title: Option<String>,
+    /// Nested media, complete (authors, series, ebook file) on expanded responses
+    #[serde(default)]
+    pub media: Option<Media>,
+    /// Only present on expanded responses
+    #[serde(default, rename = "libraryFiles")]
+    pub library_files: Vec<LibraryFile>,
     // allow extra fields
     #[serde(flatten)]
     pub extra: std::collections::HashMap<String, serde_json::Value>,
 }
 
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Author {
+    pub id: String,
+    pub name: String,
+    pub asin: Option<String>,
+    pub description: Option<String>,
+    pub image_path: Option<String>,
+    pub added_at: Option<i64>,
+    pub updated_at: Option<i64>,
+}
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct LibrariesResponse {
     pub libraries: Vec<Library>,
@@ -238,7 +995,7 @@ pub struct Library {
     pub display_order: Option<i64>,
     pub icon: Option<String>,
     #[serde(rename = "mediaType")]
-    pub media_type: Option<String>,
+    pub media_type: Option<AbsMediaType>,
     pub provider: Option<String>,
     pub settings: Option<serde_json::Value>,
     #[serde(rename = "lastScan")]
@@ -279,9 +1036,32 @@ pub struct LibrarySeries {
     // pub books: Vec<LibraryBook>,
 }
 
-// ============ Library Items (folders/files) ============
+/// Filter values of a library; ABS also sends narrators, languages and publishers, which are
+/// kept in `extra`
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct LibraryFilterData {
+    #[serde(default)]
+    pub authors: Vec<FilterDataEntry>,
+    #[serde(default)]
+    pub genres: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub series: Vec<FilterDataEntry>,
+    #[serde(flatten)]
+    pub extra: std::collections::HashMap<String, serde_json::Value>,
+}
 
+/// Author or series listed in library filter data
 #[derive(Debug, Deserialize, PartialEq)]
+pub struct FilterDataEntry {
+    pub id: String,
+    pub name: String,
+}
+
+// ============ Library Items (folders/files) ============
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct LibraryItemsResponse {
     pub results: Vec<LibraryItem>,
@@ -289,17 +1069,55 @@ pub struct LibraryItemsResponse {
     pub limit: i64,
     pub page: i64,
     pub sort_desc: bool,
-    pub media_type: LibraryMediaType,
+    pub media_type: AbsMediaType,
     pub minified: bool,
     pub collapseseries: bool,
     pub include: Option<String>,
+    /// Index of the first result; newer ABS versions send it alongside `page`
+    #[serde(default)]
+    pub offset: Option<i64>,
 }
 
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "camelCase")]
-pub enum LibraryMediaType {
+impl LibraryItemsResponse {
+    /// Whether items remain after this page. Uses `offset` when ABS sends it and falls back to
+    /// `page * limit`; a `limit` of 0 means everything was returned.
+    pub fn has_more(&self) -> bool {
+        if self.limit <= 0 || self.results.is_empty() {
+            return false;
+        }
+        let offset = self.offset.unwrap_or(self.page * self.limit);
+        offset + (self.results.len() as i64) < self.total
+    }
+}
+
+/// ABS media type of a library or library item ("book", "podcast", ...).
+/// Unknown values are kept in `Other` so newer ABS versions don't break parsing.
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(from = "String")]
+pub enum AbsMediaType {
     Book,
     Podcast,
+    Other(String),
+}
+
+impl AbsMediaType {
+    pub fn as_str(&self) -> &str {
+        match self {
+            AbsMediaType::Book => "book",
+            AbsMediaType::Podcast => "podcast",
+            AbsMediaType::Other(s) => s,
+        }
+    }
+}
+
+impl From<String> for AbsMediaType {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "book" => AbsMediaType::Book,
+            "podcast" => AbsMediaType::Podcast,
+            _ => AbsMediaType::Other(value),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq, Clone)]
@@ -314,36 +1132,140 @@ pub struct LibraryItem {
     pub rel_path: String,
     pub is_file: bool,
     /// The time when the library item was last modified on disk
+    #[serde(default)]
     pub mtime_ms: i64,
     /// The time when the library item status was changed on disk
+    #[serde(default)]
     pub ctime_ms: i64,
     /// The time when the library item was created on disk
+    #[serde(default)]
     pub birthtime_ms: i64,
     /// The time when the library item was added to the library
     pub added_at: i64,
     /// The time when the library item was last updated (Read Only)
     pub updated_at: i64,
+    #[serde(default)]
     pub is_missing: bool,
+    #[serde(default)]
     pub is_invalid: bool,
-    pub media_type: String,
-    pub media: Media,
+    pub media_type: AbsMediaType,
+    /// Missing for some invalid items
+    #[serde(default)]
+    pub media: Option<Media>,
+    /// Only present on listings, expanded item responses carry `library_files` instead
+    #[serde(default)]
     pub num_files: i64,
+    #[serde(default)]
     pub size: i64,
+    /// Only present on expanded item responses
+    #[serde(default)]
+    pub library_files: Vec<LibraryFile>,
     #[serde(flatten)]
     pub extra: std::collections::HashMap<String, serde_json::Value>,
 }
 
+impl LibraryItem {
+    /// Inode of the item's ebook file, needed for file downloads on older ABS versions
+    pub fn ebook_ino(&self) -> Option<String> {
+        ebook_ino(&self.library_files, self.media.as_ref())
+    }
+
+    /// All ebook formats (lowercase extensions) of the item, falling back to `media.ebookFormat`
+    pub fn ebook_formats(&self) -> Vec<String> {
+        let mut formats: Vec<String> = Vec::new();
+        let detected = self
+            .library_files
+            .iter()
+            .filter(|f| f.file_type.as_deref() == Some("ebook"))
+            .filter_map(|f| f.metadata.ext.as_deref())
+            .chain(self.media.as_ref().and_then(|m| m.ebook_format.as_deref()));
+        for ext in detected {
+            let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+            if !ext.is_empty() && !formats.contains(&ext) {
+                formats.push(ext);
+            }
+        }
+        formats
+    }
+
+    /// Number of ebook files of the item. Listings don't carry the file list, there an item
+    /// with `media.ebookFormat` counts as one.
+    pub fn ebook_file_count(&self) -> u32 {
+        let listed = self
+            .library_files
+            .iter()
+            .filter(|f| f.file_type.as_deref() == Some("ebook"))
+            .count() as u32;
+        if listed > 0 {
+            return listed;
+        }
+        self.media
+            .as_ref()
+            .is_some_and(|m| m.ebook_format.is_some())
+            .into()
+    }
+
+    /// Number of files of the item, from `numFiles` on listings or the expanded file list
+    pub fn file_count(&self) -> i64 {
+        self.num_files.max(self.library_files.len() as i64)
+    }
+}
+
+/// Ebook inode from the ebook library file, falling back to `media.ebookFile`
+pub fn ebook_ino(library_files: &[LibraryFile], media: Option<&Media>) -> Option<String> {
+    library_files
+        .iter()
+        .find(|f| f.file_type.as_deref() == Some("ebook"))
+        .and_then(|f| f.ino.clone())
+        .or_else(|| {
+            media?
+                .extra
+                .get("ebookFile")?
+                .get("ino")?
+                .as_str()
+                .map(str::to_string)
+        })
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryFile {
+    pub ino: Option<String>,
+    pub metadata: LibraryFileMetadata,
+    /// "ebook", "audio", "image", "metadata", ...
+    pub file_type: Option<String>,
+    #[serde(flatten)]
+    pub extra: std::collections::HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryFileMetadata {
+    pub filename: Option<String>,
+    /// Extension including the leading dot, e.g. ".epub"
+    pub ext: Option<String>,
+    pub path: Option<String>,
+    pub size: Option<i64>,
+}
+
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Media {
     pub id: String,
     pub metadata: BookMetadata,
     pub cover_path: Option<String>,
+    #[serde(default)]
     pub tags: Vec<String>,
+    /// The `num*` counts are only present on listings, expanded responses carry the lists
+    #[serde(default)]
     pub num_tracks: i64,
+    #[serde(default)]
     pub num_audio_files: i64,
+    #[serde(default)]
     pub num_chapters: i64,
+    #[serde(default)]
     pub duration: f64,
+    #[serde(default)]
     pub size: i64,
     pub ebook_format: Option<String>,
     #[serde(flatten)]
@@ -357,10 +1279,18 @@ pub struct BookMetadata {
     pub subtitle: Option<String>,
     pub title_ignore_prefix: Option<String>,
     pub author_name: Option<String>,
+    #[serde(rename = "authorNameLF")]
     pub author_name_lf: Option<String>,
+    /// Individual authors, only present on expanded responses
+    #[serde(default)]
+    pub authors: Vec<BookAuthor>,
 
     pub narrator_name: Option<String>,
     pub series_name: Option<String>,
+    /// Individual series with sequence, only present on expanded responses
+    #[serde(default)]
+    pub series: Vec<BookSeries>,
+    #[serde(default)]
     pub genres: Vec<String>,
     #[serde(
         deserialize_with = "crate::abs_client::de::opt_i64_from_str_or_num",
@@ -377,6 +1307,22 @@ pub struct BookMetadata {
     pub abridged: Option<bool>,
 }
 
+/// Author reference embedded in book metadata
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct BookAuthor {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+/// Series reference embedded in expanded book metadata
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct BookSeries {
+    pub id: String,
+    pub name: String,
+    /// Position in the series as entered in ABS, e.g. "1" or "2.5"
+    pub sequence: Option<String>,
+}
+
 impl BookMetadata {
     pub fn get_published_date(&self) -> Option<DateTime<Utc>> {
         if let Some(date_str) = &self.published_date {
@@ -438,6 +1384,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn build_item_url_forwards_include() {
+        let c = AbsClient::new("http://localhost:8080/").unwrap();
+        let url = c.item_url(
+            &Uuid::parse_str("22809dbe-3137-4879-831e-d64a6f29b005").unwrap(),
+            false,
+            Some("media,media.metadata,media.ebookFile"),
+        );
+        assert_eq!(
+            url,
+            "http://localhost:8080/api/items/22809dbe-3137-4879-831e-d64a6f29b005?include=media,media.metadata,media.ebookFile"
+        );
+    }
+
     #[test]
     fn status_deserialize() {
         let json = r#"{ "app": "audiobookshelf", "serverVersion": "2.3.4", "isInit": true }"#;
@@ -466,7 +1426,7 @@ mod tests {
             Uuid::parse_str("22809dbe-3137-4879-831e-d64a6f29b005").unwrap()
         );
         assert_eq!(libs.libraries[0].folders[0].full_path, "/a");
-        assert_eq!(libs.libraries[2].media_type.as_deref(), Some("podcast"));
+        assert_eq!(libs.libraries[2].media_type, Some(AbsMediaType::Podcast));
     }
 
     #[test]
@@ -541,9 +1501,508 @@ mod tests {
         assert_eq!(parsed.results.len(), 1);
         let item = &parsed.results[0];
         assert_eq!(item.is_file, false);
-        assert_eq!(item.media_type, "book");
-        assert_eq!(item.media.ebook_format.as_deref(), Some("pdf"));
-        let title = item.media.metadata.title.as_deref();
+        assert_eq!(item.media_type, AbsMediaType::Book);
+        let media = item.media.as_ref().unwrap();
+        assert_eq!(media.ebook_format.as_deref(), Some("pdf"));
+        let title = media.metadata.title.as_deref();
         assert_eq!(title, Some("Player's Handbook"));
     }
+
+    #[test]
+    fn media_type_deserialize() {
+        let types: Vec<AbsMediaType> =
+            serde_json::from_str(r#"["book", "podcast", "video"]"#).unwrap();
+        assert_eq!(
+            types,
+            vec![
+                AbsMediaType::Book,
+                AbsMediaType::Podcast,
+                AbsMediaType::Other("video".into()),
+            ]
+        );
+        assert_eq!(types[2].as_str(), "video");
+    }
+
+    #[test]
+    fn library_item_ebook_formats_from_library_files() {
+        let json = r#"{
+            "id": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+            "ino": "1",
+            "libraryId": "l1",
+            "folderId": "f1",
+            "path": "/books/Dune",
+            "relPath": "Dune",
+            "isFile": false,
+            "mtimeMs": 0,
+            "ctimeMs": 0,
+            "birthtimeMs": 0,
+            "addedAt": 0,
+            "updatedAt": 0,
+            "isMissing": false,
+            "isInvalid": false,
+            "mediaType": "book",
+            "media": {
+                "id": "m1",
+                "metadata": { "title": "Dune", "genres": [] },
+                "coverPath": null,
+                "tags": [],
+                "numTracks": 0,
+                "numAudioFiles": 0,
+                "numChapters": 0,
+                "duration": 0,
+                "size": 2,
+                "ebookFormat": "epub"
+            },
+            "numFiles": 3,
+            "size": 3,
+            "libraryFiles": [
+                { "ino": "2", "metadata": { "filename": "Dune.epub", "ext": ".epub", "path": "/books/Dune/Dune.epub", "size": 1 }, "fileType": "ebook" },
+                { "ino": "3", "metadata": { "filename": "Dune.PDF", "ext": ".PDF", "path": "/books/Dune/Dune.PDF", "size": 1 }, "fileType": "ebook" },
+                { "ino": "4", "metadata": { "filename": "cover.jpg", "ext": ".jpg", "path": "/books/Dune/cover.jpg", "size": 1 }, "fileType": "image" }
+            ]
+        }"#;
+
+        let item: LibraryItem = serde_json::from_str(json).unwrap();
+        assert_eq!(item.ebook_formats(), vec!["epub", "pdf"]);
+    }
+
+    #[tokio::test]
+    async fn get_cover_raw_streams_original() {
+        use poem::{
+            Response, Route, get, handler,
+            web::{Path, Query},
+        };
+
+        #[derive(Deserialize)]
+        struct CoverQuery {
+            raw: Option<String>,
+        }
+
+        #[handler]
+        fn cover_handler(Path(_id): Path<String>, Query(q): Query<CoverQuery>) -> Response {
+            let body: &[u8] = if q.raw.as_deref() == Some("1") {
+                b"original-png"
+            } else {
+                b"scaled"
+            };
+            Response::builder().content_type("image/png").body(body)
+        }
+
+        let base =
+            crate::test_support::serve(Route::new().at("/api/items/:id/cover", get(cover_handler)))
+                .await;
+        let c = AbsClient::new(base).unwrap();
+        let cover = c
+            .get_cover(&Uuid::now_v7(), None, true, &"key".to_string())
+            .await
+            .unwrap();
+        assert_eq!(cover.content_type.as_deref(), Some("image/png"));
+        assert_eq!(cover.body.into_vec().await.unwrap(), b"original-png");
+    }
+
+    #[test]
+    fn library_item_sort_validates_keys() {
+        let sort = LibraryItemSort::parse("addedAt desc").unwrap();
+        assert_eq!(sort.key(), "addedAt");
+        assert!(sort.desc());
+        assert!(
+            !LibraryItemSort::parse("media.metadata.title")
+                .unwrap()
+                .desc()
+        );
+        assert!(LibraryItemSort::parse("passwordHash").is_err());
+        assert!(LibraryItemSort::parse("addedAt sideways").is_err());
+    }
+
+    #[tokio::test]
+    async fn get_library_items_forwards_sort() {
+        use poem::{Route, get, handler, web::Query};
+
+        #[handler]
+        fn items(Query(q): Query<std::collections::HashMap<String, String>>) -> String {
+            assert_eq!(q.get("sort").map(String::as_str), Some("addedAt"));
+            assert_eq!(q.get("desc").map(String::as_str), Some("1"));
+            r#"{"results":[],"total":0,"limit":10,"page":0,"sortDesc":true,"mediaType":"book","minified":false,"collapseseries":false,"include":""}"#.to_string()
+        }
+
+        let lib_id = Uuid::now_v7();
+        let base = crate::test_support::serve(
+            Route::new().at(format!("/api/libraries/{}/items", lib_id), get(items)),
+        )
+        .await;
+        let c = AbsClient::new(base).unwrap();
+        let sort = LibraryItemSort::parse("addedAt desc").unwrap();
+        let res = c
+            .get_library_items(&lib_id, 10, None, None, None, Some(&sort), &"key".into())
+            .await
+            .unwrap();
+        assert!(res.sort_desc);
+    }
+
+    #[tokio::test]
+    async fn all_library_items_are_fetched_page_by_page() {
+        use poem::{EndpointExt, Route, get, handler, web::Data, web::Query};
+
+        #[handler]
+        fn items(
+            Query(q): Query<std::collections::HashMap<String, String>>,
+            Data(library): Data<&Vec<serde_json::Value>>,
+        ) -> poem::web::Json<serde_json::Value> {
+            let limit: usize = q["limit"].parse().unwrap();
+            let page: usize = q["page"].parse().unwrap();
+            assert!(limit > 0, "items must be paged");
+            let results: Vec<_> = library.iter().skip(page * limit).take(limit).collect();
+            poem::web::Json(serde_json::json!({
+                "results": results, "total": library.len(), "limit": limit, "page": page,
+                "sortDesc": false, "mediaType": "book", "minified": false, "collapseseries": false
+            }))
+        }
+
+        let lib_id = Uuid::now_v7();
+        let ids: Vec<Uuid> = (0..7).map(|_| Uuid::now_v7()).collect();
+        let library: Vec<_> = ids
+            .iter()
+            .map(|id| crate::test_support::library_item_json(*id, "Book"))
+            .collect();
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(format!("/api/libraries/{}/items", lib_id), get(items))
+                .data(library),
+        )
+        .await;
+        let c = AbsClient::new(base).unwrap();
+        let res = c
+            .get_library_items_in_pages(&lib_id, 3, None, None, None, &"key".into())
+            .await
+            .unwrap();
+
+        assert_eq!(res.total, 7);
+        assert_eq!(res.results.iter().map(|i| i.id).collect::<Vec<_>>(), ids);
+        assert!(!res.has_more());
+    }
+
+    #[test]
+    fn expanded_item_exposes_nested_metadata() {
+        let json = r#"{
+            "id": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+            "libraryId": "55b8b4f3-2ec7-460b-8178-e02b8b619c03",
+            "mediaType": "book",
+            "media": {
+                "id": "8f7a211c-767a-40bd-9e96-659a5c5fb6c0",
+                "metadata": {
+                    "title": "The Fellowship of the Ring",
+                    "authorName": "J. R. R. Tolkien",
+                    "authors": [{ "id": "aut_1", "name": "J. R. R. Tolkien" }],
+                    "series": [{ "id": "ser_1", "name": "The Lord of the Rings", "sequence": "1" }],
+                    "genres": ["Fantasy"],
+                    "description": "The first volume.",
+                    "language": "English"
+                },
+                "coverPath": "/books/lotr/cover.jpg",
+                "tags": [],
+                "numTracks": 0,
+                "numAudioFiles": 0,
+                "numChapters": 0,
+                "duration": 0,
+                "size": 1024,
+                "ebookFile": { "ino": "9", "metadata": { "ext": ".epub" } },
+                "ebookFormat": "epub"
+            },
+            "libraryFiles": [
+                { "ino": "9", "metadata": { "filename": "lotr.epub", "ext": ".epub" }, "fileType": "ebook" }
+            ]
+        }"#;
+
+        let item: ItemResponse = serde_json::from_str(json).unwrap();
+        let metadata = &item.media.as_ref().unwrap().metadata;
+        assert_eq!(
+            metadata.authors[0].name.as_deref(),
+            Some("J. R. R. Tolkien")
+        );
+        assert_eq!(metadata.series[0].name, "The Lord of the Rings");
+        assert_eq!(metadata.series[0].sequence.as_deref(), Some("1"));
+        assert_eq!(metadata.description.as_deref(), Some("The first volume."));
+        assert_eq!(item.library_files[0].file_type.as_deref(), Some("ebook"));
+    }
+
+    #[tokio::test]
+    async fn get_library_item_reads_expanded_response() {
+        use poem::{Route, get, handler, web::Query};
+
+        #[handler]
+        fn expanded_item(Query(q): Query<std::collections::HashMap<String, String>>) -> String {
+            assert_eq!(q.get("expanded").map(String::as_str), Some("1"));
+            r#"{
+                "id": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+                "ino": "7",
+                "oldLibraryItemId": null,
+                "libraryId": "55b8b4f3-2ec7-460b-8178-e02b8b619c03",
+                "folderId": "381d3393-0028-41fc-95b0-e3a1afb03eec",
+                "path": "/books/lotr",
+                "relPath": "lotr",
+                "isFile": false,
+                "mtimeMs": 1738971721697,
+                "ctimeMs": 1738978324038,
+                "birthtimeMs": 1699116518568,
+                "addedAt": 1703767976342,
+                "updatedAt": 1747214658742,
+                "lastScan": 1747214658742,
+                "scanVersion": "2.25.1",
+                "isMissing": false,
+                "isInvalid": false,
+                "mediaType": "book",
+                "media": {
+                    "id": "8f7a211c-767a-40bd-9e96-659a5c5fb6c0",
+                    "libraryItemId": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+                    "metadata": {
+                        "title": "The Fellowship of the Ring",
+                        "authors": [{ "id": "aut_1", "name": "J. R. R. Tolkien" }],
+                        "series": [],
+                        "genres": []
+                    },
+                    "coverPath": null,
+                    "tags": [],
+                    "audioFiles": [],
+                    "chapters": [],
+                    "duration": 0,
+                    "size": 1024,
+                    "tracks": [],
+                    "ebookFile": { "ino": "9", "metadata": { "ext": ".epub" } }
+                },
+                "libraryFiles": [
+                    { "ino": "9", "metadata": { "filename": "lotr.epub", "ext": ".epub" }, "fileType": "ebook" }
+                ],
+                "size": 1024
+            }"#
+            .to_string()
+        }
+
+        let item_id = Uuid::parse_str("075ebcee-d657-4b01-a96d-b94fadb1898c").unwrap();
+        let base = crate::test_support::serve(
+            Route::new().at(format!("/api/items/{}", item_id), get(expanded_item)),
+        )
+        .await;
+        let c = AbsClient::new(base).unwrap();
+        let item = c
+            .get_library_item(item_id, None, &"key".into())
+            .await
+            .unwrap();
+        assert_eq!(item.id, item_id);
+        assert_eq!(
+            item.media.as_ref().unwrap().metadata.authors[0]
+                .name
+                .as_deref(),
+            Some("J. R. R. Tolkien")
+        );
+        assert_eq!(item.ebook_ino().as_deref(), Some("9"));
+        assert_eq!(item.ebook_formats(), vec!["epub".to_string()]);
+    }
+
+    #[test]
+    fn minimal_item_parses_with_defaults() {
+        let json = r#"{
+            "id": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+            "ino": "7",
+            "libraryId": "55b8b4f3-2ec7-460b-8178-e02b8b619c03",
+            "folderId": "381d3393-0028-41fc-95b0-e3a1afb03eec",
+            "path": "/books/minimal",
+            "relPath": "minimal",
+            "isFile": true,
+            "addedAt": 1703767976342,
+            "updatedAt": 1747214658742,
+            "mediaType": "book",
+            "media": {
+                "id": "8f7a211c-767a-40bd-9e96-659a5c5fb6c0",
+                "metadata": { "title": "Minimal" },
+                "ebookFormat": "epub"
+            }
+        }"#;
+
+        let item: LibraryItem = serde_json::from_str(json).unwrap();
+        let media = item.media.as_ref().unwrap();
+        assert_eq!(media.num_tracks, 0);
+        assert!(media.tags.is_empty());
+        assert!(media.metadata.genres.is_empty());
+        assert!(!item.is_missing);
+    }
+
+    #[test]
+    fn has_more_uses_offset_pagination() {
+        let page = |offset: i64, count: usize| -> LibraryItemsResponse {
+            let results: Vec<_> = (0..count)
+                .map(|i| {
+                    crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i))
+                })
+                .collect();
+            serde_json::from_value(serde_json::json!({
+                "results": results, "total": 25, "limit": 10, "page": 0, "offset": offset,
+                "sortDesc": false, "mediaType": "book", "minified": false,
+                "collapseseries": false, "include": ""
+            }))
+            .unwrap()
+        };
+
+        // `page` stays 0 here, so only `offset` tells the pages apart
+        assert!(page(0, 10).has_more());
+        assert!(page(10, 10).has_more());
+        assert!(!page(20, 5).has_more());
+        assert!(!page(30, 0).has_more());
+    }
+
+    #[test]
+    fn ebook_download_path_depends_on_version() {
+        let item_id = Uuid::parse_str("075ebcee-d657-4b01-a96d-b94fadb1898c").unwrap();
+
+        let old = AbsVersion::parse("2.3.3");
+        assert_eq!(
+            ebook_download_path(old, &item_id, Some("552891213")),
+            "/api/items/075ebcee-d657-4b01-a96d-b94fadb1898c/file/552891213/download"
+        );
+
+        let new = AbsVersion::parse("v2.26.0-beta.1");
+        assert_eq!(
+            new,
+            Some(AbsVersion {
+                major: 2,
+                minor: 26,
+                patch: 0
+            })
+        );
+        assert_eq!(
+            ebook_download_path(new, &item_id, Some("552891213")),
+            "/api/items/075ebcee-d657-4b01-a96d-b94fadb1898c/ebook"
+        );
+    }
+
+    #[tokio::test]
+    async fn warmup_makes_one_status_call() {
+        use poem::{Route, get, handler};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[handler]
+        fn status() -> &'static str {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            r#"{"app":"audiobookshelf","serverVersion":"2.26.0","isInit":true}"#
+        }
+
+        let base = crate::test_support::serve(Route::new().at("/status", get(status))).await;
+        let c = AbsClient::new(base).unwrap();
+        c.warmup().await;
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        // The version detected during warmup is reused
+        assert_eq!(c.server_version().await, AbsVersion::parse("2.26.0"));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        // An unreachable ABS is only logged
+        AbsClient::new("http://127.0.0.1:1").unwrap().warmup().await;
+    }
+
+    #[tokio::test]
+    async fn server_version_is_cached() {
+        use poem::{Route, get, handler};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[handler]
+        fn status() -> &'static str {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            r#"{"app":"audiobookshelf","serverVersion":"2.3.3","isInit":true}"#
+        }
+
+        let base = crate::test_support::serve(Route::new().at("/status", get(status))).await;
+        let c = AbsClient::new(base).unwrap();
+        assert_eq!(c.server_version().await, AbsVersion::parse("2.3.3"));
+        assert_eq!(c.server_version().await, AbsVersion::parse("2.3.3"));
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unchanged_library_skips_items_fetch() {
+        use poem::{
+            EndpointExt, Route, get, handler,
+            web::{Data, Json},
+        };
+        use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+        #[derive(Default)]
+        struct State {
+            last_update: AtomicI64,
+            item_fetches: AtomicUsize,
+        }
+
+        #[handler]
+        fn library(Data(state): Data<&Arc<State>>) -> Json<serde_json::Value> {
+            Json(serde_json::json!({
+                "id": "22809dbe-3137-4879-831e-d64a6f29b005", "name": "Books", "folders": [],
+                "lastUpdate": state.last_update.load(Ordering::SeqCst)
+            }))
+        }
+
+        #[handler]
+        fn items(Data(state): Data<&Arc<State>>) -> Json<serde_json::Value> {
+            state.item_fetches.fetch_add(1, Ordering::SeqCst);
+            Json(serde_json::json!({
+                "results": [], "total": 0, "limit": 0, "page": 0, "sortDesc": false,
+                "mediaType": "book", "minified": false, "collapseseries": false
+            }))
+        }
+
+        let lib_id = Uuid::parse_str("22809dbe-3137-4879-831e-d64a6f29b005").unwrap();
+        let state = Arc::new(State::default());
+        state.last_update.store(1, Ordering::SeqCst);
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(format!("/api/libraries/{}", lib_id), get(library))
+                .at(format!("/api/libraries/{}/items", lib_id), get(items))
+                .data(state.clone()),
+        )
+        .await;
+        let c = AbsClient::new(base).unwrap();
+        let key = "key".to_string();
+        let fetch = || c.get_library_items_if_changed(&lib_id, 0, None, None, None, None, &key);
+
+        fetch().await.unwrap();
+        fetch().await.unwrap();
+        assert_eq!(state.item_fetches.load(Ordering::SeqCst), 1);
+
+        state.last_update.store(2, Ordering::SeqCst);
+        fetch().await.unwrap();
+        assert_eq!(state.item_fetches.load(Ordering::SeqCst), 2);
+
+        // Edits don't bump `lastUpdate`, so an old listing is fetched again regardless
+        for cached in c.library_items_cache.lock().unwrap().values_mut() {
+            cached.fetched_at -= LIBRARY_ITEMS_MAX_AGE;
+        }
+        fetch().await.unwrap();
+        assert_eq!(state.item_fetches.load(Ordering::SeqCst), 3);
+
+        // Listings are kept per user, without their key, and only so many of them
+        for user in 0..LIBRARY_ITEMS_CACHE_ENTRIES {
+            c.get_library_items_if_changed(&lib_id, 0, None, None, None, None, &user.to_string())
+                .await
+                .unwrap();
+        }
+        let cache = c.library_items_cache.lock().unwrap();
+        assert_eq!(cache.len(), LIBRARY_ITEMS_CACHE_ENTRIES);
+        assert!(cache.keys().all(|k| !k.contains("|key|")));
+    }
+
+    #[test]
+    fn filters_are_base64_encoded() {
+        assert_eq!(
+            AbsFilter::parse("genre:Fiction").unwrap().encode(),
+            "genres.RmljdGlvbg=="
+        );
+        assert_eq!(
+            AbsFilter::parse("authors: aut_z3leimgybl7uf3y4ab")
+                .unwrap()
+                .encode(),
+            "authors.YXV0X3ozbGVpbWd5Ymw3dWYzeTRhYg=="
+        );
+        assert!(AbsFilter::parse("shelf:Favourites").is_err());
+        assert!(AbsFilter::parse("Fiction").is_err());
+    }
 }
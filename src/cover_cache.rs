@@ -0,0 +1,141 @@
+// On-disk cache of cover images served to devices
+
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use uuid::Uuid;
+
+use crate::{AbsKoboResult, config::Config};
+
+/// Cover images kept as files in one directory, evicting the least recently served ones once the
+/// directory grows past `max_bytes`. Each file holds the content type on its first line followed
+/// by the image; its modification time records when it was last served.
+#[derive(Debug, Clone)]
+pub struct CoverCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl CoverCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// The cache configured by `COVER_CACHE_DIR`, if any
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let dir = config.cover_cache_dir.as_ref()?;
+        Some(Self::new(dir, config.cover_cache_max_mb * 1024 * 1024))
+    }
+
+    /// Cache key of one rendition of an item's cover, e.g. `raw` or a JPEG quality, as of the
+    /// item's ABS `updatedAt` so a changed cover misses the cache
+    pub fn key(item_id: &Uuid, updated_at: i64, size: Option<(u32, u32)>, format: &str) -> String {
+        match size {
+            Some((w, h)) => format!("{}-{}-{}x{}-{}", item_id, updated_at, w, h, format),
+            None => format!("{}-{}-full-{}", item_id, updated_at, format),
+        }
+    }
+
+    /// Cached image and its content type, marking the entry as recently used
+    pub async fn get(&self, key: &str) -> Option<(Vec<u8>, Option<String>)> {
+        let path = self.dir.join(key);
+        let data = tokio::fs::read(&path).await.ok()?;
+        let split = data.iter().position(|b| *b == b'\n')?;
+        let content_type = String::from_utf8_lossy(&data[..split]).into_owned();
+        let image = data[split + 1..].to_vec();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = touch(&path) {
+                tracing::debug!(error = %e, path = %path.display(), "failed to mark cached cover as used");
+            }
+        });
+        Some((image, Some(content_type).filter(|c| !c.is_empty())))
+    }
+
+    /// Store an image, then evict old entries if the cache grew too large
+    pub async fn put(
+        &self,
+        key: &str,
+        image: &[u8],
+        content_type: Option<&str>,
+    ) -> AbsKoboResult<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let mut data = Vec::with_capacity(image.len() + 32);
+        data.extend_from_slice(content_type.unwrap_or_default().as_bytes());
+        data.push(b'\n');
+        data.extend_from_slice(image);
+        // Write under a temporary name so concurrent readers never see a partial file
+        let tmp = self.dir.join(format!(".{}.{}.tmp", key, Uuid::now_v7()));
+        tokio::fs::write(&tmp, &data).await?;
+        tokio::fs::rename(&tmp, self.dir.join(key)).await?;
+
+        let (dir, max_bytes) = (self.dir.clone(), self.max_bytes);
+        tokio::task::spawn_blocking(move || evict(&dir, max_bytes)).await??;
+        Ok(())
+    }
+}
+
+pub(crate) fn touch(path: &Path) -> std::io::Result<()> {
+    std::fs::File::options()
+        .append(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// Delete the least recently used entries until the directory holds at most `max_bytes`
+pub(crate) fn evict(dir: &Path, max_bytes: u64) -> std::io::Result<()> {
+    let mut entries = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() && !entry.file_name().to_string_lossy().starts_with('.') {
+            entries.push((meta.modified()?, meta.len(), entry.path()));
+        }
+    }
+    let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+    entries.sort_by_key(|(modified, ..)| *modified);
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            // Another request may have evicted it already
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        total = total.saturating_sub(len);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn least_recently_used_covers_are_evicted() {
+        let dir = std::env::temp_dir().join(format!("cover-cache-{}", Uuid::now_v7()));
+        let cache = CoverCache::new(&dir, 45);
+
+        // Entries take 20 bytes each with their content type, `c` 11 without one
+        cache.put("a", &[1; 10], Some("image/png")).await.unwrap();
+        cache.put("b", &[2; 10], Some("image/png")).await.unwrap();
+        // Serving `a` again makes `b` the oldest entry
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        touch(&dir.join("a")).unwrap();
+        cache.put("c", &[3; 10], None).await.unwrap();
+
+        assert_eq!(
+            cache.get("a").await,
+            Some((vec![1; 10], Some("image/png".to_string())))
+        );
+        assert_eq!(cache.get("b").await, None);
+        assert_eq!(cache.get("c").await, Some((vec![3; 10], None)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
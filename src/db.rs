@@ -0,0 +1,128 @@
+// Database helpers shared by the services
+
+use std::{future::Future, time::Duration};
+
+use sea_orm::{DbErr, RuntimeErr};
+
+const BUSY_RETRY_ATTEMPTS: u32 = 5;
+const BUSY_RETRY_BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// Whether the error is SQLite reporting a busy/locked database (SQLITE_BUSY / SQLITE_LOCKED)
+pub fn is_busy_error(err: &DbErr) -> bool {
+    let runtime_err = match err {
+        DbErr::Exec(e) | DbErr::Query(e) | DbErr::Conn(e) => Some(e),
+        _ => None,
+    };
+    if let Some(RuntimeErr::SqlxError(sea_orm::sqlx::Error::Database(db_err))) = runtime_err {
+        // Only SQLite codes are numeric result codes, other backends use SQLSTATE
+        if db_err
+            .try_downcast_ref::<sea_orm::sqlx::sqlite::SqliteError>()
+            .is_none()
+        {
+            return false;
+        }
+        // Extended result codes keep the primary code in the low byte
+        if let Some(code) = db_err.code().and_then(|c| c.parse::<i32>().ok()) {
+            return matches!(code & 0xff, 5 | 6);
+        }
+    }
+    let message = err.to_string();
+    message.contains("database is locked") || message.contains("database table is locked")
+}
+
+/// Run a database write, retrying with exponential backoff while SQLite reports it is busy
+pub async fn retry_on_busy<T, F, Fut>(mut op: F) -> Result<T, DbErr>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DbErr>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(e) if attempt + 1 < BUSY_RETRY_ATTEMPTS && is_busy_error(&e) => {
+                let delay = BUSY_RETRY_BASE_DELAY * 2u32.pow(attempt);
+                tracing::warn!(error = %e, attempt, ?delay, "database busy, retrying");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn transient_lock_is_retried() {
+        let calls = Cell::new(0);
+        let res = retry_on_busy(|| {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move {
+                if n < 3 {
+                    Err(DbErr::Custom("database is locked".into()))
+                } else {
+                    Ok(n)
+                }
+            }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn other_errors_are_not_retried() {
+        let calls = Cell::new(0);
+        let res: Result<(), DbErr> = retry_on_busy(|| {
+            calls.set(calls.get() + 1);
+            async { Err(DbErr::Custom("constraint failed".into())) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    /// Runs the migrations against `TEST_POSTGRES_URL` and round-trips the uuid/timestamp columns.
+    /// The migrations are rolled back afterwards, so point it at a scratch database.
+    #[cfg(feature = "postgres")]
+    #[tokio::test]
+    async fn migrations_run_on_postgres() {
+        use entities::book_sync;
+        use migration::MigratorTrait;
+        use sea_orm::{ActiveValue::Set, Database, EntityTrait};
+        use uuid::Uuid;
+
+        let Ok(url) = std::env::var("TEST_POSTGRES_URL") else {
+            eprintln!("TEST_POSTGRES_URL not set, skipping");
+            return;
+        };
+        let db = Database::connect(&url).await.unwrap();
+        migration::Migrator::up(&db, None).await.unwrap();
+
+        let device_id = crate::test_support::insert_device(&db).await;
+        let timestamp = chrono::DateTime::from_timestamp_millis(1747214658742).unwrap();
+        let id = Uuid::now_v7();
+        book_sync::Entity::insert(book_sync::ActiveModel {
+            id: Set(id),
+            device_id: Set(device_id),
+            abs_item_id: Set(Uuid::now_v7().to_string()),
+            timestamp: Set(timestamp),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+        let row = book_sync::Entity::find_by_id(id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.device_id, device_id);
+        assert_eq!(row.timestamp, timestamp);
+
+        migration::Migrator::down(&db, None).await.unwrap();
+    }
+}
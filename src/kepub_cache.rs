@@ -0,0 +1,109 @@
+// On-disk cache of KEPUB conversions served to devices
+
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use crate::{
+    AbsKoboResult,
+    config::Config,
+    cover_cache::{evict, touch},
+};
+
+/// Converted books kept as files in one directory, evicting the least recently downloaded ones
+/// once the directory grows past `max_bytes`. Entries are keyed by the item's ABS `updatedAt`,
+/// so a book changed in ABS misses the cache and its stale conversion is replaced.
+#[derive(Debug, Clone)]
+pub struct KepubCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl KepubCache {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+        }
+    }
+
+    /// The cache configured by `KEPUB_CACHE_DIR`, if any
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let dir = config.kepub_cache_dir.as_ref()?;
+        Some(Self::new(dir, config.kepub_cache_max_mb * 1024 * 1024))
+    }
+
+    fn key(item_id: &Uuid, updated_at: i64) -> String {
+        format!("{}-{}.kepub.epub", item_id, updated_at)
+    }
+
+    /// Cached conversion of the item as of `updated_at`, marking the entry as recently used
+    pub async fn get(&self, item_id: &Uuid, updated_at: i64) -> Option<Vec<u8>> {
+        let path = self.dir.join(Self::key(item_id, updated_at));
+        let kepub = tokio::fs::read(&path).await.ok()?;
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = touch(&path) {
+                tracing::debug!(error = %e, path = %path.display(), "failed to mark cached kepub as used");
+            }
+        });
+        Some(kepub)
+    }
+
+    /// Store a conversion, drop the item's conversions of older versions, then evict old
+    /// entries if the cache grew too large
+    pub async fn put(&self, item_id: &Uuid, updated_at: i64, kepub: &[u8]) -> AbsKoboResult<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let key = Self::key(item_id, updated_at);
+        // Write under a temporary name so concurrent readers never see a partial file
+        let tmp = self.dir.join(format!(".{}.{}.tmp", key, Uuid::now_v7()));
+        tokio::fs::write(&tmp, kepub).await?;
+        tokio::fs::rename(&tmp, self.dir.join(&key)).await?;
+
+        let (dir, max_bytes) = (self.dir.clone(), self.max_bytes);
+        let stale_prefix = format!("{}-", item_id);
+        tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&stale_prefix) && name != key {
+                    match std::fs::remove_file(entry.path()) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            evict(&dir, max_bytes)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn changed_books_replace_their_cached_conversion() {
+        let dir = std::env::temp_dir().join(format!("kepub-cache-{}", Uuid::now_v7()));
+        let cache = KepubCache::new(&dir, 25);
+        let (a, b) = (Uuid::now_v7(), Uuid::now_v7());
+
+        cache.put(&a, 1, &[1; 10]).await.unwrap();
+        assert_eq!(cache.get(&a, 1).await, Some(vec![1; 10]));
+        // A newer version of the book misses the cache and replaces the old conversion
+        assert_eq!(cache.get(&a, 2).await, None);
+        cache.put(&a, 2, &[2; 10]).await.unwrap();
+        assert_eq!(cache.get(&a, 1).await, None);
+        assert_eq!(cache.get(&a, 2).await, Some(vec![2; 10]));
+
+        // Growing past the limit evicts the least recently used book
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        cache.put(&b, 1, &[3; 10]).await.unwrap();
+        cache.put(&Uuid::now_v7(), 1, &[4; 10]).await.unwrap();
+        assert_eq!(cache.get(&a, 2).await, None);
+        assert_eq!(cache.get(&b, 1).await, Some(vec![3; 10]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+// Background KEPUB conversions for books too large to convert within a download request
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// How long a finished conversion waits for a download to collect it
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// What a conversion produced
+pub enum Converted {
+    Kepub(Vec<u8>),
+    /// kepubify failed, the original EPUB is served instead
+    Epub(Vec<u8>),
+}
+
+/// State of an item's conversion job
+pub enum KepubJob {
+    /// No job for the item, or only one for an older version of it
+    Missing,
+    Running,
+    Finished(Converted),
+}
+
+struct Job {
+    updated_at: i64,
+    state: JobState,
+}
+
+enum JobState {
+    Running(JoinHandle<()>),
+    Finished(Converted, Instant),
+}
+
+type Jobs = HashMap<Uuid, Job>;
+
+/// Conversion jobs in memory, keyed by item id and tagged with the ABS `updatedAt` they convert.
+/// A finished job is kept until a download collects it or [`FINISHED_JOB_TTL`] passes, and not
+/// at all when its result went to the KEPUB cache.
+#[derive(Default)]
+pub struct KepubJobs {
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+impl KepubJobs {
+    /// The state of the conversion of `item_id` as of `updated_at`. A finished job is handed out
+    /// once and forgotten, a job for an older version of the book is cancelled.
+    pub fn take(&self, item_id: Uuid, updated_at: i64) -> KepubJob {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune(&mut jobs);
+        let Some(job) = jobs.get(&item_id) else {
+            return KepubJob::Missing;
+        };
+        if job.updated_at == updated_at
+            && let JobState::Running(handle) = &job.state
+        {
+            // A job finishing normally records its result first, so this one panicked
+            if !handle.is_finished() {
+                return KepubJob::Running;
+            }
+            tracing::warn!(item_id = %item_id, "KEPUB conversion job failed");
+        }
+        let job = jobs.remove(&item_id).unwrap();
+        match job.state {
+            JobState::Finished(converted, _) if job.updated_at == updated_at => {
+                KepubJob::Finished(converted)
+            }
+            JobState::Running(handle) => {
+                handle.abort();
+                KepubJob::Missing
+            }
+            JobState::Finished(..) => KepubJob::Missing,
+        }
+    }
+
+    /// Run `conversion` of `item_id` as of `updated_at` in the background, unless a job for that
+    /// version is already there. The job is forgotten when `conversion` yields `None`, i.e. its
+    /// result was stored in the KEPUB cache.
+    pub fn start(
+        &self,
+        item_id: Uuid,
+        updated_at: i64,
+        conversion: impl Future<Output = Option<Converted>> + Send + 'static,
+    ) {
+        let mut jobs = self.jobs.lock().unwrap();
+        prune(&mut jobs);
+        if jobs
+            .get(&item_id)
+            .is_some_and(|job| job.updated_at == updated_at)
+        {
+            return;
+        }
+        let shared = self.jobs.clone();
+        let handle = tokio::spawn(async move {
+            let converted = conversion.await;
+            let mut jobs = shared.lock().unwrap();
+            // The job may have been replaced by one for a newer version of the book
+            let Some(job) = jobs
+                .get_mut(&item_id)
+                .filter(|job| job.updated_at == updated_at)
+            else {
+                return;
+            };
+            match converted {
+                Some(converted) => job.state = JobState::Finished(converted, Instant::now()),
+                None => {
+                    jobs.remove(&item_id);
+                }
+            }
+        });
+        let job = Job {
+            updated_at,
+            state: JobState::Running(handle),
+        };
+        if let Some(Job {
+            state: JobState::Running(stale),
+            ..
+        }) = jobs.insert(item_id, job)
+        {
+            stale.abort();
+        }
+    }
+}
+
+/// Drop finished jobs nobody collected in time
+fn prune(jobs: &mut Jobs) {
+    jobs.retain(|_, job| match job.state {
+        JobState::Running(_) => true,
+        JobState::Finished(_, at) => at.elapsed() < FINISHED_JOB_TTL,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn finished_jobs_are_handed_out_once() {
+        let jobs = KepubJobs::default();
+        let item = Uuid::now_v7();
+        let (done, wait) = tokio::sync::oneshot::channel::<()>();
+        jobs.start(item, 1, async move {
+            wait.await.unwrap();
+            Some(Converted::Kepub(b"kepub".to_vec()))
+        });
+        assert!(matches!(jobs.take(item, 1), KepubJob::Running));
+
+        done.send(()).unwrap();
+        settle().await;
+        let KepubJob::Finished(Converted::Kepub(kepub)) = jobs.take(item, 1) else {
+            panic!("expected the finished conversion");
+        };
+        assert_eq!(kepub, b"kepub");
+        assert!(matches!(jobs.take(item, 1), KepubJob::Missing));
+        assert!(jobs.jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cached_and_failed_jobs_are_forgotten() {
+        let jobs = KepubJobs::default();
+        let (cached, panicked) = (Uuid::now_v7(), Uuid::now_v7());
+        jobs.start(cached, 1, async { None });
+        jobs.start(panicked, 1, async { panic!("kepubify blew up") });
+        settle().await;
+
+        assert!(!jobs.jobs.lock().unwrap().contains_key(&cached));
+        assert!(matches!(jobs.take(panicked, 1), KepubJob::Missing));
+        assert!(jobs.jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uncollected_jobs_expire() {
+        let jobs = KepubJobs::default();
+        let item = Uuid::now_v7();
+        jobs.start(item, 1, async { Some(Converted::Epub(b"epub".to_vec())) });
+        settle().await;
+        if let JobState::Finished(_, at) =
+            &mut jobs.jobs.lock().unwrap().get_mut(&item).unwrap().state
+        {
+            *at -= FINISHED_JOB_TTL;
+        }
+
+        jobs.start(Uuid::now_v7(), 1, std::future::pending());
+        assert!(!jobs.jobs.lock().unwrap().contains_key(&item));
+    }
+
+    #[tokio::test]
+    async fn jobs_for_older_versions_are_dropped() {
+        let jobs = KepubJobs::default();
+        let item = Uuid::now_v7();
+        jobs.start(item, 1, std::future::pending());
+        // A download of the changed book doesn't wait for the old conversion
+        assert!(matches!(jobs.take(item, 2), KepubJob::Missing));
+        assert!(matches!(jobs.take(item, 1), KepubJob::Missing));
+    }
+}
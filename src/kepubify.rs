@@ -0,0 +1,105 @@
+// KEPUB conversion of ebooks served to devices, by shelling out to kepubify
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use uuid::Uuid;
+
+use crate::{AbsKoboResult, config::Config};
+
+/// The `kepubify` binary, run once per conversion in a scratch directory
+#[derive(Debug, Clone)]
+pub struct Kepubify {
+    path: PathBuf,
+}
+
+impl Kepubify {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The binary configured by `KEPUBIFY_PATH`
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(&config.kepubify_path)
+    }
+
+    /// Convert an EPUB to a KEPUB. The files only live for the duration of the call.
+    #[tracing::instrument(level = "debug", skip(self, epub), fields(len = epub.len()))]
+    pub async fn convert(&self, epub: &[u8]) -> AbsKoboResult<Vec<u8>> {
+        let dir = std::env::temp_dir().join(format!("kepubify-{}", Uuid::now_v7()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let result = self.convert_in(&dir, epub).await;
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            tracing::warn!(error = %e, dir = %dir.display(), "failed to remove kepubify scratch directory");
+        }
+        result
+    }
+
+    /// Version the binary reports, failing if it can't be run
+    pub async fn version(&self) -> AbsKoboResult<String> {
+        let out = tokio::process::Command::new(&self.path)
+            .arg("--version")
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("failed to run {}", self.path.display()))?;
+        if !out.status.success() {
+            anyhow::bail!("kepubify --version exited with {}", out.status);
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    }
+
+    async fn convert_in(&self, dir: &std::path::Path, epub: &[u8]) -> AbsKoboResult<Vec<u8>> {
+        let input = dir.join("book.epub");
+        let output = dir.join("book.kepub.epub");
+        tokio::fs::write(&input, epub).await?;
+
+        let started = std::time::Instant::now();
+        let out = tokio::process::Command::new(&self.path)
+            .arg("-o")
+            .arg(&output)
+            .arg(&input)
+            .kill_on_drop(true)
+            .output()
+            .await
+            .with_context(|| format!("failed to run {}", self.path.display()))?;
+        if !out.status.success() {
+            anyhow::bail!(
+                "kepubify exited with {}: {}",
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            );
+        }
+        tracing::debug!(
+            elapsed_ms = started.elapsed().as_millis() as u64,
+            "converted ebook to kepub"
+        );
+        tokio::fs::read(&output)
+            .await
+            .context("kepubify did not write the converted book")
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_support::fake_kepubify;
+
+    #[tokio::test]
+    async fn epubs_are_converted_and_failures_reported() {
+        let dir = std::env::temp_dir().join(format!("kepubify-test-{}", Uuid::now_v7()));
+        let converted = Kepubify::new(fake_kepubify(&dir))
+            .convert(b"epub")
+            .await
+            .unwrap();
+        assert_eq!(converted, b"kepub:epub");
+
+        assert!(
+            Kepubify::new(dir.join("missing"))
+                .convert(b"epub")
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::Arc;
+
+use entities::devices;
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    http::{StatusCode, header},
+};
+use sea_orm::{DatabaseConnection, EntityTrait};
+
+use crate::kobo_api::rate_limit::device_token;
+
+/// Path prefixes of the management endpoints, the `/kobo/:auth_token/...` device routes
+/// authenticate through their path token instead
+const MANAGEMENT_PREFIXES: [&str; 4] =
+    ["/v1/devices", "/v1/users", "/v1/admin", "/v1/validate-key"];
+
+/// Requires `Authorization: Bearer <API_TOKEN>` on the management endpoints, answering `401`
+/// when it is missing or wrong. Without a configured token every request passes.
+pub struct ManagementAuth {
+    token: Option<Arc<str>>,
+}
+
+impl ManagementAuth {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token: token.map(Into::into),
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for ManagementAuth {
+    type Output = ManagementAuthEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        ManagementAuthEndpoint {
+            inner,
+            token: self.token.clone(),
+        }
+    }
+}
+
+pub struct ManagementAuthEndpoint<E> {
+    inner: E,
+    token: Option<Arc<str>>,
+}
+
+impl<E: Endpoint> Endpoint for ManagementAuthEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if let Some(token) = &self.token
+            && is_management_path(req.uri().path())
+            && !bearer_matches(&req, token)
+        {
+            tracing::warn!(path = %req.uri().path(), "rejecting management request without a valid API token");
+            return Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(header::WWW_AUTHENTICATE, "Bearer")
+                .body("Missing or invalid API token"));
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+/// Rejects `/kobo/:auth_token/...` requests whose token isn't a registered device with `401`,
+/// before any route runs. Other paths pass through.
+pub struct DeviceAuth {
+    db: Arc<DatabaseConnection>,
+}
+
+impl DeviceAuth {
+    pub fn new(db: Arc<DatabaseConnection>) -> Self {
+        Self { db }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for DeviceAuth {
+    type Output = DeviceAuthEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        DeviceAuthEndpoint {
+            inner,
+            db: self.db.clone(),
+        }
+    }
+}
+
+pub struct DeviceAuthEndpoint<E> {
+    inner: E,
+    db: Arc<DatabaseConnection>,
+}
+
+impl<E: Endpoint> Endpoint for DeviceAuthEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if is_device_path(req.uri().path()) {
+            let known = match device_token(req.uri().path()) {
+                Some(device_id) => match devices::Entity::find_by_id(device_id)
+                    .one(self.db.as_ref())
+                    .await
+                {
+                    Ok(device) => device.is_some(),
+                    Err(e) => {
+                        tracing::error!(error = %e, %device_id, "failed to look up device token");
+                        return Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body("Database error"));
+                    }
+                },
+                None => false,
+            };
+            if !known {
+                tracing::warn!(path = %req.uri().path(), "rejecting device request with an unknown auth token");
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body("Invalid auth token"));
+            }
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+fn is_device_path(path: &str) -> bool {
+    path.split('/')
+        .find(|s| !s.is_empty())
+        .is_some_and(|s| s.eq_ignore_ascii_case("kobo"))
+}
+
+fn is_management_path(path: &str) -> bool {
+    MANAGEMENT_PREFIXES.iter().any(|prefix| {
+        path.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+fn bearer_matches(req: &Request, token: &str) -> bool {
+    req.header(header::AUTHORIZATION)
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Compare without bailing out at the first differing byte, so response timing doesn't reveal
+/// how much of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{EndpointExt, Route, get, handler, post, test::TestClient};
+
+    use super::*;
+
+    #[handler]
+    fn ok() -> &'static str {
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn management_routes_require_api_token() {
+        let cli = TestClient::new(
+            Route::new()
+                .at("/v1/devices/:device_id/pending", get(ok))
+                .at("/v1/validate-key", post(ok))
+                .at("/kobo/:auth_token/v1/library/sync", get(ok))
+                .with(ManagementAuth::new(Some("secret".into()))),
+        );
+        let device_id = uuid::Uuid::now_v7();
+        let pending = format!("/v1/devices/{}/pending", device_id);
+
+        cli.get(&pending)
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        cli.get(&pending)
+            .header(header::AUTHORIZATION, "Bearer wrong")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        cli.get(&pending)
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .send()
+            .await
+            .assert_status_is_ok();
+        // Validating an ABS key would otherwise let anyone probe keys
+        cli.post("/v1/validate-key")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        cli.post("/v1/validate-key")
+            .header(header::AUTHORIZATION, "Bearer secret")
+            .send()
+            .await
+            .assert_status_is_ok();
+        // Device routes authenticate by their path token
+        cli.get(format!("/kobo/{}/v1/library/sync", device_id))
+            .send()
+            .await
+            .assert_status_is_ok();
+    }
+
+    #[tokio::test]
+    async fn device_routes_require_a_registered_token() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let cli = TestClient::new(
+            Route::new()
+                .at("/kobo/:auth_token/v1/initialization", get(ok))
+                .at("/status", get(ok))
+                .with(DeviceAuth::new(Arc::new(db))),
+        );
+
+        cli.get(format!("/kobo/{}/v1/initialization", device_id))
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.get(format!("/kobo/{}/v1/initialization", uuid::Uuid::now_v7()))
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        cli.get("/kobo/not-a-token/v1/initialization")
+            .send()
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        cli.get("/status").send().await.assert_status_is_ok();
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+
+/// Caps the book downloads fetched from ABS at the same time, so many devices downloading large
+/// books at once can't saturate ABS's disk. Downloads past the cap wait for a slot, and once
+/// `max_queued` are waiting further ones are turned away.
+pub struct DownloadLimit {
+    slots: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    max_queued: usize,
+}
+
+impl DownloadLimit {
+    pub fn new(max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            waiting: AtomicUsize::new(0),
+            max_queued,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.max_concurrent_downloads, config.max_queued_downloads)
+    }
+
+    /// A download slot, waiting for one while all are taken; `None` when the queue is full
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        // Leaves the queue on drop, also when the device gives up waiting
+        let _waiting = Waiting(&self.waiting);
+        self.slots.clone().acquire_owned().await.ok()
+    }
+}
+
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn downloads_past_the_limit_queue_then_get_turned_away() {
+        let limit = Arc::new(DownloadLimit::new(1, 1));
+        let first = limit.acquire().await.unwrap();
+
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!queued.is_finished());
+        assert!(limit.acquire().await.is_none());
+
+        drop(first);
+        assert!(queued.await.unwrap());
+        // The queue is free again
+        assert!(limit.acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn cancelled_waits_leave_the_queue() {
+        let limit = DownloadLimit::new(1, 1);
+        let _held = limit.acquire().await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), limit.acquire())
+                .await
+                .is_err()
+        );
+        // The timed out download no longer takes up the queue
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), limit.acquire())
+                .await
+                .is_err()
+        );
+    }
+}
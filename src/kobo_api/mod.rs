@@ -1,5 +1,11 @@
+pub mod auth;
+pub mod download_limit;
 pub mod models;
+pub mod path;
+pub mod probe;
+pub mod rate_limit;
 pub mod routes;
 pub mod services;
+pub mod spec;
 
 pub use routes::AbsKoboApi;
@@ -3,12 +3,21 @@ use poem_openapi::{Enum, Object, Union};
 use serde::Deserialize;
 use uuid::Uuid;
 
-use crate::abs_client::LibraryItem;
+use crate::{
+    abs_client::{self, LibraryItem, MediaProgress},
+    config::AuthorNameOrder,
+};
 
 fn timestamp_to_utc(timestamp: i64) -> DateTime<Utc> {
     Utc.timestamp_opt(timestamp, 0).unwrap()
 }
 
+fn millis_to_utc(millis: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .unwrap_or(DateTime::UNIX_EPOCH)
+}
+
 #[derive(Debug, Clone, Object, Deserialize)]
 #[oai(rename_all = "PascalCase")]
 #[serde(rename_all = "PascalCase")]
@@ -44,6 +53,25 @@ impl BookEntitlement {
             status: Default::default(),
         }
     }
+
+    /// Entitlement telling the device a book is gone. The device keeps it in its archive view
+    /// unless `hidden_from_archive` is set.
+    pub fn removed(id: Uuid, now: DateTime<Utc>, hidden_from_archive: bool) -> Self {
+        Self {
+            accessibility: Default::default(),
+            active_period: ActivePeriod { from: now },
+            created: now,
+            cross_revision_id: id,
+            id,
+            is_removed: true,
+            is_hidden_from_archive: hidden_from_archive,
+            is_locked: false,
+            last_modified: now,
+            origin_category: Default::default(),
+            revision_id: id,
+            status: Default::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Enum, Default, Deserialize)]
@@ -97,6 +125,7 @@ pub struct BookMetadata {
     pub entitlement_id: Uuid,
     pub external_ids: Vec<Uuid>,
     pub genre: Uuid,
+    pub isbn: Option<String>,
     pub is_eligible_for_kobo_love: bool,
     pub is_internet_archive: bool,
     pub is_pre_order: bool,
@@ -116,49 +145,44 @@ impl BookMetadata {
     pub fn try_from_library_item(
         value: LibraryItem,
         download_urls: Vec<String>,
+        author_order: AuthorNameOrder,
     ) -> Result<Self, anyhow::Error> {
-        let authors = value
+        let media = value
             .media
-            .metadata
-            .author_name
-            .clone()
-            .map(|author| author.split(',').map(|s| s.trim().to_string()).collect());
+            .ok_or_else(|| anyhow::anyhow!("Item {} has no media", value.id))?;
+        Self::try_from_abs_metadata(value.id, &media.metadata, download_urls, author_order)
+    }
+
+    fn try_from_abs_metadata(
+        id: Uuid,
+        metadata: &abs_client::BookMetadata,
+        download_urls: Vec<String>,
+        author_order: AuthorNameOrder,
+    ) -> Result<Self, anyhow::Error> {
+        let authors = contributor_names(metadata, author_order);
         Ok(Self {
             categories: vec![Uuid::parse_str("00000000-0000-0000-0000-000000000001")?],
-            cover_image_id: value.id,
-            cross_revision_id: value.id,
+            cover_image_id: id,
+            cross_revision_id: id,
             current_display_price: Default::default(),
             current_love_display_price: Default::default(),
-            description: value.media.metadata.description.clone(),
+            description: metadata.description.clone(),
             download_urls,
-            entitlement_id: value.id,
-            external_ids: vec![],
+            entitlement_id: id,
+            external_ids: external_ids(metadata),
             genre: Uuid::parse_str("00000000-0000-0000-0000-000000000001")?,
+            isbn: metadata.isbn.as_deref().and_then(normalized_isbn),
             is_eligible_for_kobo_love: false,
             is_internet_archive: false,
             is_pre_order: false,
             is_social_enabled: true,
             // TODO: guess language more intelligently
-            language: value
-                .media
-                .clone()
-                .metadata
-                .language
-                .unwrap_or("en".to_string()),
+            language: metadata.language.clone().unwrap_or("en".to_string()),
             phonetic_pronunciations: PhoneticPronounciations {},
-            publication_date: value
-                .media
-                .metadata
-                .get_published_date()
-                .unwrap_or_default(),
-            revision_id: value.id,
-            title: value
-                .media
-                .metadata
-                .title
-                .clone()
-                .unwrap_or("Untitled".to_string()),
-            work_id: value.id,
+            publication_date: metadata.get_published_date().unwrap_or_default(),
+            revision_id: id,
+            title: metadata.title.clone().unwrap_or("Untitled".to_string()),
+            work_id: id,
             contributors: authors.clone(),
             contributor_roles: authors.map(|authors| {
                 authors
@@ -166,11 +190,98 @@ impl BookMetadata {
                     .map(|author| KoboSyncedContributorRole { name: author })
                     .collect()
             }),
-            series: None,
+            series: kobo_series(metadata),
         })
     }
 }
 
+/// First ABS series of the book; only expanded responses carry series details
+fn kobo_series(metadata: &abs_client::BookMetadata) -> Option<KoboSyncedSeries> {
+    let series = metadata.series.first()?;
+    let number = series
+        .sequence
+        .as_deref()
+        .and_then(|s| s.trim().parse::<f64>().ok())
+        .unwrap_or_default();
+    Some(KoboSyncedSeries {
+        name: series.name.clone(),
+        number,
+        number_float: number,
+        // Older ABS versions use non-UUID series ids
+        id: Uuid::parse_str(&series.id)
+            .unwrap_or_else(|_| Uuid::new_v3(&Uuid::NAMESPACE_OID, series.id.as_bytes())),
+    })
+}
+
+/// ISBN without separators, `None` if nothing is left
+fn normalized_isbn(isbn: &str) -> Option<String> {
+    let isbn: String = isbn
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_ascii_uppercase();
+    (!isbn.is_empty()).then_some(isbn)
+}
+
+/// Stable ids derived from the book's ISBN and ASIN, so the same edition maps to the same ids
+/// across items and servers
+fn external_ids(metadata: &abs_client::BookMetadata) -> Vec<Uuid> {
+    let isbn = metadata
+        .isbn
+        .as_deref()
+        .and_then(normalized_isbn)
+        .map(|isbn| format!("isbn:{}", isbn));
+    let asin = metadata
+        .asin
+        .as_deref()
+        .map(str::trim)
+        .filter(|asin| !asin.is_empty())
+        .map(|asin| format!("asin:{}", asin.to_ascii_uppercase()));
+    [isbn, asin]
+        .into_iter()
+        .flatten()
+        .map(|id| Uuid::new_v3(&Uuid::NAMESPACE_OID, id.as_bytes()))
+        .collect()
+}
+
+/// Contributor names for a book in the requested order. Uses the individual ABS authors when
+/// available and only falls back to splitting the concatenated `authorName` on commas.
+fn contributor_names(
+    metadata: &abs_client::BookMetadata,
+    order: AuthorNameOrder,
+) -> Option<Vec<String>> {
+    let named: Vec<String> = metadata
+        .authors
+        .iter()
+        .filter_map(|a| a.name.clone())
+        .collect();
+    let names = if !named.is_empty() {
+        named
+    } else {
+        let author = metadata.author_name.as_deref()?;
+        // A single author's `authorNameLF` is used as is, joined ones can't be split back apart
+        if order == AuthorNameOrder::Sort
+            && !author.contains(',')
+            && let Some(lf) = &metadata.author_name_lf
+        {
+            return Some(vec![lf.clone()]);
+        }
+        author.split(',').map(|s| s.trim().to_string()).collect()
+    };
+    Some(match order {
+        AuthorNameOrder::Display => names,
+        AuthorNameOrder::Sort => names.iter().map(|name| last_first(name)).collect(),
+    })
+}
+
+/// "First Last" as "Last, First"; names that already contain a comma are kept as they are
+fn last_first(name: &str) -> String {
+    match name.trim().rsplit_once(char::is_whitespace) {
+        Some((first, last)) if !name.contains(',') => format!("{}, {}", last, first.trim_end()),
+        _ => name.trim().to_string(),
+    }
+}
+
 #[derive(Debug, Clone, Object, Deserialize)]
 #[oai(rename_all = "PascalCase")]
 #[serde(rename_all = "PascalCase")]
@@ -236,6 +347,39 @@ pub struct KoboSyncedReadingState {
     pub current_bookmark: KoboCurrentBookmark,
 }
 
+impl KoboSyncedReadingState {
+    /// Reading state of a book as recorded by ABS
+    pub fn from_abs_progress(entitlement_id: Uuid, progress: &MediaProgress) -> Self {
+        let last_modified = millis_to_utc(progress.last_update);
+        let started = progress.started_at.map(millis_to_utc);
+        let status = if progress.is_finished {
+            KoboSyncedStatus::Finished
+        } else if progress.progress > 0.0 || progress.ebook_progress.is_some_and(|p| p > 0.0) {
+            KoboSyncedStatus::Reading
+        } else {
+            KoboSyncedStatus::ReadyToRead
+        };
+        Self {
+            entitlement_id,
+            created: started.unwrap_or(last_modified),
+            last_modified,
+            priority_timestamp: last_modified,
+            status_info: KoboSyncedStatusInfo {
+                last_modified,
+                status,
+                times_started_read: if started.is_some() { 1.0 } else { 0.0 },
+                last_time_started_read: started,
+            },
+            statistics: KoboSyncedStatistics {
+                last_modified,
+                spent_reading_minutes: None,
+                remaining_reading_minutes: None,
+            },
+            current_bookmark: KoboCurrentBookmark::from_abs_progress(progress),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Object, Deserialize)]
 #[oai(rename_all = "PascalCase")]
 #[serde(rename_all = "PascalCase")]
@@ -274,6 +418,29 @@ pub struct KoboCurrentBookmark {
     pub location: Option<KoboCurrentBookmarkLocation>,
 }
 
+impl KoboCurrentBookmark {
+    /// Bookmark at the ABS progress. ABS mostly only knows a percentage; a location is only set
+    /// for an EPUB CFI, as a made-up one would make the device jump to the wrong spot.
+    pub fn from_abs_progress(progress: &MediaProgress) -> Self {
+        let percent = progress.ebook_progress.unwrap_or(progress.progress) * 100.0;
+        let location = progress
+            .ebook_location
+            .as_ref()
+            .filter(|location| location.starts_with("epubcfi("))
+            .map(|cfi| KoboCurrentBookmarkLocation {
+                value: cfi.clone(),
+                _type: "CFI".into(),
+                source: String::new(),
+            });
+        Self {
+            last_modified: millis_to_utc(progress.last_update),
+            progress_percent: Some(percent),
+            content_source_progress_percent: Some(percent),
+            location,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Object, Deserialize)]
 #[oai(rename_all = "PascalCase")]
 #[serde(rename_all = "PascalCase")]
@@ -284,6 +451,56 @@ pub struct KoboCurrentBookmarkLocation {
     pub source: String,
 }
 
+/// Bookmark location by type, with the value checked for plausibility. Only obviously broken
+/// values are rejected; a plausible but wrong location can't be detected without the book.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookmarkLocation {
+    /// Span in a KEPUB, e.g. `kobo.12.3`
+    KoboSpan(String),
+    /// EPUB CFI, e.g. `epubcfi(/6/4!/4/2/1:0)`
+    Cfi(String),
+    /// Location type that isn't checked
+    Other(String),
+}
+
+impl BookmarkLocation {
+    /// Check `value` against the location `type` the device reported (`KoboSpan`, `CFI` or
+    /// `EpubCfi`, case-insensitively)
+    pub fn parse(location_type: &str, value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if location_type.eq_ignore_ascii_case("KoboSpan") {
+            let mut parts = value.strip_prefix("kobo.").unwrap_or_default().split('.');
+            let plausible = parts.clone().count() >= 2
+                && parts.all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()));
+            if !plausible {
+                return Err(format!("is not a Kobo span like kobo.1.1, got {:?}", value));
+            }
+            Ok(Self::KoboSpan(value.to_string()))
+        } else if location_type.eq_ignore_ascii_case("CFI")
+            || location_type.eq_ignore_ascii_case("EpubCfi")
+        {
+            let path = match value.strip_prefix("epubcfi(") {
+                Some(rest) => rest.strip_suffix(')'),
+                None => Some(value),
+            };
+            let plausible = path.is_some_and(|path| {
+                path.starts_with('/')
+                    && path[1..].starts_with(|c: char| c.is_ascii_digit())
+                    && path.matches('[').count() == path.matches(']').count()
+            });
+            if !plausible {
+                return Err(format!(
+                    "is not an EPUB CFI like epubcfi(/6/4), got {:?}",
+                    value
+                ));
+            }
+            Ok(Self::Cfi(value.to_string()))
+        } else {
+            Ok(Self::Other(value.to_string()))
+        }
+    }
+}
+
 #[derive(Debug, Clone, Object, Deserialize)]
 #[oai(rename_all = "PascalCase")]
 #[serde(rename_all = "PascalCase")]
@@ -307,9 +524,268 @@ pub struct ChangedEntitlement {
     pub changed_entitlement: KoboSyncedBook,
 }
 
+/// Book that is no longer available, sent without metadata
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct KoboRemovedBook {
+    pub book_entitlement: BookEntitlement,
+}
+
+/// Removal, sent under the same `ChangedEntitlement` key as updates
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct RemovedEntitlement {
+    pub changed_entitlement: KoboRemovedBook,
+}
+
+/// Shelf (collection) on the device
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct KoboTag {
+    pub created: DateTime<Utc>,
+    pub id: Uuid,
+    pub items: Vec<KoboTagItem>,
+    pub last_modified: DateTime<Utc>,
+    pub name: String,
+    #[oai(rename = "Type")]
+    pub _type: String,
+}
+
+impl KoboTag {
+    /// Shelf holding the books of an ABS series. The id is derived from the series id, so the
+    /// same series always maps to the same shelf.
+    pub fn series_shelf(
+        series_id: &str,
+        name: &str,
+        book_ids: &[Uuid],
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            created: now,
+            id: Uuid::new_v3(
+                &Uuid::NAMESPACE_OID,
+                format!("abs-series-shelf:{}", series_id).as_bytes(),
+            ),
+            items: book_ids
+                .iter()
+                .map(|id| KoboTagItem {
+                    revision_id: *id,
+                    _type: "ProductRevisionTagItem".into(),
+                })
+                .collect(),
+            last_modified: now,
+            name: name.to_string(),
+            _type: "UserTag".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct KoboTagItem {
+    pub revision_id: Uuid,
+    #[oai(rename = "Type")]
+    pub _type: String,
+}
+
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct KoboSyncedTag {
+    pub tag: KoboTag,
+}
+
+#[derive(Debug, Clone, Object, Deserialize)]
+#[oai(rename_all = "PascalCase")]
+#[serde(rename_all = "PascalCase")]
+pub struct NewTag {
+    pub new_tag: KoboSyncedTag,
+}
+
 #[derive(Debug, Clone, Union, Deserialize)]
 #[serde(untagged)]
 pub enum KoboSyncEntitlement {
     NewEntitlement(NewEntitlement),
     ChangedEntitlement(ChangedEntitlement),
+    RemovedEntitlement(RemovedEntitlement),
+    NewTag(NewTag),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plausible_bookmark_locations_are_accepted() {
+        assert_eq!(
+            BookmarkLocation::parse("CFI", "epubcfi(/6/4[chap01]!/4/2/1:0)"),
+            Ok(BookmarkLocation::Cfi(
+                "epubcfi(/6/4[chap01]!/4/2/1:0)".into()
+            ))
+        );
+        assert_eq!(
+            BookmarkLocation::parse("EpubCfi", "/6/8!/4/2"),
+            Ok(BookmarkLocation::Cfi("/6/8!/4/2".into()))
+        );
+        assert_eq!(
+            BookmarkLocation::parse("KoboSpan", "kobo.12.3"),
+            Ok(BookmarkLocation::KoboSpan("kobo.12.3".into()))
+        );
+        assert_eq!(
+            BookmarkLocation::parse("Page", "12"),
+            Ok(BookmarkLocation::Other("12".into()))
+        );
+    }
+
+    #[test]
+    fn malformed_bookmark_locations_are_rejected() {
+        for (location_type, value) in [
+            ("CFI", ""),
+            ("CFI", "epubcfi()"),
+            ("CFI", "epubcfi(/6/4"),
+            ("EpubCfi", "chapter one"),
+            ("CFI", "epubcfi(/6/4[chap01!/4)"),
+            ("KoboSpan", ""),
+            ("KoboSpan", "kobo."),
+            ("KoboSpan", "span.1.1"),
+            ("KoboSpan", "kobo.1.x"),
+        ] {
+            assert!(
+                BookmarkLocation::parse(location_type, value).is_err(),
+                "{} {:?}",
+                location_type,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn contributors_from_author_ids() {
+        let metadata: abs_client::BookMetadata = serde_json::from_value(serde_json::json!({
+            "title": "Why We Can't Wait",
+            "authorName": "Martin Luther King, Jr., Jesse Jackson",
+            "authors": [
+                { "id": "aut_1", "name": "Martin Luther King, Jr." },
+                { "id": "aut_2", "name": "Jesse Jackson" }
+            ],
+            "genres": []
+        }))
+        .unwrap();
+
+        assert_eq!(
+            contributor_names(&metadata, AuthorNameOrder::Display),
+            Some(vec![
+                "Martin Luther King, Jr.".to_string(),
+                "Jesse Jackson".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn isbn_and_asin_become_identifiers() {
+        let metadata: abs_client::BookMetadata = serde_json::from_value(serde_json::json!({
+            "title": "Dune",
+            "isbn": "978-0-441-17271-9",
+            "asin": "B00B7NPRY8",
+            "genres": []
+        }))
+        .unwrap();
+
+        let book = BookMetadata::try_from_abs_metadata(
+            Uuid::now_v7(),
+            &metadata,
+            vec![],
+            AuthorNameOrder::Display,
+        )
+        .unwrap();
+        assert_eq!(book.isbn.as_deref(), Some("9780441172719"));
+        assert_eq!(
+            book.external_ids,
+            vec![
+                Uuid::new_v3(&Uuid::NAMESPACE_OID, b"isbn:9780441172719"),
+                Uuid::new_v3(&Uuid::NAMESPACE_OID, b"asin:B00B7NPRY8"),
+            ]
+        );
+
+        let metadata: abs_client::BookMetadata =
+            serde_json::from_value(serde_json::json!({ "title": "Untracked", "isbn": " " }))
+                .unwrap();
+        assert!(external_ids(&metadata).is_empty());
+    }
+
+    #[test]
+    fn sort_order_uses_last_first_names() {
+        let metadata: abs_client::BookMetadata = serde_json::from_value(serde_json::json!({
+            "title": "The Hobbit",
+            "authorName": "J. R. R. Tolkien",
+            "authorNameLF": "Tolkien, J. R. R.",
+            "genres": []
+        }))
+        .unwrap();
+        assert_eq!(
+            contributor_names(&metadata, AuthorNameOrder::Sort),
+            Some(vec!["Tolkien, J. R. R.".to_string()])
+        );
+
+        let metadata: abs_client::BookMetadata = serde_json::from_value(serde_json::json!({
+            "title": "Good Omens",
+            "authors": [
+                { "id": "aut_1", "name": "Terry Pratchett" },
+                { "id": "aut_2", "name": "Neil Gaiman" }
+            ],
+            "genres": []
+        }))
+        .unwrap();
+        assert_eq!(
+            contributor_names(&metadata, AuthorNameOrder::Sort),
+            Some(vec![
+                "Pratchett, Terry".to_string(),
+                "Gaiman, Neil".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn percent_only_progress_has_no_location() {
+        let progress: MediaProgress = serde_json::from_value(serde_json::json!({
+            "id": "li_progress",
+            "libraryItemId": "075ebcee-d657-4b01-a96d-b94fadb1898c",
+            "progress": 0.42,
+            "ebookLocation": null,
+            "isFinished": false,
+            "lastUpdate": 1747214658742_i64,
+            "startedAt": 1747000000000_i64
+        }))
+        .unwrap();
+
+        let bookmark = KoboCurrentBookmark::from_abs_progress(&progress);
+        assert_eq!(bookmark.content_source_progress_percent, Some(42.0));
+        assert!(bookmark.location.is_none());
+
+        let state = KoboSyncedReadingState::from_abs_progress(Uuid::nil(), &progress);
+        assert!(matches!(
+            state.status_info.status,
+            KoboSyncedStatus::Reading
+        ));
+    }
+
+    #[test]
+    fn series_becomes_shelf_with_its_books() {
+        let books = [Uuid::now_v7(), Uuid::now_v7()];
+        let now = Utc::now();
+
+        let shelf = KoboTag::series_shelf("ser_1", "The Expanse", &books, now);
+        assert_eq!(shelf.name, "The Expanse");
+        let items: Vec<Uuid> = shelf.items.iter().map(|i| i.revision_id).collect();
+        assert_eq!(items, books);
+        assert_eq!(
+            shelf.id,
+            KoboTag::series_shelf("ser_1", "Renamed", &[], now).id
+        );
+        assert_ne!(shelf.id, KoboTag::series_shelf("ser_2", "", &[], now).id);
+    }
 }
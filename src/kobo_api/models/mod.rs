@@ -3,7 +3,12 @@ pub use kobo::*;
 
 use std::ffi::os_str::Display;
 
-use poem_openapi::{ApiResponse, Enum, Object, payload::Json};
+use chrono::{DateTime, Utc};
+use poem_openapi::{
+    ApiResponse, Enum, Object,
+    payload::{Binary, Html, Json},
+};
+use serde::Deserialize;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Object)]
@@ -11,6 +16,24 @@ pub struct LibraryDto {
     pub id: Uuid,
     pub name: String,
     pub media_type: Option<String>,
+    /// Position of the library in the ABS UI
+    pub display_order: Option<i64>,
+}
+
+/// Values to filter a library's items by, e.g. for `SYNC_FILTER`
+#[derive(Debug, Clone, Object)]
+pub struct LibraryFilterDataDto {
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    pub authors: Vec<FilterOptionDto>,
+    pub series: Vec<FilterOptionDto>,
+}
+
+/// Author or series to filter by, referenced by `id` in ABS filters
+#[derive(Debug, Clone, Object)]
+pub struct FilterOptionDto {
+    pub id: String,
+    pub name: String,
 }
 
 #[derive(Debug, Clone, Object)]
@@ -19,8 +42,188 @@ pub struct LibraryItemDto {
     pub title: String,
     pub author: Option<String>,
     pub series: Option<String>,
+    /// Cover URL on this service; never an ABS URL or filesystem path
     pub cover_url: Option<String>,
+    /// Primary ebook format, superseded by `ebook_formats`
+    #[oai(deprecated)]
     pub ebook_format: Option<String>,
+    /// All ebook formats available for the item, e.g. ["epub", "pdf"]
+    pub ebook_formats: Vec<String>,
+    /// Files of the item in ABS, of any kind
+    pub num_files: i64,
+    /// Ebook files of the item; items without any can't be synced to a device
+    pub ebook_file_count: u32,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct SyncErrorDto {
+    /// ABS library item that failed to map
+    pub abs_item_id: String,
+    /// Mapping error message
+    pub error: String,
+    pub timestamp: DateTime<Utc>,
+    /// Consecutive syncs the item failed in; at `MAX_MAP_FAILURES` syncs skip it until it changes
+    pub failure_count: i32,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceDebugDto {
+    pub device_id: Uuid,
+    /// Last `X-Kobo-Sync-Token` the device sent, decoded when possible, with the Kobo store
+    /// token masked and truncated
+    pub last_sync_token: Option<String>,
+    pub last_sync_token_at: Option<DateTime<Utc>>,
+    /// Last analytics payload the device posted, with sensitive keys masked and truncated
+    pub last_analytics: Option<String>,
+    pub last_analytics_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct ValidateKeyRequestDto {
+    /// ABS API key to check
+    pub abs_api_key: String,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct ValidateKeyDto {
+    /// Whether ABS accepted the key
+    pub valid: bool,
+    /// Libraries the key can access
+    pub libraries: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct UserRequestDto {
+    /// ABS API key the user's devices sync with
+    pub abs_api_key: String,
+}
+
+/// Form of the `/setup` page; either the API key or the username and password are required
+#[derive(Debug, Clone, Object, Deserialize)]
+pub struct SetupRequestDto {
+    /// ABS API key, used instead of the username and password when given
+    pub abs_api_key: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct UserDto {
+    pub id: Uuid,
+    /// The user's ABS API key, redacted
+    pub abs_api_key: String,
+    /// Number of devices owned by the user
+    pub devices: u64,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct StoreProxyRequestDto {
+    /// Proxy this device's syncs to the Kobo store; `null` follows `KOBO_STORE_PROXY`
+    pub proxy_store: Option<bool>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct StoreProxyDto {
+    /// Device override, `null` when the global default applies
+    pub proxy_store: Option<bool>,
+    /// Whether the device's syncs are proxied to the Kobo store
+    pub effective: bool,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct SyncTagDto {
+    /// ABS tag a book needs to be synced to this device, `null` to sync the whole library
+    pub sync_tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceLinkRequestDto {
+    /// User the device should sync as
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceLinkDto {
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+    /// Whether the device was provisioned by this request
+    pub created: bool,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceRegisterRequestDto {
+    /// User the new device syncs as
+    pub user_id: Uuid,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceRegistrationDto {
+    /// The device's auth token, part of every `/kobo/:auth_token/...` URL
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+    /// Value for `api_endpoint` in the `[OneStoreServices]` section of the device's
+    /// `Kobo eReader.conf`
+    pub api_endpoint: String,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceDto {
+    /// The device's auth token
+    pub device_id: Uuid,
+    /// User the device syncs as
+    pub owner_id: Uuid,
+    /// When the device last synced, `null` if it never did
+    pub last_sync_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceUserRequestDto {
+    /// User sharing the device
+    pub user_id: Uuid,
+    /// Kobo `UserKey` the device presents while signed in as this user
+    pub user_key: String,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct DeviceUserDto {
+    pub device_id: Uuid,
+    pub user_id: Uuid,
+    pub user_key: String,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct PendingSyncDto {
+    /// Books the device doesn't have yet
+    pub new: u64,
+    /// Books on the device that changed in ABS since they were synced
+    pub updated: u64,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct BookResendDto {
+    pub book_id: Uuid,
+    /// Whether the device had been sent the book before
+    pub was_synced: bool,
+}
+
+/// Outcome of one dependency check of `/healthz`
+#[derive(Debug, Clone, Object)]
+pub struct HealthCheckDto {
+    pub ok: bool,
+    /// Version the dependency reported, or why it is unusable
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct HealthDto {
+    /// `ok`, or `unavailable` when ABS or the database can't be used
+    pub status: String,
+    /// Version of this server
+    pub version: String,
+    pub abs: HealthCheckDto,
+    pub database: HealthCheckDto,
+    /// Optional, devices are served plain EPUBs without it
+    pub kepubify: HealthCheckDto,
 }
 
 #[derive(Debug, Clone, Object)]
@@ -35,6 +238,17 @@ impl From<String> for ErrorDto {
     }
 }
 
+#[derive(ApiResponse)]
+pub enum HealthResponseDto {
+    /// ABS and the database are usable
+    #[oai(status = 200)]
+    Ok(Json<HealthDto>),
+
+    /// ABS or the database is unusable
+    #[oai(status = 503)]
+    ServiceUnavailable(Json<HealthDto>),
+}
+
 #[derive(ApiResponse)]
 pub enum LibraryListResponse {
     /// Libraries successfully retrieved
@@ -46,15 +260,352 @@ pub enum LibraryListResponse {
     BadGateway(Json<ErrorDto>),
 }
 
+#[derive(ApiResponse)]
+pub enum LibraryFilterDataResponseDto {
+    /// Filter values of the library
+    #[oai(status = 200)]
+    Ok(Json<LibraryFilterDataDto>),
+
+    /// Upstream ABS error
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum ValidateKeyResponseDto {
+    /// Validation result; an unknown key is reported as `valid: false`
+    #[oai(status = 200)]
+    Ok(Json<ValidateKeyDto>),
+
+    /// Upstream ABS error
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum UserListResponseDto {
+    /// All users
+    #[oai(status = 200)]
+    Ok(Json<Vec<UserDto>>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum UserResponseDto {
+    /// User updated
+    #[oai(status = 200)]
+    Ok(Json<UserDto>),
+
+    /// User created
+    #[oai(status = 201)]
+    Created(Json<UserDto>),
+
+    /// Empty API key or one ABS rejected
+    #[oai(status = 400)]
+    BadRequest(Json<ErrorDto>),
+
+    /// Unknown user
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+
+    /// The API key could not be checked with ABS
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum SetupResponseDto {
+    /// The setup page, with the `api_endpoint` once a device was registered
+    #[oai(status = 200)]
+    Ok(Html<String>),
+
+    /// Missing or rejected ABS credentials
+    #[oai(status = 400)]
+    BadRequest(Html<String>),
+
+    /// `SETUP_PAGE` is disabled
+    #[oai(status = 404)]
+    NotFound(Html<String>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Html<String>),
+
+    /// The credentials could not be checked with ABS
+    #[oai(status = 502)]
+    BadGateway(Html<String>),
+}
+
+#[derive(ApiResponse)]
+pub enum UserDeleteResponseDto {
+    /// User and their devices deleted
+    #[oai(status = 204)]
+    NoContent,
+
+    /// Unknown user
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
 #[derive(ApiResponse)]
 pub enum LibraryItemsResponseDto {
     /// Items successfully retrieved
     #[oai(status = 200)]
     Ok(Json<Vec<LibraryItemDto>>),
 
+    /// Invalid query parameters
+    #[oai(status = 400)]
+    BadRequest(Json<ErrorDto>),
+
+    /// Upstream ABS error
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum SyncErrorsResponseDto {
+    /// Mapping failures recorded during the device's last sync
+    #[oai(status = 200)]
+    Ok(Json<Vec<SyncErrorDto>>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum StoreProxyResponseDto {
+    /// Updated store proxy setting
+    #[oai(status = 200)]
+    Ok(Json<StoreProxyDto>),
+
+    /// Unknown device
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum SyncTagResponseDto {
+    /// Updated sync tag
+    #[oai(status = 200)]
+    Ok(Json<SyncTagDto>),
+
+    /// Unknown device
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceLinkResponseDto {
+    /// Device now owned by the user
+    #[oai(status = 200)]
+    Ok(Json<DeviceLinkDto>),
+
+    /// Unknown user
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceRegisterResponseDto {
+    /// Device registered for the user
+    #[oai(status = 201)]
+    Created(Json<DeviceRegistrationDto>),
+
+    /// Unknown user
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceListResponseDto {
+    /// All registered devices
+    #[oai(status = 200)]
+    Ok(Json<Vec<DeviceDto>>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceRevokeResponseDto {
+    /// Device and its sync history deleted; its token is no longer accepted
+    #[oai(status = 204)]
+    NoContent,
+
+    /// Unknown device
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceUserResponseDto {
+    /// User added to the device
+    #[oai(status = 200)]
+    Ok(Json<DeviceUserDto>),
+
+    /// Empty user key
+    #[oai(status = 400)]
+    BadRequest(Json<ErrorDto>),
+
+    /// Unknown device or user
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum PendingSyncResponseDto {
+    /// Books waiting for the device's next sync
+    #[oai(status = 200)]
+    Ok(Json<PendingSyncDto>),
+
+    /// Unknown device
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+
+    /// ABS could not be queried
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum BookResendResponseDto {
+    /// The book is sent to the device again as new on its next sync
+    #[oai(status = 200)]
+    Ok(Json<BookResendDto>),
+
+    /// Unknown device, or the book doesn't exist in ABS
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+
+    /// ABS could not be queried
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceDebugResponseDto {
+    /// Debug details of the device
+    #[oai(status = 200)]
+    Ok(Json<DeviceDebugDto>),
+
+    /// Unknown device, or debug endpoints are disabled
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum CoverResponseDto {
+    /// Cover image as served by ABS
+    #[oai(status = 200)]
+    Ok(
+        Binary<poem::Body>,
+        #[oai(header = "Content-Type")] Option<String>,
+    ),
+
+    /// Unknown device token
+    #[oai(status = 401)]
+    Unauthorized(Json<ErrorDto>),
+
+    /// Item or cover not found
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+
+    /// Upstream ABS error
+    #[oai(status = 502)]
+    BadGateway(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum DownloadResponseDto {
+    /// Ebook file as served by ABS
+    #[oai(status = 200)]
+    Ok(
+        Binary<poem::Body>,
+        #[oai(header = "Content-Type")] Option<String>,
+        #[oai(header = "Content-Length")] Option<u64>,
+        #[oai(header = "Content-Disposition")] String,
+    ),
+
+    /// KEPUB conversion started or still running, retry after the given seconds
+    #[oai(status = 202)]
+    Accepted(#[oai(header = "Retry-After")] u64),
+
+    /// Unknown book format
+    #[oai(status = 400)]
+    BadRequest(Json<ErrorDto>),
+
+    /// Unknown device token
+    #[oai(status = 401)]
+    Unauthorized(Json<ErrorDto>),
+
+    /// Item or ebook file not found
+    #[oai(status = 404)]
+    NotFound(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+
     /// Upstream ABS error
     #[oai(status = 502)]
     BadGateway(Json<ErrorDto>),
+
+    /// Too many downloads are already waiting for ABS
+    #[oai(status = 503)]
+    ServiceUnavailable(Json<ErrorDto>, #[oai(header = "Retry-After")] u64),
 }
 
 // ===== Kobo sync and device-facing DTOs (minimal, JSON passthrough where shapes vary) =====
@@ -106,6 +657,22 @@ pub enum ReadingStateGetResponseDto {
 
     #[oai(status = 404)]
     NotFound(Json<ErrorDto>),
+
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
+#[derive(ApiResponse)]
+pub enum ReadingStatesResponseDto {
+    /// All reading states stored for the device
+    #[oai(status = 200)]
+    Ok(Json<Vec<serde_json::Value>>),
+
+    #[oai(status = 401)]
+    Unauthorized(Json<ErrorDto>),
+
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
 }
 
 #[derive(ApiResponse)]
@@ -116,6 +683,12 @@ pub enum ReadingStatePutResponseDto {
 
     #[oai(status = 400)]
     BadRequest(Json<ErrorDto>),
+
+    #[oai(status = 401)]
+    Unauthorized(Json<ErrorDto>),
+
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
 }
 
 #[derive(Debug, Clone, Object)]
@@ -172,12 +745,50 @@ pub enum InitializationResponseDto {
 }
 
 #[derive(ApiResponse)]
-pub enum DeviceAuthResponseDto {
-    /// Synthetic device auth result
+pub enum AnalyticsResponseDto {
+    /// Acknowledgement in the shape the device expects
     #[oai(status = 200)]
     Ok(Json<serde_json::Value>),
 }
 
+/// Tokens issued to a device by `/v1/auth/device` and `/v1/auth/refresh`
+#[derive(Debug, Clone, Object)]
+#[oai(rename_all = "PascalCase")]
+pub struct DeviceAuthResult {
+    /// Not checked: device routes keep authenticating by their path token
+    pub access_token: String,
+    /// Exchanged for a new access token through `/v1/auth/refresh`
+    pub refresh_token: String,
+    pub tracking_id: String,
+    /// Access token lifetime in seconds
+    pub expires_in: u64,
+    pub token_type: String,
+    /// Echo of the `UserKey` the device authenticated with, empty on refresh
+    pub user_key: String,
+}
+
+#[derive(Debug, Clone, Object)]
+#[oai(rename_all = "PascalCase")]
+pub struct DeviceRefreshRequest {
+    /// Refresh token issued by the last device auth
+    pub refresh_token: String,
+}
+
+#[derive(ApiResponse)]
+pub enum DeviceAuthResponseDto {
+    /// Issued device tokens
+    #[oai(status = 200)]
+    Ok(Json<DeviceAuthResult>),
+
+    /// Unknown device token, or a refresh token that wasn't issued to the device
+    #[oai(status = 401)]
+    Unauthorized(Json<ErrorDto>),
+
+    /// Database error
+    #[oai(status = 500)]
+    InternalServerError(Json<ErrorDto>),
+}
+
 #[derive(ApiResponse)]
 pub enum NotImplementedResponseDto {
     /// Feature not implemented yet
@@ -191,11 +802,52 @@ pub enum BookFormatDto {
     Kepub,
 }
 
-impl ToString for BookFormatDto {
-    fn to_string(&self) -> String {
-        match self {
-            BookFormatDto::Epub => "epub".into(),
-            BookFormatDto::Kepub => "kepub".into(),
+impl std::fmt::Display for BookFormatDto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BookFormatDto::Epub => "epub",
+            BookFormatDto::Kepub => "kepub",
+        })
+    }
+}
+
+/// Parses the form written by `Display`, ignoring case, e.g. a download route's `:format` segment
+impl std::str::FromStr for BookFormatDto {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "epub" => Ok(BookFormatDto::Epub),
+            "kepub" => Ok(BookFormatDto::Kepub),
+            _ => Err(anyhow::anyhow!("unknown book format: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn book_formats_round_trip_through_strings() {
+        assert!(matches!(
+            "kepub".parse::<BookFormatDto>(),
+            Ok(BookFormatDto::Kepub)
+        ));
+        assert!(matches!(
+            "EPUB".parse::<BookFormatDto>(),
+            Ok(BookFormatDto::Epub)
+        ));
+        assert!("mobi".parse::<BookFormatDto>().is_err());
+
+        for format in [BookFormatDto::Epub, BookFormatDto::Kepub] {
+            let displayed = format.to_string();
+            assert_eq!(
+                displayed.parse::<BookFormatDto>().unwrap().to_string(),
+                displayed
+            );
         }
+        assert_eq!(BookFormatDto::Epub.to_string(), "epub");
+        assert_eq!(BookFormatDto::Kepub.to_string(), "kepub");
     }
 }
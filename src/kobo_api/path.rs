@@ -0,0 +1,182 @@
+use poem::{
+    Endpoint, Middleware, Request, Result,
+    http::{Uri, uri::PathAndQuery},
+};
+
+/// Literal segments of the `/kobo/...` routes; device-sent variants differing only in case are
+/// rewritten to these
+const KOBO_SEGMENTS: &[&str] = &[
+    "kobo",
+    "v1",
+    "library",
+    "sync",
+    "metadata",
+    "state",
+    "reading-states",
+    "tags",
+    "items",
+    "delete",
+    "initialization",
+    "auth",
+    "device",
+    "refresh",
+    "analytics",
+    "gettests",
+    "event",
+    "download",
+    "books",
+    "thumbnail",
+    "image.jpg",
+];
+
+/// Normalizes Kobo device paths before routing: trailing and repeated slashes are dropped and
+/// route keywords are matched case-insensitively. Other paths are left untouched.
+pub struct KoboPathNormalize;
+
+impl<E: Endpoint> Middleware<E> for KoboPathNormalize {
+    type Output = KoboPathNormalizeEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        KoboPathNormalizeEndpoint { inner }
+    }
+}
+
+pub struct KoboPathNormalizeEndpoint<E> {
+    inner: E,
+}
+
+impl<E: Endpoint> Endpoint for KoboPathNormalizeEndpoint<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        if let Some(path) = normalize_kobo_path(req.uri().path()) {
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+            let mut parts = req.uri().clone().into_parts();
+            if let Ok(pq) = PathAndQuery::try_from(path_and_query) {
+                parts.path_and_query = Some(pq);
+                if let Ok(uri) = Uri::from_parts(parts) {
+                    tracing::trace!(from = %req.uri(), to = %uri, "normalized kobo path");
+                    *req.uri_mut() = uri;
+                }
+            }
+        }
+        self.inner.call(req).await
+    }
+}
+
+/// Returns the canonical form of a `/kobo/...` path, or `None` if it is not a Kobo path or is
+/// already canonical.
+pub fn normalize_kobo_path(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if !segments
+        .first()
+        .is_some_and(|s| s.eq_ignore_ascii_case("kobo"))
+    {
+        return None;
+    }
+
+    let normalized = segments
+        .iter()
+        .map(|segment| {
+            KOBO_SEGMENTS
+                .iter()
+                .find(|known| known.eq_ignore_ascii_case(segment))
+                .copied()
+                .unwrap_or(segment)
+        })
+        .fold(String::new(), |mut acc, segment| {
+            acc.push('/');
+            acc.push_str(segment);
+            acc
+        });
+    (normalized != path).then_some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{EndpointExt, Route, get, handler, http::StatusCode, test::TestClient, web::Path};
+
+    use super::*;
+
+    const TOKEN: &str = "0199a3b2-7c4e-7d21-9f3a-5b6c7d8e9f01";
+
+    #[handler]
+    fn sync(Path(token): Path<String>) -> String {
+        token
+    }
+
+    fn client() -> TestClient<impl Endpoint> {
+        TestClient::new(
+            Route::new()
+                .at("/kobo/:auth_token/v1/library/sync", get(sync))
+                .with(KoboPathNormalize),
+        )
+    }
+
+    #[tokio::test]
+    async fn sync_path_resolves_with_and_without_trailing_slash() {
+        let cli = client();
+        for path in [
+            format!("/kobo/{}/v1/library/sync", TOKEN),
+            format!("/kobo/{}/v1/library/sync/", TOKEN),
+        ] {
+            let resp = cli.get(&path).send().await;
+            resp.assert_status_is_ok();
+            resp.assert_text(TOKEN).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_path_tolerates_case_and_repeated_slashes() {
+        let resp = client()
+            .get(format!("/Kobo/{}//v1/Library/Sync/?foo=bar", TOKEN))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text(TOKEN).await;
+    }
+
+    #[tokio::test]
+    async fn download_path_tolerates_case() {
+        #[handler]
+        fn download(Path((token, _book, format)): Path<(String, String, String)>) -> String {
+            format!("{} {}", token, format)
+        }
+
+        let cli = TestClient::new(
+            Route::new()
+                .at(
+                    "/kobo/:auth_token/v1/download/:book_id/:format",
+                    get(download),
+                )
+                .with(KoboPathNormalize),
+        );
+        let resp = cli
+            .get(format!("/kobo/{}/v1/Download/{}/kepub", TOKEN, TOKEN))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text(format!("{} kepub", TOKEN)).await;
+    }
+
+    #[test]
+    fn non_kobo_paths_are_untouched() {
+        assert_eq!(normalize_kobo_path("/v1/libraries/"), None);
+        assert_eq!(
+            normalize_kobo_path(&format!("/kobo/{}/v1/library/sync", TOKEN)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_still_404() {
+        let resp = client()
+            .get(format!("/kobo/{}/v1/library/nope/", TOKEN))
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,83 @@
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    http::{Method, StatusCode, header},
+};
+
+/// Methods the `/kobo/...` routes are served with
+const KOBO_ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, OPTIONS";
+
+/// Answers bare `OPTIONS` probes of `/kobo/...` paths with `204` and an `Allow` header instead of
+/// `405`. CORS preflights carry an `Origin` header and are left to the CORS middleware, which
+/// must wrap this one.
+pub struct KoboOptionsProbe;
+
+impl<E: Endpoint> Middleware<E> for KoboOptionsProbe {
+    type Output = KoboOptionsProbeEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        KoboOptionsProbeEndpoint { inner }
+    }
+}
+
+pub struct KoboOptionsProbeEndpoint<E> {
+    inner: E,
+}
+
+impl<E: Endpoint> Endpoint for KoboOptionsProbeEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if req.method() == Method::OPTIONS
+            && !req.headers().contains_key(header::ORIGIN)
+            && req.uri().path().starts_with("/kobo/")
+        {
+            tracing::debug!(path = %req.uri().path(), "answering OPTIONS probe");
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .header(header::ALLOW, KOBO_ALLOWED_METHODS)
+                .finish());
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{EndpointExt, Route, get, handler, middleware::Cors, test::TestClient};
+
+    use super::*;
+
+    #[handler]
+    fn sync() -> &'static str {
+        "[]"
+    }
+
+    #[tokio::test]
+    async fn bare_options_gets_allow_header() {
+        let cli = TestClient::new(
+            Route::new()
+                .at("/kobo/:auth_token/v1/library/sync", get(sync))
+                .with(KoboOptionsProbe)
+                .with(Cors::new()),
+        );
+
+        let resp = cli
+            .options("/kobo/0199a3b2-7c4e-7d21-9f3a-5b6c7d8e9f01/v1/library/sync")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::NO_CONTENT);
+        resp.assert_header(header::ALLOW, KOBO_ALLOWED_METHODS);
+
+        let resp = cli
+            .options("/kobo/0199a3b2-7c4e-7d21-9f3a-5b6c7d8e9f01/v1/library/sync")
+            .header(header::ORIGIN, "https://reader.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_header(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            "https://reader.example.com",
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use entities::devices;
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    http::{StatusCode, header},
+};
+use sea_orm::{DatabaseConnection, EntityTrait};
+use uuid::Uuid;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Fixed one-minute window request counter per key
+pub struct RateLimiter {
+    limit_per_min: u32,
+    windows: Mutex<HashMap<Uuid, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit_per_min: u32) -> Self {
+        Self {
+            limit_per_min,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a request for `key`; when the budget is used up, returns how long until it resets
+    pub fn check(&self, key: Uuid) -> std::result::Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        let (started, count) = windows.entry(key).or_insert((now, 0));
+        if now.duration_since(*started) >= WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= self.limit_per_min {
+            return Err(WINDOW.saturating_sub(now.duration_since(*started)));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+/// Limits `/kobo/:auth_token/...` requests per user, summed over all of the user's devices.
+/// Requests for unknown devices pass through so [`DeviceAuth`] can reject them.
+///
+/// [`DeviceAuth`]: crate::kobo_api::auth::DeviceAuth
+pub struct UserRateLimit {
+    limiter: Option<Arc<RateLimiter>>,
+    db: Arc<DatabaseConnection>,
+}
+
+impl UserRateLimit {
+    /// `limit_per_min` of `None` disables the limit
+    pub fn new(limit_per_min: Option<u32>, db: Arc<DatabaseConnection>) -> Self {
+        Self {
+            limiter: limit_per_min.map(|limit| Arc::new(RateLimiter::new(limit))),
+            db,
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for UserRateLimit {
+    type Output = UserRateLimitEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        UserRateLimitEndpoint {
+            inner,
+            limiter: self.limiter.clone(),
+            db: self.db.clone(),
+        }
+    }
+}
+
+pub struct UserRateLimitEndpoint<E> {
+    inner: E,
+    limiter: Option<Arc<RateLimiter>>,
+    db: Arc<DatabaseConnection>,
+}
+
+impl<E: Endpoint> Endpoint for UserRateLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        if let Some(limiter) = &self.limiter
+            && let Some(device_id) = device_token(req.uri().path())
+            && let Some(user_id) = self.owner_of(device_id).await
+            && let Err(retry_after) = limiter.check(user_id)
+        {
+            tracing::warn!(%user_id, %device_id, "user rate limit exceeded");
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(header::RETRY_AFTER, retry_after.as_secs().max(1))
+                .body("Rate limit exceeded"));
+        }
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+impl<E> UserRateLimitEndpoint<E> {
+    async fn owner_of(&self, device_id: Uuid) -> Option<Uuid> {
+        match devices::Entity::find_by_id(device_id)
+            .one(self.db.as_ref())
+            .await
+        {
+            Ok(device) => device.map(|d| d.owner_id),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to resolve device owner for rate limiting");
+                None
+            }
+        }
+    }
+}
+
+/// Device token of a `/kobo/:auth_token/...` path
+pub fn device_token(path: &str) -> Option<Uuid> {
+    let mut segments = path.split('/').filter(|s| !s.is_empty());
+    segments.next().filter(|s| s.eq_ignore_ascii_case("kobo"))?;
+    segments
+        .next()
+        .and_then(|token| Uuid::parse_str(token).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use entities::devices;
+    use poem::{EndpointExt, Route, get, handler, test::TestClient};
+    use sea_orm::ActiveValue::Set;
+
+    use super::*;
+
+    #[handler]
+    fn sync() -> &'static str {
+        "ok"
+    }
+
+    #[tokio::test]
+    async fn devices_of_one_user_share_the_user_budget() {
+        let (db, first_device) = crate::test_support::db_with_device().await;
+        let owner_id = devices::Entity::find_by_id(first_device)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap()
+            .owner_id;
+        let second_device = Uuid::now_v7();
+        devices::Entity::insert(devices::ActiveModel {
+            id: Set(second_device),
+            owner_id: Set(owner_id),
+            sync_scan_offset: Set(None),
+            proxy_store: Set(None),
+            last_sync_token: Set(None),
+            last_sync_token_at: Set(None),
+            refresh_token: Set(None),
+            sync_tag: Set(None),
+            last_analytics: Set(None),
+            last_analytics_at: Set(None),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+
+        let cli = TestClient::new(
+            Route::new()
+                .at("/kobo/:auth_token/v1/library/sync", get(sync))
+                .with(UserRateLimit::new(Some(3), Arc::new(db))),
+        );
+        let sync_path = |device: Uuid| format!("/kobo/{}/v1/library/sync", device);
+
+        cli.get(sync_path(first_device))
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.get(sync_path(first_device))
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.get(sync_path(second_device))
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let resp = cli.get(sync_path(second_device)).send().await;
+        resp.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.0.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[test]
+    fn limiter_resets_after_the_window() {
+        let limiter = RateLimiter::new(1);
+        let key = Uuid::now_v7();
+        assert!(limiter.check(key).is_ok());
+        assert!(limiter.check(key).is_err());
+        limiter.windows.lock().unwrap().get_mut(&key).unwrap().0 -= WINDOW;
+        assert!(limiter.check(key).is_ok());
+    }
+}
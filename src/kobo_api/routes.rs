@@ -6,26 +6,50 @@ use poem::http::HeaderMap;
 use poem_openapi::{
     OpenApi, Tags,
     param::{Header, Path, Query},
-    payload::{Json, PlainText},
+    payload::{Form, Html, Json, PlainText},
 };
 use uuid::Uuid;
 
+use super::download_limit::DownloadLimit;
 use super::models::{
-    DeviceAuthResponseDto, EmptyOkResponseDto, InitializationResponseDto, LibraryItemsResponseDto,
-    LibraryListResponse, MetadataResponseDto, NoContentResponseDto, ReadingStateGetResponseDto,
-    ReadingStatePutResponseDto, SyncResponseDto, TagCreateRequestDto, TagCreateResponseDto,
-    TagItemsRequestDto,
+    AnalyticsResponseDto, BookResendResponseDto, CoverResponseDto, DeviceAuthResponseDto,
+    DeviceDebugResponseDto, DeviceLinkRequestDto, DeviceLinkResponseDto, DeviceListResponseDto,
+    DeviceRefreshRequest, DeviceRegisterRequestDto, DeviceRegisterResponseDto,
+    DeviceRevokeResponseDto, DeviceUserRequestDto, DeviceUserResponseDto, DownloadResponseDto,
+    EmptyOkResponseDto, ErrorDto, HealthResponseDto, InitializationResponseDto,
+    LibraryFilterDataResponseDto, LibraryItemsResponseDto, LibraryListResponse,
+    MetadataResponseDto, NoContentResponseDto, PendingSyncResponseDto, ReadingStateGetResponseDto,
+    ReadingStatePutResponseDto, ReadingStatesResponseDto, SetupRequestDto, SetupResponseDto,
+    StoreProxyRequestDto, StoreProxyResponseDto, SyncErrorsResponseDto, SyncResponseDto,
+    SyncTagDto, SyncTagResponseDto, TagCreateRequestDto, TagCreateResponseDto, TagItemsRequestDto,
+    UserDeleteResponseDto, UserListResponseDto, UserRequestDto, UserResponseDto,
+    ValidateKeyRequestDto, ValidateKeyResponseDto,
 };
 use super::services::{
-    health::HealthService, library::LibraryService, metadata::MetadataService,
-    reading::ReadingService, sync::SyncService,
+    devices::DeviceService,
+    download::DownloadService,
+    health::HealthService,
+    library::LibraryService,
+    metadata::MetadataService,
+    reading::ReadingService,
+    setup::SetupService,
+    sync::{SyncService, kobo_user_key},
+    users::UserService,
+};
+use crate::{
+    abs_client::{AbsClient, LibraryItemSort},
+    config::Config,
+    cover_cache::CoverCache,
+    kepub_jobs::KepubJobs,
+    kepubify::Kepubify,
 };
-use crate::{abs_client::AbsClient, config::Config};
 
 pub struct AbsKoboApi {
     pub client: Arc<AbsClient>,
     pub config: Arc<Config>,
     pub db: Arc<sea_orm::DatabaseConnection>,
+    pub download_limit: Arc<DownloadLimit>,
+    pub kepub_jobs: Arc<KepubJobs>,
 }
 
 #[derive(Debug, Tags)]
@@ -48,11 +72,112 @@ impl AbsKoboApi {
         HealthService::new(&self.client).status_text().await
     }
 
-    #[oai(path = "/v1/libraries", method = "get", tag = "ApiTags::ExploreAbs")]
+    /// Check ABS, the database and kepubify, for container health probes; 503 when ABS or the
+    /// database is unusable
+    #[oai(path = "/healthz", method = "get", tag = "ApiTags::Health")]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn healthz(&self) -> HealthResponseDto {
+        HealthService::new(&self.client)
+            .health(&self.db, &Kepubify::from_config(&self.config))
+            .await
+    }
+
+    /// Check an ABS API key before saving it; nothing is persisted
+    #[oai(
+        path = "/v1/validate-key",
+        method = "post",
+        tag = "ApiTags::UserManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn validate_key(&self, body: Json<ValidateKeyRequestDto>) -> ValidateKeyResponseDto {
+        UserService::new(&self.client, &self.db)
+            .validate_key(&body.0.abs_api_key)
+            .await
+    }
+
+    /// List users with their redacted ABS API key and device count
+    #[oai(
+        path = "/v1/admin/users",
+        method = "get",
+        tag = "ApiTags::UserManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn list_users(&self) -> UserListResponseDto {
+        UserService::new(&self.client, &self.db).list().await
+    }
+
+    /// Create a user syncing with an ABS API key; the key is checked with ABS first
+    #[oai(
+        path = "/v1/admin/users",
+        method = "post",
+        tag = "ApiTags::UserManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn create_user(&self, body: Json<UserRequestDto>) -> UserResponseDto {
+        UserService::new(&self.client, &self.db)
+            .create(&body.0.abs_api_key)
+            .await
+    }
+
+    /// Replace a user's ABS API key; the key is checked with ABS first
+    #[oai(
+        path = "/v1/admin/users/:user_id",
+        method = "put",
+        tag = "ApiTags::UserManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn update_user(
+        &self,
+        Path(user_id): Path<Uuid>,
+        body: Json<UserRequestDto>,
+    ) -> UserResponseDto {
+        UserService::new(&self.client, &self.db)
+            .update(user_id, &body.0.abs_api_key)
+            .await
+    }
+
+    /// Delete a user together with their devices
+    #[oai(
+        path = "/v1/admin/users/:user_id",
+        method = "delete",
+        tag = "ApiTags::UserManagement"
+    )]
     #[tracing::instrument(level = "debug", skip(self))]
-    async fn list_libraries(&self) -> LibraryListResponse {
+    async fn delete_user(&self, Path(user_id): Path<Uuid>) -> UserDeleteResponseDto {
+        UserService::new(&self.client, &self.db)
+            .delete(user_id)
+            .await
+    }
+
+    #[oai(path = "/v1/libraries", method = "get", tag = "ApiTags::ExploreAbs")]
+    #[tracing::instrument(level = "debug", skip(self, abs_api_key))]
+    async fn list_libraries(
+        &self,
+        /// ABS API key used for this request instead of the configured one
+        #[oai(name = "X-Abs-Api-Key")]
+        Header(abs_api_key): Header<Option<String>>,
+    ) -> LibraryListResponse {
+        LibraryService::new(&self.client)
+            .list_libraries(&self.explore_api_key(abs_api_key))
+            .await
+    }
+
+    /// Genres, tags, authors and series of a library, for building filters
+    #[oai(
+        path = "/v1/libraries/:library_id/genres",
+        method = "get",
+        tag = "ApiTags::ExploreAbs"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, abs_api_key))]
+    async fn library_genres(
+        &self,
+        Path(library_id): Path<Uuid>,
+        /// ABS API key used for this request instead of the configured one
+        #[oai(name = "X-Abs-Api-Key")]
+        Header(abs_api_key): Header<Option<String>>,
+    ) -> LibraryFilterDataResponseDto {
         LibraryService::new(&self.client)
-            .list_libraries(&self.config.abs_api_key)
+            .library_filter_data(&library_id, &self.explore_api_key(abs_api_key))
             .await
     }
 
@@ -62,7 +187,22 @@ impl AbsKoboApi {
         method = "get",
         tag = "ApiTags::ExploreAbs"
     )]
-    #[tracing::instrument(level = "debug", skip(self, library_id, limit, page, include, filter))]
+    #[allow(clippy::too_many_arguments)]
+    #[tracing::instrument(
+        level = "debug",
+        skip(
+            self,
+            library_id,
+            limit,
+            page,
+            include,
+            filter,
+            sort,
+            desc,
+            device_id,
+            abs_api_key
+        )
+    )]
     async fn list_library_items(
         &self,
         library_id: Path<Uuid>,
@@ -74,6 +214,15 @@ impl AbsKoboApi {
         Query(include): Query<Option<String>>,
         /// Filter string passed to ABS
         Query(filter): Query<Option<String>>,
+        /// ABS sort key, e.g. "addedAt" or "media.metadata.title"
+        Query(sort): Query<Option<String>>,
+        /// Sort descending
+        Query(desc): Query<Option<bool>>,
+        /// Device whose Kobo thumbnail route is used for cover URLs
+        Query(device_id): Query<Option<Uuid>>,
+        /// ABS API key used for this request instead of the configured one
+        #[oai(name = "X-Abs-Api-Key")]
+        Header(abs_api_key): Header<Option<String>>,
     ) -> LibraryItemsResponseDto {
         let library_id = library_id.0;
         let limit = limit.unwrap_or(50);
@@ -82,6 +231,14 @@ impl AbsKoboApi {
         let filter_ref = filter.as_deref();
         tracing::debug!(library_id=%library_id, limit, page = page.unwrap_or(0), include = include_ref.unwrap_or(""), filter = filter_ref.unwrap_or(""), "handling list_library_items");
 
+        let sort = match sort
+            .map(|key| LibraryItemSort::new(&key, desc.unwrap_or(false)))
+            .transpose()
+        {
+            Ok(sort) => sort,
+            Err(message) => return LibraryItemsResponseDto::BadRequest(Json(message.into())),
+        };
+
         LibraryService::new(&self.client)
             .list_library_items(
                 &library_id,
@@ -89,11 +246,354 @@ impl AbsKoboApi {
                 page,
                 include_ref,
                 filter_ref,
-                &self.config.abs_api_key,
+                sort.as_ref(),
+                device_id,
+                &self.explore_api_key(abs_api_key),
+            )
+            .await
+    }
+
+    /// Proxy an item's cover from ABS
+    #[oai(
+        path = "/v1/items/:item_id/cover",
+        method = "get",
+        tag = "ApiTags::ExploreAbs"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, abs_api_key))]
+    async fn item_cover(
+        &self,
+        Path(item_id): Path<Uuid>,
+        /// Return the original, unscaled image
+        Query(raw): Query<Option<bool>>,
+        /// Requested width, ignored when `raw` is set
+        Query(width): Query<Option<u32>>,
+        /// Requested height, ignored when `raw` is set
+        Query(height): Query<Option<u32>>,
+        /// ABS API key used for this request instead of the configured one
+        #[oai(name = "X-Abs-Api-Key")]
+        Header(abs_api_key): Header<Option<String>>,
+    ) -> CoverResponseDto {
+        let raw = raw.unwrap_or(false);
+        let size = width.zip(height).filter(|_| !raw);
+        LibraryService::new(&self.client)
+            .with_cover_cache(CoverCache::from_config(&self.config))
+            .item_cover(
+                &item_id,
+                size,
+                raw,
+                self.config.fallback_cover_path.as_deref(),
+                &self.explore_api_key(abs_api_key),
+            )
+            .await
+    }
+
+    /// ABS key for an explore request: the `X-Abs-Api-Key` override when one was sent, the
+    /// configured key otherwise. Device routes always use the key of the device's user.
+    fn explore_api_key(&self, abs_api_key: Option<String>) -> String {
+        abs_api_key
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .unwrap_or_else(|| self.config.abs_api_key.clone())
+    }
+
+    /// Cover thumbnail for a device, as referenced by `image_url_template` in the initialization
+    /// resources
+    #[oai(
+        path = "/kobo/:auth_token/v1/books/:image_id/thumbnail/:width/:height/:greyscale/image.jpg",
+        method = "get",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token))]
+    async fn book_thumbnail(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Path(image_id): Path<Uuid>,
+        Path(width): Path<u32>,
+        Path(height): Path<u32>,
+        Path(greyscale): Path<bool>,
+    ) -> CoverResponseDto {
+        self.device_thumbnail(auth_token, image_id, (width, height), None, greyscale)
+            .await
+    }
+
+    /// Cover thumbnail for a device at a JPEG quality (1-100), as referenced by
+    /// `image_url_quality_template` in the initialization resources
+    #[oai(
+        path = "/kobo/:auth_token/v1/books/:image_id/thumbnail/:width/:height/:quality/:greyscale/image.jpg",
+        method = "get",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token))]
+    async fn book_thumbnail_quality(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Path(image_id): Path<Uuid>,
+        Path(width): Path<u32>,
+        Path(height): Path<u32>,
+        Path(quality): Path<u32>,
+        Path(greyscale): Path<bool>,
+    ) -> CoverResponseDto {
+        let quality = quality.clamp(1, 100) as u8;
+        self.device_thumbnail(
+            auth_token,
+            image_id,
+            (width, height),
+            Some(quality),
+            greyscale,
+        )
+        .await
+    }
+
+    async fn device_thumbnail(
+        &self,
+        auth_token: Uuid,
+        image_id: Uuid,
+        size: (u32, u32),
+        quality: Option<u8>,
+        greyscale: bool,
+    ) -> CoverResponseDto {
+        let api_key = match DeviceService::new(&self.db).abs_api_key(auth_token).await {
+            Ok(Some(api_key)) => api_key,
+            Ok(None) => {
+                return CoverResponseDto::Unauthorized(Json(
+                    "Invalid auth token".to_string().into(),
+                ));
+            }
+            Err(e) => {
+                return CoverResponseDto::InternalServerError(Json(
+                    format!("Database error: {}", e).into(),
+                ));
+            }
+        };
+        LibraryService::new(&self.client)
+            .with_cover_cache(CoverCache::from_config(&self.config))
+            .item_thumbnail(
+                &image_id,
+                size,
+                quality,
+                greyscale,
+                self.config.fallback_cover_path.as_deref(),
+                &api_key,
+            )
+            .await
+    }
+
+    /// Register a new device for a user. The returned `api_endpoint` goes into the device's
+    /// `Kobo eReader.conf`; its path token is checked on every `/kobo/...` request.
+    #[oai(
+        path = "/v1/devices",
+        method = "post",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body, headers))]
+    async fn register_device(
+        &self,
+        body: Json<DeviceRegisterRequestDto>,
+        headers: &HeaderMap,
+    ) -> DeviceRegisterResponseDto {
+        DeviceService::new(&self.db)
+            .register(
+                body.0.user_id,
+                &SyncService::device_base_url(&self.config, headers),
+            )
+            .await
+    }
+
+    /// Onboarding page where a user enters their ABS API key or login and gets the
+    /// `api_endpoint` for their Kobo. Only served with `SETUP_PAGE` enabled.
+    #[oai(path = "/setup", method = "get", tag = "ApiTags::DeviceManagement")]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn setup_page(&self) -> SetupResponseDto {
+        if !self.config.flags().setup_page {
+            return SetupResponseDto::NotFound(Html("Setup page is disabled".into()));
+        }
+        SetupService::page()
+    }
+
+    /// Register a device from the setup page form
+    #[oai(path = "/setup", method = "post", tag = "ApiTags::DeviceManagement")]
+    #[tracing::instrument(level = "debug", skip(self, form, headers))]
+    async fn setup(&self, form: Form<SetupRequestDto>, headers: &HeaderMap) -> SetupResponseDto {
+        if !self.config.flags().setup_page {
+            return SetupResponseDto::NotFound(Html("Setup page is disabled".into()));
+        }
+        SetupService::new(&self.client, &self.db)
+            .setup(form.0, &SyncService::device_base_url(&self.config, headers))
+            .await
+    }
+
+    /// List registered devices with their owner and last sync time
+    #[oai(
+        path = "/v1/admin/devices",
+        method = "get",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn list_devices(&self) -> DeviceListResponseDto {
+        DeviceService::new(&self.db).list().await
+    }
+
+    /// Mint a new device token for a user, like `POST /v1/devices`
+    #[oai(
+        path = "/v1/admin/devices",
+        method = "post",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body, headers))]
+    async fn mint_device(
+        &self,
+        body: Json<DeviceRegisterRequestDto>,
+        headers: &HeaderMap,
+    ) -> DeviceRegisterResponseDto {
+        self.register_device(body, headers).await
+    }
+
+    /// Revoke a device: its token is rejected from now on and its sync history is deleted
+    #[oai(
+        path = "/v1/admin/devices/:device_id",
+        method = "delete",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn revoke_device(&self, Path(device_id): Path<Uuid>) -> DeviceRevokeResponseDto {
+        DeviceService::new(&self.db).revoke(device_id).await
+    }
+
+    /// Turn Kobo store passthrough on or off for a single device
+    #[oai(
+        path = "/v1/devices/:device_id/store-proxy",
+        method = "put",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn set_store_proxy(
+        &self,
+        Path(device_id): Path<Uuid>,
+        body: Json<StoreProxyRequestDto>,
+    ) -> StoreProxyResponseDto {
+        DeviceService::new(&self.db)
+            .set_store_proxy(
+                device_id,
+                body.0.proxy_store,
+                self.config.flags().store_proxy,
             )
             .await
     }
 
+    /// Only sync books carrying an ABS tag to a device, e.g. a "Kobo" tag; `null` syncs the whole
+    /// library. Books already on the device stay there when they lose the tag.
+    #[oai(
+        path = "/v1/devices/:device_id/sync-tag",
+        method = "put",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn set_sync_tag(
+        &self,
+        Path(device_id): Path<Uuid>,
+        body: Json<SyncTagDto>,
+    ) -> SyncTagResponseDto {
+        DeviceService::new(&self.db)
+            .set_sync_tag(device_id, body.0.sync_tag)
+            .await
+    }
+
+    /// Link a device to a user, creating the device if needed, so its first sync already runs
+    /// with the user's ABS key
+    #[oai(
+        path = "/v1/devices/:device_id/link",
+        method = "post",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn link_device(
+        &self,
+        Path(device_id): Path<Uuid>,
+        body: Json<DeviceLinkRequestDto>,
+    ) -> DeviceLinkResponseDto {
+        DeviceService::new(&self.db)
+            .link(device_id, body.0.user_id)
+            .await
+    }
+
+    /// Share a device with another user. Syncs presenting the given Kobo `UserKey` (the
+    /// `X-Kobo-UserKey` header) use that user's ABS key; all others use the owner's.
+    #[oai(
+        path = "/v1/devices/:device_id/users",
+        method = "post",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, body))]
+    async fn add_device_user(
+        &self,
+        Path(device_id): Path<Uuid>,
+        body: Json<DeviceUserRequestDto>,
+    ) -> DeviceUserResponseDto {
+        DeviceService::new(&self.db)
+            .add_user(device_id, body.0.user_id, body.0.user_key)
+            .await
+    }
+
+    /// Debug details of a device, such as the last sync token it sent. Only served with
+    /// `DEBUG_ENDPOINTS` enabled.
+    #[oai(
+        path = "/v1/devices/:device_id/debug",
+        method = "get",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn device_debug(&self, Path(device_id): Path<Uuid>) -> DeviceDebugResponseDto {
+        if !self.config.flags().debug_endpoints {
+            return DeviceDebugResponseDto::NotFound(Json(ErrorDto {
+                message: "Debug endpoints are disabled".into(),
+            }));
+        }
+        DeviceService::new(&self.db).debug_info(device_id).await
+    }
+
+    /// Number of new and updated books waiting for the device's next sync
+    #[oai(
+        path = "/v1/devices/:device_id/pending",
+        method = "get",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn pending_sync(&self, Path(device_id): Path<Uuid>) -> PendingSyncResponseDto {
+        SyncService::new(&self.client, &self.config, &self.db)
+            .pending(device_id)
+            .await
+    }
+
+    /// Send one book to the device again as new on its next sync, e.g. after it got corrupted
+    #[oai(
+        path = "/v1/devices/:device_id/books/:book_uuid/resend",
+        method = "post",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn resend_book(
+        &self,
+        Path(device_id): Path<Uuid>,
+        Path(book_uuid): Path<Uuid>,
+    ) -> BookResendResponseDto {
+        SyncService::new(&self.client, &self.config, &self.db)
+            .resend(device_id, book_uuid)
+            .await
+    }
+
+    /// List books that failed to map during the device's last sync
+    #[oai(
+        path = "/v1/devices/:device_id/sync-errors",
+        method = "get",
+        tag = "ApiTags::DeviceManagement"
+    )]
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn list_sync_errors(&self, Path(device_id): Path<Uuid>) -> SyncErrorsResponseDto {
+        DeviceService::new(&self.db)
+            .list_sync_errors(device_id)
+            .await
+    }
+
     // ===== Kobo sync endpoints =====
 
     /// Incremental sync of the user's data
@@ -110,27 +610,97 @@ impl AbsKoboApi {
         headers: &HeaderMap,
     ) -> SyncResponseDto {
         SyncService::new(&self.client, &self.config, &self.db)
+            .with_user_key(kobo_user_key(headers))
             .sync(auth_token, kobo_sync_token, headers)
             .await
     }
 
+    // Some firmware probes routes with HEAD first. Poem would answer HEAD by running the GET
+    // handler, which for sync marks books as delivered although the device never gets them.
+
+    /// HEAD probe of the sync route, answered without syncing
+    #[oai(path = "/kobo/:auth_token/v1/library/sync", method = "head", hidden)]
+    async fn kobo_sync_head(&self, Path(_auth_token): Path<Uuid>) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
+    }
+
+    /// HEAD probe of the metadata route
+    #[oai(
+        path = "/kobo/:auth_token/v1/library/:book_uuid/metadata",
+        method = "head",
+        hidden
+    )]
+    async fn book_metadata_head(
+        &self,
+        Path(_auth_token): Path<Uuid>,
+        Path(_book_uuid): Path<Uuid>,
+    ) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
+    }
+
+    /// HEAD probe of the reading state route
+    #[oai(
+        path = "/kobo/:auth_token/v1/library/:book_uuid/state",
+        method = "head",
+        hidden
+    )]
+    async fn reading_state_head(
+        &self,
+        Path(_auth_token): Path<Uuid>,
+        Path(_book_uuid): Path<String>,
+    ) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
+    }
+
     /// Metadata for a specific book (array with single object)
     #[oai(
         path = "/kobo/:auth_token/v1/library/:book_uuid/metadata",
         method = "get",
         tag = "ApiTags::KoboSync"
     )]
-    #[tracing::instrument(level = "debug", skip(self, auth_token, book_uuid))]
+    #[tracing::instrument(level = "debug", skip(self, auth_token, book_uuid, headers))]
     async fn book_metadata(
         &self,
         Path(auth_token): Path<Uuid>,
         Path(book_uuid): Path<Uuid>,
+        headers: &HeaderMap,
     ) -> MetadataResponseDto {
-        MetadataService::new(&self.client, &self.db)
-            .get_metadata(book_uuid, auth_token)
+        MetadataService::new(&self.client, &self.config, &self.db)
+            .get_metadata(book_uuid, auth_token, headers)
             .await
     }
 
+    /// Ebook file of a book, as referenced by the `DownloadUrls` of its entitlement
+    #[oai(
+        path = "/kobo/:auth_token/v1/download/:book_uuid/:format",
+        method = "get",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token, headers))]
+    async fn download_book(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Path(book_uuid): Path<Uuid>,
+        /// `kepub` or `epub`
+        Path(format): Path<String>,
+        headers: &HeaderMap,
+    ) -> DownloadResponseDto {
+        DownloadService::new(
+            &self.client,
+            &self.config,
+            &self.db,
+            &self.download_limit,
+            &self.kepub_jobs,
+        )
+        .download(
+            auth_token,
+            book_uuid,
+            &format,
+            kobo_user_key(headers).as_deref(),
+        )
+        .await
+    }
+
     /// Get reading state for a specific book (array with single object)
     #[oai(
         path = "/kobo/:auth_token/v1/library/:book_uuid/state",
@@ -140,12 +710,31 @@ impl AbsKoboApi {
     #[tracing::instrument(level = "debug", skip(self, auth_token, book_uuid))]
     async fn get_reading_state(
         &self,
-        auth_token: Path<String>,
+        Path(auth_token): Path<Uuid>,
         book_uuid: Path<String>,
     ) -> ReadingStateGetResponseDto {
-        let _ = auth_token;
-        ReadingService::new(&self.client)
-            .get_state(&book_uuid.0)
+        ReadingService::new(&self.client, &self.db)
+            .get_state(auth_token, &book_uuid.0)
+            .await
+    }
+
+    /// All reading states stored for the device (bulk state sync)
+    #[oai(
+        path = "/kobo/:auth_token/v1/library/reading-states",
+        method = "get",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token))]
+    async fn list_reading_states(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        /// Max states per page (default 500)
+        Query(limit): Query<Option<u64>>,
+        /// Page number starting at 0
+        Query(page): Query<Option<u64>>,
+    ) -> ReadingStatesResponseDto {
+        ReadingService::new(&self.client, &self.db)
+            .list_states(auth_token, limit.unwrap_or(500), page.unwrap_or(0))
             .await
     }
 
@@ -158,13 +747,13 @@ impl AbsKoboApi {
     #[tracing::instrument(level = "debug", skip(self, auth_token, book_uuid, body))]
     async fn put_reading_state(
         &self,
-        auth_token: Path<String>,
+        Path(auth_token): Path<Uuid>,
         book_uuid: Path<String>,
         body: poem_openapi::payload::Json<serde_json::Value>,
     ) -> ReadingStatePutResponseDto {
-        let _ = auth_token;
-        ReadingService::new(&self.client)
-            .update_state(&book_uuid.0, body.0)
+        ReadingService::new(&self.client, &self.db)
+            .with_push_reading_time(self.config.flags().push_reading_time)
+            .update_state(auth_token, &book_uuid.0, body.0)
             .await
     }
 
@@ -299,7 +888,41 @@ impl AbsKoboApi {
             .await
     }
 
-    /// Device auth stub
+    /// A/B test lookup the device posts with its diagnostics; no tests are offered
+    #[oai(
+        path = "/kobo/:auth_token/v1/analytics/gettests",
+        method = "post",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token, body))]
+    async fn analytics_gettests(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Json(body): Json<serde_json::Value>,
+    ) -> AnalyticsResponseDto {
+        SyncService::new(&self.client, &self.config, &self.db)
+            .record_analytics(auth_token, body)
+            .await
+    }
+
+    /// Analytics events from the device, kept for support instead of being sent to Kobo
+    #[oai(
+        path = "/kobo/:auth_token/v1/analytics/event",
+        method = "post",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token, body))]
+    async fn analytics_event(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Json(body): Json<serde_json::Value>,
+    ) -> AnalyticsResponseDto {
+        SyncService::new(&self.client, &self.config, &self.db)
+            .record_analytics(auth_token, body)
+            .await
+    }
+
+    /// Issue device tokens; the device stays identified by its path token
     #[oai(
         path = "/kobo/:auth_token/v1/auth/device",
         method = "post",
@@ -308,12 +931,28 @@ impl AbsKoboApi {
     #[tracing::instrument(level = "debug", skip(self, auth_token, body))]
     async fn auth_device(
         &self,
-        auth_token: Path<String>,
+        Path(auth_token): Path<Uuid>,
         Json(body): Json<serde_json::Value>,
     ) -> DeviceAuthResponseDto {
-        let _ = auth_token;
         SyncService::new(&self.client, &self.config, &self.db)
-            .auth_device(body)
+            .auth_device(auth_token, body)
+            .await
+    }
+
+    /// Exchange the refresh token from the last device auth for a new access token
+    #[oai(
+        path = "/kobo/:auth_token/v1/auth/refresh",
+        method = "post",
+        tag = "ApiTags::KoboSync"
+    )]
+    #[tracing::instrument(level = "debug", skip(self, auth_token, request))]
+    async fn refresh_device_auth(
+        &self,
+        Path(auth_token): Path<Uuid>,
+        Json(request): Json<DeviceRefreshRequest>,
+    ) -> DeviceAuthResponseDto {
+        SyncService::new(&self.client, &self.config, &self.db)
+            .refresh_device_auth(auth_token, request)
             .await
     }
 }
@@ -423,8 +1062,59 @@ pub struct KoboFullTokenDetails {
     pub tags_last_modified: Option<DateTime<Utc>>,
 }
 
+/// Value of the `X-Kobo-Sync-Mode` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KoboSyncMode {
+    /// Only changes since the timestamps in the incoming token
+    Delta,
+    /// Everything, because the device sent no timestamps (first or forced sync)
+    Full,
+}
+
+impl KoboSyncMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KoboSyncMode::Delta => "delta",
+            KoboSyncMode::Full => "full",
+        }
+    }
+}
+
 impl KoboFullTokenDetails {
+    pub fn sync_mode(&self) -> KoboSyncMode {
+        let has_timestamps = self.books_last_modified.is_some()
+            || self.books_last_created.is_some()
+            || self.archive_last_modified.is_some()
+            || self.reading_state_last_modified.is_some()
+            || self.tags_last_modified.is_some();
+        if has_timestamps {
+            KoboSyncMode::Delta
+        } else {
+            KoboSyncMode::Full
+        }
+    }
+
     pub fn to_raw_token(&self) -> String {
+        Self::encode(self.timestamps())
+    }
+
+    /// Sync token for the device carrying its store token alongside our timestamps, for syncs
+    /// where the store doesn't hand out a token of its own
+    pub fn to_device_token(&self, raw_kobo_store_token: &str) -> String {
+        let mut map = self.timestamps();
+        map.insert(
+            "raw_kobo_store_token".to_string(),
+            serde_json::Value::String(raw_kobo_store_token.to_string()),
+        );
+        Self::encode(map)
+    }
+
+    fn encode(map: serde_json::Map<String, serde_json::Value>) -> String {
+        let value = serde_json::Value::Object(map);
+        base64::prelude::BASE64_STANDARD.encode(serde_json::to_string(&value).unwrap())
+    }
+
+    fn timestamps(&self) -> serde_json::Map<String, serde_json::Value> {
         let mut map = serde_json::Map::new();
         if let Some(dt) = self.books_last_modified {
             map.insert(
@@ -456,8 +1146,347 @@ impl KoboFullTokenDetails {
                 serde_json::Value::String(dt.to_rfc3339()),
             );
         }
+        map
+    }
+}
 
-        let value = serde_json::Value::Object(map);
-        base64::prelude::BASE64_STANDARD.encode(serde_json::to_string(&value).unwrap())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token_details(token: &str) -> KoboFullTokenDetails {
+        match KoboSyncToken::from_request(token).unwrap() {
+            KoboSyncToken::FullToken { details, .. } => details,
+            other => panic!("expected full token, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn first_sync_is_full() {
+        let token = base64::prelude::BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        assert_eq!(token_details(&token).sync_mode(), KoboSyncMode::Full);
+    }
+
+    #[test]
+    fn incremental_sync_is_delta() {
+        let token = base64::prelude::BASE64_STANDARD.encode(
+            r#"{"raw_kobo_store_token":"abc","books_last_modified":"2025-08-20T12:00:00+00:00"}"#,
+        );
+        assert_eq!(token_details(&token).sync_mode(), KoboSyncMode::Delta);
+    }
+
+    #[tokio::test]
+    async fn head_on_sync_answers_without_syncing() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        // Nothing listens on port 1, so a sync that reached ABS would fail with 502
+        let config = crate::test_support::config("http://127.0.0.1:1", Uuid::nil());
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        let resp = cli
+            .head(format!("/kobo/{}/v1/library/sync", device_id))
+            .header("X-Kobo-Sync-Token", "")
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_text("").await;
+    }
+
+    #[tokio::test]
+    async fn explore_requests_use_the_api_key_override() {
+        #[poem::handler]
+        fn libraries(req: &poem::Request) -> poem::Result<poem::web::Json<serde_json::Value>> {
+            if req.header("Authorization") != Some("Bearer override-key") {
+                return Err(poem::Error::from_status(
+                    poem::http::StatusCode::UNAUTHORIZED,
+                ));
+            }
+            Ok(poem::web::Json(serde_json::json!({"libraries": []})))
+        }
+        let base = crate::test_support::serve(
+            poem::Route::new().at("/api/libraries", poem::get(libraries)),
+        )
+        .await;
+        let (db, _) = crate::test_support::db_with_device().await;
+        let config = crate::test_support::config(&base, Uuid::nil());
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        cli.get("/v1/libraries")
+            .send()
+            .await
+            .assert_status(poem::http::StatusCode::BAD_GATEWAY);
+        cli.get("/v1/libraries")
+            .header("X-Abs-Api-Key", "override-key")
+            .send()
+            .await
+            .assert_status_is_ok();
+    }
+
+    #[tokio::test]
+    async fn device_auth_tokens_can_be_refreshed() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let config = crate::test_support::config("http://127.0.0.1:1", Uuid::nil());
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        let resp = cli
+            .post(format!("/kobo/{}/v1/auth/device", device_id))
+            .body_json(&serde_json::json!({"UserKey": "user-key", "ClientKey": "client"}))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let auth = resp.json().await;
+        let auth = auth.value().object();
+        auth.get("UserKey").assert_string("user-key");
+        auth.get("TokenType").assert_string("Bearer");
+        let access_token = auth.get("AccessToken").string().to_string();
+        let refresh_token = auth.get("RefreshToken").string().to_string();
+
+        let resp = cli
+            .post(format!("/kobo/{}/v1/auth/refresh", device_id))
+            .body_json(&serde_json::json!({"RefreshToken": refresh_token, "AppVersion": "4.38"}))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let refreshed = resp.json().await;
+        let refreshed = refreshed.value().object();
+        refreshed.get("RefreshToken").assert_string(&refresh_token);
+        assert_ne!(refreshed.get("AccessToken").string(), access_token);
+    }
+
+    #[tokio::test]
+    async fn analytics_payloads_are_acknowledged_and_recorded_redacted() {
+        use sea_orm::EntityTrait;
+
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let mut config = crate::test_support::config("http://127.0.0.1:1", Uuid::nil());
+        config.log_redact_keys = vec!["UserKey".into()];
+        let api = crate::test_support::api(config, db);
+        let db = api.db.clone();
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        let resp = cli
+            .post(format!("/kobo/{}/v1/analytics/gettests", device_id))
+            .body_json(&serde_json::json!({"TestKey": "test-key", "UserKey": "user-key"}))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        resp.assert_json(
+            serde_json::json!({"Result": "Success", "TestKey": "test-key", "Tests": {}}),
+        )
+        .await;
+
+        let device = entities::devices::Entity::find_by_id(device_id)
+            .one(db.as_ref())
+            .await
+            .unwrap()
+            .unwrap();
+        let recorded = device.last_analytics.unwrap();
+        assert!(recorded.contains("test-key"), "{}", recorded);
+        assert!(!recorded.contains("user-key"), "{}", recorded);
+        assert!(device.last_analytics_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn device_auth_refresh_with_bad_token_is_unauthorized() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let config = crate::test_support::config("http://127.0.0.1:1", Uuid::nil());
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        cli.post(format!("/kobo/{}/v1/auth/device", device_id))
+            .body_json(&serde_json::json!({"UserKey": "user-key"}))
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.post(format!("/kobo/{}/v1/auth/refresh", device_id))
+            .body_json(&serde_json::json!({"RefreshToken": "not-the-token"}))
+            .send()
+            .await
+            .assert_status(poem::http::StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn first_sync_of_empty_library_returns_empty_list_and_token() {
+        #[poem::handler]
+        fn no_items() -> poem::web::Json<serde_json::Value> {
+            poem::web::Json(serde_json::json!({
+                "results": [], "total": 0, "limit": 0, "page": 0, "sortDesc": true,
+                "mediaType": "book", "minified": false, "collapseseries": false, "include": ""
+            }))
+        }
+        let library_id = Uuid::now_v7();
+        let base = crate::test_support::serve(poem::Route::new().at(
+            format!("/api/libraries/{}/items", library_id),
+            poem::get(no_items),
+        ))
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        // First contact: the device still carries the token the Kobo store gave it
+        let resp = cli
+            .get(format!("/kobo/{}/v1/library/sync", device_id))
+            .header("X-Kobo-Sync-Token", "c3RvcmU.dG9rZW4")
+            .send()
+            .await;
+
+        resp.assert_status_is_ok();
+        resp.assert_header("X-Kobo-Sync-Mode", "full");
+        resp.assert_header_is_not_exist("X-Kobo-Sync");
+        let token = resp.0.headers()["X-Kobo-SyncToken"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        resp.assert_json(serde_json::json!([])).await;
+        match KoboSyncToken::from_request(&token).unwrap() {
+            KoboSyncToken::FullToken {
+                raw_kobo_store_token,
+                details,
+            } => {
+                assert_eq!(raw_kobo_store_token, "c3RvcmU.dG9rZW4");
+                assert_eq!(details.sync_mode(), KoboSyncMode::Full);
+            }
+            other => panic!("expected a full token, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_device_syncs_the_library_of_the_presented_user_key() {
+        use sea_orm::{ActiveValue::Set, EntityTrait};
+
+        /// Each ABS user sees one book, named after their key
+        #[poem::handler]
+        fn items(req: &poem::Request) -> poem::web::Json<serde_json::Value> {
+            let key = req.header("Authorization").unwrap_or_default();
+            let id = Uuid::new_v3(&Uuid::NAMESPACE_OID, key.as_bytes());
+            poem::web::Json(serde_json::json!({
+                "results": [crate::test_support::library_item_json(id, key)],
+                "total": 1, "limit": 0, "page": 0, "sortDesc": true,
+                "mediaType": "book", "minified": false, "collapseseries": false, "include": ""
+            }))
+        }
+        let library_id = Uuid::now_v7();
+        let base = crate::test_support::serve(poem::Route::new().at(
+            format!("/api/libraries/{}/items", library_id),
+            poem::get(items),
+        ))
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let second_user = Uuid::now_v7();
+        entities::user::Entity::insert(entities::user::ActiveModel {
+            id: Set(second_user),
+            abs_api_key: Set("second-key".into()),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let api = crate::test_support::api(config, db);
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        cli.post(format!("/v1/devices/{}/users", device_id))
+            .body_json(&serde_json::json!({"user_id": second_user, "user_key": "kobo-second"}))
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // A Kobo switching accounts starts over with a token without timestamps
+        let token = base64::prelude::BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let synced_title = |user_key: Option<&'static str>| {
+            let (cli, token) = (&cli, token.clone());
+            async move {
+                let mut req = cli
+                    .get(format!("/kobo/{}/v1/library/sync", device_id))
+                    .header("X-Kobo-Sync-Token", token);
+                if let Some(user_key) = user_key {
+                    req = req.header("X-Kobo-UserKey", user_key);
+                }
+                let resp = req.send().await;
+                resp.assert_status_is_ok();
+                let body = resp.json().await;
+                body.value()
+                    .array()
+                    .get(0)
+                    .object()
+                    .get("NewEntitlement")
+                    .object()
+                    .get("BookMetadata")
+                    .object()
+                    .get("Title")
+                    .string()
+                    .to_string()
+            }
+        };
+        assert_eq!(synced_title(Some("kobo-second")).await, "Bearer second-key");
+        // Unknown user keys sync as the owner
+        assert_eq!(synced_title(Some("kobo-unknown")).await, "Bearer key");
+    }
+
+    #[tokio::test]
+    async fn registered_devices_get_their_api_endpoint() {
+        use sea_orm::EntityTrait;
+
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let owner_id = entities::devices::Entity::find_by_id(device_id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap()
+            .owner_id;
+        let api = crate::test_support::api(
+            crate::test_support::config("http://abs.invalid", Uuid::now_v7()),
+            db,
+        );
+        let db = api.db.clone();
+        let cli = poem::test::TestClient::new(
+            poem::Route::new().nest("/", poem_openapi::OpenApiService::new(api, "test", "test")),
+        );
+
+        let resp = cli
+            .post("/v1/devices")
+            .header("Host", "kobo.example")
+            .body_json(&serde_json::json!({ "user_id": owner_id }))
+            .send()
+            .await;
+        resp.assert_status(poem::http::StatusCode::CREATED);
+        let body = resp.json().await;
+        let registered = body.value().object();
+        let new_device: Uuid = registered.get("device_id").string().parse().unwrap();
+        assert_eq!(
+            registered.get("api_endpoint").string(),
+            format!("http://kobo.example/kobo/{}", new_device)
+        );
+        let device = entities::devices::Entity::find_by_id(new_device)
+            .one(db.as_ref())
+            .await
+            .unwrap()
+            .expect("registered device");
+        assert_eq!(device.owner_id, owner_id);
+
+        cli.post("/v1/devices")
+            .body_json(&serde_json::json!({ "user_id": Uuid::now_v7() }))
+            .send()
+            .await
+            .assert_status(poem::http::StatusCode::NOT_FOUND);
     }
 }
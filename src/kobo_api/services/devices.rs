@@ -0,0 +1,833 @@
+use std::collections::HashMap;
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::Utc;
+use entities::{device_users, devices, sync_error, user};
+use poem_openapi::payload::Json;
+use sea_orm::{
+    ActiveValue::Set, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder,
+    sea_query::Expr,
+};
+use uuid::Uuid;
+
+use crate::{
+    AbsKoboResult,
+    config::redact_secret,
+    db::retry_on_busy,
+    kobo_api::models::{
+        DeviceDebugDto, DeviceDebugResponseDto, DeviceDto, DeviceLinkDto, DeviceLinkResponseDto,
+        DeviceListResponseDto, DeviceRegisterResponseDto, DeviceRegistrationDto,
+        DeviceRevokeResponseDto, DeviceUserDto, DeviceUserResponseDto, ErrorDto, StoreProxyDto,
+        StoreProxyResponseDto, SyncErrorDto, SyncErrorsResponseDto, SyncTagDto, SyncTagResponseDto,
+    },
+};
+
+/// Longest sync token kept for debugging, in characters
+const SYNC_TOKEN_DEBUG_MAX_LEN: usize = 2048;
+/// Longest analytics payload kept for debugging, in characters
+const ANALYTICS_DEBUG_MAX_LEN: usize = 16 * 1024;
+
+/// An item that failed to map to a Kobo book during a sync
+pub struct MapFailure {
+    pub item_id: Uuid,
+    /// ABS `updatedAt` of the failing version of the item
+    pub item_updated_at: i64,
+    pub error: String,
+}
+
+pub struct DeviceService<'a> {
+    pub db: &'a DatabaseConnection,
+}
+
+impl<'a> DeviceService<'a> {
+    pub fn new(db: &'a DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    pub async fn exists(&self, device_id: Uuid) -> AbsKoboResult<bool> {
+        Ok(devices::Entity::find_by_id(device_id)
+            .one(self.db)
+            .await?
+            .is_some())
+    }
+
+    /// ABS API key of the user owning the device, `None` for unknown devices
+    pub async fn abs_api_key(&self, device_id: Uuid) -> AbsKoboResult<Option<String>> {
+        Ok(devices::Entity::find_by_id(device_id)
+            .find_also_related(user::Entity)
+            .one(self.db)
+            .await?
+            .and_then(|(_, user)| user)
+            .map(|user| user.abs_api_key))
+    }
+
+    /// ABS API key for a device that may be shared: the key of the user added to the device for
+    /// the Kobo `user_key` it presents, otherwise the owner's key. `None` for unknown devices.
+    pub async fn abs_api_key_for(
+        &self,
+        device_id: Uuid,
+        user_key: Option<&str>,
+    ) -> AbsKoboResult<Option<String>> {
+        if let Some(user_key) = user_key {
+            let shared = device_users::Entity::find()
+                .filter(device_users::Column::DeviceId.eq(device_id))
+                .filter(device_users::Column::UserKey.eq(user_key))
+                .find_also_related(user::Entity)
+                .one(self.db)
+                .await?
+                .and_then(|(_, user)| user);
+            if let Some(user) = shared {
+                return Ok(Some(user.abs_api_key));
+            }
+        }
+        self.abs_api_key(device_id).await
+    }
+
+    /// Where the next sync should resume scanning ABS items, 0 when no scan is in progress
+    pub async fn scan_offset(&self, device_id: Uuid) -> AbsKoboResult<u64> {
+        Ok(devices::Entity::find_by_id(device_id)
+            .one(self.db)
+            .await?
+            .and_then(|d| d.sync_scan_offset)
+            .map_or(0, |offset| offset.max(0) as u64))
+    }
+
+    /// Store the scan position for the next sync, `None` once the scan has covered every item
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn set_scan_offset(&self, device_id: Uuid, offset: Option<u64>) -> AbsKoboResult<()> {
+        retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(
+                    devices::Column::SyncScanOffset,
+                    Expr::value(offset.map(|o| o as i64)),
+                )
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Remember the sync token the device sent, for the debug endpoint
+    #[tracing::instrument(level = "debug", skip(self, raw_token))]
+    pub async fn record_sync_token(&self, device_id: Uuid, raw_token: &str) -> AbsKoboResult<()> {
+        let token = debug_sync_token(raw_token);
+        let now = Utc::now();
+        retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(devices::Column::LastSyncToken, Expr::value(token.clone()))
+                .col_expr(devices::Column::LastSyncTokenAt, Expr::value(now))
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Keep the last analytics payload the device sent for support, with the values of
+    /// `redact_keys` masked and truncated to [`ANALYTICS_DEBUG_MAX_LEN`]
+    #[tracing::instrument(level = "debug", skip(self, payload, redact_keys))]
+    pub async fn record_analytics(
+        &self,
+        device_id: Uuid,
+        payload: &serde_json::Value,
+        redact_keys: &[String],
+    ) -> AbsKoboResult<()> {
+        let payload: String =
+            crate::telemetry::redact_body(payload.to_string().as_bytes(), redact_keys)
+                .chars()
+                .take(ANALYTICS_DEBUG_MAX_LEN)
+                .collect();
+        let now = Utc::now();
+        retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(devices::Column::LastAnalytics, Expr::value(payload.clone()))
+                .col_expr(devices::Column::LastAnalyticsAt, Expr::value(now))
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Issue a fresh access token and store a new refresh token, `None` for unknown devices.
+    /// Access tokens aren't stored: devices are identified by their path token, so they are
+    /// only handed out for the Kobo's own bookkeeping.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn issue_auth_tokens(
+        &self,
+        device_id: Uuid,
+    ) -> AbsKoboResult<Option<(String, String)>> {
+        let access_token = Uuid::new_v4().to_string();
+        let refresh_token = Uuid::new_v4().to_string();
+        let updated = retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(
+                    devices::Column::RefreshToken,
+                    Expr::value(refresh_token.clone()),
+                )
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await?;
+        Ok(Some((access_token, refresh_token)).filter(|_| updated.rows_affected > 0))
+    }
+
+    /// A new access token if `refresh_token` is the one last issued to the device
+    #[tracing::instrument(level = "debug", skip(self, refresh_token))]
+    pub async fn refresh_access_token(
+        &self,
+        device_id: Uuid,
+        refresh_token: &str,
+    ) -> AbsKoboResult<Option<String>> {
+        let device = devices::Entity::find_by_id(device_id)
+            .filter(devices::Column::RefreshToken.eq(refresh_token))
+            .one(self.db)
+            .await?;
+        Ok(device.map(|_| Uuid::new_v4().to_string()))
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn debug_info(&self, device_id: Uuid) -> DeviceDebugResponseDto {
+        match devices::Entity::find_by_id(device_id).one(self.db).await {
+            Ok(Some(device)) => DeviceDebugResponseDto::Ok(Json(DeviceDebugDto {
+                device_id,
+                last_sync_token: device.last_sync_token,
+                last_sync_token_at: device.last_sync_token_at,
+                last_analytics: device.last_analytics,
+                last_analytics_at: device.last_analytics_at,
+            })),
+            Ok(None) => DeviceDebugResponseDto::NotFound(Json(ErrorDto {
+                message: "Device not found".into(),
+            })),
+            Err(e) => DeviceDebugResponseDto::InternalServerError(Json(ErrorDto {
+                message: format!("Database error: {}", e),
+            })),
+        }
+    }
+
+    /// Make `user_id` the owner of the device, provisioning the device if it hasn't synced yet
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn link(&self, device_id: Uuid, user_id: Uuid) -> DeviceLinkResponseDto {
+        match self.link_device(device_id, user_id).await {
+            Ok(Some(created)) => DeviceLinkResponseDto::Ok(Json(DeviceLinkDto {
+                device_id,
+                user_id,
+                created,
+            })),
+            Ok(None) => DeviceLinkResponseDto::NotFound(Json(ErrorDto {
+                message: "User not found".into(),
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to link device");
+                DeviceLinkResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Register a new device for `user_id` under a freshly generated auth token. `base_url` is
+    /// where devices reach this server, see [`SyncService::device_base_url`].
+    ///
+    /// [`SyncService::device_base_url`]: crate::kobo_api::services::sync::SyncService::device_base_url
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn register(&self, user_id: Uuid, base_url: &str) -> DeviceRegisterResponseDto {
+        // Random rather than time-ordered, as the id is all a device needs to authenticate
+        let device_id = Uuid::new_v4();
+        match self.link_device(device_id, user_id).await {
+            Ok(Some(_)) => DeviceRegisterResponseDto::Created(Json(DeviceRegistrationDto {
+                device_id,
+                user_id,
+                api_endpoint: format!("{}/kobo/{}", base_url.trim_end_matches('/'), device_id),
+            })),
+            Ok(None) => DeviceRegisterResponseDto::NotFound(Json(ErrorDto {
+                message: "User not found".into(),
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %user_id, "failed to register device");
+                DeviceRegisterResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// All registered devices with their owner and last sync, grouped by owner
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn list(&self) -> DeviceListResponseDto {
+        match devices::Entity::find()
+            .order_by_asc(devices::Column::OwnerId)
+            .order_by_asc(devices::Column::Id)
+            .all(self.db)
+            .await
+        {
+            Ok(devices) => DeviceListResponseDto::Ok(Json(
+                devices
+                    .into_iter()
+                    .map(|device| DeviceDto {
+                        device_id: device.id,
+                        owner_id: device.owner_id,
+                        last_sync_at: device.last_sync_token_at,
+                    })
+                    .collect(),
+            )),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to list devices");
+                DeviceListResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Delete a device so its token stops working; its sync history and shared users go with it
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn revoke(&self, device_id: Uuid) -> DeviceRevokeResponseDto {
+        match retry_on_busy(|| devices::Entity::delete_by_id(device_id).exec(self.db)).await {
+            Ok(res) if res.rows_affected == 0 => {
+                DeviceRevokeResponseDto::NotFound(Json(ErrorDto {
+                    message: "Device not found".into(),
+                }))
+            }
+            Ok(_) => DeviceRevokeResponseDto::NoContent,
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to revoke device");
+                DeviceRevokeResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Let another user sync with the device: requests presenting `user_key` use that user's ABS
+    /// key instead of the owner's. Adding a key again moves it to the new user.
+    #[tracing::instrument(level = "debug", skip(self, user_key))]
+    pub async fn add_user(
+        &self,
+        device_id: Uuid,
+        user_id: Uuid,
+        user_key: String,
+    ) -> DeviceUserResponseDto {
+        let user_key = user_key.trim().to_string();
+        if user_key.is_empty() {
+            return DeviceUserResponseDto::BadRequest(Json(ErrorDto {
+                message: "user_key must not be empty".into(),
+            }));
+        }
+        match self.add_device_user(device_id, user_id, &user_key).await {
+            Ok(Some(())) => DeviceUserResponseDto::Ok(Json(DeviceUserDto {
+                device_id,
+                user_id,
+                user_key,
+            })),
+            Ok(None) => DeviceUserResponseDto::NotFound(Json(ErrorDto {
+                message: "Device or user not found".into(),
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to add device user");
+                DeviceUserResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// `None` for unknown devices or users
+    async fn add_device_user(
+        &self,
+        device_id: Uuid,
+        user_id: Uuid,
+        user_key: &str,
+    ) -> AbsKoboResult<Option<()>> {
+        let user = user::Entity::find_by_id(user_id).one(self.db).await?;
+        if user.is_none() || !self.exists(device_id).await? {
+            return Ok(None);
+        }
+        retry_on_busy(|| {
+            device_users::Entity::delete_many()
+                .filter(device_users::Column::DeviceId.eq(device_id))
+                .filter(device_users::Column::UserKey.eq(user_key))
+                .exec(self.db)
+        })
+        .await?;
+        retry_on_busy(|| {
+            device_users::Entity::insert(device_users::ActiveModel {
+                id: Set(Uuid::now_v7()),
+                device_id: Set(device_id),
+                user_id: Set(user_id),
+                user_key: Set(user_key.to_string()),
+            })
+            .exec(self.db)
+        })
+        .await?;
+        Ok(Some(()))
+    }
+
+    /// Whether the device was created, `None` for unknown users
+    async fn link_device(&self, device_id: Uuid, user_id: Uuid) -> AbsKoboResult<Option<bool>> {
+        if user::Entity::find_by_id(user_id)
+            .one(self.db)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        if self.exists(device_id).await? {
+            retry_on_busy(|| {
+                devices::Entity::update_many()
+                    .col_expr(devices::Column::OwnerId, Expr::value(user_id))
+                    .filter(devices::Column::Id.eq(device_id))
+                    .exec(self.db)
+            })
+            .await?;
+            return Ok(Some(false));
+        }
+        retry_on_busy(|| {
+            devices::Entity::insert(devices::ActiveModel {
+                id: Set(device_id),
+                owner_id: Set(user_id),
+                sync_scan_offset: Set(None),
+                proxy_store: Set(None),
+                last_sync_token: Set(None),
+                last_sync_token_at: Set(None),
+                refresh_token: Set(None),
+                sync_tag: Set(None),
+                last_analytics: Set(None),
+                last_analytics_at: Set(None),
+            })
+            .exec(self.db)
+        })
+        .await?;
+        Ok(Some(true))
+    }
+
+    /// Whether the device's syncs go to the Kobo store, falling back to `default` without override
+    pub async fn store_proxy_enabled(&self, device_id: Uuid, default: bool) -> AbsKoboResult<bool> {
+        Ok(devices::Entity::find_by_id(device_id)
+            .one(self.db)
+            .await?
+            .and_then(|d| d.proxy_store)
+            .unwrap_or(default))
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn set_store_proxy(
+        &self,
+        device_id: Uuid,
+        proxy_store: Option<bool>,
+        default: bool,
+    ) -> StoreProxyResponseDto {
+        let res = retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(devices::Column::ProxyStore, Expr::value(proxy_store))
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await;
+
+        match res {
+            Ok(res) if res.rows_affected == 0 => StoreProxyResponseDto::NotFound(Json(ErrorDto {
+                message: "Device not found".into(),
+            })),
+            Ok(_) => StoreProxyResponseDto::Ok(Json(StoreProxyDto {
+                proxy_store,
+                effective: proxy_store.unwrap_or(default),
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to update store proxy setting");
+                StoreProxyResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Tag books need to be synced to the device, `None` when the whole library syncs
+    pub async fn sync_tag(&self, device_id: Uuid) -> AbsKoboResult<Option<String>> {
+        Ok(devices::Entity::find_by_id(device_id)
+            .one(self.db)
+            .await?
+            .and_then(|d| d.sync_tag))
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn set_sync_tag(
+        &self,
+        device_id: Uuid,
+        sync_tag: Option<String>,
+    ) -> SyncTagResponseDto {
+        let sync_tag = sync_tag
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty());
+        let res = retry_on_busy(|| {
+            devices::Entity::update_many()
+                .col_expr(devices::Column::SyncTag, Expr::value(sync_tag.clone()))
+                .filter(devices::Column::Id.eq(device_id))
+                .exec(self.db)
+        })
+        .await;
+
+        match res {
+            Ok(res) if res.rows_affected == 0 => SyncTagResponseDto::NotFound(Json(ErrorDto {
+                message: "Device not found".into(),
+            })),
+            Ok(_) => SyncTagResponseDto::Ok(Json(SyncTagDto { sync_tag })),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to update sync tag");
+                SyncTagResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Items that failed to map at least `max_failures` times in a row, with the ABS `updatedAt`
+    /// of the failing version. Syncs skip them until the item changes; 0 never skips.
+    pub async fn ignored_items(
+        &self,
+        device_id: Uuid,
+        max_failures: u32,
+    ) -> AbsKoboResult<HashMap<Uuid, i64>> {
+        if max_failures == 0 {
+            return Ok(HashMap::new());
+        }
+        Ok(sync_error::Entity::find()
+            .filter(sync_error::Column::DeviceId.eq(device_id))
+            .filter(sync_error::Column::FailureCount.gte(max_failures as i32))
+            .all(self.db)
+            .await?
+            .into_iter()
+            .filter_map(|e| Some((Uuid::parse_str(&e.abs_item_id).ok()?, e.item_updated_at?)))
+            .collect())
+    }
+
+    /// Record the failures of a sync response. The first response of a sync replaces the errors
+    /// of the previous sync, keeping those of `skipped` items and counting repeated failures of
+    /// an unchanged item; `continue` responses of the same sync add to them.
+    #[tracing::instrument(level = "debug", skip(self, failures, skipped))]
+    pub async fn record_sync_errors(
+        &self,
+        device_id: Uuid,
+        failures: &[MapFailure],
+        skipped: &[Uuid],
+        starts_sync: bool,
+    ) -> AbsKoboResult<()> {
+        let previous: HashMap<String, sync_error::Model> = sync_error::Entity::find()
+            .filter(sync_error::Column::DeviceId.eq(device_id))
+            .all(self.db)
+            .await?
+            .into_iter()
+            .map(|e| (e.abs_item_id.clone(), e))
+            .collect();
+
+        let failures: Vec<&MapFailure> = if starts_sync {
+            let skipped: Vec<String> = skipped.iter().map(Uuid::to_string).collect();
+            retry_on_busy(|| {
+                sync_error::Entity::delete_many()
+                    .filter(sync_error::Column::DeviceId.eq(device_id))
+                    .filter(sync_error::Column::AbsItemId.is_not_in(skipped.clone()))
+                    .exec(self.db)
+            })
+            .await?;
+            failures.iter().collect()
+        } else {
+            // An item already recorded by an earlier response of this sync isn't counted twice
+            let fresh: Vec<&MapFailure> = failures
+                .iter()
+                .filter(|failure| {
+                    previous
+                        .get(&failure.item_id.to_string())
+                        .is_none_or(|e| e.item_updated_at != Some(failure.item_updated_at))
+                })
+                .collect();
+            let replaced: Vec<String> = fresh.iter().map(|f| f.item_id.to_string()).collect();
+            retry_on_busy(|| {
+                sync_error::Entity::delete_many()
+                    .filter(sync_error::Column::DeviceId.eq(device_id))
+                    .filter(sync_error::Column::AbsItemId.is_in(replaced.clone()))
+                    .exec(self.db)
+            })
+            .await?;
+            fresh
+        };
+
+        if failures.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        retry_on_busy(|| {
+            sync_error::Entity::insert_many(failures.iter().map(|failure| {
+                let item_id = failure.item_id.to_string();
+                let failure_count = previous
+                    .get(&item_id)
+                    .filter(|e| e.item_updated_at == Some(failure.item_updated_at))
+                    .map_or(1, |e| e.failure_count + 1);
+                sync_error::ActiveModel {
+                    id: Set(Uuid::now_v7()),
+                    device_id: Set(device_id),
+                    abs_item_id: Set(item_id),
+                    error: Set(failure.error.clone()),
+                    timestamp: Set(now),
+                    failure_count: Set(failure_count),
+                    item_updated_at: Set(Some(failure.item_updated_at)),
+                }
+            }))
+            .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn list_sync_errors(&self, device_id: Uuid) -> SyncErrorsResponseDto {
+        let res = sync_error::Entity::find()
+            .filter(sync_error::Column::DeviceId.eq(device_id))
+            .order_by_asc(sync_error::Column::AbsItemId)
+            .all(self.db)
+            .await;
+
+        match res {
+            Ok(errors) => SyncErrorsResponseDto::Ok(Json(
+                errors
+                    .into_iter()
+                    .map(|e| SyncErrorDto {
+                        abs_item_id: e.abs_item_id,
+                        error: e.error,
+                        timestamp: e.timestamp,
+                        failure_count: e.failure_count,
+                    })
+                    .collect(),
+            )),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, "failed to list sync errors");
+                SyncErrorsResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+}
+
+/// Sync token as kept for debugging: the decoded JSON with the Kobo store token masked, or the
+/// raw value if it doesn't decode, truncated to [`SYNC_TOKEN_DEBUG_MAX_LEN`]
+fn debug_sync_token(raw_token: &str) -> String {
+    let decoded = BASE64_STANDARD
+        .decode(raw_token.trim())
+        .ok()
+        .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok());
+    let token = match decoded {
+        Some(mut json) => {
+            if let Some(store_token) = json
+                .get_mut("raw_kobo_store_token")
+                .filter(|v| v.is_string())
+            {
+                *store_token = redact_secret(store_token.as_str().unwrap_or_default()).into();
+            }
+            json.to_string()
+        }
+        None => raw_token.to_string(),
+    };
+    token.chars().take(SYNC_TOKEN_DEBUG_MAX_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn malformed_item_is_recorded_as_sync_error() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let service = DeviceService::new(&db);
+        let item_id = Uuid::now_v7();
+
+        service
+            .record_sync_errors(device_id, &[failure(Uuid::now_v7(), 1, "stale")], &[], true)
+            .await
+            .unwrap();
+        service
+            .record_sync_errors(
+                device_id,
+                &[failure(item_id, 1, "missing media")],
+                &[],
+                true,
+            )
+            .await
+            .unwrap();
+
+        let SyncErrorsResponseDto::Ok(Json(errors)) = service.list_sync_errors(device_id).await
+        else {
+            panic!("expected sync errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].abs_item_id, item_id.to_string());
+        assert_eq!(errors[0].error, "missing media");
+    }
+
+    #[tokio::test]
+    async fn continue_pages_add_to_the_sync_errors() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let service = DeviceService::new(&db);
+        let (first, second) = (Uuid::now_v7(), Uuid::now_v7());
+
+        service
+            .record_sync_errors(device_id, &[failure(first, 1, "bad data")], &[], true)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            service
+                .record_sync_errors(
+                    device_id,
+                    &[
+                        failure(first, 1, "bad data"),
+                        failure(second, 1, "bad data"),
+                    ],
+                    &[],
+                    false,
+                )
+                .await
+                .unwrap();
+        }
+
+        let SyncErrorsResponseDto::Ok(Json(errors)) = service.list_sync_errors(device_id).await
+        else {
+            panic!("expected sync errors");
+        };
+        assert_eq!(errors.len(), 2);
+        // Every page of one sync counts as a single failure
+        assert!(errors.iter().all(|e| e.failure_count == 1));
+
+        // The next sync starts the list over
+        service
+            .record_sync_errors(device_id, &[], &[], true)
+            .await
+            .unwrap();
+        let SyncErrorsResponseDto::Ok(Json(errors)) = service.list_sync_errors(device_id).await
+        else {
+            panic!("expected sync errors");
+        };
+        assert!(errors.is_empty());
+    }
+
+    fn failure(item_id: Uuid, item_updated_at: i64, error: &str) -> MapFailure {
+        MapFailure {
+            item_id,
+            item_updated_at,
+            error: error.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn repeatedly_failing_item_is_skipped_until_it_changes() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let service = DeviceService::new(&db);
+        let item_id = Uuid::now_v7();
+
+        // Syncs 1-3 attempt the item and fail
+        for _ in 0..3 {
+            let ignored = service.ignored_items(device_id, 3).await.unwrap();
+            assert!(!ignored.contains_key(&item_id));
+            service
+                .record_sync_errors(device_id, &[failure(item_id, 100, "bad data")], &[], true)
+                .await
+                .unwrap();
+        }
+
+        // Sync 4 skips it, and the error stays listed
+        let ignored = service.ignored_items(device_id, 3).await.unwrap();
+        assert_eq!(ignored.get(&item_id), Some(&100));
+        service
+            .record_sync_errors(device_id, &[], &[item_id], true)
+            .await
+            .unwrap();
+        let SyncErrorsResponseDto::Ok(Json(errors)) = service.list_sync_errors(device_id).await
+        else {
+            panic!("expected sync errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].failure_count, 3);
+
+        // Once ABS updates the item it is tried again, starting a new count
+        service
+            .record_sync_errors(device_id, &[failure(item_id, 200, "bad data")], &[], true)
+            .await
+            .unwrap();
+        assert!(
+            service
+                .ignored_items(device_id, 3)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn linked_device_syncs_as_user() {
+        let (db, _) = crate::test_support::db_with_device().await;
+        let service = DeviceService::new(&db);
+        let (user_id, device_id) = (Uuid::now_v7(), Uuid::now_v7());
+        user::Entity::insert(user::ActiveModel {
+            id: Set(user_id),
+            abs_api_key: Set("provisioned-key".into()),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+
+        let DeviceLinkResponseDto::Ok(Json(link)) = service.link(device_id, user_id).await else {
+            panic!("expected the device to be linked");
+        };
+        assert!(link.created);
+        assert_eq!(
+            service.abs_api_key(device_id).await.unwrap().as_deref(),
+            Some("provisioned-key")
+        );
+
+        assert!(matches!(
+            service.link(device_id, Uuid::now_v7()).await,
+            DeviceLinkResponseDto::NotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn revoked_devices_lose_their_sync_history() {
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let service = DeviceService::new(&db);
+        service.record_sync_token(device_id, "token").await.unwrap();
+        entities::book_sync::Entity::insert(entities::book_sync::ActiveModel {
+            id: Set(Uuid::now_v7()),
+            device_id: Set(device_id),
+            abs_item_id: Set(Uuid::now_v7().to_string()),
+            timestamp: Set(Utc::now()),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+
+        let DeviceListResponseDto::Ok(Json(listed)) = service.list().await else {
+            panic!("expected the devices");
+        };
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].device_id, device_id);
+        assert!(listed[0].last_sync_at.is_some());
+
+        assert!(matches!(
+            service.revoke(device_id).await,
+            DeviceRevokeResponseDto::NoContent
+        ));
+        assert!(!service.exists(device_id).await.unwrap());
+        assert_eq!(
+            entities::book_sync::Entity::find()
+                .all(&db)
+                .await
+                .unwrap()
+                .len(),
+            0
+        );
+        assert!(matches!(
+            service.revoke(device_id).await,
+            DeviceRevokeResponseDto::NotFound(_)
+        ));
+    }
+}
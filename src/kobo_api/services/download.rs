@@ -0,0 +1,541 @@
+use futures_util::StreamExt;
+use poem_openapi::payload::{Binary, Json};
+use tokio::sync::OwnedSemaphorePermit;
+use uuid::Uuid;
+
+use crate::{
+    abs_client::{AbsClient, upstream_status},
+    config::Config,
+    kepub_cache::KepubCache,
+    kepub_jobs::{Converted, KepubJob, KepubJobs},
+    kepubify::Kepubify,
+    kobo_api::{
+        download_limit::DownloadLimit,
+        models::{BookFormatDto, DownloadResponseDto, ErrorDto},
+        services::devices::DeviceService,
+    },
+};
+
+pub struct DownloadService<'a> {
+    pub client: &'a AbsClient,
+    pub config: &'a Config,
+    pub db: &'a sea_orm::DatabaseConnection,
+    pub limit: &'a DownloadLimit,
+    pub kepub_jobs: &'a KepubJobs,
+}
+
+/// Seconds a device turned away by the download limit is asked to wait before retrying
+const DOWNLOAD_RETRY_AFTER_SECS: u64 = 30;
+/// Seconds a device is asked to wait for a background KEPUB conversion
+const KEPUB_RETRY_AFTER_SECS: u64 = 10;
+
+impl<'a> DownloadService<'a> {
+    pub fn new(
+        client: &'a AbsClient,
+        config: &'a Config,
+        db: &'a sea_orm::DatabaseConnection,
+        limit: &'a DownloadLimit,
+        kepub_jobs: &'a KepubJobs,
+    ) -> Self {
+        Self {
+            client,
+            config,
+            db,
+            limit,
+            kepub_jobs,
+        }
+    }
+
+    /// Stream a book's ebook file from ABS to the device, authenticated as the device's user (or
+    /// the shared-device user of `user_key`). `format` is the download route's `:format` segment.
+    #[tracing::instrument(level = "debug", skip(self, auth_token, user_key))]
+    pub async fn download(
+        &self,
+        auth_token: Uuid,
+        book_uuid: Uuid,
+        format: &str,
+        user_key: Option<&str>,
+    ) -> DownloadResponseDto {
+        let format = match format.parse::<BookFormatDto>() {
+            Ok(format) => format,
+            Err(e) => {
+                return DownloadResponseDto::BadRequest(Json(ErrorDto {
+                    message: e.to_string(),
+                }));
+            }
+        };
+        let api_key = match DeviceService::new(self.db)
+            .abs_api_key_for(auth_token, user_key)
+            .await
+        {
+            Ok(Some(api_key)) => api_key,
+            Ok(None) => {
+                return DownloadResponseDto::Unauthorized(Json(ErrorDto {
+                    message: "Invalid auth token".into(),
+                }));
+            }
+            Err(e) => {
+                return DownloadResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        };
+
+        // The item tells which file to fetch on older ABS versions and what to name it
+        let item = match self
+            .client
+            .get_library_item(book_uuid, self.config.metadata_include.as_deref(), &api_key)
+            .await
+        {
+            Ok(item) => item,
+            Err(e) if upstream_status(&e) == Some(reqwest::StatusCode::NOT_FOUND) => {
+                return DownloadResponseDto::NotFound(Json(ErrorDto {
+                    message: "Item not found".into(),
+                }));
+            }
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), item_id = %book_uuid, "failed to fetch item for download");
+                return DownloadResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        };
+        let title = item.media.as_ref().and_then(|m| m.metadata.title.clone());
+        let ebook_format = item
+            .media
+            .as_ref()
+            .and_then(|m| m.ebook_format.as_deref())
+            .map(str::to_ascii_lowercase)
+            .unwrap_or_else(|| "epub".to_string());
+        // Only EPUBs can be converted, other formats are passed through as they are
+        let convert = matches!(format, BookFormatDto::Kepub) && ebook_format == "epub";
+        let convert_async = convert && self.config.flags().async_kepub_conversion;
+        if convert_async {
+            match self.kepub_jobs.take(book_uuid, item.updated_at) {
+                KepubJob::Finished(converted) => {
+                    return converted_response(converted, title.as_deref(), &book_uuid);
+                }
+                KepubJob::Running => return DownloadResponseDto::Accepted(KEPUB_RETRY_AFTER_SECS),
+                KepubJob::Missing => {}
+            }
+        }
+        let cache = KepubCache::from_config(self.config).filter(|_| convert);
+        if let Some(cache) = &cache
+            && let Some(kepub) = cache.get(&book_uuid, item.updated_at).await
+        {
+            tracing::debug!(item_id = %book_uuid, "serving kepub from cache");
+            return kepub_response(kepub, title.as_deref(), &book_uuid);
+        }
+
+        let Some(permit) = self.limit.acquire().await else {
+            tracing::warn!(item_id = %book_uuid, "too many downloads waiting, turning one away");
+            return DownloadResponseDto::ServiceUnavailable(
+                Json(ErrorDto {
+                    message: "Too many downloads in progress".into(),
+                }),
+                DOWNLOAD_RETRY_AFTER_SECS,
+            );
+        };
+        let ebook = match self
+            .client
+            .get_ebook(&book_uuid, item.ebook_ino().as_deref(), &api_key)
+            .await
+        {
+            Ok(ebook) => ebook,
+            Err(e) if upstream_status(&e) == Some(reqwest::StatusCode::NOT_FOUND) => {
+                return DownloadResponseDto::NotFound(Json(ErrorDto {
+                    message: "Ebook file not found".into(),
+                }));
+            }
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), item_id = %book_uuid, "failed to fetch ebook");
+                return DownloadResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        };
+
+        if !convert {
+            return DownloadResponseDto::Ok(
+                Binary(hold_until_sent(ebook.body, permit)),
+                ebook.content_type,
+                ebook.content_length,
+                content_disposition(title.as_deref(), &book_uuid, &ebook_format),
+            );
+        }
+        let epub = match ebook.body.into_vec().await {
+            Ok(epub) => epub,
+            Err(e) => {
+                return DownloadResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        };
+        // Converting doesn't touch ABS anymore
+        drop(permit);
+        let conversion = convert_epub(
+            Kepubify::from_config(self.config),
+            cache,
+            book_uuid,
+            item.updated_at,
+            epub,
+        );
+        if convert_async {
+            tracing::debug!(item_id = %book_uuid, "converting kepub in the background");
+            // A conversion that made it into the cache is served from there
+            self.kepub_jobs
+                .start(book_uuid, item.updated_at, async move {
+                    let (converted, cached) = conversion.await;
+                    (!cached).then_some(converted)
+                });
+            return DownloadResponseDto::Accepted(KEPUB_RETRY_AFTER_SECS);
+        }
+        converted_response(conversion.await.0, title.as_deref(), &book_uuid)
+    }
+}
+
+/// Convert an EPUB and cache the result, or fall back to the EPUB if kepubify fails. Also
+/// tells whether the result was cached.
+async fn convert_epub(
+    kepubify: Kepubify,
+    cache: Option<KepubCache>,
+    item_id: Uuid,
+    updated_at: i64,
+    epub: Vec<u8>,
+) -> (Converted, bool) {
+    match kepubify.convert(&epub).await {
+        Ok(kepub) => {
+            let cached = match &cache {
+                Some(cache) => match cache.put(&item_id, updated_at, &kepub).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::warn!(error = %e, item_id = %item_id, "failed to cache kepub");
+                        false
+                    }
+                },
+                None => false,
+            };
+            (Converted::Kepub(kepub), cached)
+        }
+        Err(e) => {
+            // The device reads plain EPUBs too, just without the KEPUB reading features
+            tracing::warn!(error = %format!("{:#}", e), item_id = %item_id, "KEPUB conversion failed, serving the original epub");
+            (Converted::Epub(epub), false)
+        }
+    }
+}
+
+/// `body` keeping its download slot until it has been streamed to the device
+fn hold_until_sent(body: poem::Body, permit: OwnedSemaphorePermit) -> poem::Body {
+    poem::Body::from_bytes_stream(body.into_bytes_stream().map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    }))
+}
+
+fn converted_response(
+    converted: Converted,
+    title: Option<&str>,
+    item_id: &Uuid,
+) -> DownloadResponseDto {
+    match converted {
+        Converted::Kepub(kepub) => kepub_response(kepub, title, item_id),
+        Converted::Epub(epub) => {
+            let len = epub.len() as u64;
+            DownloadResponseDto::Ok(
+                Binary(epub.into()),
+                Some("application/epub+zip".to_string()),
+                Some(len),
+                content_disposition(title, item_id, "epub"),
+            )
+        }
+    }
+}
+
+fn kepub_response(kepub: Vec<u8>, title: Option<&str>, item_id: &Uuid) -> DownloadResponseDto {
+    let len = kepub.len() as u64;
+    DownloadResponseDto::Ok(
+        Binary(kepub.into()),
+        Some("application/epub+zip".to_string()),
+        Some(len),
+        content_disposition(title, item_id, "kepub.epub"),
+    )
+}
+
+/// `attachment` disposition naming the file after the book's title and the extension of what is
+/// served, e.g. `<Title>.kepub.epub`. Characters that are unsafe in filenames are replaced,
+/// non-ASCII ones are kept in the RFC 5987 `filename*` parameter, and the item id is used for
+/// titles with nothing left.
+fn content_disposition(title: Option<&str>, item_id: &Uuid, extension: &str) -> String {
+    let sanitized: String = title
+        .unwrap_or_default()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let sanitized = sanitized.trim().trim_matches('.');
+    let stem = if sanitized.is_empty() {
+        item_id.to_string()
+    } else {
+        sanitized.to_string()
+    };
+    let filename = format!("{}.{}", stem, extension);
+    let ascii: String = filename
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    if ascii == filename {
+        format!("attachment; filename=\"{}\"", filename)
+    } else {
+        let encoded: String = filename
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    (b as char).to_string()
+                }
+                b => format!("%{:02X}", b),
+            })
+            .collect();
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            ascii, encoded
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{Route, get, handler, web::Path};
+
+    use super::*;
+
+    #[test]
+    fn unsafe_titles_make_safe_filenames() {
+        let id = Uuid::nil();
+        assert_eq!(
+            content_disposition(Some(r#"AC/DC: "Live" \ Bootleg?"#), &id, "kepub.epub"),
+            r#"attachment; filename="AC_DC_ _Live_ _ Bootleg_.kepub.epub""#
+        );
+        assert_eq!(
+            content_disposition(Some("Mörk"), &id, "epub"),
+            r#"attachment; filename="M_rk.epub"; filename*=UTF-8''M%C3%B6rk.epub"#
+        );
+        assert_eq!(
+            content_disposition(Some(" .. "), &id, "epub"),
+            format!("attachment; filename=\"{}.epub\"", id)
+        );
+    }
+
+    #[tokio::test]
+    async fn ebook_is_streamed_with_the_device_users_key() {
+        #[handler]
+        fn item(Path(id): Path<Uuid>) -> poem::web::Json<serde_json::Value> {
+            let mut item = crate::test_support::library_item_json(id, "Some Book");
+            item["media"]["ebookFormat"] = "epub".into();
+            poem::web::Json(item)
+        }
+
+        #[handler]
+        fn ebook(req: &poem::Request) -> poem::Response {
+            if req.header("Authorization") != Some("Bearer key") {
+                return poem::Response::builder()
+                    .status(poem::http::StatusCode::UNAUTHORIZED)
+                    .finish();
+            }
+            poem::Response::builder()
+                .content_type("application/epub+zip")
+                .body("epub bytes")
+        }
+
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/items/:id", get(item))
+                .at("/api/items/:id/ebook", get(ebook)),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, Uuid::now_v7());
+        let dir = std::env::temp_dir().join(format!("download-test-{}", Uuid::now_v7()));
+        config.kepubify_path = dir.join("missing").display().to_string();
+        let limit = DownloadLimit::from_config(&config);
+        let jobs = KepubJobs::default();
+        let service = DownloadService::new(&client, &config, &db, &limit, &jobs);
+        let book = Uuid::now_v7();
+
+        // Without a working kepubify the original epub is served
+        let DownloadResponseDto::Ok(Binary(body), content_type, _, disposition) =
+            service.download(device_id, book, "kepub", None).await
+        else {
+            panic!("expected the ebook");
+        };
+        assert_eq!(body.into_string().await.unwrap(), "epub bytes");
+        assert_eq!(content_type.as_deref(), Some("application/epub+zip"));
+        assert_eq!(disposition, r#"attachment; filename="Some Book.epub""#);
+
+        #[cfg(unix)]
+        {
+            let mut config = crate::test_support::config(&base, Uuid::now_v7());
+            config.kepubify_path = crate::test_support::fake_kepubify(&dir)
+                .display()
+                .to_string();
+            config.kepub_cache_dir = Some(dir.join("cache"));
+            let DownloadResponseDto::Ok(Binary(body), _, len, disposition) =
+                DownloadService::new(&client, &config, &db, &limit, &jobs)
+                    .download(device_id, book, "kepub", None)
+                    .await
+            else {
+                panic!("expected the converted ebook");
+            };
+            assert_eq!(body.into_string().await.unwrap(), "kepub:epub bytes");
+            assert_eq!(len, Some(16));
+            assert_eq!(
+                disposition,
+                r#"attachment; filename="Some Book.kepub.epub""#
+            );
+
+            // Repeat downloads are served from the cache without converting again
+            config.kepubify_path = dir.join("missing").display().to_string();
+            let DownloadResponseDto::Ok(Binary(body), ..) =
+                DownloadService::new(&client, &config, &db, &limit, &jobs)
+                    .download(device_id, book, "kepub", None)
+                    .await
+            else {
+                panic!("expected the cached ebook");
+            };
+            assert_eq!(body.into_string().await.unwrap(), "kepub:epub bytes");
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        assert!(matches!(
+            service.download(device_id, book, "mobi", None).await,
+            DownloadResponseDto::BadRequest(_)
+        ));
+        assert!(matches!(
+            service.download(Uuid::now_v7(), book, "epub", None).await,
+            DownloadResponseDto::Unauthorized(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn downloads_past_the_queue_are_turned_away() {
+        #[handler]
+        fn item(Path(id): Path<Uuid>) -> poem::web::Json<serde_json::Value> {
+            poem::web::Json(crate::test_support::library_item_json(id, "Some Book"))
+        }
+
+        #[handler]
+        fn ebook() -> &'static str {
+            "epub bytes"
+        }
+
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/items/:id", get(item))
+                .at("/api/items/:id/ebook", get(ebook)),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, Uuid::now_v7());
+        let limit = DownloadLimit::new(1, 0);
+        let jobs = KepubJobs::default();
+        let service = DownloadService::new(&client, &config, &db, &limit, &jobs);
+        let book = Uuid::now_v7();
+
+        let DownloadResponseDto::Ok(Binary(body), ..) =
+            service.download(device_id, book, "epub", None).await
+        else {
+            panic!("expected the ebook");
+        };
+        // The first download keeps its slot until the device has read it
+        let DownloadResponseDto::ServiceUnavailable(_, retry_after) =
+            service.download(device_id, book, "epub", None).await
+        else {
+            panic!("expected the second download to be turned away");
+        };
+        assert_eq!(retry_after, DOWNLOAD_RETRY_AFTER_SECS);
+
+        assert_eq!(body.into_string().await.unwrap(), "epub bytes");
+        assert!(matches!(
+            service.download(device_id, book, "epub", None).await,
+            DownloadResponseDto::Ok(..)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn slow_conversions_answer_202_until_the_kepub_is_ready() {
+        use std::os::unix::fs::PermissionsExt;
+
+        #[handler]
+        fn item(Path(id): Path<Uuid>) -> poem::web::Json<serde_json::Value> {
+            let mut item = crate::test_support::library_item_json(id, "Big Book");
+            item["media"]["ebookFormat"] = "epub".into();
+            poem::web::Json(item)
+        }
+
+        #[handler]
+        fn ebook() -> &'static str {
+            "epub bytes"
+        }
+
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/items/:id", get(item))
+                .at("/api/items/:id/ebook", get(ebook)),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let dir = std::env::temp_dir().join(format!("download-test-{}", Uuid::now_v7()));
+        let kepubify = crate::test_support::fake_kepubify(&dir);
+        let slow_kepubify = dir.join("slow-kepubify");
+        std::fs::write(
+            &slow_kepubify,
+            format!("#!/bin/sh\nsleep 1\nexec {} \"$@\"\n", kepubify.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&slow_kepubify, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Finished conversions are collected from the job, or from the cache once there is one
+        for cache_dir in [None, Some(dir.join("cache"))] {
+            let mut config = crate::test_support::config(&base, Uuid::now_v7());
+            config.kepubify_path = slow_kepubify.display().to_string();
+            config.kepub_cache_dir = cache_dir;
+            config.flags.async_kepub_conversion = true;
+            let limit = DownloadLimit::from_config(&config);
+            let jobs = KepubJobs::default();
+            let service = DownloadService::new(&client, &config, &db, &limit, &jobs);
+            let book = Uuid::now_v7();
+
+            // The first download starts the conversion, the next ones find it still running
+            for _ in 0..2 {
+                let DownloadResponseDto::Accepted(retry_after) =
+                    service.download(device_id, book, "kepub", None).await
+                else {
+                    panic!("expected the conversion to be pending");
+                };
+                assert_eq!(retry_after, KEPUB_RETRY_AFTER_SECS);
+            }
+
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+            let (body, disposition) = loop {
+                match service.download(device_id, book, "kepub", None).await {
+                    DownloadResponseDto::Ok(Binary(body), _, _, disposition) => {
+                        break (body, disposition);
+                    }
+                    DownloadResponseDto::Accepted(_) if std::time::Instant::now() < deadline => {
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    }
+                    _ => panic!("expected the converted ebook"),
+                }
+            };
+            assert_eq!(body.into_string().await.unwrap(), "kepub:epub bytes");
+            assert_eq!(disposition, r#"attachment; filename="Big Book.kepub.epub""#);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,18 @@
-use poem_openapi::payload::PlainText;
+use std::time::Duration;
 
-use crate::abs_client::AbsClient;
+use poem_openapi::payload::{Json, PlainText};
+
+use crate::{
+    AbsKoboResult,
+    abs_client::{AbsClient, AbsVersion},
+    config::StartupAbsCheck,
+    kepubify::Kepubify,
+    kobo_api::models::{HealthCheckDto, HealthDto, HealthResponseDto},
+};
+
+/// How long `/healthz` waits for each dependency, so a hanging one fails the probe instead of
+/// timing it out
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct HealthService<'a> {
     pub client: &'a AbsClient,
@@ -22,4 +34,219 @@ impl<'a> HealthService<'a> {
             Err(e) => PlainText(format!("error: {}", e)),
         }
     }
+
+    /// Check ABS, the database and kepubify for health probes. Only ABS and the database make
+    /// the server unavailable, devices still get plain EPUBs without kepubify.
+    #[tracing::instrument(level = "debug", skip(self, db, kepubify))]
+    pub async fn health(
+        &self,
+        db: &sea_orm::DatabaseConnection,
+        kepubify: &Kepubify,
+    ) -> HealthResponseDto {
+        let (abs, database, kepubify) = tokio::join!(
+            check(async { Ok(self.client.get_status().await?.server_version) }),
+            check(async {
+                db.ping().await?;
+                Ok(None)
+            }),
+            check(async { Ok(Some(kepubify.version().await?)) }),
+        );
+        let available = abs.ok && database.ok;
+        let health = HealthDto {
+            status: if available { "ok" } else { "unavailable" }.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            abs,
+            database,
+            kepubify,
+        };
+        if available {
+            HealthResponseDto::Ok(Json(health))
+        } else {
+            tracing::warn!(?health, "health check failed");
+            HealthResponseDto::ServiceUnavailable(Json(health))
+        }
+    }
+
+    /// Ping ABS once at startup; depending on `mode` an unreachable ABS or one older than
+    /// `min_version` is only logged or is fatal
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn startup_check(
+        &self,
+        mode: StartupAbsCheck,
+        min_version: Option<AbsVersion>,
+    ) -> AbsKoboResult<()> {
+        match self.client.get_status().await {
+            Ok(s) => {
+                let reported = s.server_version.unwrap_or_default();
+                tracing::info!(version = %reported, "ABS is reachable");
+                let Some(min_version) = min_version else {
+                    return Ok(());
+                };
+                match AbsVersion::parse(&reported) {
+                    Some(version) if version < min_version => {
+                        let message = format!(
+                            "ABS {} is older than the minimum supported version {}",
+                            version, min_version
+                        );
+                        match mode {
+                            StartupAbsCheck::Warn => {
+                                tracing::warn!("{}, continuing startup", message);
+                                Ok(())
+                            }
+                            StartupAbsCheck::Fail => Err(anyhow::anyhow!(message)),
+                        }
+                    }
+                    Some(_) => Ok(()),
+                    None => {
+                        tracing::warn!(version = %reported, "could not parse the ABS version");
+                        Ok(())
+                    }
+                }
+            }
+            Err(e) => match mode {
+                StartupAbsCheck::Warn => {
+                    tracing::warn!(error = %e, "ABS is unreachable, continuing startup");
+                    Ok(())
+                }
+                StartupAbsCheck::Fail => Err(e.context("ABS is unreachable at startup")),
+            },
+        }
+    }
+}
+
+async fn check(probe: impl Future<Output = AbsKoboResult<Option<String>>>) -> HealthCheckDto {
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, probe).await {
+        Ok(Ok(detail)) => HealthCheckDto { ok: true, detail },
+        Ok(Err(e)) => HealthCheckDto {
+            ok: false,
+            detail: Some(format!("{:#}", e)),
+        },
+        Err(_) => HealthCheckDto {
+            ok: false,
+            detail: Some(format!(
+                "no answer within {}s",
+                HEALTH_CHECK_TIMEOUT.as_secs()
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{Route, get, handler, web::Json};
+
+    use super::*;
+
+    // Nothing listens on port 1, so connections are refused immediately
+    const UNREACHABLE_ABS: &str = "http://127.0.0.1:1";
+
+    #[tokio::test]
+    async fn startup_check_warn_continues_when_unreachable() {
+        let client = AbsClient::new(UNREACHABLE_ABS).unwrap();
+        let res = HealthService::new(&client)
+            .startup_check(StartupAbsCheck::Warn, None)
+            .await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn startup_check_fail_errors_when_unreachable() {
+        let client = AbsClient::new(UNREACHABLE_ABS).unwrap();
+        let res = HealthService::new(&client)
+            .startup_check(StartupAbsCheck::Fail, None)
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn startup_check_fail_passes_when_reachable() {
+        #[handler]
+        fn status() -> Json<serde_json::Value> {
+            Json(serde_json::json!({ "app": "audiobookshelf", "serverVersion": "2.26.0" }))
+        }
+        let base = crate::test_support::serve(Route::new().at("/status", get(status))).await;
+        let client = AbsClient::new(base).unwrap();
+        let res = HealthService::new(&client)
+            .startup_check(StartupAbsCheck::Fail, None)
+            .await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn healthz_needs_abs_and_the_database_but_not_kepubify() {
+        #[handler]
+        fn status() -> Json<serde_json::Value> {
+            Json(serde_json::json!({ "app": "audiobookshelf", "serverVersion": "2.26.0" }))
+        }
+        let base = crate::test_support::serve(Route::new().at("/status", get(status))).await;
+        let client = AbsClient::new(base).unwrap();
+        let (db, _) = crate::test_support::db_with_device().await;
+        let dir = std::env::temp_dir().join(format!("healthz-test-{}", uuid::Uuid::now_v7()));
+        let missing = Kepubify::new(dir.join("missing"));
+
+        let HealthResponseDto::Ok(poem_openapi::payload::Json(health)) =
+            HealthService::new(&client).health(&db, &missing).await
+        else {
+            panic!("expected a healthy server");
+        };
+        assert_eq!(health.status, "ok");
+        assert_eq!(health.abs.detail.as_deref(), Some("2.26.0"));
+        assert!(health.database.ok);
+        assert!(!health.kepubify.ok);
+
+        #[cfg(unix)]
+        {
+            let kepubify = Kepubify::new(crate::test_support::fake_kepubify(&dir));
+            let HealthResponseDto::Ok(poem_openapi::payload::Json(health)) =
+                HealthService::new(&client).health(&db, &kepubify).await
+            else {
+                panic!("expected a healthy server");
+            };
+            assert!(health.kepubify.ok);
+            assert_eq!(health.kepubify.detail.as_deref(), Some("kepubify v4.0.4"));
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        let unreachable = AbsClient::new(UNREACHABLE_ABS).unwrap();
+        let HealthResponseDto::ServiceUnavailable(poem_openapi::payload::Json(health)) =
+            HealthService::new(&unreachable).health(&db, &missing).await
+        else {
+            panic!("expected an unavailable server");
+        };
+        assert_eq!(health.status, "unavailable");
+        assert!(!health.abs.ok);
+    }
+
+    #[tokio::test]
+    async fn startup_check_enforces_min_version() {
+        #[handler]
+        fn status() -> Json<serde_json::Value> {
+            Json(serde_json::json!({ "app": "audiobookshelf", "serverVersion": "2.3.3" }))
+        }
+        let base = crate::test_support::serve(Route::new().at("/status", get(status))).await;
+        let client = AbsClient::new(base).unwrap();
+        let health = HealthService::new(&client);
+
+        let min_version = AbsVersion::parse("2.5.0");
+        let err = health
+            .startup_check(StartupAbsCheck::Fail, min_version)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ABS 2.3.3 is older than the minimum supported version 2.5.0"
+        );
+        assert!(
+            health
+                .startup_check(StartupAbsCheck::Warn, min_version)
+                .await
+                .is_ok()
+        );
+        assert!(
+            health
+                .startup_check(StartupAbsCheck::Fail, AbsVersion::parse("2.3"))
+                .await
+                .is_ok()
+        );
+    }
 }
@@ -1,37 +1,46 @@
-use poem_openapi::payload::Json;
+use std::path::Path;
+
+use image::{DynamicImage, codecs::jpeg::JpegEncoder};
+use poem_openapi::payload::{Binary, Json};
 use uuid::Uuid;
 
 use crate::{
-    abs_client::AbsClient,
+    abs_client::{
+        AbsClient, AbsStream, FilterDataEntry, Library, LibraryItemSort, upstream_status,
+    },
+    cover_cache::CoverCache,
     kobo_api::models::{
-        ErrorDto, LibraryDto, LibraryItemDto, LibraryItemsResponseDto, LibraryListResponse,
+        CoverResponseDto, ErrorDto, FilterOptionDto, LibraryDto, LibraryFilterDataDto,
+        LibraryFilterDataResponseDto, LibraryItemDto, LibraryItemsResponseDto, LibraryListResponse,
     },
 };
 
+/// JPEG quality of re-encoded thumbnails when the device doesn't ask for one
+const DEFAULT_THUMBNAIL_QUALITY: u8 = 85;
+
 pub struct LibraryService<'a> {
     pub client: &'a AbsClient,
+    pub cover_cache: Option<CoverCache>,
 }
 
 impl<'a> LibraryService<'a> {
     pub fn new(client: &'a AbsClient) -> Self {
-        Self { client }
+        Self {
+            client,
+            cover_cache: None,
+        }
+    }
+
+    /// Serve covers from and store them in `cover_cache`
+    pub fn with_cover_cache(mut self, cover_cache: Option<CoverCache>) -> Self {
+        self.cover_cache = cover_cache;
+        self
     }
 
     #[tracing::instrument(level = "debug", skip(self, api_key))]
     pub async fn list_libraries(&self, api_key: &String) -> LibraryListResponse {
         match self.client.get_libraries(api_key).await {
-            Ok(libs) => {
-                let dtos = libs
-                    .libraries
-                    .into_iter()
-                    .map(|l| LibraryDto {
-                        id: l.id,
-                        name: l.name,
-                        media_type: l.media_type,
-                    })
-                    .collect();
-                LibraryListResponse::Ok(Json(dtos))
-            }
+            Ok(libs) => LibraryListResponse::Ok(Json(library_dtos(libs.libraries))),
             Err(e) => {
                 tracing::error!(error = %format!("{:?}", e), "failed to list libraries");
                 LibraryListResponse::BadGateway(Json(ErrorDto {
@@ -41,6 +50,44 @@ impl<'a> LibraryService<'a> {
         }
     }
 
+    /// Genres, tags, authors and series present in a library
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn library_filter_data(
+        &self,
+        library_id: &Uuid,
+        api_key: &String,
+    ) -> LibraryFilterDataResponseDto {
+        match self
+            .client
+            .get_library_filter_data(library_id, api_key)
+            .await
+        {
+            Ok(data) => {
+                let options = |entries: Vec<FilterDataEntry>| {
+                    entries
+                        .into_iter()
+                        .map(|e| FilterOptionDto {
+                            id: e.id,
+                            name: e.name,
+                        })
+                        .collect()
+                };
+                LibraryFilterDataResponseDto::Ok(Json(LibraryFilterDataDto {
+                    genres: data.genres,
+                    tags: data.tags,
+                    authors: options(data.authors),
+                    series: options(data.series),
+                }))
+            }
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), %library_id, "failed to get library filter data");
+                LibraryFilterDataResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }))
+            }
+        }
+    }
+
     #[allow(clippy::too_many_arguments)]
     #[tracing::instrument(level = "debug", skip(self, include, filter))]
     pub async fn list_library_items(
@@ -50,11 +97,13 @@ impl<'a> LibraryService<'a> {
         page: Option<i64>,
         include: Option<&str>,
         filter: Option<&str>,
+        sort: Option<&LibraryItemSort>,
+        device_id: Option<Uuid>,
         api_key: &String,
     ) -> LibraryItemsResponseDto {
         let res = self
             .client
-            .get_library_items(library_id, limit, page, include, filter, api_key)
+            .get_library_items(library_id, limit, page, include, filter, sort, api_key)
             .await;
 
         match res {
@@ -63,36 +112,39 @@ impl<'a> LibraryService<'a> {
                     .results
                     .into_iter()
                     .map(|it| {
-                        let title = it
-                            .media
-                            .metadata
-                            .title
+                        let ebook_formats = it.ebook_formats();
+                        let media = it.media.as_ref();
+                        let metadata = media.map(|m| &m.metadata);
+                        let title = metadata
+                            .and_then(|m| m.title.clone())
                             .unwrap_or("Unknown Title".to_string());
                         let author = Some(
-                            it.media
-                                .metadata
-                                .author_name
+                            metadata
+                                .and_then(|m| m.author_name.clone())
                                 .unwrap_or("Unknown Author".to_string()),
                         );
                         let series = Some(
-                            it.media
-                                .metadata
-                                .series_name
+                            metadata
+                                .and_then(|m| m.series_name.clone())
                                 .unwrap_or("Unknown Series".to_string()),
                         );
-                        let cover_url = Some(it.media.cover_path.unwrap_or("".to_string()));
-                        let ebook_format = it.media.ebook_format.as_deref().map(|f| f.to_string());
+                        let ebook_format = media
+                            .and_then(|m| m.ebook_format.clone())
+                            .or_else(|| ebook_formats.first().cloned());
 
-                        // Prefer using cover_url helper which builds the public URL
-                        let computed_cover = Some(self.client.cover_url(&it.id, None, None, false));
+                        // Covers go through our own proxy so the ABS key and paths stay private
+                        let cover_url = Some(cover_proxy_url(&it.id, device_id));
 
                         LibraryItemDto {
                             id: it.id,
+                            num_files: it.file_count(),
+                            ebook_file_count: it.ebook_file_count(),
                             title,
                             author,
                             series,
-                            cover_url: computed_cover.or(cover_url),
+                            cover_url,
                             ebook_format,
+                            ebook_formats,
                         }
                     })
                     .collect();
@@ -106,4 +158,599 @@ impl<'a> LibraryService<'a> {
             }
         }
     }
+
+    /// Proxy an item's cover; `fallback_cover` is served instead of a 404 for cover-less items
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn item_cover(
+        &self,
+        item_id: &Uuid,
+        size: Option<(u32, u32)>,
+        raw: bool,
+        fallback_cover: Option<&Path>,
+        api_key: &String,
+    ) -> CoverResponseDto {
+        let version = self.cover_version(item_id, api_key).await;
+        self.cover(item_id, version, size, raw, fallback_cover, api_key)
+            .await
+            .0
+    }
+}
+
+impl LibraryService<'_> {
+    /// The item's ABS `updatedAt`, which the cached covers are keyed by. `None` without a cache,
+    /// or when the item can't be fetched, in which case the cache is bypassed.
+    async fn cover_version(&self, item_id: &Uuid, api_key: &String) -> Option<i64> {
+        self.cover_cache.as_ref()?;
+        match self.client.get_library_item(*item_id, None, api_key).await {
+            Ok(item) => Some(item.updated_at),
+            Err(e) => {
+                tracing::debug!(error = %e, %item_id, "failed to fetch item, not caching its cover");
+                None
+            }
+        }
+    }
+
+    /// The cover, and whether it is the item's own rather than the fallback placeholder
+    async fn cover(
+        &self,
+        item_id: &Uuid,
+        version: Option<i64>,
+        size: Option<(u32, u32)>,
+        raw: bool,
+        fallback_cover: Option<&Path>,
+        api_key: &String,
+    ) -> (CoverResponseDto, bool) {
+        let cache_key =
+            version.map(|v| CoverCache::key(item_id, v, size, if raw { "raw" } else { "cover" }));
+        if let Some(cached) = self.cached_cover(cache_key.as_deref()).await {
+            return (cached, true);
+        }
+        let cover = match self.client.get_cover(item_id, size, raw, api_key).await {
+            Ok(cover) => match &cache_key {
+                Some(key) => self.cache_cover(key, cover).await,
+                None => CoverResponseDto::Ok(Binary(cover.body), cover.content_type),
+            },
+            // The placeholder isn't cached, so a cover added later shows up
+            Err(e) if upstream_status(&e) == Some(reqwest::StatusCode::NOT_FOUND) => {
+                let fallback = match fallback_cover {
+                    Some(path) => fallback_cover_response(path).await,
+                    None => CoverResponseDto::NotFound(Json(ErrorDto {
+                        message: "Cover not found".into(),
+                    })),
+                };
+                return (fallback, false);
+            }
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), item_id=%item_id, "failed to fetch cover");
+                CoverResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }))
+            }
+        };
+        (cover, true)
+    }
+
+    async fn cached_cover(&self, key: Option<&str>) -> Option<CoverResponseDto> {
+        let key = key?;
+        let (image, content_type) = self.cover_cache.as_ref()?.get(key).await?;
+        tracing::debug!(%key, "serving cover from cache");
+        Some(CoverResponseDto::Ok(Binary(image.into()), content_type))
+    }
+
+    /// Read a cover from ABS into the cache and serve it
+    async fn cache_cover(&self, key: &str, cover: AbsStream) -> CoverResponseDto {
+        let image = match cover.body.into_vec().await {
+            Ok(image) => image,
+            Err(e) => {
+                return CoverResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        };
+        self.store_cover(key, &image, cover.content_type.as_deref())
+            .await;
+        CoverResponseDto::Ok(Binary(image.into()), cover.content_type)
+    }
+
+    async fn store_cover(&self, key: &str, image: &[u8], content_type: Option<&str>) {
+        if let Some(cache) = &self.cover_cache
+            && let Err(e) = cache.put(key, image, content_type).await
+        {
+            tracing::warn!(error = %e, %key, "failed to cache cover");
+        }
+    }
+
+    /// Cover thumbnail for a Kobo device. With a `quality` or `greyscale` the cover is re-encoded
+    /// as a JPEG here, otherwise ABS's scaled cover is passed through.
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn item_thumbnail(
+        &self,
+        item_id: &Uuid,
+        size: (u32, u32),
+        quality: Option<u8>,
+        greyscale: bool,
+        fallback_cover: Option<&Path>,
+        api_key: &String,
+    ) -> CoverResponseDto {
+        let version = self.cover_version(item_id, api_key).await;
+        if quality.is_none() && !greyscale {
+            return self
+                .cover(item_id, version, Some(size), false, fallback_cover, api_key)
+                .await
+                .0;
+        }
+        let quality = quality.unwrap_or(DEFAULT_THUMBNAIL_QUALITY);
+        let format = format!("q{}{}", quality, if greyscale { "-grey" } else { "" });
+        let cache_key = version.map(|v| CoverCache::key(item_id, v, Some(size), &format));
+        if let Some(cached) = self.cached_cover(cache_key.as_deref()).await {
+            return cached;
+        }
+
+        let (cover, own_cover) = self
+            .cover(item_id, version, Some(size), false, fallback_cover, api_key)
+            .await;
+        let CoverResponseDto::Ok(Binary(body), content_type) = cover else {
+            return cover;
+        };
+        let bytes = match body.into_vec().await {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return CoverResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        };
+
+        let encoded = {
+            let bytes = bytes.clone();
+            tokio::task::spawn_blocking(move || encode_thumbnail(&bytes, quality, greyscale)).await
+        };
+        match encoded {
+            Ok(Ok(jpeg)) => {
+                if let Some(key) = cache_key.filter(|_| own_cover) {
+                    self.store_cover(&key, &jpeg, Some("image/jpeg")).await;
+                }
+                CoverResponseDto::Ok(Binary(jpeg.into()), Some("image/jpeg".into()))
+            }
+            // The device can still show the cover as ABS served it
+            Ok(Err(e)) => {
+                tracing::warn!(error = %e, %item_id, "failed to re-encode cover, serving it as is");
+                CoverResponseDto::Ok(Binary(bytes.into()), content_type)
+            }
+            Err(e) => {
+                tracing::error!(error = %e, %item_id, "cover re-encoding task failed");
+                CoverResponseDto::Ok(Binary(bytes.into()), content_type)
+            }
+        }
+    }
+}
+
+/// Decode a cover and encode it as a JPEG of the given quality, in greyscale if asked to
+fn encode_thumbnail(bytes: &[u8], quality: u8, greyscale: bool) -> image::ImageResult<Vec<u8>> {
+    let cover = image::load_from_memory(bytes)?;
+    let cover = if greyscale {
+        DynamicImage::ImageLuma8(cover.to_luma8())
+    } else {
+        DynamicImage::ImageRgb8(cover.to_rgb8())
+    };
+    let mut jpeg = Vec::new();
+    cover.write_with_encoder(JpegEncoder::new_with_quality(
+        &mut jpeg,
+        quality.clamp(1, 100),
+    ))?;
+    Ok(jpeg)
+}
+
+/// Stream the configured placeholder cover
+async fn fallback_cover_response(path: &Path) -> CoverResponseDto {
+    match tokio::fs::File::open(path).await {
+        Ok(file) => CoverResponseDto::Ok(
+            Binary(poem::Body::from_async_read(file)),
+            Some(image_content_type(path).to_string()),
+        ),
+        Err(e) => {
+            tracing::error!(error = %e, path = %path.display(), "failed to open fallback cover");
+            CoverResponseDto::NotFound(Json(ErrorDto {
+                message: "Cover not found".into(),
+            }))
+        }
+    }
+}
+
+fn image_content_type(path: &Path) -> &'static str {
+    match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Cover size requested for explore DTO covers
+const EXPLORE_COVER_SIZE: (u32, u32) = (400, 600);
+
+/// Cover URL served by this service: the device's Kobo thumbnail route when a device is given,
+/// the plain cover proxy otherwise
+pub fn cover_proxy_url(item_id: &Uuid, device_id: Option<Uuid>) -> String {
+    let (width, height) = EXPLORE_COVER_SIZE;
+    match device_id {
+        Some(device_id) => format!(
+            "/kobo/{}/v1/books/{}/thumbnail/{}/{}/false/image.jpg",
+            device_id, item_id, width, height
+        ),
+        None => format!(
+            "/v1/items/{}/cover?width={}&height={}",
+            item_id, width, height
+        ),
+    }
+}
+
+/// Map ABS libraries to DTOs in ABS UI order (libraries without a display order go last)
+fn library_dtos(libraries: Vec<Library>) -> Vec<LibraryDto> {
+    let mut dtos: Vec<LibraryDto> = libraries
+        .into_iter()
+        .map(|l| LibraryDto {
+            id: l.id,
+            name: l.name,
+            media_type: l.media_type.map(|t| t.as_str().to_string()),
+            display_order: l.display_order,
+        })
+        .collect();
+    dtos.sort_by_key(|l| (l.display_order.is_none(), l.display_order));
+    dtos
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        EndpointExt, Route, get, handler,
+        web::{Data, Json as PoemJson},
+    };
+
+    use super::*;
+
+    async fn cover_with_fallback(fallback_cover: Option<&Path>) -> CoverResponseDto {
+        #[handler]
+        fn no_cover() -> poem::http::StatusCode {
+            poem::http::StatusCode::NOT_FOUND
+        }
+        let base =
+            crate::test_support::serve(Route::new().at("/api/items/:id/cover", get(no_cover)))
+                .await;
+        let client = AbsClient::new(base).unwrap();
+        LibraryService::new(&client)
+            .item_cover(&Uuid::now_v7(), None, false, fallback_cover, &"key".into())
+            .await
+    }
+
+    #[tokio::test]
+    async fn greyscale_thumbnail_is_grey_jpeg() {
+        #[handler]
+        fn color_cover() -> poem::Response {
+            poem::Response::builder()
+                .content_type("image/png")
+                .body(png(8, 12))
+        }
+        let base =
+            crate::test_support::serve(Route::new().at("/api/items/:id/cover", get(color_cover)))
+                .await;
+        let client = AbsClient::new(base).unwrap();
+
+        let res = LibraryService::new(&client)
+            .item_thumbnail(
+                &Uuid::now_v7(),
+                (8, 12),
+                Some(60),
+                true,
+                None,
+                &"key".into(),
+            )
+            .await;
+        let CoverResponseDto::Ok(Binary(body), content_type) = res else {
+            panic!("expected a thumbnail");
+        };
+        assert_eq!(content_type.as_deref(), Some("image/jpeg"));
+        let jpeg = body.into_vec().await.unwrap();
+        assert_eq!(
+            image::guess_format(&jpeg).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::L8);
+        assert_eq!((decoded.width(), decoded.height()), (8, 12));
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let cover = image::RgbImage::from_pixel(width, height, image::Rgb([200, 30, 60]));
+        let mut png = std::io::Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(cover)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[tokio::test]
+    async fn repeat_cover_requests_are_served_from_disk() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicI64, AtomicUsize, Ordering},
+        };
+
+        #[handler]
+        fn counted_cover(Data(calls): Data<&Arc<AtomicUsize>>) -> poem::Response {
+            calls.fetch_add(1, Ordering::SeqCst);
+            poem::Response::builder()
+                .content_type("image/png")
+                .body(vec![137, 80, 78, 71])
+        }
+        #[handler]
+        fn item(
+            poem::web::Path(id): poem::web::Path<Uuid>,
+            Data(updated_at): Data<&Arc<AtomicI64>>,
+        ) -> PoemJson<serde_json::Value> {
+            let mut item = crate::test_support::library_item_json(id, "Book");
+            item["updatedAt"] = updated_at.load(Ordering::SeqCst).into();
+            PoemJson(item)
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        let updated_at = Arc::new(AtomicI64::new(1));
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/items/:id", get(item))
+                .at("/api/items/:id/cover", get(counted_cover))
+                .data(calls.clone())
+                .data(updated_at.clone()),
+        )
+        .await;
+        let client = AbsClient::new(base).unwrap();
+        let dir = std::env::temp_dir().join(format!("cover-cache-{}", Uuid::now_v7()));
+        let item_id = Uuid::now_v7();
+        let cover = || async {
+            let service = LibraryService::new(&client)
+                .with_cover_cache(Some(CoverCache::new(&dir, 1024 * 1024)));
+            let CoverResponseDto::Ok(Binary(body), content_type) = service
+                .item_cover(&item_id, Some((100, 150)), false, None, &"key".into())
+                .await
+            else {
+                panic!("expected a cover");
+            };
+            assert_eq!(body.into_vec().await.unwrap(), vec![137, 80, 78, 71]);
+            assert_eq!(content_type.as_deref(), Some("image/png"));
+        };
+
+        cover().await;
+        cover().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // A book changed in ABS may have a new cover
+        updated_at.store(2, Ordering::SeqCst);
+        cover().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fallback_thumbnails_are_not_cached() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        };
+
+        #[handler]
+        fn late_cover(Data(has_cover): Data<&Arc<AtomicBool>>) -> poem::Response {
+            if !has_cover.load(Ordering::SeqCst) {
+                return poem::http::StatusCode::NOT_FOUND.into();
+            }
+            poem::Response::builder()
+                .content_type("image/png")
+                .body(png(8, 12))
+        }
+        #[handler]
+        fn item(poem::web::Path(id): poem::web::Path<Uuid>) -> PoemJson<serde_json::Value> {
+            PoemJson(crate::test_support::library_item_json(id, "Book"))
+        }
+        let has_cover = Arc::new(AtomicBool::new(false));
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/items/:id", get(item))
+                .at("/api/items/:id/cover", get(late_cover))
+                .data(has_cover.clone()),
+        )
+        .await;
+        let client = AbsClient::new(base).unwrap();
+        let dir = std::env::temp_dir().join(format!("cover-cache-{}", Uuid::now_v7()));
+        let fallback = dir.join("fallback.png");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&fallback, png(2, 3)).unwrap();
+        let item_id = Uuid::now_v7();
+        let thumbnail = || async {
+            let CoverResponseDto::Ok(Binary(body), _) = LibraryService::new(&client)
+                .with_cover_cache(Some(CoverCache::new(dir.join("cache"), 1024 * 1024)))
+                .item_thumbnail(
+                    &item_id,
+                    (8, 12),
+                    Some(60),
+                    false,
+                    Some(&fallback),
+                    &"key".into(),
+                )
+                .await
+            else {
+                panic!("expected a thumbnail");
+            };
+            let jpeg = body.into_vec().await.unwrap();
+            let decoded = image::load_from_memory(&jpeg).unwrap();
+            (decoded.width(), decoded.height())
+        };
+
+        assert_eq!(thumbnail().await, (2, 3));
+        // The cover added to ABS later replaces the placeholder
+        has_cover.store(true, Ordering::SeqCst);
+        assert_eq!(thumbnail().await, (8, 12));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_cover_serves_fallback_when_configured() {
+        let path = std::env::temp_dir().join(format!("fallback-cover-{}.png", Uuid::now_v7()));
+        std::fs::write(&path, b"placeholder-png").unwrap();
+
+        let res = cover_with_fallback(Some(&path)).await;
+        std::fs::remove_file(&path).unwrap();
+        let CoverResponseDto::Ok(Binary(body), content_type) = res else {
+            panic!("expected the fallback cover");
+        };
+        assert_eq!(content_type.as_deref(), Some("image/png"));
+        assert_eq!(body.into_vec().await.unwrap(), b"placeholder-png");
+    }
+
+    #[tokio::test]
+    async fn missing_cover_is_not_found_without_fallback() {
+        assert!(matches!(
+            cover_with_fallback(None).await,
+            CoverResponseDto::NotFound(_)
+        ));
+    }
+
+    /// Item DTOs listed through `device_id` from an ABS library serving `items`, and the ABS URL
+    async fn list_items(
+        items: Vec<serde_json::Value>,
+        device_id: Option<Uuid>,
+    ) -> (Vec<LibraryItemDto>, String) {
+        let library_id = Uuid::now_v7();
+
+        #[handler]
+        fn items_page(Data(items): Data<&Vec<serde_json::Value>>) -> PoemJson<serde_json::Value> {
+            PoemJson(serde_json::json!({
+                "results": items, "total": items.len(), "limit": 50, "page": 0, "sortDesc": false,
+                "mediaType": "book", "minified": false, "collapseseries": false, "include": ""
+            }))
+        }
+
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(
+                    format!("/api/libraries/{}/items", library_id),
+                    get(items_page),
+                )
+                .data(items),
+        )
+        .await;
+        let client = AbsClient::new(&base).unwrap();
+
+        let LibraryItemsResponseDto::Ok(Json(dtos)) = LibraryService::new(&client)
+            .list_library_items(
+                &library_id,
+                50,
+                None,
+                None,
+                None,
+                None,
+                device_id,
+                &"key".into(),
+            )
+            .await
+        else {
+            panic!("expected items");
+        };
+        (dtos, base)
+    }
+
+    #[tokio::test]
+    async fn item_cover_url_points_at_kobo_thumbnail_route() {
+        let item_id = Uuid::now_v7();
+        let item = crate::test_support::library_item_json(item_id, "Dune");
+        let device_id = Uuid::now_v7();
+
+        let (dtos, base) = list_items(vec![item], Some(device_id)).await;
+        let cover_url = dtos[0].cover_url.as_deref().unwrap();
+        assert!(
+            cover_url.starts_with(&format!(
+                "/kobo/{}/v1/books/{}/thumbnail/",
+                device_id, item_id
+            )),
+            "unexpected cover url {}",
+            cover_url
+        );
+        assert!(!cover_url.contains(&base));
+        assert!(!cover_url.contains("cover.jpg"));
+    }
+
+    #[tokio::test]
+    async fn file_counts_are_carried_through() {
+        let mut with_ebook = crate::test_support::library_item_json(Uuid::now_v7(), "Dune");
+        with_ebook["numFiles"] = 3.into();
+        let mut audio_only = crate::test_support::library_item_json(Uuid::now_v7(), "Emma");
+        audio_only["media"]["ebookFormat"] = serde_json::Value::Null;
+
+        let (dtos, _) = list_items(vec![with_ebook, audio_only], None).await;
+
+        assert_eq!((dtos[0].num_files, dtos[0].ebook_file_count), (3, 1));
+        assert_eq!((dtos[1].num_files, dtos[1].ebook_file_count), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn library_genres_come_from_filter_data() {
+        #[handler]
+        fn filter_data() -> PoemJson<serde_json::Value> {
+            PoemJson(serde_json::json!({
+                "authors": [{"id": "aut_1", "name": "Frank Herbert"}],
+                "genres": ["Fantasy", "Science Fiction"],
+                "tags": ["kobo"],
+                "series": [{"id": "ser_1", "name": "Dune"}],
+                "narrators": [],
+                "languages": ["English"],
+                "publishers": [],
+                "bookCount": 2
+            }))
+        }
+        let library_id = Uuid::now_v7();
+        let base = crate::test_support::serve(Route::new().at(
+            format!("/api/libraries/{}/filterdata", library_id),
+            get(filter_data),
+        ))
+        .await;
+        let client = AbsClient::new(base).unwrap();
+
+        let LibraryFilterDataResponseDto::Ok(Json(data)) = LibraryService::new(&client)
+            .library_filter_data(&library_id, &"key".into())
+            .await
+        else {
+            panic!("expected filter data");
+        };
+        assert_eq!(data.genres, vec!["Fantasy", "Science Fiction"]);
+        assert_eq!(data.tags, vec!["kobo"]);
+        assert_eq!(data.series[0].name, "Dune");
+        assert_eq!(data.authors[0].id, "aut_1");
+    }
+
+    #[tokio::test]
+    async fn library_filter_data_errors_are_bad_gateway() {
+        let client = AbsClient::new("http://127.0.0.1:1").unwrap();
+        let res = LibraryService::new(&client)
+            .library_filter_data(&Uuid::now_v7(), &"key".into())
+            .await;
+        assert!(matches!(res, LibraryFilterDataResponseDto::BadGateway(_)));
+    }
+
+    #[test]
+    fn libraries_sorted_by_display_order() {
+        let libs: Vec<Library> = serde_json::from_str(
+            r#"[
+                { "id": "22809dbe-3137-4879-831e-d64a6f29b005", "name": "Unordered", "folders": [], "displayOrder": null },
+                { "id": "b8df8f4c-5f93-4a10-812b-84ec4cee4389", "name": "Second", "folders": [], "displayOrder": 2 },
+                { "id": "33ed2665-4521-4a70-93f1-f49b29e39bfe", "name": "First", "folders": [], "displayOrder": 1 }
+            ]"#,
+        )
+        .unwrap();
+
+        let names: Vec<String> = library_dtos(libs).into_iter().map(|l| l.name).collect();
+        assert_eq!(names, vec!["First", "Second", "Unordered"]);
+    }
 }
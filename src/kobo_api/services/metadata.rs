@@ -1,38 +1,43 @@
-use entities::*;
+use poem::http::HeaderMap;
 use poem_openapi::payload::Json;
-use sea_orm::{ConnectionTrait, EntityOrSelect, EntityTrait};
 use uuid::Uuid;
 
 use crate::{
     AbsKoboResult,
     abs_client::AbsClient,
-    kobo_api::models::{ErrorDto, MetadataResponseDto},
+    config::Config,
+    kobo_api::{
+        models::{BookFormatDto, BookMetadata, ErrorDto, MetadataResponseDto},
+        services::{devices::DeviceService, sync::SyncService},
+    },
 };
 
 pub struct MetadataService<'a> {
     pub client: &'a AbsClient,
+    pub config: &'a Config,
     pub db: &'a sea_orm::DatabaseConnection,
 }
 
 impl<'a> MetadataService<'a> {
-    pub fn new(client: &'a AbsClient, db: &'a sea_orm::DatabaseConnection) -> Self {
-        Self { client, db }
+    pub fn new(
+        client: &'a AbsClient,
+        config: &'a Config,
+        db: &'a sea_orm::DatabaseConnection,
+    ) -> Self {
+        Self { client, config, db }
     }
 
     async fn get_api_key(&self, device_id: Uuid) -> AbsKoboResult<Option<String>> {
-        if let Some((_, Some(user))) = devices::Entity::find_by_id(device_id)
-            .select_also(user::Entity)
-            .one(self.db)
-            .await?
-        {
-            Ok(Some(user.abs_api_key))
-        } else {
-            Ok(None)
-        }
+        DeviceService::new(self.db).abs_api_key(device_id).await
     }
 
-    #[tracing::instrument(level = "debug", skip(self, book_uuid))]
-    pub async fn get_metadata(&self, book_uuid: Uuid, auth_token: Uuid) -> MetadataResponseDto {
+    #[tracing::instrument(level = "debug", skip(self, book_uuid, headers))]
+    pub async fn get_metadata(
+        &self,
+        book_uuid: Uuid,
+        auth_token: Uuid,
+        headers: &HeaderMap,
+    ) -> MetadataResponseDto {
         let api_key = match self.get_api_key(auth_token).await {
             Ok(Some(api_key)) => api_key,
             _ => {
@@ -41,7 +46,13 @@ impl<'a> MetadataService<'a> {
                 }));
             }
         };
-        let item = match self.client.get_item(book_uuid, false, None, &api_key).await {
+        let include = self.config.metadata_include.as_deref();
+        // Expanded responses carry the nested authors, series and files the mapping relies on
+        let item = match self
+            .client
+            .get_library_item(book_uuid, include, &api_key)
+            .await
+        {
             Ok(item) => item,
             Err(_) => {
                 return MetadataResponseDto::NotFound(Json(ErrorDto {
@@ -50,6 +61,24 @@ impl<'a> MetadataService<'a> {
             }
         };
 
-        MetadataResponseDto::Ok(Json(todo!()))
+        let download_urls = vec![SyncService::get_download_url_for_book(
+            &SyncService::device_base_url(self.config, headers),
+            auth_token,
+            &book_uuid,
+            &BookFormatDto::Kepub,
+        )];
+        match BookMetadata::try_from_library_item(
+            item,
+            download_urls,
+            self.config.author_name_order,
+        ) {
+            Ok(metadata) => MetadataResponseDto::Ok(Json(metadata)),
+            Err(e) => {
+                tracing::error!(error = %e, item_id = %book_uuid, "Failed to map item metadata");
+                MetadataResponseDto::NotFound(Json(ErrorDto {
+                    message: format!("Item metadata unavailable: {}", e),
+                }))
+            }
+        }
     }
 }
@@ -1,5 +1,9 @@
+pub mod devices;
+pub mod download;
 pub mod health;
 pub mod library;
 pub mod metadata;
 pub mod reading;
+pub mod setup;
 pub mod sync;
+pub mod users;
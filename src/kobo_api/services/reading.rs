@@ -1,63 +1,729 @@
-use poem_openapi::payload::Json;
+use chrono::{DateTime, Utc};
+use entities::reading_state;
+use futures_util::{StreamExt, stream};
+use poem_openapi::{payload::Json, types::ToJSON};
+use sea_orm::{
+	ActiveValue::Set, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+	QueryOrder,
+};
 use serde_json::json;
+use uuid::Uuid;
 
 use crate::{
-	abs_client::AbsClient,
-	kobo_api::models::{ErrorDto, ReadingStateGetResponseDto, ReadingStatePutResponseDto},
+	AbsKoboResult,
+	abs_client::{AbsClient, LocalSession, MediaProgress, MediaProgressUpdate, upstream_status},
+	db::retry_on_busy,
+	kobo_api::{
+		models::{
+			BookmarkLocation, ErrorDto, KoboSyncedReadingState, ReadingStateGetResponseDto, ReadingStatePutResponseDto, ReadingStatesResponseDto,
+		},
+		services::devices::DeviceService,
+	},
 };
 
 pub struct ReadingService<'a> {
 	pub client: &'a AbsClient,
+	pub db: &'a DatabaseConnection,
+	pub push_reading_time: bool,
 }
 
 impl<'a> ReadingService<'a> {
-	pub fn new(client: &'a AbsClient) -> Self {
-		Self { client }
+	pub fn new(client: &'a AbsClient, db: &'a DatabaseConnection) -> Self {
+		Self { client, db, push_reading_time: false }
+	}
+
+	/// Record reading time reported in state updates as ABS sessions
+	pub fn with_push_reading_time(mut self, push_reading_time: bool) -> Self {
+		self.push_reading_time = push_reading_time;
+		self
+	}
+
+	async fn find_state(&self, device_id: Uuid, book_uuid: Uuid) -> AbsKoboResult<Option<reading_state::Model>> {
+		Ok(reading_state::Entity::find()
+			.filter(reading_state::Column::DeviceId.eq(device_id))
+			.filter(reading_state::Column::AbsItemId.eq(book_uuid.to_string()))
+			.one(self.db)
+			.await?)
+	}
+
+	/// Replace the stored reading state of a book on a device, returning the replaced state.
+	/// `last_modified` is when the state was last changed on whichever side it came from, used
+	/// for last-writer-wins against ABS.
+	async fn save_state(&self, device_id: Uuid, book_uuid: Uuid, mut state: serde_json::Value, last_modified: DateTime<Utc>) -> AbsKoboResult<Option<serde_json::Value>> {
+		let previous = self.find_state(device_id, book_uuid).await?;
+		let priority = priority_timestamp(&state, previous.as_ref().map(|p| &p.state), last_modified);
+		state["PriorityTimestamp"] = json!(priority.to_rfc3339());
+
+		retry_on_busy(|| {
+			reading_state::Entity::delete_many()
+				.filter(reading_state::Column::DeviceId.eq(device_id))
+				.filter(reading_state::Column::AbsItemId.eq(book_uuid.to_string()))
+				.exec(self.db)
+		})
+		.await?;
+
+		retry_on_busy(|| {
+			reading_state::Entity::insert(reading_state::ActiveModel {
+				id: Set(Uuid::now_v7()),
+				device_id: Set(device_id),
+				abs_item_id: Set(book_uuid.to_string()),
+				state: Set(state.clone()),
+				last_modified: Set(last_modified),
+			})
+			.exec(self.db)
+		})
+		.await?;
+		Ok(previous.map(|p| p.state))
+	}
+
+	/// The user's ABS progress for a book, if ABS has any
+	async fn abs_progress(&self, book_uuid: Uuid, api_key: &String) -> Option<MediaProgress> {
+		match self.client.get_media_progress(&book_uuid, api_key).await {
+			Ok(progress) => progress,
+			Err(e) => {
+				tracing::warn!(error = %e, %book_uuid, "failed to fetch ABS progress");
+				None
+			}
+		}
+	}
+
+	/// Reading state rebuilt from the user's ABS progress, only when ABS was updated after
+	/// `known`, the last change the device already has
+	async fn newer_abs_state(
+		&self,
+		device_id: Uuid,
+		book_uuid: Uuid,
+		known: Option<DateTime<Utc>>,
+	) -> Option<(serde_json::Value, DateTime<Utc>)> {
+		let api_key = DeviceService::new(self.db).abs_api_key(device_id).await.ok()??;
+		let progress = self.abs_progress(book_uuid, &api_key).await?;
+		let updated = progress.last_updated();
+		if known.is_some_and(|known| updated <= known) {
+			return None;
+		}
+		let state = KoboSyncedReadingState::from_abs_progress(book_uuid, &progress).to_json()?;
+		Some((state, updated))
 	}
 
 	#[tracing::instrument(level = "debug", skip(self, book_uuid))]
-	pub async fn get_state(&self, book_uuid: &str) -> ReadingStateGetResponseDto {
-		if uuid::Uuid::parse_str(book_uuid).is_err() {
+	pub async fn get_state(&self, device_id: Uuid, book_uuid: &str) -> ReadingStateGetResponseDto {
+		let Ok(book_uuid) = Uuid::parse_str(book_uuid) else {
 			return ReadingStateGetResponseDto::NotFound(Json(ErrorDto { message: "Invalid book UUID".into() }));
+		};
+		match self.find_state(device_id, book_uuid).await {
+			Ok(stored) => {
+				let known = stored.as_ref().map(|s| s.last_modified);
+				let state = match self.newer_abs_state(device_id, book_uuid, known).await {
+					Some((state, updated)) => {
+						// The device now gets the ABS position, so that's what it knows from here on
+						if let Err(e) = self.save_state(device_id, book_uuid, state.clone(), updated).await {
+							tracing::warn!(error = %e, "failed to store ABS reading state");
+						}
+						state
+					}
+					None => stored.map(|s| s.state).unwrap_or_else(|| json!({ "EntitlementId": book_uuid })),
+				};
+				ReadingStateGetResponseDto::Ok(Json(vec![state]))
+			}
+			Err(e) => {
+				tracing::error!(error = %e, "failed to load reading state");
+				ReadingStateGetResponseDto::InternalServerError(Json(ErrorDto { message: format!("Database error: {}", e) }))
+			}
+		}
+	}
+
+	/// All stored reading states of a device, most recently modified first
+	#[tracing::instrument(level = "debug", skip(self))]
+	pub async fn list_states(&self, device_id: Uuid, limit: u64, page: u64) -> ReadingStatesResponseDto {
+		match DeviceService::new(self.db).exists(device_id).await {
+			Ok(true) => {}
+			Ok(false) => {
+				return ReadingStatesResponseDto::Unauthorized(Json(ErrorDto { message: "Invalid auth token".into() }));
+			}
+			Err(e) => {
+				return ReadingStatesResponseDto::InternalServerError(Json(ErrorDto { message: format!("Database error: {}", e) }));
+			}
+		}
+
+		let res = reading_state::Entity::find()
+			.filter(reading_state::Column::DeviceId.eq(device_id))
+			.order_by_desc(reading_state::Column::LastModified)
+			.paginate(self.db, limit.max(1))
+			.fetch_page(page)
+			.await;
+		match res {
+			Ok(states) => ReadingStatesResponseDto::Ok(Json(states.into_iter().map(|s| s.state).collect())),
+			Err(e) => {
+				tracing::error!(error = %e, "failed to list reading states");
+				ReadingStatesResponseDto::InternalServerError(Json(ErrorDto { message: format!("Database error: {}", e) }))
+			}
+		}
+	}
+
+	/// Forward a reading state to ABS unless ABS progress changed after `last_modified`. The
+	/// state is already stored locally, so a failure only affects the result reported for this
+	/// entry.
+	async fn push_progress(&self, book_uuid: Uuid, state: &serde_json::Value, last_modified: DateTime<Utc>, api_key: Option<&String>) -> &'static str {
+		let Some(api_key) = api_key else {
+			return "Error";
+		};
+		if let Some(progress) = self.abs_progress(book_uuid, api_key).await
+			&& progress.last_updated() >= last_modified
+		{
+			tracing::debug!(%book_uuid, abs_updated = %progress.last_updated(), %last_modified, "ABS progress is newer, not pushing");
+			return "Ignored";
+		}
+		let update = abs_progress_update(state);
+		match self.client.update_media_progress(&book_uuid, &update, api_key).await {
+			Ok(()) => "Success",
+			Err(e) => {
+				tracing::warn!(error = %e, %book_uuid, "failed to push progress to ABS");
+				"Error"
+			}
+		}
+	}
+
+	/// Record `minutes` of reading ending at `ended_at` as an ABS session when enabled by
+	/// `PUSH_READING_TIME`
+	async fn push_reading_time(&self, book_uuid: Uuid, minutes: f64, ended_at: DateTime<Utc>, api_key: Option<&String>) -> &'static str {
+		if !self.push_reading_time || minutes <= 0.0 {
+			return "Ignored";
+		}
+		let Some(api_key) = api_key else {
+			return "Error";
+		};
+		let seconds = minutes * 60.0;
+		let started_at = ended_at - chrono::Duration::milliseconds((seconds * 1000.0) as i64);
+		let session = LocalSession {
+			id: Uuid::new_v4().to_string(),
+			library_item_id: book_uuid,
+			media_type: "book".into(),
+			time_listening: seconds,
+			started_at: started_at.timestamp_millis(),
+			updated_at: ended_at.timestamp_millis(),
+			date: ended_at.format("%Y-%m-%d").to_string(),
+			day_of_week: ended_at.format("%A").to_string(),
+		};
+		match self.client.sync_local_session(&session, api_key).await {
+			Ok(()) => "Success",
+			// Older ABS versions have no local sessions, the time just isn't recorded there
+			Err(e) if upstream_status(&e) == Some(reqwest::StatusCode::NOT_FOUND) => {
+				tracing::debug!(%book_uuid, "ABS does not support local sessions, reading time not pushed");
+				"Ignored"
+			}
+			Err(e) => {
+				tracing::warn!(error = %e, %book_uuid, "failed to push reading time to ABS");
+				"Error"
+			}
 		}
-		let state = json!({
-			"EntitlementId": book_uuid,
-		});
-		ReadingStateGetResponseDto::Ok(Json(vec![state]))
 	}
 
 	#[tracing::instrument(level = "debug", skip(self, book_uuid, payload))]
-	pub async fn update_state(&self, book_uuid: &str, payload: serde_json::Value) -> ReadingStatePutResponseDto {
-		if uuid::Uuid::parse_str(book_uuid).is_err() {
+	pub async fn update_state(&self, device_id: Uuid, book_uuid: &str, payload: serde_json::Value) -> ReadingStatePutResponseDto {
+		let Ok(book_uuid) = Uuid::parse_str(book_uuid) else {
 			return ReadingStatePutResponseDto::BadRequest(Json(ErrorDto { message: "Invalid book UUID".into() }));
+		};
+		let states = match validate_reading_states(&payload) {
+			Ok(states) => states,
+			Err(message) => {
+				tracing::debug!(%message, "rejected reading state");
+				return ReadingStatePutResponseDto::BadRequest(Json(ErrorDto { message }));
+			}
+		};
+
+		let devices = DeviceService::new(self.db);
+		match devices.exists(device_id).await {
+			Ok(true) => {}
+			Ok(false) => {
+				return ReadingStatePutResponseDto::Unauthorized(Json(ErrorDto { message: "Invalid auth token".into() }));
+			}
+			Err(e) => {
+				return ReadingStatePutResponseDto::InternalServerError(Json(ErrorDto { message: format!("Database error: {}", e) }));
+			}
 		}
-		// Basic validation for required fields
-		let first = payload
-			.get("ReadingStates")
-			.and_then(|v| v.as_array())
-			.and_then(|arr| arr.get(0));
-		let cb = first.and_then(|st| st.get("CurrentBookmark"));
-		let has_location = cb.and_then(|c| c.get("Location")).is_some();
-		let has_cspp = cb
-			.and_then(|c| c.get("ContentSourceProgressPercent"))
-			.and_then(|v| v.as_f64().or_else(|| v.as_i64().map(|i| i as f64)))
-			.is_some();
-		if !has_location || !has_cspp {
-			return ReadingStatePutResponseDto::BadRequest(Json(ErrorDto { message: "Missing Location or ContentSourceProgressPercent".into() }));
+
+		// Entries without an EntitlementId belong to the book in the path
+		let states: Vec<(Uuid, serde_json::Value, DateTime<Utc>)> = states
+			.iter()
+			.map(|state| {
+				let book = state["EntitlementId"].as_str().and_then(|id| Uuid::parse_str(id).ok()).unwrap_or(book_uuid);
+				let mut state = state.clone();
+				state["EntitlementId"] = json!(book);
+				let last_modified = state_last_modified(&state);
+				(book, state, last_modified)
+			})
+			.collect();
+		// Devices report the total time spent in a book, so the time read since the last update is
+		// the growth over the stored state
+		let mut reading_minutes = Vec::with_capacity(states.len());
+		for (book, state, last_modified) in &states {
+			match self.save_state(device_id, *book, state.clone(), *last_modified).await {
+				Ok(previous) => reading_minutes.push(spent_reading_minutes(state) - previous.as_ref().map_or(0.0, spent_reading_minutes)),
+				Err(e) => {
+					tracing::error!(error = %e, "failed to save reading state");
+					return ReadingStatePutResponseDto::InternalServerError(Json(ErrorDto { message: format!("Database error: {}", e) }));
+				}
+			}
 		}
+
+		let api_key = devices.abs_api_key(device_id).await.unwrap_or_else(|e| {
+			tracing::warn!(error = %e, "failed to look up ABS key, progress not pushed");
+			None
+		});
+		let api_key = api_key.as_ref();
+		let update_results: Vec<serde_json::Value> = stream::iter(states.into_iter().zip(reading_minutes))
+			.map(|((book, state, last_modified), minutes)| async move {
+				// The session goes first: ABS takes progress from a session newer than its stored
+				// progress, and the progress push below then replaces it with the actual position
+				let statistics = self.push_reading_time(book, minutes, last_modified, api_key).await;
+				let result = self.push_progress(book, &state, last_modified, api_key).await;
+				json!({
+					"EntitlementId": book,
+					"CurrentBookmarkResult": { "Result": result },
+					"StatisticsResult": { "Result": statistics },
+					"StatusInfoResult": { "Result": result }
+				})
+			})
+			.buffer_unordered(PROGRESS_PUSH_CONCURRENCY)
+			.collect()
+			.await;
+
 		let result = json!({
 			"RequestResult": "Success",
-			"UpdateResults": [
-				{
-					"EntitlementId": book_uuid,
-					"CurrentBookmarkResult": { "Result": "Success" },
-					"StatisticsResult": { "Result": "Ignored" },
-					"StatusInfoResult": { "Result": "Success" }
-				}
-			]
+			"UpdateResults": update_results
 		});
-		let _ = payload; // unused for now
 		ReadingStatePutResponseDto::Ok(Json(result))
 	}
 }
 
+/// Max progress updates in flight to ABS for one reading-state request
+const PROGRESS_PUSH_CONCURRENCY: usize = 4;
+
+const READING_STATUSES: &[&str] = &["ReadyToRead", "Reading", "Finished"];
+
+/// Check every `ReadingStates` entry against the shape Kobo devices send and return the entries.
+/// Errors name the offending entry and field.
+fn validate_reading_states(payload: &serde_json::Value) -> Result<&Vec<serde_json::Value>, String> {
+	let states = payload
+		.get("ReadingStates")
+		.and_then(|v| v.as_array())
+		.filter(|states| !states.is_empty())
+		.ok_or("ReadingStates must be a non-empty array")?;
+
+	for (i, state) in states.iter().enumerate() {
+		let field = |path: &str| format!("ReadingStates[{}].{}", i, path);
+
+		if let Some(id) = state.get("EntitlementId")
+			&& id.as_str().is_none_or(|id| Uuid::parse_str(id).is_err())
+		{
+			return Err(format!("{} must be a UUID, got {}", field("EntitlementId"), id));
+		}
+
+		let bookmark = state.get("CurrentBookmark");
+		let Some(location) = bookmark.and_then(|b| b.get("Location")) else {
+			return Err(format!("{} is required", field("CurrentBookmark.Location")));
+		};
+		// Firmware occasionally sends junk locations that would make the device jump around once
+		// synced back
+		if let Some(location_type) = location.get("Type").and_then(|t| t.as_str()) {
+			let value = location.get("Value").and_then(|v| v.as_str()).unwrap_or_default();
+			if let Err(e) = BookmarkLocation::parse(location_type, value) {
+				return Err(format!("{} {}", field("CurrentBookmark.Location.Value"), e));
+			}
+		}
+		match bookmark.and_then(|b| b.get("ContentSourceProgressPercent")) {
+			None => return Err(format!("{} is required", field("CurrentBookmark.ContentSourceProgressPercent"))),
+			Some(v) => check_percent(v, &field("CurrentBookmark.ContentSourceProgressPercent"))?,
+		}
+		if let Some(v) = bookmark.and_then(|b| b.get("ProgressPercent")) {
+			check_percent(v, &field("CurrentBookmark.ProgressPercent"))?;
+		}
+
+		if let Some(status) = state.get("StatusInfo").and_then(|s| s.get("Status"))
+			&& !status.as_str().is_some_and(|s| READING_STATUSES.contains(&s))
+		{
+			return Err(format!("{} must be one of {}, got {}", field("StatusInfo.Status"), READING_STATUSES.join("/"), status));
+		}
+
+		for path in ["LastModified", "PriorityTimestamp", "StatusInfo.LastModified", "CurrentBookmark.LastModified", "Statistics.LastModified"] {
+			let value = path.split('.').try_fold(state, |v, key| v.get(key));
+			if let Some(value) = value.filter(|v| !v.is_null()) {
+				let valid = value.as_str().is_some_and(|s| chrono::DateTime::parse_from_rfc3339(s).is_ok());
+				if !valid {
+					return Err(format!("{} must be an RFC 3339 timestamp, got {}", field(path), value));
+				}
+			}
+		}
+	}
+	Ok(states)
+}
+
+/// When the device last changed a reading state, falling back to now for states without
+/// timestamps
+fn state_last_modified(state: &serde_json::Value) -> DateTime<Utc> {
+	[&state["LastModified"], &state["CurrentBookmark"]["LastModified"]]
+		.into_iter()
+		.find_map(|v| v.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok()))
+		.map(|t| t.with_timezone(&Utc))
+		.unwrap_or_else(Utc::now)
+}
+
+/// `PriorityTimestamp` for a state: when its bookmark last moved, so devices list recently read
+/// books first. Never earlier than the book's previous priority, as devices expect it to only grow.
+fn priority_timestamp(state: &serde_json::Value, previous: Option<&serde_json::Value>, last_modified: DateTime<Utc>) -> DateTime<Utc> {
+	let parse = |v: &serde_json::Value| v.as_str().and_then(|s| DateTime::parse_from_rfc3339(s).ok()).map(|t| t.with_timezone(&Utc));
+	let bookmarked = parse(&state["CurrentBookmark"]["LastModified"]).unwrap_or(last_modified);
+	match previous.and_then(|p| parse(&p["PriorityTimestamp"])) {
+		Some(previous) => previous.max(bookmarked),
+		None => bookmarked,
+	}
+}
+
+/// ABS progress for a Kobo reading state. The bookmark location is only forwarded when it is an
+/// EPUB CFI (`Type: CFI` or an `epubcfi(...)` value), as ABS readers can't resolve Kobo spans.
+fn abs_progress_update(state: &serde_json::Value) -> MediaProgressUpdate {
+	let bookmark = &state["CurrentBookmark"];
+	let progress = bookmark["ContentSourceProgressPercent"].as_f64().unwrap_or(0.0) / 100.0;
+	let location = &bookmark["Location"];
+	let ebook_location = location["Value"]
+		.as_str()
+		.filter(|value| location["Type"].as_str().is_some_and(|t| t.eq_ignore_ascii_case("cfi")) || value.starts_with("epubcfi("))
+		.map(str::to_string);
+	if let Some(value) = &ebook_location {
+		tracing::debug!(location = %value, source = ?location["Source"].as_str(), "forwarding CFI location");
+	}
+	MediaProgressUpdate {
+		progress,
+		is_finished: state["StatusInfo"]["Status"].as_str().map(|s| s == "Finished"),
+		ebook_location,
+		ebook_progress: Some(progress),
+	}
+}
+
+/// Total minutes the device spent in the book, 0 when not reported
+fn spent_reading_minutes(state: &serde_json::Value) -> f64 {
+	state["Statistics"]["SpentReadingMinutes"].as_f64().unwrap_or(0.0).max(0.0)
+}
+
+fn check_percent(value: &serde_json::Value, field: &str) -> Result<(), String> {
+	match value.as_f64() {
+		Some(percent) if (0.0..=100.0).contains(&percent) => Ok(()),
+		Some(percent) => Err(format!("{} must be between 0 and 100, got {}", field, percent)),
+		None => Err(format!("{} must be a number, got {}", field, value)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn reading_state_payload(percent: f64) -> serde_json::Value {
+		json!({
+			"ReadingStates": [{
+				"CurrentBookmark": {
+					"ContentSourceProgressPercent": percent,
+					"Location": { "Value": "epubcfi(/6/4)", "Type": "CFI", "Source": "OEBPS/ch1.xhtml" }
+				}
+			}]
+		})
+	}
+
+	#[test]
+	fn out_of_range_percent_is_rejected() {
+		let err = validate_reading_states(&reading_state_payload(140.0)).unwrap_err();
+		assert_eq!(err, "ReadingStates[0].CurrentBookmark.ContentSourceProgressPercent must be between 0 and 100, got 140");
+	}
+
+	#[test]
+	fn invalid_status_is_rejected() {
+		let mut payload = reading_state_payload(50.0);
+		payload["ReadingStates"][0]["StatusInfo"] = json!({ "Status": "Skimming", "LastModified": "2025-08-20T12:00:00Z" });
+		let err = validate_reading_states(&payload).unwrap_err();
+		assert_eq!(err, r#"ReadingStates[0].StatusInfo.Status must be one of ReadyToRead/Reading/Finished, got "Skimming""#);
+	}
+
+	#[test]
+	fn malformed_location_is_rejected() {
+		let mut payload = reading_state_payload(50.0);
+		payload["ReadingStates"][0]["CurrentBookmark"]["Location"] = json!({ "Value": "", "Type": "KoboSpan", "Source": "OEBPS/ch1.xhtml" });
+		let err = validate_reading_states(&payload).unwrap_err();
+		assert_eq!(err, r#"ReadingStates[0].CurrentBookmark.Location.Value is not a Kobo span like kobo.1.1, got """#);
+
+		payload["ReadingStates"][0]["CurrentBookmark"]["Location"] = json!({ "Value": "garbage", "Type": "CFI", "Source": "OEBPS/ch1.xhtml" });
+		let err = validate_reading_states(&payload).unwrap_err();
+		assert_eq!(err, r#"ReadingStates[0].CurrentBookmark.Location.Value is not an EPUB CFI like epubcfi(/6/4), got "garbage""#);
+
+		// The payload's own `epubcfi(/6/4)` location is fine
+		assert!(validate_reading_states(&reading_state_payload(50.0)).is_ok());
+	}
+
+	#[test]
+	fn invalid_timestamp_is_rejected() {
+		let mut payload = reading_state_payload(50.0);
+		payload["ReadingStates"][0]["LastModified"] = json!("yesterday");
+		let err = validate_reading_states(&payload).unwrap_err();
+		assert!(err.starts_with("ReadingStates[0].LastModified must be an RFC 3339 timestamp"), "{}", err);
+	}
+
+	#[tokio::test]
+	async fn list_states_returns_all_states_of_device() {
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new("http://localhost").unwrap();
+		let service = ReadingService::new(&client, &db);
+		let (book_a, book_b) = (Uuid::now_v7(), Uuid::now_v7());
+
+		for (book, percent) in [(book_a, 10.0), (book_b, 55.0)] {
+			let res = service.update_state(device_id, &book.to_string(), reading_state_payload(percent)).await;
+			assert!(matches!(res, ReadingStatePutResponseDto::Ok(_)));
+		}
+
+		let ReadingStatesResponseDto::Ok(Json(states)) = service.list_states(device_id, 100, 0).await else {
+			panic!("expected reading states");
+		};
+		assert_eq!(states.len(), 2);
+		let mut ids: Vec<&str> = states.iter().filter_map(|s| s["EntitlementId"].as_str()).collect();
+		ids.sort();
+		let mut expected = vec![book_a.to_string(), book_b.to_string()];
+		expected.sort();
+		assert_eq!(ids, expected);
+	}
+
+	#[tokio::test]
+	async fn newer_bookmark_moves_priority_forward() {
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new("http://127.0.0.1:1").unwrap();
+		let service = ReadingService::new(&client, &db);
+		let book = Uuid::now_v7();
+		let bookmarked_at = |at: &str| {
+			let mut payload = reading_state_payload(20.0);
+			payload["ReadingStates"][0]["CurrentBookmark"]["LastModified"] = json!(at);
+			payload
+		};
+		let priority = || async {
+			let stored = service.find_state(device_id, book).await.unwrap().unwrap();
+			stored.state["PriorityTimestamp"].as_str().unwrap().to_string()
+		};
+
+		service.update_state(device_id, &book.to_string(), bookmarked_at("2025-08-20T12:00:00Z")).await;
+		let first = priority().await;
+		service.update_state(device_id, &book.to_string(), bookmarked_at("2025-08-21T08:30:00Z")).await;
+		let second = priority().await;
+		assert_eq!(first, "2025-08-20T12:00:00+00:00");
+		assert_eq!(second, "2025-08-21T08:30:00+00:00");
+
+		// A late-arriving older bookmark doesn't move the book back
+		service.update_state(device_id, &book.to_string(), bookmarked_at("2025-08-19T07:00:00Z")).await;
+		assert_eq!(priority().await, second);
+	}
+
+	#[tokio::test]
+	async fn progress_push_includes_cfi_location() {
+		use std::sync::{Arc, Mutex};
+
+		use poem::{
+			EndpointExt, Route, handler, patch,
+			web::{Data, Json as PoemJson, Path},
+		};
+
+		type Pushed = Arc<Mutex<Vec<(String, serde_json::Value)>>>;
+
+		#[handler]
+		fn progress(Path(id): Path<String>, PoemJson(body): PoemJson<serde_json::Value>, Data(pushed): Data<&Pushed>) {
+			pushed.lock().unwrap().push((id, body));
+		}
+
+		let pushed = Pushed::default();
+		let base = crate::test_support::serve(Route::new().at("/api/me/progress/:id", patch(progress)).data(pushed.clone())).await;
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new(base).unwrap();
+		let book = Uuid::now_v7();
+
+		let res = ReadingService::new(&client, &db).update_state(device_id, &book.to_string(), reading_state_payload(25.0)).await;
+		assert!(matches!(res, ReadingStatePutResponseDto::Ok(_)));
+
+		let pushed = pushed.lock().unwrap();
+		assert_eq!(pushed.len(), 1);
+		assert_eq!(pushed[0].0, book.to_string());
+		assert_eq!(pushed[0].1, json!({ "progress": 0.25, "ebookLocation": "epubcfi(/6/4)", "ebookProgress": 0.25 }));
+	}
+
+	type Pushed = std::sync::Arc<std::sync::Mutex<Vec<serde_json::Value>>>;
+
+	/// ABS serving progress last updated at `abs_updated` and recording progress pushes
+	async fn serve_progress(abs_updated: DateTime<Utc>) -> (String, Pushed) {
+		use poem::{
+			EndpointExt, Route, handler,
+			web::{Data, Json as PoemJson},
+		};
+
+		#[handler]
+		fn progress(Data(updated): Data<&DateTime<Utc>>) -> PoemJson<serde_json::Value> {
+			PoemJson(json!({
+				"progress": 0.8,
+				"ebookLocation": "epubcfi(/6/8)",
+				"ebookProgress": 0.8,
+				"isFinished": false,
+				"lastUpdate": updated.timestamp_millis(),
+				"startedAt": updated.timestamp_millis()
+			}))
+		}
+
+		#[handler]
+		fn push(PoemJson(body): PoemJson<serde_json::Value>, Data(pushed): Data<&Pushed>) {
+			pushed.lock().unwrap().push(body);
+		}
+
+		let pushed = Pushed::default();
+		let base = crate::test_support::serve(
+			Route::new()
+				.at("/api/me/progress/:id", poem::get(progress).patch(push))
+				.data(abs_updated)
+				.data(pushed.clone()),
+		)
+		.await;
+		(base, pushed)
+	}
+
+	fn payload_modified_at(percent: f64, last_modified: DateTime<Utc>) -> serde_json::Value {
+		let mut payload = reading_state_payload(percent);
+		payload["ReadingStates"][0]["LastModified"] = json!(last_modified.to_rfc3339());
+		payload
+	}
+
+	#[tokio::test]
+	async fn newer_device_state_is_pushed() {
+		let abs_updated = Utc::now() - chrono::Duration::days(1);
+		let (base, pushed) = serve_progress(abs_updated).await;
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new(base).unwrap();
+		let book = Uuid::now_v7();
+
+		let payload = payload_modified_at(30.0, Utc::now());
+		let ReadingStatePutResponseDto::Ok(Json(result)) = ReadingService::new(&client, &db).update_state(device_id, &book.to_string(), payload).await else {
+			panic!("expected a successful update");
+		};
+		assert_eq!(result["UpdateResults"][0]["CurrentBookmarkResult"]["Result"], "Success");
+		assert_eq!(pushed.lock().unwrap().len(), 1);
+	}
+
+	#[tokio::test]
+	async fn newer_abs_progress_wins() {
+		let abs_updated = Utc::now() - chrono::Duration::hours(1);
+		let (base, pushed) = serve_progress(abs_updated).await;
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new(base).unwrap();
+		let service = ReadingService::new(&client, &db);
+		let book = Uuid::now_v7();
+
+		// The device reports a position older than the one read in ABS since
+		let payload = payload_modified_at(30.0, abs_updated - chrono::Duration::days(1));
+		let ReadingStatePutResponseDto::Ok(Json(result)) = service.update_state(device_id, &book.to_string(), payload).await else {
+			panic!("expected a successful update");
+		};
+		assert_eq!(result["UpdateResults"][0]["CurrentBookmarkResult"]["Result"], "Ignored");
+		assert!(pushed.lock().unwrap().is_empty());
+
+		// ...and gets the ABS position back instead of its own
+		let ReadingStateGetResponseDto::Ok(Json(states)) = service.get_state(device_id, &book.to_string()).await else {
+			panic!("expected a reading state");
+		};
+		assert_eq!(states[0]["CurrentBookmark"]["ContentSourceProgressPercent"], json!(80.0));
+	}
+
+	#[tokio::test]
+	async fn failed_push_is_reported_per_entry() {
+		use poem::{Response, Route, handler, http::StatusCode, patch, web::Path};
+
+		const FAILING: &str = "0199ed4a-0000-7000-8000-000000000002";
+
+		#[handler]
+		fn progress(Path(id): Path<String>) -> Response {
+			let status = if id == FAILING { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+			Response::builder().status(status).finish()
+		}
+
+		let base = crate::test_support::serve(Route::new().at("/api/me/progress/:id", patch(progress))).await;
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new(base).unwrap();
+		let books = ["0199ed4a-0000-7000-8000-000000000001", FAILING, "0199ed4a-0000-7000-8000-000000000003"];
+		let mut payload = reading_state_payload(40.0);
+		let state = payload["ReadingStates"][0].clone();
+		payload["ReadingStates"] = books
+			.iter()
+			.map(|id| {
+				let mut state = state.clone();
+				state["EntitlementId"] = json!(id);
+				state
+			})
+			.collect();
+
+		let ReadingStatePutResponseDto::Ok(Json(result)) = ReadingService::new(&client, &db).update_state(device_id, books[0], payload).await else {
+			panic!("expected a successful update");
+		};
+		let results = result["UpdateResults"].as_array().unwrap();
+		assert_eq!(results.len(), 3);
+		for id in books {
+			let entry = results.iter().find(|r| r["EntitlementId"] == id).unwrap();
+			let expected = if id == FAILING { "Error" } else { "Success" };
+			assert_eq!(entry["CurrentBookmarkResult"]["Result"], expected, "{}", id);
+		}
+	}
+
+	#[tokio::test]
+	async fn reading_time_is_pushed_in_seconds_when_enabled() {
+		use std::sync::{Arc, Mutex};
+
+		use poem::{
+			EndpointExt, Route, handler, patch, post,
+			web::{Data, Json as PoemJson},
+		};
+
+		type Sessions = Arc<Mutex<Vec<serde_json::Value>>>;
+
+		#[handler]
+		fn session(PoemJson(body): PoemJson<serde_json::Value>, Data(sessions): Data<&Sessions>) {
+			sessions.lock().unwrap().push(body);
+		}
+
+		#[handler]
+		fn progress() {}
+
+		let sessions = Sessions::default();
+		let base = crate::test_support::serve(
+			Route::new()
+				.at("/api/session/local", post(session))
+				.at("/api/me/progress/:id", patch(progress))
+				.data(sessions.clone()),
+		)
+		.await;
+		let (db, device_id) = crate::test_support::db_with_device().await;
+		let client = AbsClient::new(base).unwrap();
+		let book = Uuid::now_v7();
+		let spent = |minutes: f64| {
+			let mut payload = reading_state_payload(40.0);
+			payload["ReadingStates"][0]["Statistics"] = json!({ "SpentReadingMinutes": minutes });
+			payload
+		};
+
+		// Off by default
+		ReadingService::new(&client, &db).update_state(device_id, &book.to_string(), spent(5.0)).await;
+		assert!(sessions.lock().unwrap().is_empty());
+
+		let service = ReadingService::new(&client, &db).with_push_reading_time(true);
+		let ReadingStatePutResponseDto::Ok(Json(result)) = service.update_state(device_id, &book.to_string(), spent(12.5)).await else {
+			panic!("expected a successful update");
+		};
+		assert_eq!(result["UpdateResults"][0]["StatisticsResult"]["Result"], "Success");
+		{
+			let sessions = sessions.lock().unwrap();
+			assert_eq!(sessions.len(), 1);
+			assert_eq!(sessions[0]["libraryItemId"], json!(book));
+			// Only the 7.5 minutes read since the last update
+			assert_eq!(sessions[0]["timeListening"], json!(450.0));
+		}
+
+		// Nothing was read since, so there is no session to record
+		service.update_state(device_id, &book.to_string(), spent(12.5)).await;
+		assert_eq!(sessions.lock().unwrap().len(), 1);
+	}
+}
@@ -0,0 +1,244 @@
+// Onboarding page that registers a device without using the management API
+
+use poem_openapi::payload::{Html, Json};
+use reqwest::StatusCode;
+
+use crate::{
+    abs_client::{AbsClient, upstream_status},
+    kobo_api::{
+        models::{DeviceRegisterResponseDto, SetupRequestDto, SetupResponseDto},
+        services::{devices::DeviceService, users::UserService},
+    },
+};
+
+pub struct SetupService<'a> {
+    pub client: &'a AbsClient,
+    pub db: &'a sea_orm::DatabaseConnection,
+}
+
+impl<'a> SetupService<'a> {
+    pub fn new(client: &'a AbsClient, db: &'a sea_orm::DatabaseConnection) -> Self {
+        Self { client, db }
+    }
+
+    /// The empty setup form
+    pub fn page() -> SetupResponseDto {
+        SetupResponseDto::Ok(render(None, None))
+    }
+
+    /// Register a device for the ABS user behind the submitted API key or login, creating the
+    /// user on first use. Anyone with valid ABS credentials can do this, like logging into ABS.
+    #[tracing::instrument(level = "debug", skip(self, form))]
+    pub async fn setup(&self, form: SetupRequestDto, base_url: &str) -> SetupResponseDto {
+        let api_key = match self.api_key(form).await {
+            Ok(api_key) => api_key,
+            Err(response) => return response,
+        };
+        let users = UserService::new(self.client, self.db);
+        match users.accessible_libraries(&api_key).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return SetupResponseDto::BadRequest(render(
+                    Some("ABS rejected the API key"),
+                    None,
+                ));
+            }
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), "failed to check ABS API key");
+                return SetupResponseDto::BadGateway(render(
+                    Some(&format!("ABS error: {}", e)),
+                    None,
+                ));
+            }
+        }
+        let user_id = match users.find_or_create(&api_key).await {
+            Ok(user_id) => user_id,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to store setup user");
+                return SetupResponseDto::InternalServerError(render(
+                    Some(&format!("Database error: {}", e)),
+                    None,
+                ));
+            }
+        };
+        match DeviceService::new(self.db)
+            .register(user_id, base_url)
+            .await
+        {
+            DeviceRegisterResponseDto::Created(Json(device)) => {
+                SetupResponseDto::Ok(render(None, Some(&device.api_endpoint)))
+            }
+            DeviceRegisterResponseDto::NotFound(Json(e))
+            | DeviceRegisterResponseDto::InternalServerError(Json(e)) => {
+                SetupResponseDto::InternalServerError(render(Some(&e.message), None))
+            }
+        }
+    }
+
+    /// The submitted API key, or the token of the submitted ABS login
+    async fn api_key(&self, form: SetupRequestDto) -> Result<String, SetupResponseDto> {
+        let non_empty =
+            |v: Option<String>| v.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        if let Some(api_key) = non_empty(form.abs_api_key) {
+            return Ok(api_key);
+        }
+        let (Some(username), Some(password)) = (non_empty(form.username), form.password) else {
+            return Err(SetupResponseDto::BadRequest(render(
+                Some("Enter an ABS API key or your ABS username and password"),
+                None,
+            )));
+        };
+        match self.client.login(&username, &password).await {
+            Ok(token) => Ok(token),
+            Err(e) if upstream_status(&e) == Some(StatusCode::UNAUTHORIZED) => Err(
+                SetupResponseDto::BadRequest(render(Some("Wrong ABS username or password"), None)),
+            ),
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), "failed to log into ABS");
+                Err(SetupResponseDto::BadGateway(render(
+                    Some(&format!("ABS error: {}", e)),
+                    None,
+                )))
+            }
+        }
+    }
+}
+
+/// The setup page with an optional error message above the form, or the registered device's
+/// `api_endpoint` instead of the form
+fn render(error: Option<&str>, api_endpoint: Option<&str>) -> Html<String> {
+    let content = match api_endpoint {
+        Some(api_endpoint) => format!(
+            r#"<p>Your Kobo is registered. Connect it to a computer, open <code>.kobo/Kobo/Kobo eReader.conf</code> and set this line in the <code>[OneStoreServices]</code> section:</p>
+<pre>api_endpoint={}</pre>
+<p>Eject the Kobo and sync it. Keep the URL private, it is all a device needs to read your library.</p>"#,
+            escape_html(api_endpoint)
+        ),
+        None => format!(
+            r#"{}<form method="post">
+<p>Enter an ABS API key or your ABS username and password.</p>
+<label>API key <input name="abs_api_key" autocomplete="off"></label>
+<p>or</p>
+<label>Username <input name="username" autocomplete="username"></label>
+<label>Password <input name="password" type="password" autocomplete="current-password"></label>
+<button type="submit">Register Kobo</button>
+</form>"#,
+            error
+                .map(|e| format!("<p class=\"error\">{}</p>\n", escape_html(e)))
+                .unwrap_or_default()
+        ),
+    };
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Set up a Kobo</title>
+<style>
+body {{ font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }}
+label {{ display: block; margin: 0.5em 0; }}
+input {{ display: block; width: 100%; }}
+pre {{ white-space: pre-wrap; word-break: break-all; background: #eee; padding: 0.5em; }}
+.error {{ color: #b00; }}
+</style>
+</head>
+<body>
+<h1>Set up a Kobo</h1>
+{}
+</body>
+</html>
+"#,
+        content
+    ))
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use entities::user;
+    use poem::{Route, get, handler, http::StatusCode as PoemStatus, post, web::Json as PoemJson};
+    use sea_orm::EntityTrait;
+
+    use super::*;
+
+    #[handler]
+    fn libraries(req: &poem::Request) -> poem::Result<PoemJson<serde_json::Value>> {
+        if req.header("Authorization") != Some("Bearer good-key") {
+            return Err(poem::Error::from_status(PoemStatus::UNAUTHORIZED));
+        }
+        Ok(PoemJson(serde_json::json!({ "libraries": [] })))
+    }
+
+    #[handler]
+    fn login(
+        PoemJson(body): PoemJson<serde_json::Value>,
+    ) -> poem::Result<PoemJson<serde_json::Value>> {
+        if body["username"] != "reader" || body["password"] != "secret" {
+            return Err(poem::Error::from_status(PoemStatus::UNAUTHORIZED));
+        }
+        Ok(PoemJson(
+            serde_json::json!({ "user": { "token": "good-key" } }),
+        ))
+    }
+
+    fn form(abs_api_key: Option<&str>, credentials: Option<(&str, &str)>) -> SetupRequestDto {
+        SetupRequestDto {
+            abs_api_key: abs_api_key.map(str::to_string),
+            username: credentials.map(|(u, _)| u.to_string()),
+            password: credentials.map(|(_, p)| p.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn logging_in_registers_a_device_for_the_abs_user() {
+        let base = crate::test_support::serve(
+            Route::new()
+                .at("/api/libraries", get(libraries))
+                .at("/login", post(login)),
+        )
+        .await;
+        let client = AbsClient::new(&base).unwrap();
+        let (db, _) = crate::test_support::db_with_device().await;
+        let service = SetupService::new(&client, &db);
+
+        let SetupResponseDto::Ok(Html(page)) = service
+            .setup(
+                form(None, Some(("reader", "secret"))),
+                "https://sync.example",
+            )
+            .await
+        else {
+            panic!("expected the api_endpoint");
+        };
+        assert!(page.contains("api_endpoint=https://sync.example/kobo/"));
+
+        // Setting up another Kobo with the same key reuses the user
+        assert!(matches!(
+            service
+                .setup(form(Some(" good-key "), None), "https://sync.example")
+                .await,
+            SetupResponseDto::Ok(_)
+        ));
+        let users = user::Entity::find().all(&db).await.unwrap();
+        assert_eq!(users.len(), 2);
+
+        for rejected in [
+            form(None, None),
+            form(Some("bad-key"), None),
+            form(None, Some(("reader", "wrong"))),
+        ] {
+            assert!(matches!(
+                service.setup(rejected, "https://sync.example").await,
+                SetupResponseDto::BadRequest(_)
+            ));
+        }
+    }
+}
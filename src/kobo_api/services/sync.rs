@@ -1,20 +1,27 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
 
 use chrono::{DateTime, TimeZone, Utc};
-use entities::{book_sync, devices, prelude::BookSync, user};
+use entities::{book_sync, device_sync_state, prelude::BookSync, sync_state};
+use futures_util::{StreamExt, stream};
 use poem::http::HeaderMap;
-use poem_openapi::payload::Json;
+use poem_openapi::{payload::Json, types::ToJSON};
 use sea_orm::{ActiveValue::Set, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
     AbsKoboResult,
-    abs_client::{AbsClient, LibraryItem},
-    config::Config,
+    abs_client::{AbsClient, AbsFilter, AbsMediaType, LibraryItem, MediaProgress, upstream_status},
+    config::{Config, StoreErrorPolicy},
+    db::retry_on_busy,
     kobo_api::{
         models::*,
         routes::{KoboFullTokenDetails, KoboSyncToken},
+        services::devices::{DeviceService, MapFailure},
     },
 };
 // no_std: poem-openapi will serialize headers
@@ -23,10 +30,30 @@ pub struct SyncService<'a> {
     pub abs_client: &'a AbsClient,
     pub config: &'a Config,
     pub db: &'a DatabaseConnection,
+    /// Kobo `UserKey` the device presented, selecting the user of a shared device
+    pub user_key: Option<String>,
+}
+
+/// Header devices send their Kobo `UserKey` in
+pub const KOBO_USER_KEY_HEADER: &str = "x-kobo-userkey";
+
+/// Kobo `UserKey` of a device request, if it sent one
+pub fn kobo_user_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(KOBO_USER_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
 }
 
-static KOBO_STOREAPI_URL: &str = "https://storeapi.kobo.com";
+/// Kobo store sync responses keyed by device and sync token, with the instant they expire
+type StoreSyncCache = HashMap<(Uuid, String), (Instant, StoreSyncResult)>;
+static STORE_SYNC_CACHE: LazyLock<Mutex<StoreSyncCache>> = LazyLock::new(Default::default);
 static KOBO_IMAGEHOST_URL: &str = "https://cdn.kobo.com/book-images";
+/// Max author lookups in flight to ABS while preparing a sync batch
+const AUTHOR_FETCH_CONCURRENCY: usize = 4;
+/// How often sync history is checked against `SYNC_HISTORY_RETENTION_DAYS`
+const SYNC_HISTORY_PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
 impl<'a> SyncService<'a> {
     pub fn new(abs_client: &'a AbsClient, config: &'a Config, db: &'a DatabaseConnection) -> Self {
@@ -34,52 +61,117 @@ impl<'a> SyncService<'a> {
             abs_client,
             config,
             db,
+            user_key: None,
         }
     }
 
-    // TODO: replace with actual urls
-    #[tracing::instrument(level = "debug", skip(self, format))]
-    fn get_download_url_for_book(&self, library_item_id: &Uuid, format: &BookFormatDto) -> String {
-        format!("https://example.com/download/{}", library_item_id,)
+    /// Sync as the user added to a shared device for this Kobo `UserKey`, see
+    /// [`DeviceService::abs_api_key_for`]
+    pub fn with_user_key(mut self, user_key: Option<String>) -> Self {
+        self.user_key = user_key;
+        self
+    }
+
+    /// URL of the device download route for a book, under `base_url` (see
+    /// [`Self::device_base_url`])
+    pub fn get_download_url_for_book(
+        base_url: &str,
+        auth_token: Uuid,
+        library_item_id: &Uuid,
+        format: &BookFormatDto,
+    ) -> String {
+        format!(
+            "{}/kobo/{}/v1/download/{}/{}",
+            base_url.trim_end_matches('/'),
+            auth_token,
+            library_item_id,
+            format
+        )
+    }
+
+    /// Base URL the device reached this server at, from the reverse proxy's `X-Forwarded-*`
+    /// headers or `Host`, so download URLs keep the scheme the device used (Kobo refuses mixed
+    /// content). Requests without a host header fall back to the bind address.
+    pub fn device_base_url(config: &Config, headers: &HeaderMap) -> String {
+        crate::kobo_api::spec::infer_server_url(headers).unwrap_or_else(|| {
+            let scheme = crate::kobo_api::spec::forwarded_proto(headers)
+                .unwrap_or_else(|| "http".to_string());
+            format!("{}://{}", scheme, config.bind_addr())
+        })
     }
 
     async fn get_api_key(&self, device_id: Uuid) -> AbsKoboResult<Option<String>> {
-        if let Some((_, Some(user))) = devices::Entity::find_by_id(device_id)
-            .select_also(user::Entity)
-            .one(self.db)
-            .await?
-        {
-            Ok(Some(user.abs_api_key))
-        } else {
-            Ok(None)
-        }
+        DeviceService::new(self.db)
+            .abs_api_key_for(device_id, self.user_key.as_deref())
+            .await
     }
 
     const SYNC_ITEM_LIMIT: usize = 100;
 
-    #[tracing::instrument(level = "debug", skip(self, auth_token, books_last_modified))]
+    /// Books in the current scan window that need syncing, ordered by `(updated_at, id)` and
+    /// starting after `cursor` when a previous response was cut off. Books added after
+    /// `books_last_created` are new to the device, books changed after `books_last_modified` are
+    /// updates.
+    #[tracing::instrument(
+        level = "debug",
+        skip(self, auth_token, books_last_modified, books_last_created)
+    )]
     async fn collect_books_to_sync(
         &self,
         auth_token: Uuid,
         books_last_modified: &Option<DateTime<Utc>>,
-    ) -> AbsKoboResult<Vec<(SyncType, LibraryItem)>> {
-        let user_api_key = self.get_api_key(auth_token).await?;
-        let user_api_key = match user_api_key {
-            Some(key) => key,
-            None => {
-                tracing::error!("No API key found for device {}", auth_token);
-                return Ok(vec![]);
-            }
+        books_last_created: &Option<DateTime<Utc>>,
+        cursor: Option<&SyncCursor>,
+    ) -> AbsKoboResult<BookScan> {
+        let Some(user_api_key) = self.get_api_key(auth_token).await? else {
+            tracing::error!("No API key found for device {}", auth_token);
+            return Ok(BookScan::default());
         };
+        let mut scan = self
+            .scan_books(
+                auth_token,
+                books_last_modified,
+                books_last_created,
+                cursor,
+                &user_api_key,
+            )
+            .await?;
+        let batch = scan.books.len().min(Self::SYNC_ITEM_LIMIT);
+        self.prefetch_details(&mut scan.books[..batch], &user_api_key)
+            .await;
+        scan.progress = self
+            .new_book_progress(&scan.books[..batch], &user_api_key)
+            .await;
+        Ok(scan)
+    }
 
-        let books = self
-            .abs_client
-            .get_library_items(&self.config.library_id, 0, None, None, None, &user_api_key)
+    /// The books [`Self::collect_books_to_sync`] would sync, without fetching their details or
+    /// ABS progress; enough to count them
+    async fn scan_books(
+        &self,
+        auth_token: Uuid,
+        books_last_modified: &Option<DateTime<Utc>>,
+        books_last_created: &Option<DateTime<Utc>>,
+        cursor: Option<&SyncCursor>,
+        user_api_key: &String,
+    ) -> AbsKoboResult<BookScan> {
+        let sync_tag = DeviceService::new(self.db).sync_tag(auth_token).await?;
+        let filter = self.config.sync_filter.as_ref().map(AbsFilter::encode);
+        let LibraryListing {
+            items,
+            next_offset,
+            diagnostics,
+            complete,
+            resumed,
+        } = self
+            .list_libraries(auth_token, filter.as_deref(), user_api_key)
             .await?;
 
-        // Get the last modified timestamp for books or fall back to UNIX_EPOCH
+        // Get the last modified and created timestamps for books or fall back to UNIX_EPOCH
         let books_last_modified =
             books_last_modified.unwrap_or_else(|| DateTime::<Utc>::from(std::time::UNIX_EPOCH));
+        let books_last_created =
+            books_last_created.unwrap_or_else(|| DateTime::<Utc>::from(std::time::UNIX_EPOCH));
 
         // Build a hashmap from the already synced book IDs
         let already_synced_ids: HashMap<Uuid, book_sync::Model> = BookSync::find()
@@ -95,17 +187,74 @@ impl<'a> SyncService<'a> {
             })
             .collect();
 
-        let book_list = books.results.into_iter().filter_map(|item| {
-            // Filter for recently added books
-            if item.media.ebook_format == Some("epub".to_string()) {
+        // A complete listing holds every (filtered) library, so synced books missing from it were
+        // removed. An empty listing is more likely a setup problem.
+        let removed = if complete && !items.is_empty() {
+            let listed: HashSet<Uuid> = items.iter().map(|item| item.id).collect();
+            already_synced_ids
+                .keys()
+                .filter(|id| !listed.contains(id))
+                .copied()
+                .collect()
+        } else {
+            vec![]
+        };
+
+        let grace_cutoff = (Utc::now()
+            - chrono::Duration::seconds(self.config.sync_new_book_grace_secs as i64))
+        .timestamp_millis();
+        let mut deferred = vec![];
+        let mut malformed = vec![];
+
+        let book_list = items.into_iter().filter_map(|item| {
+            // Only books can be synced to the device, skip podcasts and unknown media
+            if item.media_type != AbsMediaType::Book {
+                return None;
+            }
+
+            // Recorded like a mapping failure, unless an earlier page of a cut-off sync did
+            if item.media.is_none() {
+                tracing::warn!(item_id = %item.id, "skipping item without media");
+                if cursor.is_none_or(|c| c.is_before(&item)) {
+                    malformed.push(MapFailure {
+                        item_id: item.id,
+                        item_updated_at: item.updated_at,
+                        error: format!("Item {} has no media", item.id),
+                    });
+                }
+                return None;
+            }
+
+            // Only the ebook is synced, so audio files don't matter either way: audiobooks
+            // without an ebook are skipped and ones with an ebook are synced for it
+            if !has_allowed_ebook(&item, &self.config.allowed_ebook_formats) {
+                tracing::debug!(item_id = %item.id, formats = ?item.ebook_formats(), "skipping item without an allowed ebook format");
+                return None;
+            }
+
+            if let Some(tag) = &sync_tag
+                && !has_tag(&item, tag)
+            {
+                tracing::debug!(item_id = %item.id, %tag, "skipping item without the device's sync tag");
+                return None;
+            }
+
+            // ABS may still be scanning freshly added items, leave them for a later sync
+            if self.config.sync_new_book_grace_secs > 0
+                && item.added_at > grace_cutoff
+                && !already_synced_ids.contains_key(&item.id)
+            {
+                tracing::debug!(item_id = %item.id, "deferring item within the new book grace period");
+                deferred.push((item.added_at, item.updated_at));
                 return None;
             }
 
-            let added_date = Utc.timestamp_opt(item.added_at, 0).unwrap();
-            let is_recently_added = added_date > books_last_modified;
+            // ABS timestamps are in milliseconds
+            let added_date = Utc.timestamp_millis_opt(item.added_at).unwrap();
+            let is_recently_added = added_date > books_last_created;
 
             // Filter for recently updated books
-            let updated_date = Utc.timestamp_opt(item.updated_at, 0).unwrap();
+            let updated_date = Utc.timestamp_millis_opt(item.updated_at).unwrap();
             let is_recently_updated = updated_date > books_last_modified;
 
             // Filter books for updates after last sync
@@ -117,7 +266,8 @@ impl<'a> SyncService<'a> {
                 };
 
             if (is_recently_added || is_recently_updated) && !current_version_synced {
-                if already_synced_ids.contains_key(&item.id) {
+                // Books added before the created watermark are already on the device
+                if already_synced_ids.contains_key(&item.id) || !is_recently_added {
                     Some((SyncType::Update, item))
                 } else {
                     Some((SyncType::New, item))
@@ -127,7 +277,367 @@ impl<'a> SyncService<'a> {
             }
         });
 
-        Ok(book_list.collect())
+        let mut book_list: Vec<_> = book_list
+            .filter(|(_, item)| cursor.is_none_or(|c| c.is_before(item)))
+            .collect();
+        book_list.sort_by_key(|(_, item)| (item.updated_at, item.id));
+
+        Ok(BookScan {
+            books: book_list,
+            next_offset,
+            diagnostics,
+            removed,
+            progress: HashMap::new(),
+            complete,
+            resumed,
+            deferred,
+            malformed,
+        })
+    }
+
+    /// Items of the synced libraries for this sync. Without a scan cap every library is listed in
+    /// full; with one, only the page of `max_scan` items at the device's scan offset, which counts
+    /// through the libraries one after another. A library that fails to list is skipped with a
+    /// diagnostic so the others still sync; only when every library fails is the sync failed.
+    async fn list_libraries(
+        &self,
+        auth_token: Uuid,
+        filter: Option<&str>,
+        api_key: &String,
+    ) -> AbsKoboResult<LibraryListing> {
+        let scan = match self.config.sync_max_scan_items {
+            Some(max_scan) => Some((
+                max_scan,
+                DeviceService::new(self.db).scan_offset(auth_token).await?,
+            )),
+            None => None,
+        };
+        let mut listing = LibraryListing {
+            items: vec![],
+            next_offset: None,
+            diagnostics: vec![],
+            complete: scan.is_none(),
+            resumed: scan.is_some_and(|(_, offset)| offset > 0),
+        };
+        let libraries = &self.config.library_ids;
+        // Items in the libraries before the current one, which earlier syncs already scanned
+        let mut scanned = 0;
+        let mut listed_any = false;
+        let mut last_error = None;
+        for (index, library_id) in libraries.iter().enumerate() {
+            let (limit, page) = match scan {
+                Some((max_scan, offset)) => (
+                    max_scan as i64,
+                    Some(((offset - scanned) / max_scan) as i64),
+                ),
+                None => (0, None),
+            };
+            let books = match self
+                .abs_client
+                .get_library_items_if_changed(
+                    library_id,
+                    limit,
+                    page,
+                    None,
+                    filter,
+                    Some(&self.config.sync_item_sort),
+                    api_key,
+                )
+                .await
+            {
+                Ok(books) => {
+                    listed_any = true;
+                    books
+                }
+                Err(e) => {
+                    // With a scan cap the library counts as empty, which may shift the scan
+                    // window while it keeps failing
+                    listing.complete = false;
+                    listing.diagnostics.push(ScanDiagnostic::LibraryFailed {
+                        library_id: *library_id,
+                        error: format!("{:#}", e),
+                    });
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            if books.total == 0 {
+                listing.diagnostics.push(ScanDiagnostic::EmptyLibrary {
+                    library_id: *library_id,
+                });
+            }
+            let Some((_, offset)) = scan else {
+                listing.items.extend(books.results);
+                continue;
+            };
+            let total = books.total.max(0) as u64;
+            if offset - scanned >= total {
+                scanned += total;
+                continue;
+            }
+            let more = books.has_more() || index + 1 < libraries.len();
+            listing.next_offset = Some(offset + books.results.len() as u64).filter(|_| more);
+            listing.items = books.results;
+            break;
+        }
+        match last_error {
+            Some(e) if !listed_any => Err(e),
+            _ => Ok(listing),
+        }
+    }
+
+    async fn load_cursor(&self, auth_token: Uuid) -> AbsKoboResult<Option<SyncCursor>> {
+        Ok(device_sync_state::Entity::find_by_id(auth_token)
+            .one(self.db)
+            .await?
+            .and_then(|state| {
+                Some(SyncCursor {
+                    updated_at: state.cursor_updated_at,
+                    item_id: Uuid::parse_str(&state.cursor_item_id).ok()?,
+                })
+            }))
+    }
+
+    /// Remember the last book sent in a cut-off response, or clear the cursor with `None`
+    async fn store_cursor(
+        &self,
+        auth_token: Uuid,
+        cursor: Option<SyncCursor>,
+    ) -> AbsKoboResult<()> {
+        retry_on_busy(|| device_sync_state::Entity::delete_by_id(auth_token).exec(self.db)).await?;
+        let Some(cursor) = cursor else {
+            return Ok(());
+        };
+        let now = Utc::now();
+        retry_on_busy(|| {
+            device_sync_state::Entity::insert(device_sync_state::ActiveModel {
+                device_id: Set(auth_token),
+                cursor_updated_at: Set(cursor.updated_at),
+                cursor_item_id: Set(cursor.item_id.to_string()),
+                last_modified: Set(now),
+            })
+            .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Timestamps of the device's last sync token as recorded by [`Self::store_sync_state`], for
+    /// devices that come back with only a store token
+    async fn load_sync_state(
+        &self,
+        auth_token: Uuid,
+    ) -> AbsKoboResult<Option<KoboFullTokenDetails>> {
+        Ok(sync_state::Entity::find_by_id(auth_token)
+            .one(self.db)
+            .await?
+            .map(|state| KoboFullTokenDetails {
+                books_last_modified: state.books_last_modified,
+                books_last_created: state.books_last_created,
+                archive_last_modified: None,
+                reading_state_last_modified: state.reading_state_last_modified,
+                tags_last_modified: None,
+            }))
+    }
+
+    /// Remember the timestamps handed to the device in its sync token
+    async fn store_sync_state(
+        &self,
+        auth_token: Uuid,
+        details: &KoboFullTokenDetails,
+    ) -> AbsKoboResult<()> {
+        retry_on_busy(|| sync_state::Entity::delete_by_id(auth_token).exec(self.db)).await?;
+        let now = Utc::now();
+        retry_on_busy(|| {
+            sync_state::Entity::insert(sync_state::ActiveModel {
+                device_id: Set(auth_token),
+                books_last_modified: Set(details.books_last_modified),
+                books_last_created: Set(details.books_last_created),
+                reading_state_last_modified: Set(details.reading_state_last_modified),
+                updated_at: Set(now),
+            })
+            .exec(self.db)
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Move the device's scan position forward, or reset it once the whole library was covered
+    async fn advance_scan(&self, auth_token: Uuid, next_offset: Option<u64>) -> AbsKoboResult<()> {
+        if self.config.sync_max_scan_items.is_none() {
+            return Ok(());
+        }
+        DeviceService::new(self.db)
+            .set_scan_offset(auth_token, next_offset)
+            .await
+    }
+
+    /// Removal entitlements for books that left the library, forgetting that they were synced
+    /// so they are only sent once. `KEEP_ARCHIVED_VISIBLE` decides whether the device keeps
+    /// showing them in its archive.
+    async fn removed_entitlements(
+        &self,
+        auth_token: Uuid,
+        removed: &[Uuid],
+    ) -> Vec<KoboSyncEntitlement> {
+        tracing::info!(count = removed.len(), "sending removed books to the device");
+        let ids: Vec<String> = removed.iter().map(Uuid::to_string).collect();
+        if let Err(e) = retry_on_busy(|| {
+            book_sync::Entity::delete_many()
+                .filter(book_sync::Column::DeviceId.eq(auth_token))
+                .filter(book_sync::Column::AbsItemId.is_in(ids.clone()))
+                .exec(self.db)
+        })
+        .await
+        {
+            tracing::error!(error = %e, "Failed to forget removed books");
+        }
+
+        let now = Utc::now();
+        let hidden = !self.config.flags().keep_archived_visible;
+        removed
+            .iter()
+            .map(|id| {
+                KoboSyncEntitlement::RemovedEntitlement(RemovedEntitlement {
+                    changed_entitlement: KoboRemovedBook {
+                        book_entitlement: BookEntitlement::removed(*id, now, hidden),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// One shelf per ABS series in the synced libraries, holding the series' books
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn series_shelves(&self, auth_token: Uuid) -> AbsKoboResult<Vec<KoboSyncEntitlement>> {
+        let Some(api_key) = self.get_api_key(auth_token).await? else {
+            return Ok(vec![]);
+        };
+        let now = Utc::now();
+        let mut shelves = vec![];
+        for library_id in &self.config.library_ids {
+            let series = match self
+                .abs_client
+                .get_library_series(&library_id.to_string(), 0, None, None, &api_key)
+                .await
+            {
+                Ok(series) => series,
+                Err(e) => {
+                    tracing::warn!(error = %e, %library_id, "Failed to list series, skipping the library's shelves");
+                    continue;
+                }
+            };
+            for series in series.results {
+                let filter = AbsFilter::new("series", &series.id)
+                    .expect("series is a filter group")
+                    .encode();
+                let items = self
+                    .abs_client
+                    .get_all_library_items(library_id, None, Some(&filter), None, &api_key)
+                    .await?;
+                let book_ids: Vec<Uuid> = items
+                    .results
+                    .iter()
+                    .filter(|item| item.media_type == AbsMediaType::Book)
+                    .map(|item| item.id)
+                    .collect();
+                if book_ids.is_empty() {
+                    continue;
+                }
+                shelves.push(KoboSyncEntitlement::NewTag(NewTag {
+                    new_tag: KoboSyncedTag {
+                        tag: KoboTag::series_shelf(&series.id, &series.name, &book_ids, now),
+                    },
+                }));
+            }
+        }
+        Ok(shelves)
+    }
+
+    /// Fill in the details listings leave out (individual authors and series) for a batch of
+    /// books before entitlements are built from them: the expanded items are fetched in one
+    /// request, then authors still missing a display name are resolved concurrently, once each.
+    /// Books keep their listing data when a lookup fails.
+    #[tracing::instrument(level = "debug", skip(self, books, api_key), fields(books = books.len()))]
+    async fn prefetch_details(&self, books: &mut [(SyncType, LibraryItem)], api_key: &String) {
+        if books.is_empty() {
+            return;
+        }
+        let ids: Vec<Uuid> = books.iter().map(|(_, item)| item.id).collect();
+        match self.abs_client.get_library_items_batch(&ids, api_key).await {
+            Ok(expanded) => {
+                let mut expanded: HashMap<Uuid, LibraryItem> =
+                    expanded.into_iter().map(|item| (item.id, item)).collect();
+                for (_, item) in books.iter_mut() {
+                    if let Some(media) = expanded.remove(&item.id).and_then(|e| e.media) {
+                        item.media = Some(media);
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to fetch expanded items, syncing listing details");
+            }
+        }
+
+        let author_ids: HashSet<String> = books
+            .iter()
+            .filter_map(|(_, item)| item.media.as_ref())
+            .flat_map(|media| &media.metadata.authors)
+            .filter(|a| a.name.is_none())
+            .map(|a| a.id.clone())
+            .collect();
+        let names: HashMap<String, String> = stream::iter(author_ids)
+            .map(|author_id| async move {
+                match self.abs_client.get_author(&author_id, api_key).await {
+                    Ok(author) => Some((author_id, author.name)),
+                    Err(e) => {
+                        tracing::warn!(error = %e, %author_id, "Failed to resolve author");
+                        None
+                    }
+                }
+            })
+            .buffer_unordered(AUTHOR_FETCH_CONCURRENCY)
+            .filter_map(|resolved| async move { resolved })
+            .collect()
+            .await;
+        for author in books
+            .iter_mut()
+            .filter_map(|(_, item)| item.media.as_mut())
+            .flat_map(|media| media.metadata.authors.iter_mut())
+            .filter(|a| a.name.is_none())
+        {
+            author.name = names.get(&author.id).cloned();
+        }
+    }
+
+    /// The user's ABS progress on the books new to the device, so a device that has never seen a
+    /// book starts where the user left off elsewhere. Books the device already has keep its own
+    /// reading state, which the reading state endpoints reconcile with ABS. One `/api/me` call
+    /// covers the whole batch; without it books are synced without a reading state.
+    async fn new_book_progress(
+        &self,
+        books: &[(SyncType, LibraryItem)],
+        api_key: &String,
+    ) -> HashMap<Uuid, MediaProgress> {
+        let new_books: HashSet<Uuid> = books
+            .iter()
+            .filter(|(sync_type, _)| matches!(sync_type, SyncType::New))
+            .map(|(_, item)| item.id)
+            .collect();
+        if new_books.is_empty() {
+            return HashMap::new();
+        }
+        match self.abs_client.get_my_progress(api_key).await {
+            Ok(progress) => progress
+                .into_iter()
+                .filter_map(|p| Some((p.library_item_id?, p)))
+                .filter(|(id, _)| new_books.contains(id))
+                .collect(),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to fetch ABS progress, syncing without reading states");
+                HashMap::new()
+            }
+        }
     }
 
     #[tracing::instrument(level = "debug", skip(self))]
@@ -137,8 +647,12 @@ impl<'a> SyncService<'a> {
         raw_kobo_sync_token: String,
         headers: &HeaderMap,
     ) -> SyncResponseDto {
-        // Minimal stub: no changes; return empty list with a dummy sync token
-        let _ = auth_token;
+        if let Err(e) = DeviceService::new(self.db)
+            .record_sync_token(auth_token, &raw_kobo_sync_token)
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to record sync token");
+        }
         let kobo_sync_token = match KoboSyncToken::from_request(&raw_kobo_sync_token) {
             Ok(token) => token,
             Err(e) => {
@@ -151,29 +665,42 @@ impl<'a> SyncService<'a> {
 
         tracing::info!("Kobo Sync Token Received");
         tracing::info!(?kobo_sync_token, "Kobo Sync Token Details");
-        tracing::info!(
-            "Download link format: {}",
-            // TODO: replace with actual implementation
-            "https://example.com/download/{book_id}/{format}"
-        );
 
         // Check kobo token. If No token, return with 400, if only raw token was provided set local timestamps to unix epoch, else use the values from the token
-        let token_details = match kobo_sync_token {
+        let (raw_kobo_store_token, token_details) = match kobo_sync_token {
             KoboSyncToken::NoToken => {
                 return SyncResponseDto::Unauthorized(Json(crate::kobo_api::models::ErrorDto {
                     message: "Kobo Sync Token is required".to_string(),
                 }));
             }
-            KoboSyncToken::OnlyRawToken { .. } => KoboFullTokenDetails {
-                books_last_modified: None,
-                books_last_created: None,
-                archive_last_modified: None,
-                reading_state_last_modified: None,
-                tags_last_modified: None,
-            },
-            KoboSyncToken::FullToken { details, .. } => details,
+            // The device lost our token, e.g. because the store handed it a new one; carry on from
+            // the timestamps recorded server-side, or start over for a device that never synced
+            KoboSyncToken::OnlyRawToken {
+                raw_kobo_store_token,
+            } => {
+                let stored = self.load_sync_state(auth_token).await.unwrap_or_else(|e| {
+                    tracing::error!(error = %e, "Failed to load sync state, starting over");
+                    None
+                });
+                (
+                    raw_kobo_store_token,
+                    stored.unwrap_or(KoboFullTokenDetails {
+                        books_last_modified: None,
+                        books_last_created: None,
+                        archive_last_modified: None,
+                        reading_state_last_modified: None,
+                        tags_last_modified: None,
+                    }),
+                )
+            }
+            KoboSyncToken::FullToken {
+                raw_kobo_store_token,
+                details,
+            } => (raw_kobo_store_token, details),
         };
 
+        let sync_mode = token_details.sync_mode();
+
         // TODO: check if the user has ever synced books for this kobo, and if not, set the
         let KoboFullTokenDetails {
             books_last_modified,
@@ -195,11 +722,21 @@ impl<'a> SyncService<'a> {
 
         let archive_last_modified: Option<DateTime<Utc>> = None;
 
-        let sync_results = match self
-            .collect_books_to_sync(auth_token, &books_last_modified)
+        let cursor = self.load_cursor(auth_token).await.unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed to load sync cursor, starting from the top");
+            None
+        });
+
+        let scan = match self
+            .collect_books_to_sync(
+                auth_token,
+                &books_last_modified,
+                &books_last_created,
+                cursor.as_ref(),
+            )
             .await
         {
-            Ok(results) => results,
+            Ok(scan) => scan,
             Err(e) => {
                 tracing::error!(error = %e, "Failed to collect books for sync");
                 return SyncResponseDto::BadGateway(Json(crate::kobo_api::models::ErrorDto {
@@ -207,62 +744,169 @@ impl<'a> SyncService<'a> {
                 }));
             }
         };
+        for diagnostic in &scan.diagnostics {
+            tracing::warn!(device_id = %auth_token, "{}", diagnostic);
+        }
 
-        tracing::info!("Collected {} books to sync", sync_results.len());
-        let book_count = sync_results.len();
+        tracing::info!("Collected {} books to sync", scan.books.len());
+        let book_count = scan.books.len();
+        let scan_incomplete = scan.next_offset.is_some();
+        let next_offset = scan.next_offset;
+        let progress = scan.progress;
 
         // limit sync items
-        let sync_results: Vec<_> = sync_results
-            .into_iter()
-            .take(Self::SYNC_ITEM_LIMIT)
-            .collect();
+        let sync_results: Vec<_> = scan.books.into_iter().take(Self::SYNC_ITEM_LIMIT).collect();
+        let max_response_bytes = self
+            .config
+            .sync_max_response_kb
+            .map(|kb| kb as usize * 1024);
+        let mut response_bytes = 0;
+        let mut size_limited_at = None;
+
+        let ignored = DeviceService::new(self.db)
+            .ignored_items(auth_token, self.config.max_map_failures)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to load repeatedly failing items");
+                HashMap::new()
+            });
 
+        let base_url = Self::device_base_url(self.config, headers);
         let mut entitlements = Vec::new();
-        for (sync_type, result) in &sync_results {
-            let download_urls =
-                vec![self.get_download_url_for_book(&result.id, &BookFormatDto::Kepub)];
+        let mut failures = scan.malformed;
+        let mut skipped = Vec::new();
+        // `(added_at, updated_at)` of the books sent and of the ones held back (deferred or failed
+        // to map), and the `last_update` of the reading states sent, which the token's watermarks
+        // move up to
+        let mut sent_books = Vec::new();
+        let mut held_back = scan.deferred;
+        let mut sent_reading_states = Vec::new();
+        for (index, (sync_type, result)) in sync_results.iter().enumerate() {
+            if ignored.get(&result.id) == Some(&result.updated_at) {
+                tracing::debug!(item_id = %result.id, "skipping item that keeps failing to map");
+                skipped.push(result.id);
+                continue;
+            }
 
-            let book_metadata =
-                match BookMetadata::try_from_library_item(result.clone(), download_urls) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        tracing::error!(error = %e, "Failed to create book metadata");
-                        continue;
-                    }
-                };
+            let download_urls = vec![Self::get_download_url_for_book(
+                &base_url,
+                auth_token,
+                &result.id,
+                &BookFormatDto::Kepub,
+            )];
+
+            let mut book_metadata = match BookMetadata::try_from_library_item(
+                result.clone(),
+                download_urls,
+                self.config.author_name_order,
+            ) {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::error!(error = %e, item_id = %result.id, "Failed to create book metadata");
+                    failures.push(MapFailure {
+                        item_id: result.id,
+                        item_updated_at: result.updated_at,
+                        error: e.to_string(),
+                    });
+                    held_back.push((result.added_at, result.updated_at));
+                    continue;
+                }
+            };
+
+            if !self.config.flags().sync_include_description {
+                book_metadata.description = None;
+            }
 
             let book_entitlement = BookEntitlement::from_library_item(result);
 
-            let reading_state = None;
+            let book_progress = match sync_type {
+                SyncType::New => progress.get(&result.id),
+                _ => None,
+            };
+            let reading_state =
+                book_progress.map(|p| KoboSyncedReadingState::from_abs_progress(result.id, p));
 
             let book = KoboSyncedBook {
                 book_entitlement,
                 book_metadata,
                 reading_state,
             };
+
+            // Leave the remaining books for the next request once the response gets too large
+            // for the device, but always send at least one
+            let book_bytes = book.to_json().map_or(0, |json| json.to_string().len());
+            if let Some(max_bytes) = max_response_bytes
+                && !entitlements.is_empty()
+                && response_bytes + book_bytes > max_bytes
+            {
+                tracing::info!(
+                    sent = entitlements.len(),
+                    response_bytes,
+                    "sync response size limit reached, continuing in the next request"
+                );
+                size_limited_at = Some(index);
+                break;
+            }
+            response_bytes += book_bytes;
             entitlements.push((sync_type, book));
+            sent_books.push((result.added_at, result.updated_at));
+            sent_reading_states.extend(book_progress.map(|p| p.last_update));
 
             // Remove previous sync entries for this book
-            book_sync::Entity::delete_many()
-                .filter(book_sync::Column::DeviceId.eq(auth_token))
-                .filter(book_sync::Column::AbsItemId.eq(result.id.to_string()))
-                .exec(self.db)
-                .await
-                .ok();
+            retry_on_busy(|| {
+                book_sync::Entity::delete_many()
+                    .filter(book_sync::Column::DeviceId.eq(auth_token))
+                    .filter(book_sync::Column::AbsItemId.eq(result.id.to_string()))
+                    .exec(self.db)
+            })
+            .await
+            .ok();
 
             // Insert new sync entry for this book
-            book_sync::Entity::insert(book_sync::ActiveModel {
-                id: Set(Uuid::now_v7()),
-                device_id: Set(auth_token),
-                abs_item_id: Set(result.id.to_string()),
-                timestamp: Set(Utc::now()),
+            let synced_at = Utc::now();
+            retry_on_busy(|| {
+                book_sync::Entity::insert(book_sync::ActiveModel {
+                    id: Set(Uuid::now_v7()),
+                    device_id: Set(auth_token),
+                    abs_item_id: Set(result.id.to_string()),
+                    timestamp: Set(synced_at),
+                })
+                .exec(self.db)
             })
-            .exec(self.db)
             .await
             .ok();
         }
 
-        let entitlements = entitlements
+        // Continue requests carry on the sync their first response started, so they add to its
+        // errors
+        let starts_sync = cursor.is_none() && !scan.resumed;
+        if let Err(e) = DeviceService::new(self.db)
+            .record_sync_errors(auth_token, &failures, &skipped, starts_sync)
+            .await
+        {
+            tracing::error!(error = %e, "Failed to record sync errors");
+        }
+
+        // Keep the scan window in place until all of its books fit into one response; books cut
+        // off by SYNC_ITEM_LIMIT or the response size limit are resumed after the cursor by the
+        // next request
+        let delivered = size_limited_at.unwrap_or(sync_results.len());
+        let cut_off = book_count > delivered;
+        let next_cursor = delivered
+            .checked_sub(1)
+            .and_then(|last| sync_results.get(last))
+            .filter(|_| cut_off)
+            .map(|(_, item)| SyncCursor::after(item));
+        if next_cursor.is_none()
+            && let Err(e) = self.advance_scan(auth_token, next_offset).await
+        {
+            tracing::error!(error = %e, "Failed to store sync scan position");
+        }
+        if let Err(e) = self.store_cursor(auth_token, next_cursor).await {
+            tracing::error!(error = %e, "Failed to store sync cursor");
+        }
+
+        let mut entitlements = entitlements
             .into_iter()
             .map(|(sync_type, entitlement)| match sync_type {
                 SyncType::New => KoboSyncEntitlement::NewEntitlement(NewEntitlement {
@@ -274,96 +918,362 @@ impl<'a> SyncService<'a> {
             })
             .collect::<Vec<_>>();
 
+        if !scan.removed.is_empty() {
+            entitlements.extend(self.removed_entitlements(auth_token, &scan.removed).await);
+        }
+
+        // Series membership only changes along with books, so shelves are refreshed whenever
+        // books are synced
+        if self.config.flags().sync_series_as_shelves && !entitlements.is_empty() {
+            match self.series_shelves(auth_token).await {
+                Ok(shelves) => entitlements.extend(shelves),
+                Err(e) => tracing::error!(error = %e, "Failed to build series shelves"),
+            }
+        }
+
+        // A sync that covered every library moves the watermarks up to what it delivered, so the
+        // next one only looks at later changes. Cut-off and windowed syncs keep the incoming ones
+        // until the last response, and so does a sync that missed a failed library.
+        let finished = !cut_off && !scan_incomplete && scan.complete;
         let kobo_sync_token = KoboFullTokenDetails {
-            books_last_modified,
-            books_last_created,
+            books_last_modified: if finished {
+                advance_watermark(
+                    books_last_modified,
+                    sent_books.iter().map(|(_, updated_at)| *updated_at),
+                    held_back.iter().map(|(_, updated_at)| *updated_at),
+                )
+            } else {
+                books_last_modified
+            },
+            books_last_created: if finished {
+                advance_watermark(
+                    books_last_created,
+                    sent_books.iter().map(|(added_at, _)| *added_at),
+                    held_back.iter().map(|(added_at, _)| *added_at),
+                )
+            } else {
+                books_last_created
+            },
             archive_last_modified,
-            reading_state_last_modified,
+            reading_state_last_modified: if finished {
+                advance_watermark(
+                    reading_state_last_modified,
+                    sent_reading_states.into_iter(),
+                    std::iter::empty(),
+                )
+            } else {
+                reading_state_last_modified
+            },
             tags_last_modified,
         };
+        if let Err(e) = self.store_sync_state(auth_token, &kobo_sync_token).await {
+            tracing::error!(error = %e, "Failed to store sync state");
+        }
 
-        let rq_client = reqwest::Client::new();
-        let req = rq_client
-            .get(format!("{}/v1/library/sync", KOBO_STOREAPI_URL))
-            .headers(headers.clone())
-            .header("Host", "")
-            .header(KoboSyncToken::HEADER_NAME, kobo_sync_token.to_raw_token());
-
-        let resp = match req.send().await {
-            Ok(resp) => resp,
-            Err(e) => {
-                tracing::error!(error = %e, "Failed to send sync request");
-                return SyncResponseDto::BadGateway(Json(crate::kobo_api::models::ErrorDto {
-                    message: format!("Failed to send sync request: {}", e),
-                }));
-            }
+        let proxy_store = DeviceService::new(self.db)
+            .store_proxy_enabled(auth_token, self.config.flags().store_proxy)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to read store proxy setting, using default");
+                self.config.flags().store_proxy
+            });
+        let store_result = if proxy_store {
+            self.cached_store_sync(auth_token, headers, &kobo_sync_token.to_raw_token())
+                .await
+        } else {
+            tracing::debug!("Kobo store proxy disabled for device");
+            // Without the store there is no token coming back, so mint one; a first sync would
+            // otherwise hand the store's own token back and the device would never leave it
+            Ok(StoreSyncResult::fallback(
+                &kobo_sync_token.to_device_token(&raw_kobo_store_token),
+            ))
         };
-
-        let kobo_storeapi_headers = resp.headers().clone();
-        let kobo_storeapi_raw_token = kobo_storeapi_headers
-            .get(KoboSyncToken::HEADER_NAME)
-            .map(|v| v.to_str().unwrap_or("").to_string())
-            .unwrap_or("".to_string());
-        let x_kobo_sync = kobo_storeapi_headers
-            .get("x-kobo-sync")
-            .map(|v| v.to_str().unwrap_or("").to_string());
-        let x_kobo_sync_mode = kobo_storeapi_headers
-            .get("x-kobo-sync-mode")
-            .map(|v| v.to_str().unwrap_or("").to_string());
-        let x_kobo_recent_reads = kobo_storeapi_headers
-            .get("x-kobo-recent-reads")
-            .map(|v| v.to_str().unwrap_or("").to_string());
-
-        let kobo_store_entitlements: Vec<KoboSyncEntitlement> = {
-            let text = resp.text().await.expect("Failed to read response text");
-            serde_json::from_str(&text).expect("Failed to parse response JSON")
+        let store = match resolve_store_sync(
+            store_result,
+            self.config.store_error_policy,
+            &raw_kobo_sync_token,
+        ) {
+            Ok(store) => store,
+            Err(e) => return SyncResponseDto::BadGateway(Json(e)),
         };
 
-        let all_entitlements = [entitlements, kobo_store_entitlements].concat();
+        let all_entitlements = [entitlements, store.entitlements].concat();
 
-        let x_kobo_sync = if book_count > Self::SYNC_ITEM_LIMIT {
+        let x_kobo_sync = if cut_off || scan_incomplete {
             Some("continue".to_string())
         } else {
-            x_kobo_sync
+            store.x_kobo_sync
         };
 
         SyncResponseDto::Ok(
             Json(all_entitlements),
-            kobo_storeapi_raw_token,
+            store.sync_token,
             x_kobo_sync,
-            x_kobo_sync_mode,
-            x_kobo_recent_reads,
+            // The mode describes our merged response, so it is derived from the incoming token
+            // rather than forwarded from the store
+            Some(sync_mode.as_str().to_string()),
+            store.x_kobo_recent_reads,
         )
     }
 
-    #[tracing::instrument(level = "debug", skip(self, req))]
-    pub async fn create_tag(&self, req: TagCreateRequestDto) -> TagCreateResponseDto {
-        if req.name.trim().is_empty() {
-            return TagCreateResponseDto::BadRequest(Json(crate::kobo_api::models::ErrorDto {
-                message: "Name is required".to_string(),
-            }));
+    /// Delete sync history older than `SYNC_HISTORY_RETENTION_DAYS` for books that are no longer
+    /// in any synced library. Rows of books still in the library are kept however old they are, since
+    /// losing them would offer the book to the device as new again. Returns the rows deleted.
+    pub async fn prune_sync_history(&self) -> AbsKoboResult<u64> {
+        let Some(days) = self.config.sync_history_retention_days else {
+            return Ok(0);
+        };
+        // Every library has to be listed, a book missing because its library failed isn't gone
+        let mut present = HashSet::new();
+        for library_id in &self.config.library_ids {
+            let items = self
+                .abs_client
+                .get_all_library_items(library_id, None, None, None, &self.config.abs_api_key)
+                .await?;
+            present.extend(items.results.iter().map(|item| item.id.to_string()));
+        }
+        // An empty listing is more likely a misconfigured library than every book being gone
+        if present.is_empty() {
+            tracing::warn!(libraries = ?self.config.library_ids, "libraries are empty, not pruning sync history");
+            return Ok(0);
         }
-        let id = Uuid::new_v4().to_string();
-        TagCreateResponseDto::Created(Json(id))
-    }
-
-    #[tracing::instrument(level = "debug", skip(self, _tag_id, _name))]
-    pub async fn rename_tag(&self, _tag_id: &str, _name: &str) -> EmptyOkResponseDto {
-        EmptyOkResponseDto::Ok
-    }
 
-    #[tracing::instrument(level = "debug", skip(self, _tag_id))]
-    pub async fn delete_tag(&self, _tag_id: &str) -> EmptyOkResponseDto {
-        EmptyOkResponseDto::Ok
-    }
+        let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+        let stale: Vec<Uuid> = BookSync::find()
+            .filter(book_sync::Column::Timestamp.lt(cutoff))
+            .all(self.db)
+            .await?
+            .into_iter()
+            .filter(|row| !present.contains(&row.abs_item_id))
+            .map(|row| row.id)
+            .collect();
 
-    #[tracing::instrument(level = "debug", skip(self, _tag_id, _items))]
-    pub async fn add_tag_items(
-        &self,
-        _tag_id: &str,
-        _items: Vec<TagItemDto>,
-    ) -> EmptyOkResponseDto {
-        EmptyOkResponseDto::Ok
+        let mut deleted = 0;
+        // Stay well below the bind parameter limits of the database backends
+        for chunk in stale.chunks(500) {
+            deleted += retry_on_busy(|| {
+                book_sync::Entity::delete_many()
+                    .filter(book_sync::Column::Id.is_in(chunk.iter().copied()))
+                    .exec(self.db)
+            })
+            .await?
+            .rows_affected;
+        }
+        Ok(deleted)
+    }
+
+    /// Count the books the device's next sync would deliver, without syncing anything
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn pending(&self, device_id: Uuid) -> PendingSyncResponseDto {
+        match DeviceService::new(self.db).exists(device_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return PendingSyncResponseDto::NotFound(Json(ErrorDto {
+                    message: "Device not found".into(),
+                }));
+            }
+            Err(e) => {
+                return PendingSyncResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        }
+
+        let stored = async {
+            let api_key = self.get_api_key(device_id).await?;
+            let state = self.load_sync_state(device_id).await?;
+            let cursor = self.load_cursor(device_id).await?;
+            AbsKoboResult::Ok((api_key, state, cursor))
+        };
+        let (user_api_key, state, cursor) = match stored.await {
+            Ok((Some(api_key), state, cursor)) => (api_key, state, cursor),
+            Ok((None, ..)) => {
+                tracing::error!("No API key found for device {}", device_id);
+                return PendingSyncResponseDto::Ok(Json(PendingSyncDto { new: 0, updated: 0 }));
+            }
+            Err(e) => {
+                return PendingSyncResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        };
+        let (books_last_modified, books_last_created) = state
+            .map(|s| (s.books_last_modified, s.books_last_created))
+            .unwrap_or_default();
+
+        // Counted against what the device was last told, like its next sync would
+        match self
+            .scan_books(
+                device_id,
+                &books_last_modified,
+                &books_last_created,
+                cursor.as_ref(),
+                &user_api_key,
+            )
+            .await
+        {
+            Ok(scan) => {
+                let new = scan
+                    .books
+                    .iter()
+                    .filter(|(sync_type, _)| matches!(sync_type, SyncType::New))
+                    .count() as u64;
+                PendingSyncResponseDto::Ok(Json(PendingSyncDto {
+                    new,
+                    updated: scan.books.len() as u64 - new,
+                }))
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to collect pending books");
+                PendingSyncResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("Failed to collect pending books: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Forget that the device was sent the book, so its next sync delivers it again as new; for
+    /// books that got corrupted on the device
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn resend(&self, device_id: Uuid, book_id: Uuid) -> BookResendResponseDto {
+        let api_key = match self.get_api_key(device_id).await {
+            Ok(Some(api_key)) => api_key,
+            Ok(None) => {
+                return BookResendResponseDto::NotFound(Json(ErrorDto {
+                    message: "Device not found".into(),
+                }));
+            }
+            Err(e) => {
+                return BookResendResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }));
+            }
+        };
+
+        match self
+            .abs_client
+            .get_library_item(book_id, None, &api_key)
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if upstream_status(&e) == Some(reqwest::StatusCode::NOT_FOUND) => {
+                return BookResendResponseDto::NotFound(Json(ErrorDto {
+                    message: "Book not found in ABS".into(),
+                }));
+            }
+            Err(e) => {
+                tracing::error!(error = %e, %book_id, "failed to look up book to resend");
+                return BookResendResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }));
+            }
+        }
+
+        match retry_on_busy(|| {
+            book_sync::Entity::delete_many()
+                .filter(book_sync::Column::DeviceId.eq(device_id))
+                .filter(book_sync::Column::AbsItemId.eq(book_id.to_string()))
+                .exec(self.db)
+        })
+        .await
+        {
+            Ok(res) => BookResendResponseDto::Ok(Json(BookResendDto {
+                book_id,
+                was_synced: res.rows_affected > 0,
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %device_id, %book_id, "failed to reset book sync");
+                BookResendResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Store sync response for the device and token, reused for `KOBO_STORE_CACHE_SECS` (or the
+    /// store's shorter `max-age`) so rapid repeat syncs don't hit the store again
+    async fn cached_store_sync(
+        &self,
+        device_id: Uuid,
+        headers: &HeaderMap,
+        sync_token: &str,
+    ) -> AbsKoboResult<StoreSyncResult> {
+        let key = (device_id, sync_token.to_string());
+        let now = Instant::now();
+        if let Some((expires, cached)) = STORE_SYNC_CACHE.lock().unwrap().get(&key)
+            && *expires > now
+        {
+            tracing::debug!(%device_id, "reusing cached Kobo store sync response");
+            return Ok(cached.clone());
+        }
+
+        let store = self.fetch_store_sync(headers, sync_token).await?;
+        let ttl = Duration::from_secs(self.config.store_cache_secs);
+        let ttl = store.max_age.map_or(ttl, |max_age| ttl.min(max_age));
+        let mut cache = STORE_SYNC_CACHE.lock().unwrap();
+        cache.retain(|_, (expires, _)| *expires > now);
+        if !ttl.is_zero() {
+            cache.insert(key, (now + ttl, store.clone()));
+        }
+        Ok(store)
+    }
+
+    /// Forward the sync request to the Kobo store and parse its entitlements and sync headers
+    #[tracing::instrument(level = "debug", skip(self, headers, sync_token))]
+    async fn fetch_store_sync(
+        &self,
+        headers: &HeaderMap,
+        sync_token: &str,
+    ) -> AbsKoboResult<StoreSyncResult> {
+        // A hanging store would otherwise hold up the whole sync
+        let rq_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(self.config.store_proxy_timeout_secs))
+            .build()?;
+        let resp = rq_client
+            .get(format!(
+                "{}/v1/library/sync",
+                self.config.kobo_store_url.trim_end_matches('/')
+            ))
+            .headers(headers.clone())
+            .header("Host", "")
+            .header(KoboSyncToken::HEADER_NAME, sync_token)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        let resp_headers = resp.headers().clone();
+        let body = resp.text().await?;
+        StoreSyncResult::from_response(status, &resp_headers, &body)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, req))]
+    pub async fn create_tag(&self, req: TagCreateRequestDto) -> TagCreateResponseDto {
+        if req.name.trim().is_empty() {
+            return TagCreateResponseDto::BadRequest(Json(crate::kobo_api::models::ErrorDto {
+                message: "Name is required".to_string(),
+            }));
+        }
+        let id = Uuid::new_v4().to_string();
+        TagCreateResponseDto::Created(Json(id))
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, _tag_id, _name))]
+    pub async fn rename_tag(&self, _tag_id: &str, _name: &str) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, _tag_id))]
+    pub async fn delete_tag(&self, _tag_id: &str) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
+    }
+
+    #[tracing::instrument(level = "debug", skip(self, _tag_id, _items))]
+    pub async fn add_tag_items(
+        &self,
+        _tag_id: &str,
+        _items: Vec<TagItemDto>,
+    ) -> EmptyOkResponseDto {
+        EmptyOkResponseDto::Ok
     }
 
     #[tracing::instrument(level = "debug", skip(self, _tag_id, _items))]
@@ -394,18 +1304,218 @@ impl<'a> SyncService<'a> {
         InitializationResponseDto::Ok(Json(resources))
     }
 
+    /// Accept a diagnostics payload the device posts to `/v1/analytics/*`, keeping the last one
+    /// for support. The device only checks for a successful result, so recording failures are
+    /// logged rather than reported.
     #[tracing::instrument(level = "debug", skip(self, body))]
-    pub async fn auth_device(&self, body: serde_json::Value) -> DeviceAuthResponseDto {
-        let user_key = body.get("UserKey").cloned().unwrap_or(json!(""));
-        let resp = json!({
-            "AccessToken": Uuid::new_v4().to_string(),
-            "RefreshToken": Uuid::new_v4().to_string(),
-            "TrackingId": Uuid::new_v4().to_string(),
-            "ExpiresIn": 3600,
-            "TokenType": "Bearer",
-            "UserKey": user_key
-        });
-        DeviceAuthResponseDto::Ok(Json(resp))
+    pub async fn record_analytics(
+        &self,
+        auth_token: Uuid,
+        body: serde_json::Value,
+    ) -> AnalyticsResponseDto {
+        if let Err(e) = DeviceService::new(self.db)
+            .record_analytics(auth_token, &body, &self.config.log_redact_keys)
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to record device analytics");
+        }
+        AnalyticsResponseDto::Ok(Json(json!({
+            "Result": "Success",
+            "TestKey": body.get("TestKey").cloned().unwrap_or_else(|| json!("")),
+            "Tests": {}
+        })))
+    }
+
+    /// Lifetime of issued access tokens in seconds, as reported to devices
+    const ACCESS_TOKEN_EXPIRES_IN: u64 = 3600;
+
+    /// Issue new tokens to a device, storing the refresh token against it
+    #[tracing::instrument(level = "debug", skip(self, auth_token, body))]
+    pub async fn auth_device(
+        &self,
+        auth_token: Uuid,
+        body: serde_json::Value,
+    ) -> DeviceAuthResponseDto {
+        let user_key = body
+            .get("UserKey")
+            .and_then(|key| key.as_str())
+            .unwrap_or_default()
+            .to_string();
+        match DeviceService::new(self.db)
+            .issue_auth_tokens(auth_token)
+            .await
+        {
+            Ok(Some((access_token, refresh_token))) => DeviceAuthResponseDto::Ok(Json(
+                Self::auth_result(access_token, refresh_token, user_key),
+            )),
+            Ok(None) => DeviceAuthResponseDto::Unauthorized(Json(ErrorDto {
+                message: "Invalid auth token".into(),
+            })),
+            Err(e) => DeviceAuthResponseDto::InternalServerError(Json(ErrorDto {
+                message: format!("Database error: {}", e),
+            })),
+        }
+    }
+
+    /// Issue a new access token in exchange for the refresh token last issued to the device
+    #[tracing::instrument(level = "debug", skip(self, auth_token, request))]
+    pub async fn refresh_device_auth(
+        &self,
+        auth_token: Uuid,
+        request: DeviceRefreshRequest,
+    ) -> DeviceAuthResponseDto {
+        match DeviceService::new(self.db)
+            .refresh_access_token(auth_token, &request.refresh_token)
+            .await
+        {
+            Ok(Some(access_token)) => DeviceAuthResponseDto::Ok(Json(Self::auth_result(
+                access_token,
+                request.refresh_token,
+                String::new(),
+            ))),
+            Ok(None) => {
+                tracing::warn!(device_id = %auth_token, "rejecting device auth refresh with an unknown refresh token");
+                DeviceAuthResponseDto::Unauthorized(Json(ErrorDto {
+                    message: "Invalid refresh token".into(),
+                }))
+            }
+            Err(e) => DeviceAuthResponseDto::InternalServerError(Json(ErrorDto {
+                message: format!("Database error: {}", e),
+            })),
+        }
+    }
+
+    fn auth_result(
+        access_token: String,
+        refresh_token: String,
+        user_key: String,
+    ) -> DeviceAuthResult {
+        DeviceAuthResult {
+            access_token,
+            refresh_token,
+            tracking_id: Uuid::new_v4().to_string(),
+            expires_in: Self::ACCESS_TOKEN_EXPIRES_IN,
+            token_type: "Bearer".into(),
+            user_key,
+        }
+    }
+}
+
+/// Position of the last book sent in a response cut off by `SYNC_ITEM_LIMIT`
+#[derive(Debug, Clone, PartialEq)]
+struct SyncCursor {
+    /// ABS `updatedAt` of the book
+    updated_at: i64,
+    item_id: Uuid,
+}
+
+impl SyncCursor {
+    fn after(item: &LibraryItem) -> Self {
+        Self {
+            updated_at: item.updated_at,
+            item_id: item.id,
+        }
+    }
+
+    /// Whether `item` comes after the cursor in `(updated_at, id)` order
+    fn is_before(&self, item: &LibraryItem) -> bool {
+        (self.updated_at, self.item_id) < (item.updated_at, item.id)
+    }
+}
+
+/// Whether the item has an ebook file in one of the allowed formats
+fn has_allowed_ebook(item: &LibraryItem, allowed: &[String]) -> bool {
+    item.ebook_formats()
+        .iter()
+        .any(|format| allowed.contains(format))
+}
+
+/// Whether the item carries the ABS tag, ignoring case
+fn has_tag(item: &LibraryItem, tag: &str) -> bool {
+    item.media
+        .as_ref()
+        .is_some_and(|m| m.tags.iter().any(|t| t.trim().eq_ignore_ascii_case(tag)))
+}
+
+/// Prune sync history once a day according to `SYNC_HISTORY_RETENTION_DAYS`
+pub async fn run_sync_history_pruning(
+    abs_client: Arc<AbsClient>,
+    config: Arc<Config>,
+    db: Arc<DatabaseConnection>,
+) {
+    let mut interval = tokio::time::interval(SYNC_HISTORY_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match SyncService::new(&abs_client, &config, &db)
+            .prune_sync_history()
+            .await
+        {
+            Ok(deleted) => tracing::info!(deleted, "pruned sync history"),
+            Err(e) => tracing::error!(error = %e, "failed to prune sync history"),
+        }
+    }
+}
+
+/// Books selected from one scan of the ABS library
+#[derive(Default)]
+struct BookScan {
+    books: Vec<(SyncType, LibraryItem)>,
+    /// Item offset to resume from on the next sync, `None` when the scan reached the end
+    next_offset: Option<u64>,
+    /// Suspicious conditions noticed while scanning
+    diagnostics: Vec<ScanDiagnostic>,
+    /// Books synced to the device that are no longer in the library; only known when the whole
+    /// library was scanned
+    removed: Vec<Uuid>,
+    /// ABS progress of the books new to the device, by item id
+    progress: HashMap<Uuid, MediaProgress>,
+    /// Whether every library was listed in full, rather than a scan window or without a failed
+    /// library
+    complete: bool,
+    /// Whether the scan continues the scan window of an earlier response
+    resumed: bool,
+    /// `(added_at, updated_at)` of books left for a later sync by the new book grace period
+    deferred: Vec<(i64, i64)>,
+    /// Items too broken to be considered at all, recorded as sync errors
+    malformed: Vec<MapFailure>,
+}
+
+/// Items listed from the synced libraries, see [`SyncService::list_libraries`]
+struct LibraryListing {
+    items: Vec<LibraryItem>,
+    /// Item offset to resume from on the next sync, `None` when the scan reached the end
+    next_offset: Option<u64>,
+    diagnostics: Vec<ScanDiagnostic>,
+    /// Whether every library was listed in full, so books missing from `items` are gone
+    complete: bool,
+    /// Whether the listing continues a scan window of an earlier response
+    resumed: bool,
+}
+
+/// Scan outcome that is not an error but usually points at a setup problem
+#[derive(Debug, PartialEq)]
+enum ScanDiagnostic {
+    /// ABS reported no items at all for the configured library. This is rarely a truly empty
+    /// library and more often a user without access to it or a library filter hiding everything.
+    EmptyLibrary { library_id: Uuid },
+    /// Listing one of several libraries failed; the others were synced without it
+    LibraryFailed { library_id: Uuid, error: String },
+}
+
+impl std::fmt::Display for ScanDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScanDiagnostic::EmptyLibrary { library_id } => write!(
+                f,
+                "ABS returned no items for library {}; check that the user can access it",
+                library_id
+            ),
+            ScanDiagnostic::LibraryFailed { library_id, error } => write!(
+                f,
+                "failed to list library {}, syncing the other libraries without it: {}",
+                library_id, error
+            ),
+        }
     }
 }
 
@@ -416,3 +1526,1409 @@ enum SyncType {
     /// Book was updated, requiring re-sync
     Update,
 }
+
+/// Entitlements and sync headers returned by the Kobo store
+#[derive(Debug, Clone)]
+struct StoreSyncResult {
+    entitlements: Vec<KoboSyncEntitlement>,
+    sync_token: String,
+    x_kobo_sync: Option<String>,
+    x_kobo_recent_reads: Option<String>,
+    /// How long the store allows the response to be reused, zero for `no-store`/`no-cache`
+    max_age: Option<Duration>,
+}
+
+impl StoreSyncResult {
+    fn from_response(
+        status: reqwest::StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) -> AbsKoboResult<Self> {
+        if !status.is_success() {
+            anyhow::bail!("Kobo store returned {}", status);
+        }
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map(|v| v.to_str().unwrap_or("").to_string())
+        };
+        Ok(Self {
+            entitlements: serde_json::from_str(body)?,
+            sync_token: header(KoboSyncToken::HEADER_NAME).unwrap_or_default(),
+            x_kobo_sync: header("x-kobo-sync"),
+            x_kobo_recent_reads: header("x-kobo-recent-reads"),
+            max_age: header("cache-control").and_then(|v| cache_control_max_age(&v)),
+        })
+    }
+
+    /// Empty store result echoing the token the device sent us
+    fn fallback(incoming_token: &str) -> Self {
+        Self {
+            entitlements: vec![],
+            sync_token: incoming_token.to_string(),
+            x_kobo_sync: None,
+            x_kobo_recent_reads: None,
+            max_age: None,
+        }
+    }
+}
+
+/// Max age allowed by a `Cache-Control` header, zero if it forbids reuse
+fn cache_control_max_age(value: &str) -> Option<Duration> {
+    value.split(',').map(str::trim).find_map(|directive| {
+        let directive = directive.to_ascii_lowercase();
+        if directive == "no-store" || directive == "no-cache" {
+            return Some(Duration::ZERO);
+        }
+        directive
+            .strip_prefix("max-age=")
+            .and_then(|secs| secs.trim_matches('"').parse().ok())
+            .map(Duration::from_secs)
+    })
+}
+
+/// Watermark after a finished sync: the newest delivered timestamp (ABS milliseconds), kept
+/// below the oldest book held back so it still counts as new or changed next time, and never
+/// moved backwards
+fn advance_watermark(
+    current: Option<DateTime<Utc>>,
+    delivered: impl Iterator<Item = i64>,
+    held_back: impl Iterator<Item = i64>,
+) -> Option<DateTime<Utc>> {
+    let Some(newest) = delivered.max() else {
+        return current;
+    };
+    let mark = held_back
+        .min()
+        .map_or(newest, |oldest_held| newest.min(oldest_held - 1));
+    let Some(mark) = DateTime::from_timestamp_millis(mark) else {
+        return current;
+    };
+    Some(current.map_or(mark, |current| current.max(mark)))
+}
+
+/// Apply the configured store error policy to the outcome of the store proxy call
+fn resolve_store_sync(
+    result: AbsKoboResult<StoreSyncResult>,
+    policy: StoreErrorPolicy,
+    incoming_token: &str,
+) -> Result<StoreSyncResult, ErrorDto> {
+    match (result, policy) {
+        (Ok(store), _) => Ok(store),
+        (Err(e), StoreErrorPolicy::Fallback) => {
+            tracing::warn!(error = %e, "Kobo store sync failed, serving local entitlements only");
+            Ok(StoreSyncResult::fallback(incoming_token))
+        }
+        (Err(e), StoreErrorPolicy::Fail) => {
+            tracing::error!(error = %e, "Kobo store sync failed");
+            Err(ErrorDto {
+                message: format!("Kobo store sync failed: {}", e),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine, prelude::BASE64_STANDARD};
+    use poem::{
+        EndpointExt, Route, get, handler,
+        web::{Data, Query},
+    };
+
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct ItemsQuery {
+        limit: usize,
+        page: Option<usize>,
+    }
+
+    /// Paged `/api/libraries/{id}/items` over a fixed list of items; `limit=0` returns everything
+    #[handler]
+    fn items(
+        Query(q): Query<ItemsQuery>,
+        Data(library): Data<&Vec<serde_json::Value>>,
+    ) -> poem::web::Json<serde_json::Value> {
+        let page = q.page.unwrap_or(0);
+        let limit = if q.limit == 0 { library.len() } else { q.limit };
+        let results: Vec<_> = library
+            .iter()
+            .skip(page * limit)
+            .take(limit)
+            .cloned()
+            .collect();
+        poem::web::Json(json!({
+            "results": results, "total": library.len(), "limit": q.limit, "page": page,
+            "sortDesc": true, "mediaType": "book", "minified": false,
+            "collapseseries": false, "include": ""
+        }))
+    }
+
+    /// Mock ABS serving `count` books in one library; returns the base URL, library and book ids
+    async fn serve_library(count: usize) -> (String, Uuid, Vec<Uuid>) {
+        let book_ids: Vec<Uuid> = (0..count).map(|_| Uuid::now_v7()).collect();
+        let library: Vec<_> = book_ids
+            .iter()
+            .enumerate()
+            .map(|(i, id)| crate::test_support::library_item_json(*id, &format!("Book {}", i)))
+            .collect();
+        let (base, library_id) = serve_items(library).await;
+        (base, library_id, book_ids)
+    }
+
+    #[handler]
+    fn library_item(
+        poem::web::Path(id): poem::web::Path<String>,
+        Data(library): Data<&Vec<serde_json::Value>>,
+    ) -> poem::Result<poem::web::Json<serde_json::Value>> {
+        library
+            .iter()
+            .find(|item| item["id"] == json!(id))
+            .map(|item| poem::web::Json(item.clone()))
+            .ok_or_else(|| poem::Error::from_status(poem::http::StatusCode::NOT_FOUND))
+    }
+
+    /// Mock ABS serving the given items in one library; returns the base URL and library id
+    async fn serve_items(library: Vec<serde_json::Value>) -> (String, Uuid) {
+        let library_id = Uuid::now_v7();
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(format!("/api/libraries/{}/items", library_id), get(items))
+                .at("/api/items/:id", get(library_item))
+                .data(library),
+        )
+        .await;
+        (base, library_id)
+    }
+
+    #[tokio::test]
+    async fn device_without_store_proxy_gets_only_local_entitlements() {
+        let (base, library_id, book_ids) = serve_library(2).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+        DeviceService::new(&db)
+            .set_store_proxy(device_id, Some(false), config.flags().store_proxy)
+            .await;
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+
+        let res = SyncService::new(&client, &config, &db)
+            .sync(device_id, token.clone(), &HeaderMap::new())
+            .await;
+
+        let SyncResponseDto::Ok(Json(entitlements), sync_token, ..) = res else {
+            panic!("expected a successful sync");
+        };
+        assert_eq!(entitlements.len(), book_ids.len());
+        assert!(entitlements.iter().all(|e| matches!(
+            e,
+            KoboSyncEntitlement::NewEntitlement(n) if book_ids.contains(&n.new_entitlement.book_entitlement.id)
+        )));
+        // The store was never asked, so the token is minted here: it keeps the device's store
+        // token and moves the watermarks up to the books just sent
+        let KoboSyncToken::FullToken {
+            raw_kobo_store_token,
+            details,
+        } = KoboSyncToken::from_request(&sync_token).unwrap()
+        else {
+            panic!("expected a full token, got {}", sync_token);
+        };
+        assert_eq!(raw_kobo_store_token, "abc");
+        let item = crate::test_support::library_item_json(book_ids[0], "Book 0");
+        assert_eq!(
+            details.books_last_modified.map(|dt| dt.timestamp_millis()),
+            item["updatedAt"].as_i64()
+        );
+        assert_eq!(
+            details.books_last_created.map(|dt| dt.timestamp_millis()),
+            item["addedAt"].as_i64()
+        );
+    }
+
+    #[tokio::test]
+    async fn store_only_token_resumes_from_the_stored_sync_state() {
+        let (base, library_id, book_ids) = serve_library(2).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), first_token, ..) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert_eq!(entitlements.len(), book_ids.len());
+
+        // A device presenting only a store token continues from the timestamps recorded with
+        // the last sync instead of receiving the whole library again
+        let SyncResponseDto::Ok(Json(entitlements), sync_token, _, sync_mode, _) = service
+            .sync(device_id, "store.token".into(), &HeaderMap::new())
+            .await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert!(entitlements.is_empty());
+        assert_eq!(sync_mode.as_deref(), Some("delta"));
+        let details = |token: &str| match KoboSyncToken::from_request(token).unwrap() {
+            KoboSyncToken::FullToken { details, .. } => details,
+            other => panic!("expected our token, got {:?}", other),
+        };
+        let (first, resumed) = (details(&first_token), details(&sync_token));
+        assert!(first.books_last_modified.is_some());
+        assert_eq!(resumed.books_last_modified, first.books_last_modified);
+        assert_eq!(resumed.books_last_created, first.books_last_created);
+    }
+
+    #[tokio::test]
+    async fn second_sync_with_the_minted_token_is_delta() {
+        let (base, library_id, book_ids) = serve_library(2).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), token, _, sync_mode, _) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert_eq!(entitlements.len(), book_ids.len());
+        assert_eq!(sync_mode.as_deref(), Some("full"));
+
+        let SyncResponseDto::Ok(Json(entitlements), _, _, sync_mode, _) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert!(entitlements.is_empty());
+        assert_eq!(sync_mode.as_deref(), Some("delta"));
+    }
+
+    #[tokio::test]
+    async fn minted_token_moves_past_the_incoming_watermarks() {
+        let (old, new) = (Uuid::now_v7(), Uuid::now_v7());
+        let old_item = crate::test_support::library_item_json(old, "Old");
+        let mut new_item = crate::test_support::library_item_json(new, "New");
+        new_item["addedAt"] = json!(old_item["addedAt"].as_i64().unwrap() + 1000);
+        new_item["updatedAt"] = json!(old_item["updatedAt"].as_i64().unwrap() + 1000);
+        let (base, library_id) = serve_items(vec![old_item.clone(), new_item.clone()]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+
+        // The device already has the old book
+        let at = |item: &serde_json::Value, key: &str| {
+            DateTime::from_timestamp_millis(item[key].as_i64().unwrap()).unwrap()
+        };
+        let incoming = KoboFullTokenDetails {
+            books_last_modified: Some(at(&old_item, "updatedAt")),
+            books_last_created: Some(at(&old_item, "addedAt")),
+            archive_last_modified: None,
+            reading_state_last_modified: None,
+            tags_last_modified: None,
+        };
+        let SyncResponseDto::Ok(Json(entitlements), sync_token, ..) =
+            SyncService::new(&client, &config, &db)
+                .sync(
+                    device_id,
+                    incoming.to_device_token("store-token"),
+                    &HeaderMap::new(),
+                )
+                .await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert!(matches!(
+            entitlements.as_slice(),
+            [KoboSyncEntitlement::NewEntitlement(n)] if n.new_entitlement.book_entitlement.id == new
+        ));
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&BASE64_STANDARD.decode(&sync_token).unwrap()).unwrap();
+        assert_eq!(json["raw_kobo_store_token"], "store-token");
+        assert_eq!(
+            json["books_last_modified"],
+            at(&new_item, "updatedAt").to_rfc3339()
+        );
+        assert_eq!(
+            json["books_last_created"],
+            at(&new_item, "addedAt").to_rfc3339()
+        );
+    }
+
+    #[test]
+    fn watermarks_stop_short_of_held_back_books() {
+        let at = |millis| DateTime::from_timestamp_millis(millis);
+        assert_eq!(
+            advance_watermark(at(10), [20, 30].into_iter(), std::iter::empty()),
+            at(30)
+        );
+        // A book held back at 25 still has to look changed to the next sync
+        assert_eq!(
+            advance_watermark(at(10), [20, 30].into_iter(), [25].into_iter()),
+            at(24)
+        );
+        // Watermarks never move backwards, and stay put when nothing was sent
+        assert_eq!(
+            advance_watermark(at(10), [20].into_iter(), [5].into_iter()),
+            at(10)
+        );
+        assert_eq!(
+            advance_watermark(None, std::iter::empty(), std::iter::empty()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn descriptions_are_omitted_when_disabled() {
+        let book = Uuid::now_v7();
+        let mut item = crate::test_support::library_item_json(book, "Described");
+        item["media"]["metadata"]["description"] = json!("A very long blurb");
+        let (base, library_id) = serve_items(vec![item]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        config.flags.sync_include_description = false;
+
+        let res = SyncService::new(&client, &config, &db)
+            .sync(
+                device_id,
+                BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#),
+                &HeaderMap::new(),
+            )
+            .await;
+
+        let SyncResponseDto::Ok(Json(entitlements), ..) = res else {
+            panic!("expected a successful sync");
+        };
+        let [KoboSyncEntitlement::NewEntitlement(new)] = entitlements.as_slice() else {
+            panic!("expected one new entitlement");
+        };
+        assert_eq!(new.new_entitlement.book_metadata.description, None);
+    }
+
+    #[tokio::test]
+    async fn sync_records_redacted_token() {
+        let (base, library_id, _) = serve_library(1).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+        DeviceService::new(&db)
+            .set_store_proxy(device_id, Some(false), config.flags().store_proxy)
+            .await;
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"secret-store-token"}"#);
+
+        SyncService::new(&client, &config, &db)
+            .sync(device_id, token, &HeaderMap::new())
+            .await;
+
+        let device = entities::devices::Entity::find_by_id(device_id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        let recorded = device.last_sync_token.expect("sync token recorded");
+        assert!(!recorded.contains("secret-store-token"));
+        assert!(recorded.contains("****oken"));
+        assert!(device.last_sync_token_at.is_some());
+    }
+
+    /// Run a sync and return the ids of the new entitlements and the `X-Kobo-Sync` header
+    async fn sync_new_ids(
+        service: &SyncService<'_>,
+        device_id: Uuid,
+    ) -> (Vec<Uuid>, Option<String>) {
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), _, x_kobo_sync, ..) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        let ids = entitlements
+            .iter()
+            .filter_map(|e| match e {
+                KoboSyncEntitlement::NewEntitlement(n) => {
+                    Some(n.new_entitlement.book_entitlement.id)
+                }
+                _ => None,
+            })
+            .collect();
+        (ids, x_kobo_sync)
+    }
+
+    #[tokio::test]
+    async fn new_books_carry_the_users_abs_progress() {
+        #[handler]
+        fn me(Data(library): Data<&Vec<serde_json::Value>>) -> poem::web::Json<serde_json::Value> {
+            // Only the first book has been read in the ABS web reader
+            poem::web::Json(json!({
+                "id": "usr_1",
+                "mediaProgress": [{
+                    "libraryItemId": library[0]["id"],
+                    "progress": 0.4,
+                    "ebookLocation": "epubcfi(/6/8!/4/2/1:0)",
+                    "ebookProgress": 0.4,
+                    "isFinished": false,
+                    "lastUpdate": 1747214658742_i64,
+                    "startedAt": 1747000000000_i64
+                }]
+            }))
+        }
+
+        let library_id = Uuid::now_v7();
+        let library: Vec<_> = (0..2)
+            .map(|i| crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i)))
+            .collect();
+        let read_id: Uuid = serde_json::from_value(library[0]["id"].clone()).unwrap();
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(format!("/api/libraries/{}/items", library_id), get(items))
+                .at("/api/me", get(me))
+                .data(library),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), ..) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert_eq!(entitlements.len(), 2);
+        for entitlement in &entitlements {
+            let KoboSyncEntitlement::NewEntitlement(new) = entitlement else {
+                panic!("expected a new entitlement");
+            };
+            let book = &new.new_entitlement;
+            if book.book_entitlement.id == read_id {
+                let state = book.reading_state.as_ref().expect("reading state");
+                assert!(matches!(
+                    state.status_info.status,
+                    KoboSyncedStatus::Reading
+                ));
+                assert_eq!(state.current_bookmark.progress_percent, Some(40.0));
+            } else {
+                assert!(book.reading_state.is_none());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_batch_details_take_a_bounded_number_of_abs_calls() {
+        use poem::{Endpoint, post, web::Path};
+
+        #[handler]
+        fn batch(
+            Data(library): Data<&Vec<serde_json::Value>>,
+        ) -> poem::web::Json<serde_json::Value> {
+            let expanded: Vec<_> = library
+                .iter()
+                .cloned()
+                .map(|mut item| {
+                    let metadata = &mut item["media"]["metadata"];
+                    metadata["authors"] = json!([{ "id": "aut_1" }, { "id": "aut_2" }]);
+                    metadata["series"] =
+                        json!([{ "id": "ser_1", "name": "Saga", "sequence": "1" }]);
+                    item
+                })
+                .collect();
+            poem::web::Json(json!({ "libraryItems": expanded }))
+        }
+
+        #[handler]
+        fn status() -> &'static str {
+            r#"{"app":"audiobookshelf","serverVersion":"2.26.0","isInit":true}"#
+        }
+
+        #[handler]
+        fn author(Path(id): Path<String>) -> poem::web::Json<serde_json::Value> {
+            poem::web::Json(json!({ "id": id, "name": format!("Author {}", id) }))
+        }
+
+        let library_id = Uuid::now_v7();
+        let library: Vec<_> = (0..10)
+            .map(|i| crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i)))
+            .collect();
+        let calls = Arc::new(Mutex::new(Vec::<String>::new()));
+        let recorded = calls.clone();
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(format!("/api/libraries/{}/items", library_id), get(items))
+                .at("/api/items/batch/get", post(batch))
+                .at("/api/authors/:id", get(author))
+                .at("/status", get(status))
+                .data(library)
+                .around(move |ep, req| {
+                    let recorded = recorded.clone();
+                    async move {
+                        recorded.lock().unwrap().push(req.uri().path().to_string());
+                        ep.call(req).await
+                    }
+                }),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), ..) =
+            service.sync(device_id, token, &HeaderMap::new()).await
+        else {
+            panic!("expected a successful sync");
+        };
+        assert_eq!(entitlements.len(), 10);
+        for entitlement in &entitlements {
+            let KoboSyncEntitlement::NewEntitlement(new) = entitlement else {
+                panic!("expected a new entitlement");
+            };
+            let metadata = &new.new_entitlement.book_metadata;
+            assert_eq!(metadata.series.as_ref().unwrap().name, "Saga");
+            assert_eq!(
+                metadata.contributors.as_deref(),
+                Some(&["Author aut_1".to_string(), "Author aut_2".to_string()][..])
+            );
+        }
+
+        let calls = calls.lock().unwrap();
+        let count = |path: &str| calls.iter().filter(|c| c.starts_with(path)).count();
+        assert_eq!(count("/api/items/batch/get"), 1);
+        assert_eq!(count("/api/authors/"), 2);
+        // Library lookup, listing, server status, batch and one call per distinct author
+        assert!(calls.len() <= 6, "{:?}", calls);
+    }
+
+    #[tokio::test]
+    async fn https_forwarded_sync_gets_https_download_urls() {
+        let (base, library_id, book_ids) = serve_library(1).await;
+        assert!(base.starts_with("http://"));
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert("host", "kobo.example".parse().unwrap());
+
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let SyncResponseDto::Ok(Json(entitlements), ..) =
+            service.sync(device_id, token, &headers).await
+        else {
+            panic!("expected a successful sync");
+        };
+        let KoboSyncEntitlement::NewEntitlement(new) = &entitlements[0] else {
+            panic!("expected a new entitlement");
+        };
+        let urls = &new.new_entitlement.book_metadata.download_urls;
+        assert_eq!(
+            urls,
+            &vec![format!(
+                "https://kobo.example/kobo/{}/v1/download/{}/kepub",
+                device_id, book_ids[0]
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn resent_book_is_new_on_next_sync() {
+        let (base, library_id, book_ids) = serve_library(2).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+        let (synced, _) = sync_new_ids(&service, device_id).await;
+        assert_eq!(synced.len(), 2);
+
+        let BookResendResponseDto::Ok(Json(resend)) = service.resend(device_id, book_ids[1]).await
+        else {
+            panic!("expected the book to be reset");
+        };
+        assert!(resend.was_synced);
+
+        let (synced, _) = sync_new_ids(&service, device_id).await;
+        assert_eq!(synced, vec![book_ids[1]]);
+        assert!(matches!(
+            service.resend(device_id, Uuid::now_v7()).await,
+            BookResendResponseDto::NotFound(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn continue_syncs_resume_after_cursor() {
+        let (base, library_id, book_ids) = serve_library(SyncService::SYNC_ITEM_LIMIT + 1).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        let service = SyncService::new(&client, &config, &db);
+
+        let (first, x_kobo_sync) = sync_new_ids(&service, device_id).await;
+        assert_eq!(first.len(), SyncService::SYNC_ITEM_LIMIT);
+        assert_eq!(x_kobo_sync.as_deref(), Some("continue"));
+        let cursor = service.load_cursor(device_id).await.unwrap().unwrap();
+        assert_eq!(&cursor.item_id, first.last().unwrap());
+
+        let (second, x_kobo_sync) = sync_new_ids(&service, device_id).await;
+        assert_eq!(second.len(), 1);
+        assert!(!first.contains(&second[0]));
+        assert!(book_ids.contains(&second[0]));
+        assert_eq!(x_kobo_sync, None);
+        assert_eq!(service.load_cursor(device_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_split_across_syncs() {
+        let library: Vec<_> = (0..3)
+            .map(|i| {
+                let mut item =
+                    crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i));
+                item["media"]["metadata"]["description"] = json!("x".repeat(3000));
+                item
+            })
+            .collect();
+        let (base, library_id) = serve_items(library).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        // Books take about 4.5 KB each with their descriptions, so two fit but not three
+        config.sync_max_response_kb = Some(10);
+        let service = SyncService::new(&client, &config, &db);
+
+        let (first, x_kobo_sync) = sync_new_ids(&service, device_id).await;
+        assert_eq!(first.len(), 2);
+        assert_eq!(x_kobo_sync.as_deref(), Some("continue"));
+
+        let (second, x_kobo_sync) = sync_new_ids(&service, device_id).await;
+        assert_eq!(second.len(), 1);
+        assert!(!first.contains(&second[0]));
+        assert_eq!(x_kobo_sync, None);
+    }
+
+    #[tokio::test]
+    async fn size_cut_off_syncs_continue_from_the_stored_cursor() {
+        let library: Vec<_> = (0..5)
+            .map(|i| {
+                let mut item =
+                    crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i));
+                item["media"]["metadata"]["description"] = json!("x".repeat(3000));
+                item
+            })
+            .collect();
+        let book_ids: Vec<Uuid> = library
+            .iter()
+            .map(|item| item["id"].as_str().unwrap().parse().unwrap())
+            .collect();
+        let (base, library_id) = serve_items(library).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        // Two books of about 4.5 KB fit per response
+        config.sync_max_response_kb = Some(10);
+        let service = SyncService::new(&client, &config, &db);
+
+        let mut token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        let mut headers = HeaderMap::new();
+        let mut pages = Vec::new();
+        loop {
+            let SyncResponseDto::Ok(Json(entitlements), sync_token, x_kobo_sync, ..) =
+                service.sync(device_id, token.clone(), &headers).await
+            else {
+                panic!("expected a successful sync");
+            };
+            let ids: Vec<Uuid> = entitlements
+                .iter()
+                .filter_map(|e| match e {
+                    KoboSyncEntitlement::NewEntitlement(n) => {
+                        Some(n.new_entitlement.book_entitlement.id)
+                    }
+                    _ => None,
+                })
+                .collect();
+            let cursor = service.load_cursor(device_id).await.unwrap();
+            pages.push(ids.clone());
+            if x_kobo_sync.as_deref() != Some("continue") {
+                assert_eq!(cursor, None);
+                break;
+            }
+            // The cursor points past the last book sent
+            assert_eq!(cursor.unwrap().item_id, *ids.last().unwrap());
+            assert!(pages.len() < 5, "sync never finished");
+            token = sync_token;
+            headers.insert("x-kobo-sync", "continue".parse().unwrap());
+        }
+
+        assert_eq!(
+            pages.iter().map(Vec::len).collect::<Vec<_>>(),
+            vec![2, 2, 1]
+        );
+        // Every book arrives exactly once
+        let mut synced: Vec<Uuid> = pages.concat();
+        synced.sort();
+        let mut expected = book_ids;
+        expected.sort();
+        assert_eq!(synced, expected);
+    }
+
+    #[tokio::test]
+    async fn scan_cap_continues_and_resumes_across_syncs() {
+        let (base, library_id, book_ids) = serve_library(3).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.sync_max_scan_items = Some(2);
+        let service = SyncService::new(&client, &config, &db);
+
+        let first = service
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert_eq!(first.books.len(), 2);
+        assert_eq!(first.next_offset, Some(2));
+        service
+            .advance_scan(device_id, first.next_offset)
+            .await
+            .unwrap();
+
+        let second = service
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = second.books.iter().map(|(_, item)| item.id).collect();
+        assert_eq!(ids, vec![book_ids[2]]);
+        assert_eq!(second.next_offset, None);
+    }
+
+    #[tokio::test]
+    async fn uncapped_scan_walks_every_page_of_a_large_library() {
+        use crate::abs_client::ALL_ITEMS_PAGE_SIZE;
+
+        /// Like `items`, but never returns more than one page, as a server capping `limit` would
+        #[handler]
+        fn capped_items(
+            Query(q): Query<ItemsQuery>,
+            Data(library): Data<&Vec<serde_json::Value>>,
+        ) -> poem::web::Json<serde_json::Value> {
+            let max = ALL_ITEMS_PAGE_SIZE as usize;
+            let limit = if q.limit == 0 { max } else { q.limit.min(max) };
+            let page = q.page.unwrap_or(0);
+            let results: Vec<_> = library.iter().skip(page * limit).take(limit).collect();
+            poem::web::Json(json!({
+                "results": results, "total": library.len(), "limit": q.limit, "page": page,
+                "sortDesc": true, "mediaType": "book", "minified": false,
+                "collapseseries": false, "include": ""
+            }))
+        }
+
+        let library: Vec<_> = (0..=ALL_ITEMS_PAGE_SIZE)
+            .map(|i| crate::test_support::library_item_json(Uuid::now_v7(), &format!("Book {}", i)))
+            .collect();
+        let library_id = Uuid::now_v7();
+        let base = crate::test_support::serve(
+            Route::new()
+                .at(
+                    format!("/api/libraries/{}/items", library_id),
+                    get(capped_items),
+                )
+                .data(library),
+        )
+        .await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert_eq!(scan.books.len(), ALL_ITEMS_PAGE_SIZE as usize + 1);
+        assert_eq!(scan.next_offset, None);
+    }
+
+    #[tokio::test]
+    async fn failing_library_does_not_block_the_others() {
+        let book = Uuid::now_v7();
+        let (base, library_id) =
+            serve_items(vec![crate::test_support::library_item_json(book, "Book")]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        let missing = Uuid::now_v7();
+        config.library_ids = vec![missing, library_id];
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = scan.books.iter().map(|(_, item)| item.id).collect();
+        assert_eq!(ids, vec![book]);
+        // Books of the failed library may still exist, so nothing counts as removed
+        assert!(scan.removed.is_empty());
+        assert!(matches!(
+            scan.diagnostics.as_slice(),
+            [ScanDiagnostic::LibraryFailed { library_id, .. }] if *library_id == missing
+        ));
+
+        config.library_ids = vec![missing];
+        assert!(
+            SyncService::new(&client, &config, &db)
+                .collect_books_to_sync(device_id, &None, &None, None)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_items_stay_listed_across_continue_syncs() {
+        let broken = Uuid::now_v7();
+        let mut broken_item = crate::test_support::library_item_json(broken, "Broken");
+        broken_item.as_object_mut().unwrap().remove("media");
+        broken_item["updatedAt"] = json!(broken_item["updatedAt"].as_i64().unwrap() - 1000);
+        let library = vec![
+            broken_item,
+            crate::test_support::library_item_json(Uuid::now_v7(), "First"),
+            crate::test_support::library_item_json(Uuid::now_v7(), "Second"),
+        ];
+        let (base, library_id) = serve_items(library).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let mut config = crate::test_support::config(&base, library_id);
+        config.flags.store_proxy = false;
+        // One book per response
+        config.sync_max_response_kb = Some(1);
+        let cli = poem::test::TestClient::new(Route::new().nest(
+            "/",
+            poem_openapi::OpenApiService::new(crate::test_support::api(config, db), "test", "test"),
+        ));
+
+        let mut token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+        for continues in [true, false] {
+            let resp = cli
+                .get(format!("/kobo/{}/v1/library/sync", device_id))
+                .header("X-Kobo-Sync-Token", &token)
+                .send()
+                .await;
+            resp.assert_status_is_ok();
+            if continues {
+                resp.assert_header("x-kobo-sync", "continue");
+            } else {
+                resp.assert_header_is_not_exist("x-kobo-sync");
+            }
+            token = resp.0.headers()[KoboSyncToken::HEADER_NAME]
+                .to_str()
+                .unwrap()
+                .to_string();
+        }
+
+        // The item only turned up in the first response, and is still listed after the second
+        let resp = cli
+            .get(format!("/v1/devices/{}/sync-errors", device_id))
+            .send()
+            .await;
+        resp.assert_status_is_ok();
+        let json = resp.json().await;
+        let errors = json.value().array();
+        errors.assert_len(1);
+        errors
+            .get(0)
+            .object()
+            .get("abs_item_id")
+            .assert_string(&broken.to_string());
+        errors
+            .get(0)
+            .object()
+            .get("error")
+            .assert_string(&format!("Item {} has no media", broken));
+    }
+
+    #[tokio::test]
+    async fn new_books_within_grace_period_are_deferred() {
+        let (settled, fresh) = (Uuid::now_v7(), Uuid::now_v7());
+        let mut fresh_item = crate::test_support::library_item_json(fresh, "Still scanning");
+        fresh_item["addedAt"] = json!(Utc::now().timestamp_millis() - 10_000);
+        let library = vec![
+            crate::test_support::library_item_json(settled, "Settled"),
+            fresh_item,
+        ];
+        let (base, library_id) = serve_items(library).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.sync_new_book_grace_secs = 60;
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = scan.books.iter().map(|(_, item)| item.id).collect();
+        assert_eq!(ids, vec![settled]);
+
+        config.sync_new_book_grace_secs = 0;
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert_eq!(scan.books.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn old_book_updated_since_last_sync_is_an_update() {
+        let (old, added) = (Uuid::now_v7(), Uuid::now_v7());
+        let watermark = Utc.timestamp_millis_opt(1_750_000_000_000).unwrap();
+        let mut old_item = crate::test_support::library_item_json(old, "Old");
+        old_item["addedAt"] = json!(1_700_000_000_000_i64);
+        old_item["updatedAt"] = json!(1_760_000_000_000_i64);
+        let mut added_item = crate::test_support::library_item_json(added, "Added");
+        added_item["addedAt"] = json!(1_760_000_000_000_i64);
+        added_item["updatedAt"] = json!(1_760_000_000_000_i64);
+        let (base, library_id) = serve_items(vec![old_item, added_item]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &Some(watermark), &Some(watermark), None)
+            .await
+            .unwrap();
+
+        assert_eq!(scan.books.len(), 2);
+        for (sync_type, item) in &scan.books {
+            if item.id == old {
+                assert!(matches!(sync_type, SyncType::Update));
+            } else {
+                assert!(matches!(sync_type, SyncType::New));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn items_without_media_are_skipped() {
+        let (broken, book) = (Uuid::now_v7(), Uuid::now_v7());
+        let mut broken_item = crate::test_support::library_item_json(broken, "Broken");
+        broken_item.as_object_mut().unwrap().remove("media");
+        let library = vec![
+            broken_item,
+            crate::test_support::library_item_json(book, "Fine"),
+        ];
+        let (base, library_id) = serve_items(library).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = scan.books.iter().map(|(_, item)| item.id).collect();
+        assert_eq!(ids, vec![book]);
+    }
+
+    #[tokio::test]
+    async fn removed_books_follow_keep_archived_visible() {
+        let (base, library_id, _) = serve_library(1).await;
+        let (db, visible_device) = crate::test_support::db_with_device().await;
+        let hidden_device = crate::test_support::insert_device(&db).await;
+        let client = AbsClient::new(&base).unwrap();
+        let archived = Uuid::now_v7();
+
+        for (device_id, keep_visible) in [(visible_device, true), (hidden_device, false)] {
+            book_sync::Entity::insert(book_sync::ActiveModel {
+                id: Set(Uuid::now_v7()),
+                device_id: Set(device_id),
+                abs_item_id: Set(archived.to_string()),
+                timestamp: Set(Utc::now()),
+            })
+            .exec(&db)
+            .await
+            .unwrap();
+            let mut config = crate::test_support::config(&base, library_id);
+            config.flags.store_proxy = false;
+            config.flags.keep_archived_visible = keep_visible;
+            let service = SyncService::new(&client, &config, &db);
+
+            let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+            let SyncResponseDto::Ok(Json(entitlements), ..) =
+                service.sync(device_id, token, &HeaderMap::new()).await
+            else {
+                panic!("expected a successful sync");
+            };
+            let removed: Vec<_> = entitlements
+                .iter()
+                .filter_map(|e| match e {
+                    KoboSyncEntitlement::RemovedEntitlement(r) => {
+                        Some(&r.changed_entitlement.book_entitlement)
+                    }
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(removed.len(), 1);
+            assert_eq!(removed[0].id, archived);
+            assert!(removed[0].is_removed);
+            assert_eq!(removed[0].is_hidden_from_archive, !keep_visible);
+
+            // The removal is only sent once
+            let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+            let SyncResponseDto::Ok(Json(entitlements), ..) =
+                service.sync(device_id, token, &HeaderMap::new()).await
+            else {
+                panic!("expected a successful sync");
+            };
+            assert!(
+                !entitlements
+                    .iter()
+                    .any(|e| matches!(e, KoboSyncEntitlement::RemovedEntitlement(_)))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_counts_new_and_updated_books() {
+        let (base, library_id, book_ids) = serve_library(3).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+        // Synced before the book's last ABS update, so it counts as updated
+        book_sync::Entity::insert(book_sync::ActiveModel {
+            id: Set(Uuid::now_v7()),
+            device_id: Set(device_id),
+            abs_item_id: Set(book_ids[0].to_string()),
+            timestamp: Set(Utc.timestamp_millis_opt(1_700_000_000_000).unwrap()),
+        })
+        .exec(&db)
+        .await
+        .unwrap();
+
+        let PendingSyncResponseDto::Ok(Json(pending)) = SyncService::new(&client, &config, &db)
+            .pending(device_id)
+            .await
+        else {
+            panic!("expected pending counts");
+        };
+        assert_eq!(pending.new, 2);
+        assert_eq!(pending.updated, 1);
+    }
+
+    #[tokio::test]
+    async fn pending_counts_from_the_stored_sync_state() {
+        let (base, library_id, _) = serve_library(3).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+        let service = SyncService::new(&client, &config, &db);
+        // The device was last told about everything up to now
+        service
+            .store_sync_state(
+                device_id,
+                &KoboFullTokenDetails {
+                    books_last_modified: Some(Utc::now()),
+                    books_last_created: Some(Utc::now()),
+                    archive_last_modified: None,
+                    reading_state_last_modified: None,
+                    tags_last_modified: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let PendingSyncResponseDto::Ok(Json(pending)) = service.pending(device_id).await else {
+            panic!("expected pending counts");
+        };
+        assert_eq!(pending.new, 0);
+        assert_eq!(pending.updated, 0);
+    }
+
+    #[tokio::test]
+    async fn sync_history_of_removed_books_is_pruned() {
+        let (base, library_id, book_ids) = serve_library(1).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.sync_history_retention_days = Some(30);
+        let long_ago = Utc::now() - chrono::Duration::days(90);
+        let removed = Uuid::now_v7().to_string();
+        let rows = [
+            // Still in the library, kept however old
+            (book_ids[0].to_string(), long_ago),
+            // Removed from the library long ago
+            (removed.clone(), long_ago),
+            // Removed, but recently synced
+            (Uuid::now_v7().to_string(), Utc::now()),
+        ];
+        for (abs_item_id, timestamp) in rows {
+            book_sync::Entity::insert(book_sync::ActiveModel {
+                id: Set(Uuid::now_v7()),
+                device_id: Set(device_id),
+                abs_item_id: Set(abs_item_id),
+                timestamp: Set(timestamp),
+            })
+            .exec(&db)
+            .await
+            .unwrap();
+        }
+
+        let deleted = SyncService::new(&client, &config, &db)
+            .prune_sync_history()
+            .await
+            .unwrap();
+
+        assert_eq!(deleted, 1);
+        let left: Vec<String> = BookSync::find()
+            .all(&db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.abs_item_id)
+            .collect();
+        assert_eq!(left.len(), 2);
+        assert!(left.contains(&book_ids[0].to_string()));
+        assert!(!left.contains(&removed));
+    }
+
+    #[tokio::test]
+    async fn only_items_with_allowed_ebooks_are_collected() {
+        let (ebook, audio, mixed) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        let mut ebook_item = crate::test_support::library_item_json(ebook, "Ebook");
+        ebook_item["media"]["ebookFormat"] = json!("epub");
+        let mut audio_item = crate::test_support::library_item_json(audio, "Audio");
+        audio_item["media"]["ebookFormat"] = json!(null);
+        audio_item["media"]["numAudioFiles"] = json!(12);
+        audio_item["media"]["numTracks"] = json!(12);
+        let mut mixed_item = crate::test_support::library_item_json(mixed, "Mixed");
+        mixed_item["media"]["ebookFormat"] = json!("epub");
+        mixed_item["media"]["numAudioFiles"] = json!(12);
+        mixed_item["media"]["numTracks"] = json!(12);
+        let (base, library_id) = serve_items(vec![ebook_item, audio_item, mixed_item]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let mut ids: Vec<Uuid> = scan.books.iter().map(|(_, item)| item.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![ebook, mixed]);
+
+        config.allowed_ebook_formats = vec!["pdf".into()];
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert!(scan.books.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_books_with_the_device_sync_tag_are_collected() {
+        let (tagged, untagged) = (Uuid::now_v7(), Uuid::now_v7());
+        let mut tagged_item = crate::test_support::library_item_json(tagged, "Tagged");
+        tagged_item["media"]["tags"] = json!(["Fiction", "kobo"]);
+        let mut untagged_item = crate::test_support::library_item_json(untagged, "Untagged");
+        untagged_item["media"]["tags"] = json!(["Fiction"]);
+        let (base, library_id) = serve_items(vec![tagged_item, untagged_item]).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+
+        DeviceService::new(&db)
+            .set_sync_tag(device_id, Some("Kobo".into()))
+            .await;
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        let ids: Vec<Uuid> = scan.books.iter().map(|(_, item)| item.id).collect();
+        assert_eq!(ids, vec![tagged]);
+    }
+
+    #[tokio::test]
+    async fn empty_library_is_reported() {
+        let (base, library_id, _) = serve_library(0).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let config = crate::test_support::config(&base, library_id);
+        let service = SyncService::new(&client, &config, &db);
+
+        let scan = service
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert!(scan.books.is_empty());
+        assert_eq!(
+            scan.diagnostics,
+            vec![ScanDiagnostic::EmptyLibrary { library_id }]
+        );
+
+        let (base, library_id, _) = serve_library(1).await;
+        let config = crate::test_support::config(&base, library_id);
+        let client = AbsClient::new(&base).unwrap();
+        let scan = SyncService::new(&client, &config, &db)
+            .collect_books_to_sync(device_id, &None, &None, None)
+            .await
+            .unwrap();
+        assert!(scan.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn store_error_falls_back_to_local_entitlements() {
+        let result = StoreSyncResult::from_response(
+            reqwest::StatusCode::INTERNAL_SERVER_ERROR,
+            &HeaderMap::new(),
+            "Internal Server Error",
+        );
+        assert!(result.is_err());
+
+        let store =
+            resolve_store_sync(result, StoreErrorPolicy::Fallback, "incoming-token").unwrap();
+        assert!(store.entitlements.is_empty());
+        assert_eq!(store.sync_token, "incoming-token");
+    }
+
+    #[tokio::test]
+    async fn failing_store_still_serves_local_entitlements() {
+        #[handler]
+        fn store_sync() -> poem::Response {
+            poem::Response::builder()
+                .status(poem::http::StatusCode::INTERNAL_SERVER_ERROR)
+                .body("Internal Server Error")
+        }
+
+        let store =
+            crate::test_support::serve(Route::new().at("/v1/library/sync", get(store_sync))).await;
+        let (base, library_id, book_ids) = serve_library(2).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.kobo_store_url = store;
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+
+        let SyncResponseDto::Ok(Json(entitlements), sync_token, ..) =
+            SyncService::new(&client, &config, &db)
+                .sync(device_id, token.clone(), &HeaderMap::new())
+                .await
+        else {
+            panic!("expected the sync to fall back to local entitlements");
+        };
+        assert_eq!(entitlements.len(), book_ids.len());
+        assert_eq!(sync_token, token);
+    }
+
+    #[test]
+    fn store_error_fails_when_configured() {
+        let result = StoreSyncResult::from_response(
+            reqwest::StatusCode::UNAUTHORIZED,
+            &HeaderMap::new(),
+            "",
+        );
+        assert!(resolve_store_sync(result, StoreErrorPolicy::Fail, "incoming-token").is_err());
+    }
+
+    #[tokio::test]
+    async fn repeat_syncs_reuse_store_response() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        };
+
+        #[handler]
+        fn store_sync(Data(calls): Data<&Arc<AtomicUsize>>) -> poem::Response {
+            calls.fetch_add(1, Ordering::SeqCst);
+            poem::Response::builder()
+                .header(KoboSyncToken::HEADER_NAME, "store-token")
+                .body("[]")
+        }
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let store = crate::test_support::serve(
+            Route::new()
+                .at("/v1/library/sync", get(store_sync))
+                .data(calls.clone()),
+        )
+        .await;
+        let (base, library_id, _) = serve_library(0).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.kobo_store_url = store;
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+
+        let service = SyncService::new(&client, &config, &db);
+        for _ in 0..2 {
+            let res = service
+                .sync(device_id, token.clone(), &HeaderMap::new())
+                .await;
+            let SyncResponseDto::Ok(_, sync_token, ..) = res else {
+                panic!("expected a successful sync");
+            };
+            assert_eq!(sync_token, "store-token");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_store_falls_back_to_local_entitlements() {
+        #[handler]
+        async fn store_sync() -> &'static str {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            "[]"
+        }
+
+        let store =
+            crate::test_support::serve(Route::new().at("/v1/library/sync", get(store_sync))).await;
+        let (base, library_id, _) = serve_library(1).await;
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let client = AbsClient::new(&base).unwrap();
+        let mut config = crate::test_support::config(&base, library_id);
+        config.kobo_store_url = store;
+        config.store_proxy_timeout_secs = 1;
+        let token = BASE64_STANDARD.encode(r#"{"raw_kobo_store_token":"abc"}"#);
+
+        let started = Instant::now();
+        let res = SyncService::new(&client, &config, &db)
+            .sync(device_id, token, &HeaderMap::new())
+            .await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let SyncResponseDto::Ok(Json(entitlements), ..) = res else {
+            panic!("expected the sync to fall back to local entitlements");
+        };
+        assert_eq!(entitlements.len(), 1);
+    }
+
+    #[test]
+    fn store_cache_control_is_respected() {
+        assert_eq!(
+            cache_control_max_age("private, max-age=5"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(cache_control_max_age("no-store"), Some(Duration::ZERO));
+        assert_eq!(cache_control_max_age("private"), None);
+    }
+
+    #[test]
+    fn store_success_keeps_store_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(KoboSyncToken::HEADER_NAME, "store-token".parse().unwrap());
+        headers.insert("x-kobo-sync", "continue".parse().unwrap());
+        let store =
+            StoreSyncResult::from_response(reqwest::StatusCode::OK, &headers, "[]").unwrap();
+        assert_eq!(store.sync_token, "store-token");
+        assert_eq!(store.x_kobo_sync.as_deref(), Some("continue"));
+    }
+}
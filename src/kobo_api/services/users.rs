@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+
+use entities::{devices, user};
+use poem_openapi::payload::Json;
+use reqwest::StatusCode;
+use sea_orm::{
+    ActiveValue::Set, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+    QueryOrder, QuerySelect, sea_query::Expr,
+};
+use uuid::Uuid;
+
+use crate::{
+    AbsKoboResult,
+    abs_client::{AbsClient, upstream_status},
+    config::redact_secret,
+    db::retry_on_busy,
+    kobo_api::models::{
+        ErrorDto, UserDeleteResponseDto, UserDto, UserListResponseDto, UserResponseDto,
+        ValidateKeyDto, ValidateKeyResponseDto,
+    },
+};
+
+pub struct UserService<'a> {
+    pub client: &'a AbsClient,
+    pub db: &'a DatabaseConnection,
+}
+
+impl<'a> UserService<'a> {
+    pub fn new(client: &'a AbsClient, db: &'a DatabaseConnection) -> Self {
+        Self { client, db }
+    }
+
+    /// Check an ABS API key against `/api/libraries` without storing it
+    #[tracing::instrument(level = "debug", skip(self, api_key))]
+    pub async fn validate_key(&self, api_key: &String) -> ValidateKeyResponseDto {
+        match self.accessible_libraries(api_key).await {
+            Ok(libraries) => ValidateKeyResponseDto::Ok(Json(ValidateKeyDto {
+                valid: libraries.is_some(),
+                libraries: libraries.unwrap_or_default(),
+            })),
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), "failed to validate ABS API key");
+                ValidateKeyResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Libraries the key can access, `None` when ABS rejects the key
+    pub async fn accessible_libraries(&self, api_key: &String) -> AbsKoboResult<Option<Vec<Uuid>>> {
+        match self.client.get_libraries(api_key).await {
+            Ok(libs) => Ok(Some(libs.libraries.into_iter().map(|l| l.id).collect())),
+            Err(e)
+                if matches!(
+                    upstream_status(&e),
+                    Some(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Trimmed API key, or the response rejecting it when it is empty or ABS does not accept it
+    async fn checked_key(&self, api_key: &str) -> Result<String, UserResponseDto> {
+        let api_key = api_key.trim().to_string();
+        if api_key.is_empty() {
+            return Err(UserResponseDto::BadRequest(Json(ErrorDto {
+                message: "abs_api_key must not be empty".into(),
+            })));
+        }
+        match self.accessible_libraries(&api_key).await {
+            Ok(Some(_)) => Ok(api_key),
+            Ok(None) => Err(UserResponseDto::BadRequest(Json(ErrorDto {
+                message: "ABS rejected the API key".into(),
+            }))),
+            Err(e) => {
+                tracing::error!(error = %format!("{:?}", e), "failed to check ABS API key");
+                Err(UserResponseDto::BadGateway(Json(ErrorDto {
+                    message: format!("ABS error: {}", e),
+                })))
+            }
+        }
+    }
+
+    /// All users with the number of devices they own
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn list(&self) -> UserListResponseDto {
+        match self.users().await {
+            Ok(users) => UserListResponseDto::Ok(Json(users)),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to list users");
+                UserListResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn users(&self) -> AbsKoboResult<Vec<UserDto>> {
+        let owners: Vec<Uuid> = devices::Entity::find()
+            .select_only()
+            .column(devices::Column::OwnerId)
+            .into_tuple()
+            .all(self.db)
+            .await?;
+        let mut device_counts: HashMap<Uuid, u64> = HashMap::new();
+        for owner in owners {
+            *device_counts.entry(owner).or_default() += 1;
+        }
+        Ok(user::Entity::find()
+            .order_by_asc(user::Column::Id)
+            .all(self.db)
+            .await?
+            .into_iter()
+            .map(|u| UserDto {
+                devices: device_counts.get(&u.id).copied().unwrap_or(0),
+                id: u.id,
+                abs_api_key: redact_secret(&u.abs_api_key),
+            })
+            .collect())
+    }
+
+    /// Id of the user syncing with `abs_api_key`, creating the user if there is none yet. The key
+    /// is stored as given, check it with ABS first.
+    pub async fn find_or_create(&self, abs_api_key: &str) -> AbsKoboResult<Uuid> {
+        if let Some(user) = user::Entity::find()
+            .filter(user::Column::AbsApiKey.eq(abs_api_key))
+            .one(self.db)
+            .await?
+        {
+            return Ok(user.id);
+        }
+        let id = Uuid::now_v7();
+        retry_on_busy(|| {
+            user::Entity::insert(user::ActiveModel {
+                id: Set(id),
+                abs_api_key: Set(abs_api_key.to_string()),
+            })
+            .exec(self.db)
+        })
+        .await?;
+        Ok(id)
+    }
+
+    /// Create a user syncing with an ABS API key, once ABS accepted the key
+    #[tracing::instrument(level = "debug", skip(self, abs_api_key))]
+    pub async fn create(&self, abs_api_key: &str) -> UserResponseDto {
+        let abs_api_key = match self.checked_key(abs_api_key).await {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+        let id = Uuid::now_v7();
+        let insert = retry_on_busy(|| {
+            user::Entity::insert(user::ActiveModel {
+                id: Set(id),
+                abs_api_key: Set(abs_api_key.clone()),
+            })
+            .exec(self.db)
+        })
+        .await;
+        match insert {
+            Ok(_) => UserResponseDto::Created(Json(UserDto {
+                id,
+                abs_api_key: redact_secret(&abs_api_key),
+                devices: 0,
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, "failed to create user");
+                UserResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Replace a user's ABS API key, once ABS accepted the new key
+    #[tracing::instrument(level = "debug", skip(self, abs_api_key))]
+    pub async fn update(&self, user_id: Uuid, abs_api_key: &str) -> UserResponseDto {
+        let abs_api_key = match self.checked_key(abs_api_key).await {
+            Ok(key) => key,
+            Err(response) => return response,
+        };
+        match self.update_key(user_id, &abs_api_key).await {
+            Ok(Some(devices)) => UserResponseDto::Ok(Json(UserDto {
+                id: user_id,
+                abs_api_key: redact_secret(&abs_api_key),
+                devices,
+            })),
+            Ok(None) => UserResponseDto::NotFound(Json(ErrorDto {
+                message: "User not found".into(),
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, %user_id, "failed to update user");
+                UserResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+
+    /// Number of devices of the updated user, `None` for an unknown user
+    async fn update_key(&self, user_id: Uuid, abs_api_key: &str) -> AbsKoboResult<Option<u64>> {
+        let updated = retry_on_busy(|| {
+            user::Entity::update_many()
+                .col_expr(user::Column::AbsApiKey, Expr::value(abs_api_key))
+                .filter(user::Column::Id.eq(user_id))
+                .exec(self.db)
+        })
+        .await?;
+        if updated.rows_affected == 0 {
+            return Ok(None);
+        }
+        Ok(Some(
+            devices::Entity::find()
+                .filter(devices::Column::OwnerId.eq(user_id))
+                .count(self.db)
+                .await?,
+        ))
+    }
+
+    /// Delete a user; their devices and sync history go with them
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn delete(&self, user_id: Uuid) -> UserDeleteResponseDto {
+        match retry_on_busy(|| user::Entity::delete_by_id(user_id).exec(self.db)).await {
+            Ok(res) if res.rows_affected == 0 => UserDeleteResponseDto::NotFound(Json(ErrorDto {
+                message: "User not found".into(),
+            })),
+            Ok(_) => UserDeleteResponseDto::NoContent,
+            Err(e) => {
+                tracing::error!(error = %e, %user_id, "failed to delete user");
+                UserDeleteResponseDto::InternalServerError(Json(ErrorDto {
+                    message: format!("Database error: {}", e),
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{
+        Request, Route, get, handler, http::StatusCode as PoemStatus, web::Json as PoemJson,
+    };
+
+    use super::*;
+
+    #[handler]
+    fn libraries(req: &Request) -> poem::Result<PoemJson<serde_json::Value>> {
+        if req.header("Authorization") != Some("Bearer good-key") {
+            return Err(poem::Error::from_status(PoemStatus::UNAUTHORIZED));
+        }
+        Ok(PoemJson(serde_json::json!({
+            "libraries": [
+                { "id": "33ed2665-4521-4a70-93f1-f49b29e39bfe", "name": "Books", "folders": [] }
+            ]
+        })))
+    }
+
+    async fn validate(key: &str) -> ValidateKeyDto {
+        let base =
+            crate::test_support::serve(Route::new().at("/api/libraries", get(libraries))).await;
+        let client = AbsClient::new(base).unwrap();
+        let (db, _) = crate::test_support::db_with_device().await;
+        match UserService::new(&client, &db)
+            .validate_key(&key.to_string())
+            .await
+        {
+            ValidateKeyResponseDto::Ok(Json(dto)) => dto,
+            ValidateKeyResponseDto::BadGateway(Json(e)) => {
+                panic!("unexpected error: {}", e.message)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn valid_key_lists_libraries() {
+        let dto = validate("good-key").await;
+        assert!(dto.valid);
+        assert_eq!(
+            dto.libraries,
+            vec![uuid::Uuid::parse_str("33ed2665-4521-4a70-93f1-f49b29e39bfe").unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_key_is_reported() {
+        let dto = validate("bad-key").await;
+        assert!(!dto.valid);
+        assert!(dto.libraries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn users_are_managed_with_keys_abs_accepts() {
+        let base =
+            crate::test_support::serve(Route::new().at("/api/libraries", get(libraries))).await;
+        let client = AbsClient::new(base).unwrap();
+        let (db, device_id) = crate::test_support::db_with_device().await;
+        let service = UserService::new(&client, &db);
+
+        assert!(matches!(
+            service.create("bad-key").await,
+            UserResponseDto::BadRequest(_)
+        ));
+        let UserResponseDto::Created(Json(created)) = service.create(" good-key ").await else {
+            panic!("expected the user to be created");
+        };
+        assert_eq!(created.devices, 0);
+        let stored = user::Entity::find_by_id(created.id)
+            .one(&db)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.abs_api_key, "good-key");
+
+        let UserListResponseDto::Ok(Json(users)) = service.list().await else {
+            panic!("expected the users");
+        };
+        assert_eq!(users.len(), 2);
+        let owner = users.iter().find(|u| u.id != created.id).unwrap();
+        assert_eq!(owner.devices, 1);
+        assert_eq!(owner.abs_api_key, "****");
+
+        assert!(matches!(
+            service.update(owner.id, "").await,
+            UserResponseDto::BadRequest(_)
+        ));
+        assert!(matches!(
+            service.update(Uuid::now_v7(), "good-key").await,
+            UserResponseDto::NotFound(_)
+        ));
+        let UserResponseDto::Ok(Json(updated)) = service.update(owner.id, "good-key").await else {
+            panic!("expected the user to be updated");
+        };
+        assert_eq!(updated.devices, 1);
+
+        // Deleting a user takes their devices with them
+        assert!(matches!(
+            service.delete(owner.id).await,
+            UserDeleteResponseDto::NoContent
+        ));
+        assert!(
+            devices::Entity::find_by_id(device_id)
+                .one(&db)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            service.delete(owner.id).await,
+            UserDeleteResponseDto::NotFound(_)
+        ));
+    }
+}
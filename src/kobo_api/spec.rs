@@ -0,0 +1,155 @@
+use poem::{
+    Endpoint, Request, Response,
+    http::{HeaderMap, header},
+};
+
+/// Serve the OpenAPI spec with its `servers` entry pointing at the host the request came in on,
+/// so "Try it" in the docs UI works behind reverse proxies. `fallback_server` is used when the
+/// request carries no usable host header.
+pub fn spec_endpoint(spec: String, fallback_server: String) -> impl Endpoint {
+    let spec: serde_json::Value = serde_json::from_str(&spec).expect("OpenAPI spec is valid JSON");
+    poem::endpoint::make_sync(move |req: Request| {
+        let server = infer_server_url(req.headers()).unwrap_or_else(|| fallback_server.clone());
+        Response::builder()
+            .content_type("application/json")
+            .body(spec_with_server(&spec, &server))
+    })
+}
+
+/// Build `scheme://host` from `X-Forwarded-Proto`/`X-Forwarded-Host`, falling back to `Host`.
+pub fn infer_server_url(headers: &HeaderMap) -> Option<String> {
+    let host = first_value(headers, "x-forwarded-host")
+        .or_else(|| first_value(headers, header::HOST.as_str()))?;
+    let scheme = forwarded_proto(headers).unwrap_or_else(|| "http".to_string());
+    Some(format!("{}://{}", scheme, host))
+}
+
+/// Scheme the client used according to the reverse proxy's `X-Forwarded-Proto`, lowercased
+pub fn forwarded_proto(headers: &HeaderMap) -> Option<String> {
+    first_value(headers, "x-forwarded-proto").map(|v| v.to_ascii_lowercase())
+}
+
+/// First entry of a possibly comma separated header, as proxies append to forwarded headers
+fn first_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn spec_with_server(spec: &serde_json::Value, server: &str) -> String {
+    let mut spec = spec.clone();
+    spec["servers"] = serde_json::json!([{ "url": server }]);
+    spec.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::{http::StatusCode, test::TestClient};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn forwarded_host_appears_in_spec() {
+        let spec = r#"{"openapi":"3.0.0","servers":[{"url":"http://localhost:3000"}]}"#;
+        let cli = TestClient::new(spec_endpoint(
+            spec.to_string(),
+            "http://localhost:3000".to_string(),
+        ));
+
+        let resp = cli
+            .get("/")
+            .header("Host", "internal:3000")
+            .header("X-Forwarded-Host", "books.example.com")
+            .header("X-Forwarded-Proto", "https")
+            .send()
+            .await;
+        resp.assert_status(StatusCode::OK);
+        let json = resp.json().await;
+        json.value()
+            .object()
+            .get("servers")
+            .array()
+            .get(0)
+            .object()
+            .get("url")
+            .assert_string("https://books.example.com");
+    }
+
+    #[test]
+    fn host_header_without_forwarding() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "kobo.lan:3000".parse().unwrap());
+        assert_eq!(
+            infer_server_url(&headers).as_deref(),
+            Some("http://kobo.lan:3000")
+        );
+        assert_eq!(infer_server_url(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn full_spec_builds() {
+        use poem_openapi::OpenApiService;
+
+        let (db, _) = crate::test_support::db_with_device().await;
+        let config = crate::test_support::config("http://abs.local", uuid::Uuid::nil());
+        let api = crate::test_support::api(config, db);
+        let spec: serde_json::Value =
+            serde_json::from_str(&OpenApiService::new(api, "ABS Kobo API", "test").spec()).unwrap();
+
+        let paths = spec["paths"].as_object().unwrap();
+        for path in ["/kobo/{auth_token}/v1/library/sync", "/v1/libraries"] {
+            assert!(paths.contains_key(path), "missing {}", path);
+        }
+    }
+
+    /// poem-openapi keys schemas by name only, so two DTOs sharing a name would silently
+    /// overwrite each other in the spec
+    #[test]
+    fn dto_schema_names_are_unique() {
+        let sources = [
+            include_str!("models/mod.rs"),
+            include_str!("models/kobo.rs"),
+        ];
+        let mut names = std::collections::HashMap::new();
+        for source in sources {
+            let mut schema = false;
+            let mut rename = None;
+            for line in source.lines().map(str::trim) {
+                if line.starts_with("#[derive(")
+                    && ["Object", "Enum", "Union", "NewType"]
+                        .iter()
+                        .any(|d| line.contains(d))
+                {
+                    schema = true;
+                } else if schema && line.starts_with("#[oai(") {
+                    rename = line
+                        .split("rename = \"")
+                        .nth(1)
+                        .and_then(|rest| rest.split('"').next());
+                } else if schema
+                    && let Some(decl) = line
+                        .strip_prefix("pub struct ")
+                        .or_else(|| line.strip_prefix("pub enum "))
+                {
+                    let ident = decl
+                        .split(|c: char| !c.is_alphanumeric() && c != '_')
+                        .next()
+                        .unwrap();
+                    let name = rename.take().unwrap_or(ident).to_string();
+                    *names.entry(name).or_insert(0) += 1;
+                    schema = false;
+                }
+            }
+        }
+        assert!(names.len() > 10, "no DTOs found");
+        let duplicates: Vec<_> = names.iter().filter(|(_, n)| **n > 1).collect();
+        assert!(
+            duplicates.is_empty(),
+            "duplicate schema names: {:?}",
+            duplicates
+        );
+    }
+}
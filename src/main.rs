@@ -1,6 +1,14 @@
 mod abs_client;
 mod config;
+mod cover_cache;
+mod db;
+mod kepub_cache;
+mod kepub_jobs;
+mod kepubify;
 mod kobo_api;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
 
 use std::{path::Path, sync::Arc};
 
@@ -47,6 +55,11 @@ async fn main() -> AbsKoboResult<()> {
         dotenvy::from_filename(".env")?;
     };
     let config = Config::load();
+    // `abs_kobo_sync config` prints the resolved configuration and exits
+    if std::env::args().nth(1).as_deref() == Some("config") {
+        print!("{}", config.resolved_summary());
+        return Ok(());
+    }
     match config.validate() {
         Ok(_) => {}
         Err(e) => {
@@ -66,14 +79,9 @@ async fn main() -> AbsKoboResult<()> {
     let has_api_key = !config.abs_api_key.is_empty();
     tracing::info!(abs_base = %config.abs_base_url, has_api_key, "configured ABS client");
 
-    // let status = client.get_status().await?;
-
-    // eprintln!(
-    //     "ABS Version is: {}",
-    //     status
-    //         .server_version
-    //         .context("Failed to get server version")?
-    // );
+    kobo_api::services::health::HealthService::new(&client)
+        .startup_check(config.startup_abs_check, config.min_abs_version)
+        .await?;
 
     // let libraries = client.get_libraries().await?;
 
@@ -100,21 +108,89 @@ pub async fn run_poem(
     db: Arc<sea_orm::DatabaseConnection>,
 ) -> AbsKoboResult<()> {
     let version = env!("CARGO_PKG_VERSION");
-    let api = kobo_api::AbsKoboApi { client, config, db };
-    let api_service =
-        OpenApiService::new(api, "ABS Kobo API", version).server("http://localhost:3000");
+    let bind_addr = config.bind_addr().to_string();
+    let body_logging = telemetry::BodyLogging::new(config.log_redact_keys.clone());
+    let access_log_json = config.flags().access_log_json;
+    if config.api_token.is_none() {
+        tracing::warn!("API_TOKEN is not set, management endpoints are open to anyone");
+    }
+    let management_auth = kobo_api::auth::ManagementAuth::new(config.api_token.clone());
+    let device_auth = kobo_api::auth::DeviceAuth::new(db.clone());
+    let user_rate_limit =
+        kobo_api::rate_limit::UserRateLimit::new(config.user_rate_limit_per_min, db.clone());
+    if config.sync_history_retention_days.is_some() {
+        tokio::spawn(kobo_api::services::sync::run_sync_history_pruning(
+            client.clone(),
+            config.clone(),
+            db.clone(),
+        ));
+    }
+    if config.flags().warmup_abs {
+        let client = client.clone();
+        tokio::spawn(async move { client.warmup().await });
+    }
+    #[cfg(feature = "tls")]
+    let tls = tls_config(&config).await?;
+    let download_limit = Arc::new(kobo_api::download_limit::DownloadLimit::from_config(
+        &config,
+    ));
+    let api = kobo_api::AbsKoboApi {
+        client,
+        config,
+        db,
+        download_limit,
+        kepub_jobs: Arc::default(),
+    };
+    let fallback_server = "http://localhost:3000";
+    let api_service = OpenApiService::new(api, "ABS Kobo API", version).server(fallback_server);
     //.extra_request_header(poem_openapi::ExtraHeader::new("X-Abs-Kobo-Version"))
     let ui = api_service.rapidoc();
     let spec = api_service.spec();
     let route = Route::new()
         .nest("/", api_service)
         .nest("/ui", ui)
-        .nest("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
+        .nest(
+            "/spec",
+            kobo_api::spec::spec_endpoint(spec, fallback_server.to_string()),
+        )
+        .with(management_auth)
+        .with(device_auth)
+        .with(user_rate_limit)
+        .with(kobo_api::path::KoboPathNormalize)
+        .with(kobo_api::probe::KoboOptionsProbe)
         .with(Cors::new())
-        .with(PoemTracing);
+        .with(body_logging)
+        .with_if(!access_log_json, PoemTracing)
+        .with_if(access_log_json, telemetry::JsonAccessLog::stdout());
 
-    let bind_addr = "0.0.0.0:3000";
+    let listener = TcpListener::bind(bind_addr.clone());
+    #[cfg(feature = "tls")]
+    if let Some(tls) = tls {
+        use poem::listener::Listener;
+        tracing::info!(%bind_addr, "starting HTTPS server");
+        Server::new(listener.rustls(tls)).run(route).await?;
+        return Ok(());
+    }
     tracing::info!(%bind_addr, "starting HTTP server");
-    Server::new(TcpListener::bind(bind_addr)).run(route).await?;
+    Server::new(listener).run(route).await?;
     Ok(())
 }
+
+/// Certificate and key of `TLS_CERT_PATH`/`TLS_KEY_PATH`, `None` to serve plain HTTP
+#[cfg(feature = "tls")]
+async fn tls_config(config: &Config) -> AbsKoboResult<Option<poem::listener::RustlsConfig>> {
+    use poem::listener::{RustlsCertificate, RustlsConfig};
+
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) else {
+        return Ok(None);
+    };
+    let cert = tokio::fs::read(cert_path)
+        .await
+        .with_context(|| format!("Failed to read TLS_CERT_PATH {}", cert_path.display()))?;
+    let key = tokio::fs::read(key_path)
+        .await
+        .with_context(|| format!("Failed to read TLS_KEY_PATH {}", key_path.display()))?;
+    Ok(Some(
+        RustlsConfig::new().fallback(RustlsCertificate::new().cert(cert).key(key)),
+    ))
+}
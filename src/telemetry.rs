@@ -0,0 +1,233 @@
+// Request/response body logging with redaction of sensitive JSON keys, and JSON access logs
+
+use std::{sync::Arc, time::Instant};
+
+use poem::{
+    Body, Endpoint, IntoResponse, Middleware, Request, Response, Result, http::HeaderMap,
+    http::header,
+};
+use serde_json::json;
+
+use crate::kobo_api::rate_limit::device_token;
+
+const REDACTED: &str = "***";
+
+/// Logs JSON request and response bodies at debug level, masking the values of `redact_keys`
+pub struct BodyLogging {
+    redact_keys: Arc<Vec<String>>,
+}
+
+impl BodyLogging {
+    pub fn new(redact_keys: Vec<String>) -> Self {
+        Self {
+            redact_keys: Arc::new(redact_keys),
+        }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for BodyLogging {
+    type Output = BodyLoggingEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        BodyLoggingEndpoint {
+            inner,
+            redact_keys: self.redact_keys.clone(),
+        }
+    }
+}
+
+pub struct BodyLoggingEndpoint<E> {
+    inner: E,
+    redact_keys: Arc<Vec<String>>,
+}
+
+impl<E: Endpoint> Endpoint for BodyLoggingEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        if !tracing::enabled!(tracing::Level::DEBUG) {
+            return self.inner.call(req).await.map(IntoResponse::into_response);
+        }
+
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+        if is_json(req.headers()) {
+            let bytes = req.take_body().into_bytes().await?;
+            tracing::debug!(%method, %uri, body = %redact_body(&bytes, &self.redact_keys), "request body");
+            req.set_body(Body::from_bytes(bytes));
+        }
+
+        let mut resp = self.inner.call(req).await?.into_response();
+        if is_json(resp.headers()) {
+            let bytes = resp.take_body().into_bytes().await?;
+            tracing::debug!(%method, %uri, status = %resp.status(), body = %redact_body(&bytes, &self.redact_keys), "response body");
+            resp.set_body(Body::from_bytes(bytes));
+        }
+        Ok(resp)
+    }
+}
+
+/// Destination of access log lines
+type AccessLogSink = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Writes one JSON line per request (method, path, status, latency and the device of `/kobo`
+/// routes) for log aggregators, in place of poem's human-readable request tracing
+pub struct JsonAccessLog {
+    sink: AccessLogSink,
+}
+
+impl JsonAccessLog {
+    /// Access log written to stdout, next to but independent of the tracing output
+    pub fn stdout() -> Self {
+        Self::new(Arc::new(|line| println!("{}", line)))
+    }
+
+    pub fn new(sink: AccessLogSink) -> Self {
+        Self { sink }
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for JsonAccessLog {
+    type Output = JsonAccessLogEndpoint<E>;
+
+    fn transform(&self, inner: E) -> Self::Output {
+        JsonAccessLogEndpoint {
+            inner,
+            sink: self.sink.clone(),
+        }
+    }
+}
+
+pub struct JsonAccessLogEndpoint<E> {
+    inner: E,
+    sink: AccessLogSink,
+}
+
+impl<E: Endpoint> Endpoint for JsonAccessLogEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> Result<Self::Output> {
+        let started = Instant::now();
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let res = self.inner.call(req).await.map(IntoResponse::into_response);
+        let status = match &res {
+            Ok(resp) => resp.status(),
+            Err(e) => e.status(),
+        };
+        let line = json!({
+            "timestamp": chrono::Utc::now().to_rfc3339(),
+            "method": method,
+            "path": path,
+            "status": status.as_u16(),
+            "latency_ms": started.elapsed().as_secs_f64() * 1000.0,
+            "device_id": device_token(&path),
+        });
+        (self.sink)(&line.to_string());
+        res
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("json"))
+}
+
+/// Render a body for logging with the values of `redact_keys` masked (keys match case-insensitively)
+pub fn redact_body(bytes: &[u8], redact_keys: &[String]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(mut value) => {
+            redact_value(&mut value, redact_keys);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes of non-JSON body>", bytes.len()),
+    }
+}
+
+fn redact_value(value: &mut serde_json::Value, redact_keys: &[String]) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if redact_keys.iter().any(|k| k.eq_ignore_ascii_case(key)) {
+                    *v = serde_json::Value::String(REDACTED.into());
+                } else {
+                    redact_value(v, redact_keys);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for v in items {
+                redact_value(v, redact_keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn access_log_lines_are_json() {
+        use std::sync::Mutex;
+
+        use poem::{EndpointExt, Route, get, handler, test::TestClient};
+
+        #[handler]
+        fn sync() -> &'static str {
+            "ok"
+        }
+        let lines = Arc::new(Mutex::new(Vec::<String>::new()));
+        let sink = lines.clone();
+        let cli = TestClient::new(
+            Route::new()
+                .at("/kobo/:auth_token/v1/library/sync", get(sync))
+                .with(JsonAccessLog::new(Arc::new(move |line| {
+                    sink.lock().unwrap().push(line.to_string())
+                }))),
+        );
+        let device_id = uuid::Uuid::now_v7();
+
+        cli.get(format!("/kobo/{}/v1/library/sync", device_id))
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.get("/missing")
+            .send()
+            .await
+            .assert_status(poem::http::StatusCode::NOT_FOUND);
+
+        let lines = lines.lock().unwrap();
+        let entries: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries[0]["method"], "GET");
+        assert_eq!(
+            entries[0]["path"],
+            format!("/kobo/{}/v1/library/sync", device_id)
+        );
+        assert_eq!(entries[0]["status"], 200);
+        assert!(entries[0]["latency_ms"].as_f64().is_some());
+        assert_eq!(entries[0]["device_id"], device_id.to_string());
+        assert_eq!(entries[1]["status"], 404);
+        assert!(entries[1]["device_id"].is_null());
+    }
+
+    #[test]
+    fn logged_body_masks_sensitive_keys() {
+        let keys: Vec<String> = ["UserKey", "AccessToken", "RefreshToken", "abs_api_key"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        let body = br#"{"AccessToken":"secret-a","TokenType":"Bearer","Nested":[{"refreshtoken":"secret-r"}],"UserKey":"secret-u"}"#;
+
+        let logged = redact_body(body, &keys);
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains(r#""TokenType":"Bearer""#));
+        assert!(logged.contains(r#""AccessToken":"***""#));
+    }
+}
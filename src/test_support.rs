@@ -0,0 +1,173 @@
+// Helpers shared by unit tests
+
+use std::sync::Arc;
+
+use entities::{devices, user};
+use migration::MigratorTrait;
+use poem::{
+    Endpoint, Server,
+    listener::{Acceptor, Listener, TcpListener},
+};
+use sea_orm::{ActiveValue::Set, Database, DatabaseConnection, EntityTrait};
+use uuid::Uuid;
+
+use crate::{
+    abs_client::{AbsClient, LibraryItemSort},
+    config::{AuthorNameOrder, Config, FeatureFlags, StartupAbsCheck, StoreErrorPolicy},
+    kobo_api::{AbsKoboApi, download_limit::DownloadLimit},
+};
+
+/// Serve `app` on a random local port and return its base URL, e.g. to stand in for ABS
+pub async fn serve(app: impl Endpoint + 'static) -> String {
+    let acceptor = TcpListener::bind("127.0.0.1:0")
+        .into_acceptor()
+        .await
+        .unwrap();
+    let addr = *acceptor.local_addr()[0].as_socket_addr().unwrap();
+    tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+    format!("http://{}", addr)
+}
+
+/// Migrated in-memory database with one user (ABS key "key") owning one device; returns the device id
+pub async fn db_with_device() -> (DatabaseConnection, Uuid) {
+    let db = Database::connect("sqlite::memory:").await.unwrap();
+    migration::Migrator::up(&db, None).await.unwrap();
+    let device_id = insert_device(&db).await;
+    (db, device_id)
+}
+
+/// Insert a user (ABS key "key") owning one device into a migrated database; returns the device id
+pub async fn insert_device(db: &DatabaseConnection) -> Uuid {
+    let user_id = Uuid::now_v7();
+    let device_id = Uuid::now_v7();
+    user::Entity::insert(user::ActiveModel {
+        id: Set(user_id),
+        abs_api_key: Set("key".into()),
+    })
+    .exec(db)
+    .await
+    .unwrap();
+    devices::Entity::insert(devices::ActiveModel {
+        id: Set(device_id),
+        owner_id: Set(user_id),
+        sync_scan_offset: Set(None),
+        proxy_store: Set(None),
+        last_sync_token: Set(None),
+        last_sync_token_at: Set(None),
+        refresh_token: Set(None),
+        sync_tag: Set(None),
+        last_analytics: Set(None),
+        last_analytics_at: Set(None),
+    })
+    .exec(db)
+    .await
+    .unwrap();
+    device_id
+}
+
+/// API over a (mock) ABS server and a migrated in-memory database
+pub fn api(config: Config, db: DatabaseConnection) -> AbsKoboApi {
+    AbsKoboApi {
+        client: Arc::new(AbsClient::new(&config.abs_base_url).unwrap()),
+        download_limit: Arc::new(DownloadLimit::from_config(&config)),
+        config: Arc::new(config),
+        db: Arc::new(db),
+        kepub_jobs: Arc::default(),
+    }
+}
+
+/// Config pointing at a (mock) ABS server with every optional setting at its default
+pub fn config(abs_base_url: &str, library_id: Uuid) -> Config {
+    Config {
+        abs_api_key: "key".into(),
+        abs_base_url: abs_base_url.into(),
+        kepubify_path: "kepubify".into(),
+        db_connection_string: "sqlite::memory:".into(),
+        library_ids: vec![library_id],
+        flags: FeatureFlags::default(),
+        store_error_policy: StoreErrorPolicy::Fallback,
+        kobo_store_url: "https://storeapi.kobo.com".into(),
+        store_cache_secs: 30,
+        store_proxy_timeout_secs: 15,
+        metadata_include: None,
+        log_redact_keys: vec![],
+        startup_abs_check: StartupAbsCheck::Warn,
+        min_abs_version: None,
+        allowed_ebook_formats: vec!["epub".into(), "pdf".into()],
+        sync_filter: None,
+        sync_item_sort: LibraryItemSort::parse("addedAt desc").unwrap(),
+        sync_max_scan_items: None,
+        sync_max_response_kb: None,
+        sync_history_retention_days: None,
+        sync_new_book_grace_secs: 0,
+        fallback_cover_path: None,
+        cover_cache_dir: None,
+        cover_cache_max_mb: 256,
+        kepub_cache_dir: None,
+        kepub_cache_max_mb: 1024,
+        max_concurrent_downloads: 4,
+        max_queued_downloads: 16,
+        user_rate_limit_per_min: None,
+        max_map_failures: 3,
+        author_name_order: AuthorNameOrder::Display,
+        api_token: None,
+        tls_cert_path: None,
+        tls_key_path: None,
+    }
+}
+
+/// Minimal ABS library item (a PDF book) as returned by `/api/libraries/{id}/items`
+pub fn library_item_json(id: Uuid, title: &str) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "ino": "1",
+        "oldLibraryItemId": null,
+        "libraryId": "55b8b4f3-2ec7-460b-8178-e02b8b619c03",
+        "folderId": "381d3393-0028-41fc-95b0-e3a1afb03eec",
+        "path": format!("/books/{}", title),
+        "relPath": title,
+        "isFile": false,
+        "mtimeMs": 1738971721697_i64,
+        "ctimeMs": 1738978324038_i64,
+        "birthtimeMs": 1699116518568_i64,
+        "addedAt": 1703767976342_i64,
+        "updatedAt": 1747214658742_i64,
+        "isMissing": false,
+        "isInvalid": false,
+        "mediaType": "book",
+        "media": {
+            "id": Uuid::now_v7(),
+            "metadata": {
+                "title": title,
+                "authorName": "Jane Doe",
+                "genres": []
+            },
+            "coverPath": format!("/books/{}/cover.jpg", title),
+            "tags": [],
+            "numTracks": 0,
+            "numAudioFiles": 0,
+            "numChapters": 0,
+            "duration": 0,
+            "size": 1024,
+            "ebookFormat": "pdf"
+        },
+        "numFiles": 1,
+        "size": 1024
+    })
+}
+
+/// Stand-in for kepubify that writes its input, prefixed with `kepub:`, to the `-o` path
+#[cfg(unix)]
+pub fn fake_kepubify(dir: &std::path::Path) -> std::path::PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::create_dir_all(dir).unwrap();
+    let path = dir.join("kepubify");
+    std::fs::write(
+        &path,
+        "#!/bin/sh\n[ \"$1\" = --version ] && { echo kepubify v4.0.4; exit 0; }\n[ \"$1\" = -o ] || exit 2\n{ printf 'kepub:'; cat \"$3\"; } > \"$2\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}